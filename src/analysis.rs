@@ -0,0 +1,255 @@
+//! Summaries of the pattern compatibility graph, useful for judging whether a model is likely to
+//! produce contradictions before spending time on generation.
+
+use crate::{
+    pattern::{PatternConstraints, PatternId, PatternSampler},
+    wave::Wave,
+};
+
+use ilattice3 as lat;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+
+pub struct ConstraintGraphSummary {
+    /// For each `OffsetId` (by index), the number of compatible patterns at that offset, averaged
+    /// over all patterns.
+    pub mean_branching: Vec<f32>,
+    /// Patterns with the fewest compatible patterns summed over all offsets, tightest first. Each
+    /// entry carries that sum.
+    pub tightest_patterns: Vec<(PatternId, u32)>,
+    /// Patterns with no compatible patterns at some offset, along with that offset. These can't go
+    /// in any slot with a neighbor at that offset, so they're certain to cause contradictions
+    /// unless they're only ever placed on the output's boundary.
+    pub dead_patterns: Vec<(PatternId, lat::Point)>,
+    /// Patterns whose removal would split the (undirected) compatibility graph into more pieces.
+    pub articulation_patterns: Vec<PatternId>,
+    /// A heuristic in `[0, 1]`. Models scoring close to 0 have sparse, fragile constraints and tend
+    /// to contradict often; models close to 1 are permissive.
+    pub health: f32,
+}
+
+/// Analyze `constraints`, reporting up to `num_tightest` of the most constrained patterns.
+pub fn summarize_constraints(
+    constraints: &PatternConstraints,
+    num_tightest: usize,
+) -> ConstraintGraphSummary {
+    let num_patterns = constraints.num_patterns() as usize;
+    let offset_group = constraints.get_offset_group();
+    let num_offsets = offset_group.num_offsets();
+
+    let mut branching_sums = vec![0u32; num_offsets];
+    let mut pattern_sums = Vec::with_capacity(num_patterns);
+    let mut log_branching_sum = 0.0;
+    let mut neighbors = Vec::with_capacity(num_patterns);
+    let mut dead_patterns = Vec::new();
+    for pattern in (0..num_patterns).map(PatternId::from) {
+        let mut pattern_sum = 0;
+        let mut pattern_neighbors = HashSet::new();
+        for (offset_id, offset) in offset_group.iter() {
+            let num_compatible = constraints.num_compatible(pattern, offset_id);
+            if num_compatible == 0 {
+                dead_patterns.push((pattern, *offset));
+            }
+            branching_sums[offset_id.0] += num_compatible;
+            pattern_sum += num_compatible;
+            log_branching_sum += (1.0 + num_compatible as f32).ln();
+
+            for other in constraints.iter_compatible(pattern, offset_id) {
+                if other != pattern {
+                    pattern_neighbors.insert(other.0 as usize);
+                }
+            }
+        }
+        pattern_sums.push((pattern, pattern_sum));
+        neighbors.push(pattern_neighbors.into_iter().collect());
+    }
+
+    let mean_branching = branching_sums
+        .iter()
+        .map(|sum| *sum as f32 / num_patterns.max(1) as f32)
+        .collect();
+
+    pattern_sums.sort_by_key(|(_, sum)| *sum);
+    pattern_sums.truncate(num_tightest);
+
+    let articulation_patterns = find_articulation_patterns(&neighbors);
+
+    // Each (pattern, offset) pair scores how close it is to allowing every pattern, on a log scale
+    // so that a handful of compatible patterns is already much better than one. Articulation
+    // patterns are penalized since contradictions tend to start around them.
+    let num_pairs = (num_patterns * num_offsets).max(1) as f32;
+    let max_log_branching = (1.0 + num_patterns as f32).ln().max(f32::EPSILON);
+    let branching_score = log_branching_sum / (num_pairs * max_log_branching);
+    let articulation_fraction = articulation_patterns.len() as f32 / num_patterns.max(1) as f32;
+    let health = (branching_score * (1.0 - articulation_fraction)).clamp(0.0, 1.0);
+
+    ConstraintGraphSummary {
+        mean_branching,
+        tightest_patterns: pattern_sums,
+        dead_patterns,
+        articulation_patterns,
+        health,
+    }
+}
+
+/// Counts the patterns by weight, in buckets that double in size. Each entry is the smallest weight
+/// of a bucket and the number of patterns in it, starting with the bucket of 0 and ending with the
+/// bucket of the largest weight.
+pub fn weight_histogram(sampler: &PatternSampler) -> Vec<(u32, usize)> {
+    let bucket = |weight: u32| (32 - weight.leading_zeros()) as usize;
+
+    let mut counts = Vec::new();
+    for pattern in (0..sampler.num_patterns() as usize).map(PatternId::from) {
+        let b = bucket(sampler.get_weight(pattern));
+        if b >= counts.len() {
+            counts.resize(b + 1, 0);
+        }
+        counts[b] += 1;
+    }
+
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(b, count)| (if b == 0 { 0 } else { 1 << (b - 1) }, count))
+        .collect()
+}
+
+/// Roughly how many bytes a `Generator` for `constraints` needs to fill an output of `output_size`
+/// slots. Most of it is proportional to slots × patterns × offsets.
+pub fn estimate_generator_memory(
+    constraints: &PatternConstraints,
+    output_size: lat::Point,
+) -> usize {
+    let num_slots = (output_size.x * output_size.y * output_size.z).max(0) as usize;
+
+    Wave::estimate_memory(
+        num_slots,
+        constraints.num_patterns() as usize,
+        constraints.get_offset_group().num_offsets(),
+    )
+}
+
+/// Writes `constraints` as a Graphviz DOT graph, with a node for each pattern and an edge from `a` to
+/// `b` labeled with every offset where `b` may be placed relative to `a`. Compatibility is
+/// symmetric, so only one offset of each opposite pair is written. Nodes show the image at the path
+/// given by `thumbnail`, if any, e.g. a picture of the pattern's tile.
+pub fn write_constraint_dot<W, F>(
+    mut writer: W,
+    constraints: &PatternConstraints,
+    thumbnail: F,
+) -> io::Result<()>
+where
+    W: Write,
+    F: Fn(PatternId) -> Option<String>,
+{
+    let offset_group = constraints.get_offset_group();
+
+    writeln!(writer, "digraph patterns {{")?;
+    writeln!(writer, "    node [shape=box, labelloc=b];")?;
+    for pattern in (0..constraints.num_patterns() as usize).map(PatternId::from) {
+        match thumbnail(pattern) {
+            Some(path) => writeln!(
+                writer,
+                "    p{0} [label=\"{0}\", image=\"{1}\"];",
+                pattern.0,
+                escape_dot(&path)
+            )?,
+            None => writeln!(writer, "    p{0} [label=\"{0}\"];", pattern.0)?,
+        }
+    }
+
+    for pattern in (0..constraints.num_patterns() as usize).map(PatternId::from) {
+        // Group the offsets by neighbor so each pair of patterns gets a single edge.
+        let mut neighbor_offsets: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        for (offset_id, offset) in offset_group.iter() {
+            if offset_group.opposite(offset_id).0 < offset_id.0 {
+                continue;
+            }
+            for other in constraints.iter_compatible(pattern, offset_id) {
+                neighbor_offsets
+                    .entry(other.0)
+                    .or_default()
+                    .push(offset.to_string());
+            }
+        }
+        for (other, offsets) in neighbor_offsets.iter() {
+            writeln!(
+                writer,
+                "    p{} -> p{} [label=\"{}\"];",
+                pattern.0,
+                other,
+                escape_dot(&offsets.join("\n"))
+            )?;
+        }
+    }
+    writeln!(writer, "}}")?;
+
+    Ok(())
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Tarjan's algorithm, iterative so large models don't overflow the stack.
+fn find_articulation_patterns(neighbors: &[Vec<usize>]) -> Vec<PatternId> {
+    const UNVISITED: usize = usize::MAX;
+
+    let n = neighbors.len();
+    let mut discovery = vec![UNVISITED; n];
+    let mut low = vec![0; n];
+    let mut is_articulation = vec![false; n];
+    let mut time = 0;
+
+    for root in 0..n {
+        if discovery[root] != UNVISITED {
+            continue;
+        }
+        discovery[root] = time;
+        low[root] = time;
+        time += 1;
+        let mut root_children = 0;
+
+        // Each entry is (vertex, parent, index of the next neighbor to visit).
+        let mut stack = vec![(root, UNVISITED, 0)];
+        while let Some(top) = stack.last_mut() {
+            let (v, parent) = (top.0, top.1);
+            if top.2 < neighbors[v].len() {
+                let w = neighbors[v][top.2];
+                top.2 += 1;
+                if discovery[w] == UNVISITED {
+                    discovery[w] = time;
+                    low[w] = time;
+                    time += 1;
+                    if v == root {
+                        root_children += 1;
+                    }
+                    stack.push((w, v, 0));
+                } else if w != parent {
+                    low[v] = low[v].min(discovery[w]);
+                }
+            } else {
+                stack.pop();
+                if parent != UNVISITED {
+                    low[parent] = low[parent].min(low[v]);
+                    if parent != root && low[v] >= discovery[parent] {
+                        is_articulation[parent] = true;
+                    }
+                }
+            }
+        }
+
+        if root_children > 1 {
+            is_articulation[root] = true;
+        }
+    }
+
+    is_articulation
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| **a)
+        .map(|(i, _)| PatternId::from(i))
+        .collect()
+}
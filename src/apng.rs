@@ -0,0 +1,138 @@
+//! A minimal animated PNG encoder. Unlike GIF, APNG keeps every frame in full RGBA.
+
+use flate2::{write::ZlibEncoder, Compression, Crc};
+use image::RgbaImage;
+use std::io::{self, Seek, SeekFrom, Write};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Writes an APNG one frame at a time. All frames must be the same size. The number of frames is
+/// only known at the end, so `finish` seeks back to fill it in.
+pub struct ApngEncoder<W> {
+    writer: W,
+    delay_ms: u16,
+    size: Option<(u32, u32)>,
+    /// Where the acTL chunk starts.
+    actl_position: u64,
+    num_frames: u32,
+    /// Shared by the fcTL and fdAT chunks.
+    sequence_number: u32,
+}
+
+impl<W: Write + Seek> ApngEncoder<W> {
+    /// Each frame is shown for `delay_ms`, and the animation loops forever.
+    pub fn new(writer: W, delay_ms: u16) -> Self {
+        ApngEncoder {
+            writer,
+            delay_ms,
+            size: None,
+            actl_position: 0,
+            num_frames: 0,
+            sequence_number: 0,
+        }
+    }
+
+    pub fn encode_frame(&mut self, frame: &RgbaImage) -> io::Result<()> {
+        let (width, height) = frame.dimensions();
+        match self.size {
+            None => self.write_header(width, height)?,
+            Some(size) if size != (width, height) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Frame is {}x{} but the animation is {}x{}",
+                        width, height, size.0, size.1
+                    ),
+                ))
+            }
+            Some(_) => (),
+        }
+
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend_from_slice(&self.next_sequence_number().to_be_bytes());
+        fctl.extend_from_slice(&width.to_be_bytes());
+        fctl.extend_from_slice(&height.to_be_bytes());
+        fctl.extend_from_slice(&[0; 8]); // Offset.
+        fctl.extend_from_slice(&self.delay_ms.to_be_bytes());
+        fctl.extend_from_slice(&1000u16.to_be_bytes());
+        // Don't dispose, and replace the previous frame instead of blending with it.
+        fctl.extend_from_slice(&[0, 0]);
+        write_chunk(&mut self.writer, b"fcTL", &fctl)?;
+
+        // Every scanline uses filter type 0 (none).
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for row in frame.as_raw().chunks(width as usize * 4) {
+            encoder.write_all(&[0])?;
+            encoder.write_all(row)?;
+        }
+        let data = encoder.finish()?;
+        if self.num_frames == 0 {
+            // The first frame is also the default image.
+            write_chunk(&mut self.writer, b"IDAT", &data)?;
+        } else {
+            let mut fdat = Vec::with_capacity(data.len() + 4);
+            fdat.extend_from_slice(&self.next_sequence_number().to_be_bytes());
+            fdat.extend_from_slice(&data);
+            write_chunk(&mut self.writer, b"fdAT", &fdat)?;
+        }
+        self.num_frames += 1;
+
+        Ok(())
+    }
+
+    /// Writes the end of the file and the final frame count. Nothing is written if there were no
+    /// frames.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.size.is_none() {
+            return Ok(self.writer);
+        }
+        write_chunk(&mut self.writer, b"IEND", &[])?;
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.actl_position))?;
+        write_chunk(&mut self.writer, b"acTL", &actl(self.num_frames))?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self, width: u32, height: u32) -> io::Result<()> {
+        self.writer.write_all(&PNG_SIGNATURE)?;
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        // 8-bit RGBA, no interlacing.
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_chunk(&mut self.writer, b"IHDR", &ihdr)?;
+        self.actl_position = self.writer.stream_position()?;
+        write_chunk(&mut self.writer, b"acTL", &actl(0))?;
+        self.size = Some((width, height));
+
+        Ok(())
+    }
+
+    fn next_sequence_number(&mut self) -> u32 {
+        self.sequence_number += 1;
+
+        self.sequence_number - 1
+    }
+}
+
+/// The animation control chunk, for a looping animation.
+fn actl(num_frames: u32) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[..4].copy_from_slice(&num_frames.to_be_bytes());
+
+    data
+}
+
+fn write_chunk<W: Write>(writer: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(chunk_type)?;
+    writer.write_all(data)?;
+    writer.write_all(&crc.sum().to_be_bytes())
+}
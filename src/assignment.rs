@@ -0,0 +1,181 @@
+//! Exporting the raw pattern assignment of an output, for pipelines that place the tiles
+//! themselves.
+
+use crate::pattern::{PatternId, PatternIndex, PatternTileSet};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, Indexer, VecLatticeMap};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::Path;
+use std::{error, fmt};
+
+const ASSIGNMENT_MAGIC: [u8; 4] = *b"WFCA";
+const ASSIGNMENT_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AssignmentFormat {
+    Json,
+    Ron,
+    /// Little-endian, with a header of "WFCA", the version (1), and the output size as 3 `i32`s,
+    /// followed by the pattern of each point as a `u32`. Then the tile size as 3 `i32`s and the
+    /// number of patterns as a `u32`, followed by the voxels of each pattern's tile, each encoded
+    /// with bincode. For `u8`, `u32`, and `[u8; 4]` voxels that's just their bytes.
+    Binary,
+}
+
+impl AssignmentFormat {
+    /// Guesses the format from the extension of `path`: "json", "ron", or anything else for
+    /// binary.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => AssignmentFormat::Json,
+            Some("ron") => AssignmentFormat::Ron,
+            _ => AssignmentFormat::Binary,
+        }
+    }
+}
+
+/// The pattern at each point of an output, along with the tile of each pattern. Points and tile
+/// voxels are both listed with X varying fastest, then Y, then Z.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PatternAssignment<T> {
+    pub size: [i32; 3],
+    pub patterns: Vec<PatternIndex>,
+    pub tile_size: [i32; 3],
+    /// The voxels of each pattern's tile, indexed by pattern.
+    pub tiles: Vec<Vec<T>>,
+}
+
+impl<T: Clone> PatternAssignment<T> {
+    pub fn new<I: Clone + Indexer>(
+        assignment: &VecLatticeMap<PatternId>,
+        tiles: &PatternTileSet<T, I>,
+    ) -> Self {
+        let extent = assignment.get_extent();
+        let patterns = xyz_order(*extent.get_local_supremum())
+            .map(|p| assignment.get_world(&(*extent.get_minimum() + p)).0)
+            .collect();
+
+        let tile_extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), tiles.tile_size);
+        let tile_voxels = tiles
+            .tiles
+            .get_raw()
+            .iter()
+            .map(|tile| {
+                let tile_lattice = tile.clone().put_in_extent(tile_extent);
+
+                xyz_order(tiles.tile_size)
+                    .map(|p| tile_lattice.get_world(&p))
+                    .collect()
+            })
+            .collect();
+
+        PatternAssignment {
+            size: (*extent.get_local_supremum()).into(),
+            patterns,
+            tile_size: tiles.tile_size.into(),
+            tiles: tile_voxels,
+        }
+    }
+}
+
+impl<T: Serialize> PatternAssignment<T> {
+    pub fn write<W: Write>(
+        &self,
+        mut writer: W,
+        format: AssignmentFormat,
+    ) -> Result<(), AssignmentError> {
+        match format {
+            AssignmentFormat::Json => serde_json::to_writer(writer, self)?,
+            AssignmentFormat::Ron => {
+                let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+                writer.write_all(text.as_bytes())?;
+            }
+            AssignmentFormat::Binary => {
+                writer.write_all(&ASSIGNMENT_MAGIC)?;
+                writer.write_all(&ASSIGNMENT_VERSION.to_le_bytes())?;
+                write_i32s(&mut writer, &self.size)?;
+                for pattern in self.patterns.iter() {
+                    writer.write_all(&pattern.to_le_bytes())?;
+                }
+                write_i32s(&mut writer, &self.tile_size)?;
+                writer.write_all(&(self.tiles.len() as u32).to_le_bytes())?;
+                for voxel in self.tiles.iter().flatten() {
+                    bincode::serialize_into(&mut writer, voxel)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn xyz_order(size: lat::Point) -> impl Iterator<Item = lat::Point> {
+    (0..size.z).flat_map(move |z| {
+        (0..size.y).flat_map(move |y| (0..size.x).map(move |x| [x, y, z].into()))
+    })
+}
+
+fn write_i32s<W: Write>(writer: &mut W, values: &[i32]) -> io::Result<()> {
+    for v in values.iter() {
+        writer.write_all(&v.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum AssignmentError {
+    Io(io::Error),
+    Ron(ron::Error),
+    Json(serde_json::Error),
+    Encoding(bincode::Error),
+}
+
+impl fmt::Display for AssignmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssignmentError::Io(e) => write!(f, "{}", e),
+            AssignmentError::Ron(e) => write!(f, "{}", e),
+            AssignmentError::Json(e) => write!(f, "{}", e),
+            AssignmentError::Encoding(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for AssignmentError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AssignmentError::Io(e) => Some(e),
+            AssignmentError::Ron(e) => Some(e),
+            AssignmentError::Json(e) => Some(e),
+            AssignmentError::Encoding(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for AssignmentError {
+    fn from(e: io::Error) -> Self {
+        AssignmentError::Io(e)
+    }
+}
+
+impl From<ron::Error> for AssignmentError {
+    fn from(e: ron::Error) -> Self {
+        AssignmentError::Ron(e)
+    }
+}
+
+impl From<serde_json::Error> for AssignmentError {
+    fn from(e: serde_json::Error) -> Self {
+        AssignmentError::Json(e)
+    }
+}
+
+impl From<bincode::Error> for AssignmentError {
+    fn from(e: bincode::Error) -> Self {
+        AssignmentError::Encoding(e)
+    }
+}
@@ -0,0 +1,51 @@
+//! Running a `Generator` on an async executor, e.g. in a web service.
+
+use crate::{
+    generate::{Generator, UpdateResult},
+    pattern::{PatternConstraints, PatternSampler},
+};
+
+use rand::Rng;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+impl<R: Rng> Generator<R> {
+    /// Like `run_to_completion`, but yields to the executor after every `updates_per_yield` updates
+    /// so other tasks aren't starved. Apply timeouts with the executor's own tools, e.g.
+    /// `tokio::time::timeout`. If the future is dropped before it finishes, the generator can still
+    /// be resumed.
+    pub async fn run_async(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        updates_per_yield: usize,
+    ) -> UpdateResult {
+        loop {
+            match self.run_steps(sampler, constraints, updates_per_yield.max(1)) {
+                UpdateResult::Continue => YieldNow { yielded: false }.await,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns `Pending` once, after asking to be polled again, so the executor can run other tasks
+/// first.
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+
+        Poll::Pending
+    }
+}
@@ -0,0 +1,156 @@
+//! Running many generators from one model in parallel.
+
+use crate::{
+    error::WfcError,
+    generate::{
+        CancellationToken, GenerationError, Generator, GeneratorConfig, UpdateResult,
+        NUM_SEED_BYTES,
+    },
+    model::Model,
+    pattern::PatternId,
+};
+
+use ilattice3 as lat;
+use ilattice3::VecLatticeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// Generates one output for each seed, on `num_threads` threads that share `model`. Each output
+/// only depends on its seed, so the results (in the same order as `seeds`) are the same for any
+/// number of threads. There are no retries; a failed seed gives the contradiction that ended it.
+/// Returns an error iff `config` is invalid for the output.
+pub fn generate_batch<T>(
+    model: &Model<T>,
+    seeds: &[[u8; NUM_SEED_BYTES]],
+    output_size: lat::Point,
+    config: &GeneratorConfig,
+    num_threads: usize,
+) -> Result<Vec<Result<VecLatticeMap<PatternId>, GenerationError>>, WfcError>
+where
+    T: 'static + Send + Sync,
+{
+    config.validate(output_size, model.constraints().num_patterns())?;
+
+    let seeds = Arc::new(seeds.to_vec());
+    let next_seed = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    let workers: Vec<_> = (0..num_threads.max(1).min(seeds.len()))
+        .map(|_| {
+            let model = model.clone();
+            let config = config.clone();
+            let seeds = seeds.clone();
+            let next_seed = next_seed.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || loop {
+                let i = next_seed.fetch_add(1, Ordering::SeqCst);
+                if i >= seeds.len() {
+                    break;
+                }
+                let result = generate_one(&model, seeds[i], output_size, &config);
+                if sender.send((i, result)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+    // Only the workers hold senders now, so the receiver stops when they're all done.
+    drop(sender);
+
+    let mut results: Vec<_> = seeds.iter().map(|_| None).collect();
+    for (i, result) in receiver.into_iter() {
+        results[i] = Some(result);
+    }
+    for worker in workers.into_iter() {
+        worker.join().expect("Batch worker thread panicked");
+    }
+
+    Ok(results
+        .into_iter()
+        .map(|result| result.expect("Every seed is generated"))
+        .collect())
+}
+
+/// Runs a generator for each seed on its own thread, sharing `model`, and returns the first
+/// output to succeed along with the index of its seed. The other generators are cancelled as soon
+/// as one succeeds. For models that often contradict, this tends to be faster than retrying seeds
+/// one after another, but which seed wins depends on timing. Returns `None` if every seed fails or
+/// `cancel` is triggered, and an error iff `config` is invalid for the output.
+pub fn race_seeds<T>(
+    model: &Model<T>,
+    seeds: &[[u8; NUM_SEED_BYTES]],
+    output_size: lat::Point,
+    config: &GeneratorConfig,
+    cancel: &CancellationToken,
+) -> Result<Option<(usize, VecLatticeMap<PatternId>)>, WfcError>
+where
+    T: 'static + Send + Sync,
+{
+    config.validate(output_size, model.constraints().num_patterns())?;
+
+    let finished = CancellationToken::new();
+    let (sender, receiver) = mpsc::channel();
+
+    let racers: Vec<_> = seeds
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(i, seed)| {
+            let model = model.clone();
+            let config = config.clone();
+            let cancel = cancel.clone();
+            let finished = finished.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || {
+                let (sampler, constraints) = (model.sampler(), model.constraints());
+                let mut generator =
+                    Generator::with_config(seed, output_size, sampler, constraints, &config)
+                        .expect("Config was validated");
+                loop {
+                    if finished.is_cancelled() || cancel.is_cancelled() {
+                        return;
+                    }
+                    match generator.update(sampler, constraints) {
+                        UpdateResult::Continue => (),
+                        UpdateResult::Success => {
+                            // Nobody is listening if another racer already won.
+                            let _ = sender.send((i, generator.result()));
+
+                            return;
+                        }
+                        _ => return,
+                    }
+                }
+            })
+        })
+        .collect();
+    // Only the racers hold senders now, so `recv` fails once they've all lost.
+    drop(sender);
+
+    let winner = receiver.recv().ok();
+    finished.cancel();
+    for racer in racers.into_iter() {
+        racer.join().expect("Racing generator thread panicked");
+    }
+
+    Ok(winner)
+}
+
+fn generate_one<T>(
+    model: &Model<T>,
+    seed: [u8; NUM_SEED_BYTES],
+    output_size: lat::Point,
+    config: &GeneratorConfig,
+) -> Result<VecLatticeMap<PatternId>, GenerationError> {
+    let (sampler, constraints) = (model.sampler(), model.constraints());
+    let mut generator = Generator::with_config(seed, output_size, sampler, constraints, config)
+        .expect("Config was validated");
+    match generator.run_to_completion(sampler, constraints) {
+        UpdateResult::Success => Ok(generator.result()),
+        UpdateResult::Failure(e) => Err(e),
+        _ => unreachable!("run_to_completion only stops once it succeeds or fails"),
+    }
+}
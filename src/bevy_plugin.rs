@@ -0,0 +1,237 @@
+//! A Bevy plugin that runs a `Generator` for a few updates each frame and turns the result into a
+//! voxel mesh.
+
+use crate::{
+    event::ObserverEvent,
+    generate::{GenerationError, Generator, UpdateResult},
+    pattern::{PatternConstraints, PatternId, PatternSampler},
+};
+
+use bevy::prelude::*;
+use bevy::render::{mesh::Indices, pipeline::PrimitiveTopology};
+use ilattice3 as lat;
+use ilattice3::{prelude::*, VecLatticeMap};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+
+/// Runs the `WfcGeneration` resource, whenever there is one, for up to `updates_per_frame` updates
+/// each frame. The generator's `ObserverEvent`s are forwarded as Bevy events, followed by one
+/// `WfcFinished` when it stops. On success, a `WfcOutput` resource is inserted.
+pub struct WfcPlugin {
+    pub updates_per_frame: usize,
+}
+
+impl Default for WfcPlugin {
+    fn default() -> Self {
+        WfcPlugin {
+            updates_per_frame: 16,
+        }
+    }
+}
+
+impl Plugin for WfcPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(UpdateBudget(self.updates_per_frame))
+            .add_event::<ObserverEvent>()
+            .add_event::<WfcFinished>()
+            .add_system(run_generation.system());
+    }
+}
+
+struct UpdateBudget(usize);
+
+/// Insert this resource to start generating.
+pub struct WfcGeneration {
+    generator: Generator,
+    sampler: PatternSampler,
+    constraints: PatternConstraints,
+    events: Mutex<Receiver<ObserverEvent>>,
+    /// Which patterns get a cube in the output mesh.
+    is_solid: Box<dyn Fn(PatternId) -> bool + Send + Sync>,
+    finished: bool,
+}
+
+impl WfcGeneration {
+    pub fn new(
+        mut generator: Generator,
+        sampler: PatternSampler,
+        constraints: PatternConstraints,
+        is_solid: impl Fn(PatternId) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let events = Mutex::new(generator.observe_events());
+
+        WfcGeneration {
+            generator,
+            sampler,
+            constraints,
+            events,
+            is_solid: Box::new(is_solid),
+            finished: false,
+        }
+    }
+
+    pub fn generator(&self) -> &Generator {
+        &self.generator
+    }
+}
+
+/// Sent once the `WfcGeneration` stops.
+#[derive(Clone, Debug)]
+pub enum WfcFinished {
+    Success,
+    Failure(GenerationError),
+}
+
+/// The result of a successful `WfcGeneration`.
+pub struct WfcOutput {
+    pub patterns: VecLatticeMap<PatternId>,
+    pub mesh: Handle<Mesh>,
+}
+
+fn run_generation(
+    commands: &mut Commands,
+    budget: Res<UpdateBudget>,
+    generation: Option<ResMut<WfcGeneration>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut observer_events: ResMut<Events<ObserverEvent>>,
+    mut finished_events: ResMut<Events<WfcFinished>>,
+) {
+    let mut generation = match generation {
+        Some(generation) if !generation.finished => generation,
+        _ => return,
+    };
+    let generation = &mut *generation;
+
+    let mut result = UpdateResult::Continue;
+    for _ in 0..budget.0 {
+        result = generation
+            .generator
+            .update(&generation.sampler, &generation.constraints);
+        if !matches!(result, UpdateResult::Continue) {
+            break;
+        }
+    }
+    for event in generation.events.lock().unwrap().try_iter() {
+        observer_events.send(event);
+    }
+
+    match result {
+        UpdateResult::Continue => (),
+        UpdateResult::Success => {
+            let patterns = generation.generator.result();
+            let mesh = meshes.add(voxel_mesh(&patterns, &generation.is_solid));
+            commands.insert_resource(WfcOutput { patterns, mesh });
+            finished_events.send(WfcFinished::Success);
+            generation.finished = true;
+        }
+        UpdateResult::Failure(e) => {
+            finished_events.send(WfcFinished::Failure(e));
+            generation.finished = true;
+        }
+        UpdateResult::Cancelled | UpdateResult::TimedOut => {
+            unreachable!("Generator::update doesn't cancel or time out")
+        }
+    }
+}
+
+/// The outward normal and corners (counterclockwise from outside) of each face of a unit cube.
+const CUBE_FACES: [([i32; 3], [[f32; 3]; 4]); 6] = [
+    (
+        [1, 0, 0],
+        [
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [1.0, 1.0, 1.0],
+            [1.0, 0.0, 1.0],
+        ],
+    ),
+    (
+        [-1, 0, 0],
+        [
+            [0.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 1.0, 1.0],
+            [0.0, 1.0, 0.0],
+        ],
+    ),
+    (
+        [0, 1, 0],
+        [
+            [0.0, 1.0, 0.0],
+            [0.0, 1.0, 1.0],
+            [1.0, 1.0, 1.0],
+            [1.0, 1.0, 0.0],
+        ],
+    ),
+    (
+        [0, -1, 0],
+        [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 0.0, 1.0],
+            [0.0, 0.0, 1.0],
+        ],
+    ),
+    (
+        [0, 0, 1],
+        [
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+            [1.0, 1.0, 1.0],
+            [0.0, 1.0, 1.0],
+        ],
+    ),
+    (
+        [0, 0, -1],
+        [
+            [0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [1.0, 0.0, 0.0],
+        ],
+    ),
+];
+
+/// A mesh with a unit cube for each slot whose pattern `is_solid`. Faces between two solid slots
+/// are left out.
+pub fn voxel_mesh(
+    patterns: &VecLatticeMap<PatternId>,
+    is_solid: impl Fn(PatternId) -> bool,
+) -> Mesh {
+    let extent = patterns.get_extent();
+    let solid_at = |p: &lat::Point| extent.contains_world(p) && is_solid(patterns.get_world(p));
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut indices = Vec::new();
+    for p in extent {
+        if !solid_at(&p) {
+            continue;
+        }
+        for (normal, corners) in CUBE_FACES.iter() {
+            if solid_at(&(p + lat::Point::from(*normal))) {
+                continue;
+            }
+            let first_index = positions.len() as u32;
+            for corner in corners.iter() {
+                positions.push([
+                    p.x as f32 + corner[0],
+                    p.y as f32 + corner[1],
+                    p.z as f32 + corner[2],
+                ]);
+                normals.push([normal[0] as f32, normal[1] as f32, normal[2] as f32]);
+            }
+            uvs.extend_from_slice(&[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]);
+            indices.extend([0, 1, 2, 0, 2, 3].iter().map(|i| first_index + i));
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+
+    mesh
+}
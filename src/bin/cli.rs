@@ -1,25 +1,119 @@
+#[cfg(feature = "serve")]
+#[path = "common/http_api.rs"]
+mod http_api;
+#[cfg(feature = "serve")]
+#[path = "cli/serve.rs"]
+mod serve;
+
 use ilattice3_wfc::*;
 
-use dot_vox::DotVoxData;
 use flexi_logger::{default_format, Logger};
 use ilattice3 as lat;
-use ilattice3::{GetExtent, PeriodicYLevelsIndexer, VecLatticeMap, VoxColor, EMPTY_VOX_COLOR};
-use image::{Rgba, RgbaImage};
+use ilattice3::{
+    GetExtent, GetWorldRef, PeriodicYLevelsIndexer, Tile, VecLatticeMap, VoxColor, EMPTY_VOX_COLOR,
+};
+use image::{
+    imageops::{self, FilterType},
+    ImageError, Rgba, RgbaImage,
+};
 use indicatif::ProgressBar;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::File;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use std::{error, fmt};
+use structopt::StructOpt;
+
+/// Learns the patterns of an example input and generates outputs that look like it.
+#[derive(structopt::StructOpt)]
+#[structopt(name = "wfc")]
+enum Command {
+    /// Extracts the patterns of INPUT and saves the model to OUTPUT, so outputs can be generated
+    /// from it many times without extracting again, e.g.
+    /// `extract input.vox model.wfc -t 8 8 8 -p 2 2 2`.
+    Extract(Args),
+    /// Generates OUTPUT from INPUT, e.g. `generate model.wfc out.vox -o 32 32 32 --seed 7`.
+    Generate(Args),
+    /// Draws the patterns of INPUT to OUTPUT, as an image or VOX file with one pattern after
+    /// another, and the compatibility graph to --graph if given.
+    Render(Args),
+    /// Prints statistics of the model of INPUT, as with --analyze. --output-size is used to
+    /// estimate the memory needed to generate.
+    Analyze(Args),
+    /// Runs an HTTP service that extracts models from uploaded samples (or loads uploaded models)
+    /// and generates from them in the background, with progress to poll and results to download.
+    #[cfg(feature = "serve")]
+    Serve(serve::ServeArgs),
+}
+
+impl Command {
+    fn into_args(self) -> Args {
+        let (stage, mut args) = match self {
+            Command::Extract(args) => (Stage::Extract, args),
+            Command::Generate(args) => (Stage::Generate, args),
+            Command::Render(args) => (Stage::Render, args),
+            Command::Analyze(args) => (Stage::Analyze, args),
+            #[cfg(feature = "serve")]
+            Command::Serve(_) => panic!("`serve` has no Args"),
+        };
+        args.stage = stage;
+
+        args
+    }
+}
+
+/// Which subcommand the `Args` are for. Every stage but `Generate` stops after the model is built.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Stage {
+    Extract,
+    Generate,
+    Render,
+    Analyze,
+}
 
 #[derive(structopt::StructOpt)]
 struct Args {
-    /// Path to the input file, either an image or a VOX file.
-    #[structopt(parse(from_os_str))]
+    /// Path to the input file: an image, VOX file, Tiled map, or tileset file, or a model saved by
+    /// `extract`. A saved model remembers the input it came from, which must still exist, since
+    /// its format (and VOX palette) is needed to write the output.
+    #[structopt(parse(from_os_str), required_unless = "config")]
     input_path: PathBuf,
 
-    /// Path to the output file.
+    /// Path to the output file, the model for `extract`, or the drawing of the patterns for
+    /// `render`. Not used by `analyze`.
     #[structopt(parse(from_os_str))]
-    output_path: PathBuf,
+    output_path: Option<PathBuf>,
+
+    #[structopt(skip = Stage::Generate)]
+    stage: Stage,
+
+    /// Read options from this TOML file, or RON if the extension is "ron", so a run can be
+    /// repeated and shared. Each key is the long name of an option, e.g. `seed = "b"`,
+    /// `output-size = [32, 32, 1]`, or `diagonal = true`, and "input" and "output" give the paths
+    /// if the command line doesn't. Options given on the command line win.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Keep running, and extract and generate again whenever the input or an --extra-input
+    /// changes, e.g. while editing the input in another program. Each output is numbered, e.g.
+    /// "out_0.png", then "out_1.png". Stop with Ctrl-C.
+    #[structopt(long, conflicts_with_all = &["load-model", "resume"])]
+    watch: bool,
+
+    /// How to read the input: "auto" goes by the file extension, and "heightmap" voxelizes a
+    /// grayscale image into terrain, with each pixel's brightness as the height of its column. The
+    /// output of a heightmap is a VOX file.
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "heightmap"])]
+    input_mode: InputMode,
+
+    /// With --input-mode heightmap, the height in voxels of a white pixel.
+    #[structopt(long, default_value = "16")]
+    heightmap_height: i32,
 
     /// If the input lattice contains tiles (repeated patterns larger than 1 voxel), set this size
     /// to capture that structure. This is also much more efficient.
@@ -31,38 +125,326 @@ struct Args {
     #[structopt(short, long)]
     pattern_size: Vec<i32>,
 
+    /// How many tiles the patterns in adjacent output slots share along each axis. Defaults to full
+    /// overlap (pattern size minus 1). Less overlap trades strictness for variety; each output slot
+    /// then covers (pattern size - overlap) tiles.
+    #[structopt(long)]
+    overlap: Vec<i32>,
+
+    /// For VOX input, the index of the model in the file to learn from. Defaults to the first.
+    #[structopt(long)]
+    input_model: Option<usize>,
+
+    /// For VOX input, learn from all of the models in the file, merged into one lattice where the
+    /// scene places them, instead of one model.
+    #[structopt(long, conflicts_with = "input-model")]
+    merge_models: bool,
+
+    /// Only learn from the part of the input starting at this voxel, e.g. to train on one region
+    /// of a large VOX scene. Measured from the input's minimum corner. Defaults to 0 0 0.
+    #[structopt(long)]
+    input_min: Vec<i32>,
+
+    /// The size in voxels of the part of the input to learn from. Defaults to the rest of the
+    /// input past --input-min.
+    #[structopt(long)]
+    input_size: Vec<i32>,
+
+    /// Extract a pattern at every voxel of the input and make patterns compatible whenever they
+    /// agree where they overlap, like Gumin's original overlapping model. Pattern size and overlap
+    /// are then measured in voxels, and --tile-size is ignored.
+    #[structopt(long)]
+    overlapping: bool,
+
+    /// More example inputs, in the same format as the input, to learn the patterns from. Patterns
+    /// found in several examples are merged and their weights summed. VOX examples must use the
+    /// input's palette.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["load-model", "overlapping"])]
+    extra_input: Vec<PathBuf>,
+
+    /// Remove patterns that occur fewer than this many times in the input, along with any patterns
+    /// that can no longer be placed without them.
+    #[structopt(long, conflicts_with_all = &["load-model", "ground"])]
+    min_pattern_weight: Option<u32>,
+
+    /// Constrain diagonal neighbors as well as face neighbors (8 neighbors in 2D, 26 in 3D).
+    #[structopt(long)]
+    diagonal: bool,
+
+    /// Constrain every pair of slots whose patterns overlap, not only neighbors. Keeps patterns
+    /// larger than 2 consistent with all of the slots they cover, at some cost in speed.
+    #[structopt(long, conflicts_with = "diagonal")]
+    all_overlaps: bool,
+
+    /// How to treat the edges of the input: "periodic" wraps around to the opposite edge, "clamp"
+    /// repeats the voxels on the edge, and "skip" only extracts patterns that fit inside the input.
+    /// Patterns that were only seen at an edge, and so can't be placed, are removed.
+    #[structopt(
+        long,
+        default_value = "periodic",
+        possible_values = &["periodic", "clamp", "skip"],
+        parse(try_from_str = parse_input_boundary)
+    )]
+    input_boundary: InputBoundary,
+
+    /// Also learn from rotated or mirrored copies of the input: "mirror-x" adds its mirror image
+    /// along X, "rotate-z" its quarter turns about Z, "dihedral" both, which are all 8 transforms
+    /// of a 2D image, and "full" every rotation and reflection of a cube. Only "full" turns 3D
+    /// inputs onto their sides. Transforms that would change the shape of the tiles or patterns,
+    /// like a quarter turn of 2x1x1 tiles, are skipped.
+    #[structopt(
+        long,
+        default_value = "none",
+        possible_values = &["none", "mirror-x", "rotate-z", "dihedral", "full"],
+        parse(try_from_str = parse_symmetry)
+    )]
+    symmetry: Symmetry,
+
+    /// What to do when the input isn't a whole number of tiles: "error", "crop" off the partial
+    /// tiles at the far edges, or "pad" them out with --pad-value.
+    #[structopt(
+        long,
+        default_value = "error",
+        possible_values = &["error", "crop", "pad"]
+    )]
+    partial_tiles: PartialTilesPolicy,
+
+    /// The value for --partial-tiles pad: a palette index for VOX input, a global tile ID for Tiled
+    /// maps, or an RRGGBB or RRGGBBAA hex color for images. Pads with empty voxels, empty tiles, or
+    /// transparent pixels by default.
+    #[structopt(long)]
+    pad_value: Option<String>,
+
+    /// Reduce the colors of an image input to at most this many before extracting patterns, so
+    /// photographs and anti-aliased images don't explode into thousands of patterns. Each
+    /// --extra-input is mapped to the nearest of the input's reduced colors.
+    #[structopt(long)]
+    quantize_colors: Option<usize>,
+
+    /// Weigh each occurrence of a pattern by how many of its voxels aren't empty, so patterns that
+    /// are mostly air don't dominate sparse inputs. Transparent pixels and empty Tiled tiles count
+    /// as empty too.
+    #[structopt(long, conflicts_with_all = &["load-model", "overlapping"])]
+    mask_empty: bool,
+
+    /// Make patterns compatible whenever they agree where they overlap, instead of only when they
+    /// were adjacent in the input. Gives more variety for small inputs.
+    #[structopt(long)]
+    agreement: bool,
+
+    /// Restrict the bottom Z layer of the output to patterns found on the bottom layer of the
+    /// input, so terrain stays on the ground.
+    #[structopt(long, conflicts_with_all = &["load-model", "overlapping"])]
+    ground: bool,
+
+    /// Restrict the slots on each face of the output to patterns found on the same face of the
+    /// input, e.g. so the output is closed off by walls wherever the input is.
+    #[structopt(long, conflicts_with_all = &["load-model", "overlapping", "min-pattern-weight"])]
+    edges: bool,
+
+    /// Generate in two passes for larger structures: first a coarse output from the input
+    /// downsampled by this factor along each axis, then the full output, where each coarse slot
+    /// restricts the slots under it to the patterns found within the same coarse pattern in the
+    /// input. The output size must be a multiple of this.
+    #[structopt(long, conflicts_with_all = &["load-model", "overlapping", "min-pattern-weight"])]
+    coarse_scale: Vec<i32>,
+
+    /// With --ground, also keep patterns that only occur on the bottom layer of the input from
+    /// appearing higher up.
+    #[structopt(long, requires = "ground")]
+    ground_exclusive: bool,
+
     /// Size of the generated output in tiles.
     #[structopt(short, long)]
     output_size: Vec<i32>,
 
-    /// A 32-byte string serving as the seed for the random number generator. Results are
-    /// reproducible from a given seed.
+    /// A string serving as the seed for the random number generator. Results are reproducible from
+    /// a given seed. Seeds longer than 16 bytes are hashed, so all of their characters count.
     #[structopt(short, long, default_value = "1")]
     seed: String,
 
-    /// Produce an animated GIF showing each update of the generator algorithm.
-    #[structopt(short, long, parse(from_os_str))]
+    /// Ignore --seed and generate from a new random seed, which is printed so the output can be
+    /// reproduced.
+    #[structopt(long)]
+    random_seed: bool,
+
+    /// Retry with a new seed (derived from the previous one) up to this many times in total when
+    /// generation fails.
+    #[structopt(long, default_value = "1")]
+    max_attempts: usize,
+
+    /// Generate this many outputs in parallel instead of one, from the seed and the seeds derived
+    /// from it as with --max-attempts. They're written next to the output path and numbered, e.g.
+    /// "out_0.png". Variants that fail are skipped, without retries.
+    #[structopt(
+        long,
+        conflicts_with_all = &["ground", "edges", "coarse-scale", "gif", "checkpoint", "resume"]
+    )]
+    variants: Option<usize>,
+
+    /// Run this many generators at once, with the seed and the seeds derived from it as with
+    /// --max-attempts, and keep the first output to succeed. Often faster than --max-attempts for
+    /// models that contradict a lot, but the winner depends on timing, so it isn't reproducible.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "variants", "ground", "edges", "coarse-scale", "gif", "debug-entropy",
+            "contradiction-report", "checkpoint", "resume"
+        ]
+    )]
+    race: Option<usize>,
+
+    /// With --variants, the number of outputs generated at once.
+    #[structopt(long, default_value = "4")]
+    threads: usize,
+
+    /// Produce an animation showing each update of the generator algorithm, in the format given
+    /// by --anim-format.
+    #[structopt(short, long, alias = "anim", parse(from_os_str))]
     gif: Option<PathBuf>,
 
+    /// "gif" for an animated GIF, or "apng" or "webp" for an animated PNG or WebP, which keep the
+    /// full color of every frame (2D image input only). "vox" writes a numbered VOX file per frame,
+    /// showing the collapsed slots (VOX input only). "montage" (or "png-seq") writes a numbered PNG
+    /// per frame, with the Z slices of the superposition side by side. For "vox" and "montage", the
+    /// --gif path is used as the prefix of the frame file names.
+    #[structopt(
+        long,
+        default_value = "gif",
+        possible_values = &["gif", "apng", "webp", "vox", "montage", "png-seq"]
+    )]
+    anim_format: AnimFormat,
+
+    /// Tint the slots that lost patterns since the previous frame of an animated image
+    /// (--anim-format gif, apng, or webp), to show what propagation is doing.
+    #[structopt(long)]
+    highlight_changes: bool,
+
+    /// Draw the superposition in the terminal for every --skip-frames updates, one character per
+    /// two slots, instead of writing an animation (2D image input only). Needs a terminal with
+    /// 24-bit color.
+    #[structopt(long, conflicts_with = "gif")]
+    terminal: bool,
+
+    /// Write a heatmap of each slot's entropy for every --skip-frames updates, as numbered PNGs with
+    /// this prefix, to see where the generator stalls or fails. Blue is low entropy, red is high,
+    /// and collapsed slots are transparent. 3D outputs show the Z slices side by side.
+    #[structopt(long, parse(from_os_str))]
+    debug_entropy: Option<PathBuf>,
+
+    /// If generation fails, explain the last contradiction in this text file: the slot that was
+    /// left without patterns, what its neighbors still allow, and the chain of removals that
+    /// emptied it. Except for Tiled input, the superposition around the slot is also drawn to a PNG
+    /// with the same name, with the slot tinted red.
+    #[structopt(long, parse(from_os_str))]
+    contradiction_report: Option<PathBuf>,
+
     /// Take one GIF frame for every N updates of the generator.
     #[structopt(long, default_value = "1")]
     skip_frames: usize,
 
+    /// Stop adding frames to the GIF after this many.
+    #[structopt(long)]
+    gif_max_frames: Option<usize>,
+
+    /// Stop adding frames to the GIF once the file reaches this size (in MiB).
+    #[structopt(long)]
+    gif_max_mb: Option<u64>,
+
+    /// If the GIF could exceed --gif-max-frames, increase --skip-frames so the whole run fits
+    /// instead of dropping the last frames.
+    #[structopt(long)]
+    auto_skip_frames: bool,
+
     /// Path where the pattern palette image/vox should be saved.
     #[structopt(long, parse(from_os_str))]
     palette: Option<PathBuf>,
 
+    /// Save the extracted model (patterns, weights, and constraints) to this path.
+    #[structopt(long, parse(from_os_str))]
+    save_model: Option<PathBuf>,
+
+    /// Load a model saved with --save-model instead of extracting patterns from the input. The
+    /// input file is still read for its format (and VOX palette), but --tile-size and
+    /// --pattern-size are ignored.
+    #[structopt(long, parse(from_os_str))]
+    load_model: Option<PathBuf>,
+
+    /// Save the generator's state to this path when interrupted with Ctrl-C, so the run can be
+    /// continued with --resume.
+    #[structopt(long, parse(from_os_str))]
+    checkpoint: Option<PathBuf>,
+
+    /// With --checkpoint, also save the generator's state every N updates. 0 only saves on
+    /// interrupt.
+    #[structopt(long, default_value = "0")]
+    checkpoint_every: usize,
+
+    /// Continue a run saved with --checkpoint instead of starting a new one. The model must be the
+    /// same as the saved run's, e.g. by also using --load-model.
+    #[structopt(long, parse(from_os_str))]
+    resume: Option<PathBuf>,
+
+    /// Forbid the adjacencies listed in this file after extracting patterns. Each line is
+    /// "A B DX DY DZ", forbidding pattern B at offset (DX, DY, DZ) from pattern A, or "A B" to
+    /// forbid it at every offset. Pattern IDs are in the order of the --palette output. Blank lines
+    /// and lines starting with "#" are ignored.
+    #[structopt(long, parse(from_os_str))]
+    forbid: Option<PathBuf>,
+
+    /// Write the output as a Minecraft schematic instead of an image or VOX file: "schem" for a
+    /// Sponge schematic (WorldEdit) or "litematic" for Litematica. Requires --blocks.
+    #[structopt(
+        long,
+        possible_values = &["schem", "litematic"],
+        parse(try_from_str = parse_schematic_format),
+        requires = "blocks"
+    )]
+    output_format: Option<SchematicFormat>,
+
+    /// The Minecraft block for each pattern, for --output-format. Each line is "A BLOCK", e.g.
+    /// "3 minecraft:oak_log[axis=x]", and patterns that aren't listed become air. Pattern IDs are
+    /// in the order of the --palette output. Blank lines and lines starting with "#" are ignored.
+    #[structopt(long, parse(from_os_str))]
+    blocks: Option<PathBuf>,
+
+    /// Also write the pattern chosen for each point, and the tile of each pattern, to this path:
+    /// JSON if the extension is "json", RON if it's "ron", or otherwise a flat binary file.
+    #[structopt(long, parse(from_os_str))]
+    assignment: Option<PathBuf>,
+
+    /// Print statistics of the model after extracting patterns: the pattern count, weight
+    /// distribution, and compatibility graph, dead patterns that are certain to cause
+    /// contradictions, and the memory needed to generate the output.
+    #[structopt(long)]
+    analyze: bool,
+
+    /// Write the pattern compatibility graph to this path as a Graphviz DOT file. For image inputs,
+    /// the tile of each pattern is also saved as a PNG in a "<NAME>_patterns" directory next to the
+    /// graph, and shown on the pattern's node.
+    #[structopt(long, parse(from_os_str))]
+    graph: Option<PathBuf>,
+
     /// A log config string, e.g. "info" or "debug, module = trace".
     #[structopt(short, long)]
     log: Option<String>,
 }
 
-#[paw::main]
-fn main(args: Args) -> Result<(), CliError> {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || r.store(false, Ordering::SeqCst))
-        .expect("Failed to register SIGINT handler");
+fn main() -> Result<(), CliError> {
+    let argv = with_config(std::env::args_os().collect())?;
+
+    let cancel = CancellationToken::new();
+    let c = cancel.clone();
+    ctrlc::set_handler(move || c.cancel()).expect("Failed to register SIGINT handler");
+
+    let args = match Command::from_iter(&argv) {
+        #[cfg(feature = "serve")]
+        Command::Serve(serve_args) => return serve::serve(serve_args, &cancel),
+        command => command.into_args(),
+    };
+    if let Some(config_path) = &args.config {
+        println!("Read options from {:?}", config_path);
+    }
 
     if let Some(log_config) = &args.log {
         Logger::with_str(log_config.as_str())
@@ -72,6 +454,76 @@ fn main(args: Args) -> Result<(), CliError> {
             .unwrap_or_else(|e| panic!("Logger initialization failed with {}", e));
     }
 
+    if args.watch {
+        watch(&argv, &cancel)
+    } else {
+        run(args, &cancel)
+    }
+}
+
+/// How often --watch checks the inputs for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Runs with the arguments in `argv` whenever the modification time of an input changes, numbering
+/// the outputs, until cancelled. A run that fails is reported without stopping.
+fn watch(argv: &[OsString], cancel: &CancellationToken) -> Result<(), CliError> {
+    if Command::from_iter(argv).into_args().stage != Stage::Generate {
+        return Err(CliError::Usage(
+            "--watch can only be used with `generate`".to_string(),
+        ));
+    }
+
+    let mut num_runs = 0;
+    let mut last_modified = None;
+    while !cancel.is_cancelled() {
+        let mut args = Command::from_iter(argv).into_args();
+        // Files that can't be read yet, e.g. while they're being saved, are checked again later.
+        let modified: Option<Vec<SystemTime>> = std::iter::once(&args.input_path)
+            .chain(args.extra_input.iter())
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect();
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            let input_path = args.input_path.clone();
+            args.output_path = args.output_path.map(|path| variant_path(&path, num_runs));
+            num_runs += 1;
+            if let Err(e) = run(args, cancel) {
+                println!("Error: {}", e);
+            }
+            println!("Watching {:?} for changes", input_path);
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+
+    Ok(())
+}
+
+/// Extracts a model and, for `generate`, generates from it.
+fn run(mut args: Args, cancel: &CancellationToken) -> Result<(), CliError> {
+    if args.output_path.is_none() && args.stage != Stage::Analyze {
+        return Err(CliError::Usage("Missing the OUTPUT path".to_string()));
+    }
+
+    let extension = args.input_path.extension().unwrap_or_default();
+    if extension == "ron" || extension == "json" {
+        if args.stage != Stage::Generate {
+            return Err(CliError::Usage(
+                "Tileset files can only be used with `generate`".to_string(),
+            ));
+        }
+
+        return generate_from_tileset(args, cancel);
+    }
+    if let Some(source) = read_model_source(&args.input_path)? {
+        if args.watch {
+            return Err(CliError::Usage(
+                "--watch extracts the model again, so it can't be used with a saved model"
+                    .to_string(),
+            ));
+        }
+        source.apply(&mut args)?;
+    }
+
     let ProcessedInput {
         input_lattice,
         tile_size,
@@ -89,7 +541,7 @@ fn main(args: Args) -> Result<(), CliError> {
             lattice,
             output_size,
             color_palette,
-            running,
+            cancel,
         )?,
         InputLattice::Image(lattice) => generate_image(
             args,
@@ -98,7 +550,17 @@ fn main(args: Args) -> Result<(), CliError> {
             pattern_shape,
             lattice,
             output_size,
-            running,
+            cancel,
+        )?,
+        InputLattice::Tiled(lattice, map) => generate_tiled(
+            args,
+            seed,
+            tile_size,
+            pattern_shape,
+            lattice,
+            output_size,
+            map,
+            cancel,
         )?,
     }
 
@@ -113,15 +575,234 @@ struct ProcessedInput<I> {
     output_size: lat::Point,
 }
 
+/// A value in a --config file.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ConfigValue {
+    Flag(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    /// Appends the option `name` with this value to `argv`. A false flag is left out.
+    fn push_option(&self, name: &str, argv: &mut Vec<OsString>) -> Result<(), CliError> {
+        if let ConfigValue::Flag(false) = self {
+            return Ok(());
+        }
+        argv.push(format!("--{}", name).into());
+        match self {
+            ConfigValue::Flag(_) => (),
+            ConfigValue::List(values) => {
+                for value in values.iter() {
+                    argv.push(value.to_arg(name)?);
+                }
+            }
+            value => argv.push(value.to_arg(name)?),
+        }
+
+        Ok(())
+    }
+
+    fn to_arg(&self, name: &str) -> Result<OsString, CliError> {
+        match self {
+            ConfigValue::Integer(i) => Ok(i.to_string().into()),
+            ConfigValue::Float(x) => Ok(x.to_string().into()),
+            ConfigValue::Text(s) => Ok(s.into()),
+            ConfigValue::Flag(_) | ConfigValue::List(_) => Err(CliError::Config(format!(
+                "The values of {:?} must be numbers or strings",
+                name
+            ))),
+        }
+    }
+}
+
+fn read_config(path: &Path) -> Result<BTreeMap<String, ConfigValue>, CliError> {
+    let text = std::fs::read_to_string(path)?;
+    let parsed = if path.extension().unwrap_or_default() == "ron" {
+        ron::de::from_str(&text).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&text).map_err(|e| e.to_string())
+    };
+
+    parsed.map_err(|e| CliError::Config(format!("{:?}: {}", path, e)))
+}
+
+/// Adds the options in the --config file, if any, to `argv`, except those already given.
+fn with_config(mut argv: Vec<OsString>) -> Result<Vec<OsString>, CliError> {
+    let matches = Command::clap().get_matches_from(&argv);
+    let (subcommand, sub_matches) = match matches.subcommand() {
+        (name, Some(sub_matches)) => (name, sub_matches),
+        _ => return Ok(argv),
+    };
+    let config_path = match sub_matches.value_of_os("config") {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(argv),
+    };
+    let mut config = read_config(&config_path)?;
+
+    let input = config.remove("input");
+    let output = config.remove("output");
+    if sub_matches.value_of_os("input-path").is_none() {
+        let mut paths = Vec::new();
+        for path in input.iter().chain(output.iter()) {
+            paths.push(path.to_arg("input")?);
+        }
+        // Right after the subcommand, so they can't be mistaken for the values of an option.
+        let position = argv.iter().position(|a| a == subcommand).unwrap() + 1;
+        argv.splice(position..position, paths);
+    }
+    for (key, value) in config.iter() {
+        let name = key.replace('_', "-");
+        if sub_matches.occurrences_of(&name) == 0 {
+            value.push_option(&name, &mut argv)?;
+        }
+    }
+
+    Ok(argv)
+}
+
+/// Written in front of the model by `extract`, so `generate` can find the input the model came
+/// from, which it still reads for its format (and VOX palette), and the sizes it was extracted
+/// with.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct ModelSource {
+    /// Absolute, so the model can be used from any directory.
+    input_path: PathBuf,
+    input_mode: InputMode,
+    heightmap_height: i32,
+    input_model: Option<usize>,
+    merge_models: bool,
+    tile_size: Vec<i32>,
+    pattern_size: Vec<i32>,
+}
+
+/// Tells a model saved by `extract` apart from one saved by --save-model, which has no source.
+const MODEL_SOURCE_MAGIC: [u8; 4] = *b"WFCS";
+
+impl ModelSource {
+    fn new(args: &Args) -> io::Result<Self> {
+        Ok(ModelSource {
+            input_path: std::fs::canonicalize(&args.input_path)?,
+            input_mode: args.input_mode,
+            heightmap_height: args.heightmap_height,
+            input_model: args.input_model,
+            merge_models: args.merge_models,
+            tile_size: args.tile_size.clone(),
+            pattern_size: args.pattern_size.clone(),
+        })
+    }
+
+    fn write<W: Write>(&self, mut writer: W) -> Result<(), CliError> {
+        writer.write_all(&MODEL_SOURCE_MAGIC)?;
+
+        Ok(bincode::serialize_into(writer, self).map_err(ModelFileError::Encoding)?)
+    }
+
+    /// Makes `args` load the saved model at `args.input_path` instead of extracting one.
+    fn apply(self, args: &mut Args) -> Result<(), CliError> {
+        if args.stage == Stage::Extract {
+            return Err(CliError::Usage(format!(
+                "{:?} is already a model",
+                args.input_path
+            )));
+        }
+        let extracting = !args.extra_input.is_empty()
+            || args.min_pattern_weight.is_some()
+            || args.ground
+            || args.edges
+            || !args.coarse_scale.is_empty();
+        if extracting || args.load_model.is_some() {
+            return Err(CliError::Usage(
+                "A saved model can't be used with options that need to extract patterns"
+                    .to_string(),
+            ));
+        }
+        println!("Extracted from {:?}", self.input_path);
+
+        args.load_model = Some(std::mem::replace(&mut args.input_path, self.input_path));
+        args.input_mode = self.input_mode;
+        args.heightmap_height = self.heightmap_height;
+        args.input_model = self.input_model;
+        args.merge_models = self.merge_models;
+        args.tile_size = self.tile_size;
+        args.pattern_size = self.pattern_size;
+
+        Ok(())
+    }
+}
+
+/// Reads the `ModelSource` of a model saved by `extract`, or returns `None` for any other file.
+fn read_model_source(path: &Path) -> Result<Option<ModelSource>, CliError> {
+    skip_model_source(&mut BufReader::new(File::open(path)?))
+}
+
+/// Opens a model for `load_model`, skipping the `ModelSource` if it was saved by `extract`.
+fn open_model(path: &Path) -> Result<BufReader<File>, CliError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    skip_model_source(&mut reader)?;
+
+    Ok(reader)
+}
+
+/// Reads the `ModelSource` at the start of `reader`, if there is one, leaving the reader at the
+/// start of the model.
+fn skip_model_source<R: BufRead>(reader: &mut R) -> Result<Option<ModelSource>, CliError> {
+    if !reader.fill_buf()?.starts_with(&MODEL_SOURCE_MAGIC) {
+        return Ok(None);
+    }
+    reader.consume(MODEL_SOURCE_MAGIC.len());
+
+    Ok(Some(
+        bincode::deserialize_from(reader).map_err(ModelFileError::Encoding)?,
+    ))
+}
+
+impl Args {
+    /// Checked in `main` for every stage but `analyze`.
+    fn output(&self) -> &Path {
+        self.output_path
+            .as_deref()
+            .expect("Missing the OUTPUT path")
+    }
+
+    /// Where to save the model, if anywhere.
+    fn model_output(&self) -> Option<&Path> {
+        match self.stage {
+            Stage::Extract => self.output_path.as_deref(),
+            _ => self.save_model.as_deref(),
+        }
+    }
+
+    /// Where to draw the patterns, if anywhere.
+    fn palette_output(&self) -> Option<&Path> {
+        match self.stage {
+            Stage::Render => self.output_path.as_deref(),
+            _ => self.palette.as_deref(),
+        }
+    }
+
+    fn analyze(&self) -> bool {
+        self.analyze || self.stage == Stage::Analyze
+    }
+}
+
 enum InputLattice<I> {
     // Vox lattice stores indices into a color palette.
     Vox(VecLatticeMap<VoxColor, I>, VoxColorPalette),
     // Images just store the colors directly.
     Image(VecLatticeMap<Rgba<u8>, I>),
+    // Tiled maps store global tile IDs, which refer to the map's tilesets.
+    Tiled(VecLatticeMap<u32, I>, TiledMap),
 }
 
+#[derive(Clone)]
 struct VoxColorPalette {
     colors: Vec<u32>,
+    /// The MATL chunks, which give palette colors materials like glass or emission.
+    materials: Vec<dot_vox::Material>,
 }
 
 fn process_args(args: &Args) -> Result<ProcessedInput<PeriodicYLevelsIndexer>, CliError> {
@@ -133,39 +814,86 @@ fn process_args(args: &Args) -> Result<ProcessedInput<PeriodicYLevelsIndexer>, C
     if !tile_size_is_valid(&args.pattern_size) {
         panic!("Pattern size must specify 3 positive dimensions");
     }
-    if !tile_size_is_valid(&args.output_size) {
+    let needs_output_size = args.stage == Stage::Generate || args.analyze();
+    if needs_output_size && !tile_size_is_valid(&args.output_size) {
         panic!("Output size must specify 3 positive dimensions");
     }
     let tile_size = lat::Point::from(get_three_elements(&args.tile_size));
     let pattern_size = lat::Point::from(get_three_elements(&args.pattern_size));
-    let output_size = lat::Point::from(get_three_elements(&args.output_size));
+    let output_size = if needs_output_size {
+        lat::Point::from(get_three_elements(&args.output_size))
+    } else {
+        // Nothing is generated, so any size will do.
+        [1, 1, 1].into()
+    };
 
-    if args.gif.is_some() && output_size.z > 2 {
-        panic!("GIF output not supported for 3D output");
+    let animated_image = args.gif.is_some() && args.anim_format.animation_format().is_some();
+    if animated_image && output_size.z > 2 {
+        panic!("Animated images not supported for 3D output; use --anim-format vox or montage");
     }
 
-    let mut seed = [0; NUM_SEED_BYTES];
-    let seed_bytes = args.seed.as_bytes();
-    let copy_bytes = seed_bytes.len().min(NUM_SEED_BYTES);
-    seed[..copy_bytes].clone_from_slice(&seed_bytes[..copy_bytes]);
+    let seed = parse_seed(&args);
 
     let extension = args
         .input_path
         .extension()
         .expect("Input file has no extention");
-    let (input_lattice, offsets) = if extension == "vox" {
-        let input_vox =
-            dot_vox::load(args.input_path.to_str().unwrap()).expect("Failed to load VOX file");
-        let model_index = 0;
+    let is_vox = extension == "vox" || args.input_mode == InputMode::Heightmap;
+    if animated_image && is_vox {
+        panic!("Animated images not supported for VOX input; use --anim-format vox or montage");
+    }
+    if args.terminal && (is_vox || extension == "tmx" || output_size.z > 1) {
+        panic!("--terminal is only supported for 2D image input and output");
+    }
+    if args.gif.is_some() && !is_vox && args.anim_format == AnimFormat::Vox {
+        panic!("VOX animation requires VOX input; use --anim-format gif or montage");
+    }
+    let (input_lattice, offsets) = if is_vox {
+        let (input_lattice, palette) = load_vox_input(args, &args.input_path)?;
+        let input_lattice = fit_input(
+            args,
+            select_input(args, input_lattice)?,
+            &tile_size,
+            EMPTY_VOX_COLOR,
+            |s| s.parse().ok(),
+        )?;
 
         (
-            InputLattice::Vox(
-                VecLatticeMap::from_vox_with_indexer(indexer, &input_vox, model_index),
-                VoxColorPalette {
-                    colors: input_vox.palette,
-                },
-            ),
-            face_3d_offsets(),
+            InputLattice::Vox(input_lattice, palette),
+            if args.diagonal {
+                corner_3d_offsets()
+            } else {
+                face_3d_offsets()
+            },
+        )
+    } else if extension == "tmx" {
+        if args.gif.is_some() {
+            panic!("Animation not supported for Tiled input");
+        }
+        assert_eq!(
+            pattern_size.z, 1,
+            "3D Tiled maps not supported, use --pattern-size x y 1"
+        );
+        assert_eq!(
+            output_size.z, 1,
+            "3D Tiled maps not supported, use --output-size x y 1"
+        );
+        let (input_lattice, map) = read_tmx(&std::fs::read_to_string(&args.input_path)?)?;
+        let input_lattice = fit_input(
+            args,
+            select_input(args, input_lattice)?,
+            &tile_size,
+            0,
+            |s| s.parse().ok(),
+        )?;
+
+        (
+            InputLattice::Tiled(input_lattice, map),
+            if args.diagonal {
+                corner_2d_offsets()
+            } else {
+                edge_2d_offsets()
+            },
         )
     } else {
         assert_eq!(
@@ -177,25 +905,71 @@ fn process_args(args: &Args) -> Result<ProcessedInput<PeriodicYLevelsIndexer>, C
             "3D images not supported, use --output-size x y 1"
         );
         let input_img = image::open(args.input_path.as_os_str())?;
+        let mut input_lattice = select_input(args, (&input_img.to_rgba(), indexer).into())?;
+        if let Some(num_colors) = args.quantize_colors {
+            input_lattice = quantize_colors(&input_lattice, num_colors);
+        }
+        let input_lattice = fit_input(
+            args,
+            input_lattice,
+            &tile_size,
+            Rgba([0; 4]),
+            parse_hex_color,
+        )?;
 
         (
-            InputLattice::Image((&input_img.to_rgba(), indexer).into()),
-            edge_2d_offsets(),
+            InputLattice::Image(input_lattice),
+            if args.diagonal {
+                corner_2d_offsets()
+            } else {
+                edge_2d_offsets()
+            },
         )
     };
 
+    let offset_group = if args.all_overlaps {
+        OffsetGroup::within_radius(&pattern_size)
+    } else {
+        OffsetGroup::new(&offsets).expect("Invalid built-in offsets")
+    };
+    let mut pattern_shape = PatternShape::with_full_overlap(pattern_size, offset_group);
+    if !args.overlap.is_empty() {
+        pattern_shape.overlap = lat::Point::from(get_three_elements(&args.overlap));
+    }
+    if args.agreement {
+        pattern_shape.adjacency = AdjacencyRule::Agreement;
+    }
+    pattern_shape.boundary = args.input_boundary;
+    pattern_shape.symmetry = args.symmetry;
+
     Ok(ProcessedInput {
         input_lattice,
         tile_size,
-        pattern_shape: PatternShape {
-            size: pattern_size,
-            offset_group: OffsetGroup::new(&offsets),
-        },
+        pattern_shape,
         seed,
         output_size,
     })
 }
 
+/// The length of the seeds made by --random-seed. Short enough to be used without hashing.
+const RANDOM_SEED_LEN: usize = NUM_SEED_BYTES;
+
+/// The seed chosen by --seed or --random-seed.
+fn parse_seed(args: &Args) -> [u8; NUM_SEED_BYTES] {
+    let seed_str = if args.random_seed {
+        thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(RANDOM_SEED_LEN)
+            .collect()
+    } else {
+        args.seed.clone()
+    };
+    let seed = seed_from_str(&seed_str);
+    println!("Seed = {:?} {:?}", seed_str, seed);
+
+    seed
+}
+
 fn tile_size_is_valid(size: &[i32]) -> bool {
     for c in size.iter() {
         if *c <= 0 {
@@ -223,54 +997,182 @@ fn generate_image(
     pattern_shape: PatternShape,
     input_lattice: VecLatticeMap<Rgba<u8>, PeriodicYLevelsIndexer>,
     output_size: lat::Point,
-    running: Arc<AtomicBool>,
+    cancel: &CancellationToken,
 ) -> Result<(), CliError> {
     println!(
         "Input size in voxels = {}",
         input_lattice.get_extent().get_local_supremum()
     );
 
-    let (sampler, constraints, pattern_tiles) =
-        process_patterns_in_lattice(&input_lattice, &tile_size, &pattern_shape);
-    println!(
-        "Found {} patterns in input lattice",
-        constraints.num_patterns()
-    );
+    let mut extra_inputs = Vec::with_capacity(args.extra_input.len());
+    let palette: Option<Vec<_>> = args.quantize_colors.map(|_| {
+        let mut colors: Vec<_> = input_lattice
+            .get_extent()
+            .into_iter()
+            .map(|p| input_lattice.get_world_ref(&p).0)
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+
+        colors.into_iter().map(Rgba).collect()
+    });
+    for path in args.extra_input.iter() {
+        let img = image::open(path)?;
+        let mut extra_input = (&img.to_rgba(), PeriodicYLevelsIndexer {}).into();
+        if let Some(palette) = &palette {
+            extra_input = map_to_palette(&extra_input, palette);
+        }
+        extra_inputs.push(fit_input(
+            &args,
+            extra_input,
+            &tile_size,
+            Rgba([0; 4]),
+            parse_hex_color,
+        )?);
+    }
 
-    if let Some(palette_path) = args.palette {
+    let (model, edges) = extract_or_load_model(
+        &args,
+        &input_lattice,
+        &extra_inputs,
+        tile_size,
+        &pattern_shape,
+        |c| c.0[3] == 0,
+        |c| c.0,
+        |c| Rgba(*c),
+    )?;
+    if args.analyze() {
+        print_model_analysis(model.sampler(), model.constraints(), output_size);
+    }
+    if let Some(graph_path) = &args.graph {
+        let thumbnail_dir = save_pattern_thumbnails(graph_path, model.tiles())?;
+        save_constraint_graph(graph_path, model.constraints(), |pattern| {
+            Some(
+                thumbnail_dir
+                    .join(format!("{}.png", pattern.0))
+                    .display()
+                    .to_string(),
+            )
+        })?;
+    }
+    if let Some(palette_path) = args.palette_output() {
         // Save the palette image for debugging.
         let palette_lattice =
-            make_palette_lattice(&pattern_tiles.clone().into(), Rgba([0; 4]), 512);
+            make_palette_lattice(&model.tiles().clone().into(), Rgba([0; 4]), 512);
         let palette_img: RgbaImage = (&palette_lattice).into();
         palette_img.save(palette_path)?;
     }
+    if args.stage != Stage::Generate {
+        return Ok(());
+    }
 
-    let skip_frames = args.skip_frames;
-    let mut gif_maker = args
-        .gif
-        .map(|gif_path| GifMaker::new(gif_path, pattern_tiles.clone(), skip_frames));
-
-    if let Some(result) = generate(
+    let ground = ground_constraint(&args, &input_lattice, tile_size, &pattern_shape)?;
+    let coarse = coarse_patterns(
+        &args,
+        &input_lattice,
+        tile_size,
+        &pattern_shape,
         seed,
-        &sampler,
-        &constraints,
         output_size,
-        &mut gif_maker,
-        running,
-    ) {
+    )?;
+    let checkpoints = Checkpoints::from_args(&args);
+
+    let skip_frames = gif_skip_frames(&args, output_size);
+    let gif_limits = GifLimits {
+        max_frames: args.gif_max_frames,
+        max_bytes: args.gif_max_mb.map(|mb| mb * 1024 * 1024),
+    };
+
+    let save_variant = |result: &VecLatticeMap<PatternId>, path: &Path| {
+        let colors = color_final_patterns_rgba(result, model.tiles());
+        let variant_img: RgbaImage = (&colors).into();
+        println!("Writing {:?}", path);
+
+        Ok(variant_img.save(path)?)
+    };
+    if generate_variants(&args, seed, &model, output_size, save_variant)? {
+        return Ok(());
+    }
+
+    let mut animation = match args.gif.clone() {
+        Some(path) if args.anim_format.animation_format().is_some() => {
+            println!("Writing {:?}", path);
+            let mut maker = GifMaker::with_format(
+                path,
+                model.tiles().clone(),
+                skip_frames,
+                gif_limits,
+                args.anim_format.animation_format().unwrap(),
+            )?;
+            if args.highlight_changes {
+                maker.set_highlight(Some(HIGHLIGHT_COLOR));
+            }
+
+            Some(Animation::Gif(Box::new(maker)))
+        }
+        Some(prefix) => {
+            let tiles = model.tiles().clone();
+            let write_frame = move |slots: &VecLatticeMap<PatternSet>, path: &Path| {
+                let superposition = color_superposition(slots, &tiles);
+                let montage_img: RgbaImage = (&slice_montage(&superposition, Rgba([0; 4]))).into();
+
+                Ok(montage_img.save(path)?)
+            };
+
+            Some(Animation::Frames(FrameSequence::new(
+                prefix,
+                "png",
+                skip_frames,
+                Box::new(write_frame),
+            )))
+        }
+        None if args.terminal => Some(Animation::Terminal(Box::new(TerminalRenderer::new(
+            model.tiles().clone(),
+            skip_frames,
+        )))),
+        None => None,
+    };
+
+    let result = match args.race {
+        Some(num_racers) => race(seed, num_racers, &model, output_size, cancel),
+        None => generate(
+            seed,
+            args.max_attempts,
+            &model,
+            output_size,
+            coarse.as_ref(),
+            ground.as_ref(),
+            edges.as_ref(),
+            &checkpoints,
+            &mut animation,
+            entropy_heatmaps(&args, model.constraints().num_patterns()),
+            contradiction_writer(
+                &args,
+                model.tiles().tile_size,
+                Some(Box::new(|region| {
+                    color_superposition(region, model.tiles())
+                })),
+            ),
+            cancel,
+        )?,
+    };
+    if let Some(result) = result {
         assert!(
-            constraints.assignment_is_valid(&result),
+            model.constraints().assignment_is_valid(&result),
             "BUG: produced output that doesn't satisfy constraints"
         );
-        let colors = color_final_patterns_rgba(&result, &pattern_tiles);
-        let final_img: RgbaImage = (&colors).into();
-        println!("Writing {:?}", args.output_path);
-        final_img.save(args.output_path)?;
-
-        if let Some(maker) = gif_maker {
-            maker.save()?;
+        save_assignment(&args, &result, &model.tiles().map_voxels(|c| c.0))?;
+        if !save_schematic(&args, &result, model.constraints())? {
+            let colors = color_final_patterns_rgba(&result, model.tiles());
+            let final_img: RgbaImage = (&colors).into();
+            println!("Writing {:?}", args.output());
+            final_img.save(args.output())?;
         }
     }
+    // Finish the animation even if generation failed, since it shows where the contradiction was.
+    if let Some(animation) = animation {
+        animation.finish()?;
+    }
 
     Ok(())
 }
@@ -283,105 +1185,1692 @@ fn generate_vox(
     input_lattice: VecLatticeMap<VoxColor, PeriodicYLevelsIndexer>,
     output_size: lat::Point,
     color_palette: VoxColorPalette,
-    running: Arc<AtomicBool>,
-) -> Result<(), std::io::Error> {
+    cancel: &CancellationToken,
+) -> Result<(), CliError> {
     println!(
         "Input size = {}",
         input_lattice.get_extent().get_local_supremum()
     );
 
-    if let Some(palette_path) = args.palette {
-        let tiles = find_unique_tiles(&input_lattice, &tile_size);
-        println!("Found {} unique tiles", tiles.tiles.len());
+    if let Some(palette_path) = args.palette_output() {
+        let tiles = find_unique_tiles(&input_lattice, &tile_size, None);
+        println!(
+            "Found {} unique tiles among {} tiles, with counts {:?}",
+            tiles.tiles.len(),
+            tiles.occurrences.len(),
+            tiles.counts()
+        );
         // Save the palette vox for debugging.
         let palette_lattice = make_palette_lattice(&tiles, EMPTY_VOX_COLOR, std::u8::MAX as usize);
-        save_vox(&palette_path, palette_lattice, &color_palette)?;
+        save_vox(palette_path, palette_lattice, &color_palette)?;
     }
 
-    let (sampler, constraints, pattern_tiles) =
-        process_patterns_in_lattice(&input_lattice, &tile_size, &pattern_shape);
-    println!(
-        "Found {} patterns in input lattice",
-        constraints.num_patterns()
-    );
-
-    if let Some(result) = generate::<NilFrameConsumer>(
-        seed,
-        &sampler,
-        &constraints,
-        output_size,
-        &mut None,
-        running,
-    ) {
-        let colors = color_final_patterns_vox(&result, &pattern_tiles);
-        save_vox(&args.output_path, colors, &color_palette)?;
+    let mut extra_inputs = Vec::with_capacity(args.extra_input.len());
+    for path in args.extra_input.iter() {
+        let (extra_input, palette) = load_vox_input(&args, path)?;
+        assert!(
+            palette.colors == color_palette.colors,
+            "{:?} must use the same palette as the input",
+            path
+        );
+        extra_inputs.push(fit_input(
+            &args,
+            extra_input,
+            &tile_size,
+            EMPTY_VOX_COLOR,
+            |s| s.parse().ok(),
+        )?);
     }
 
-    Ok(())
+    let (model, edges) = extract_or_load_model(
+        &args,
+        &input_lattice,
+        &extra_inputs,
+        tile_size,
+        &pattern_shape,
+        |c| *c == EMPTY_VOX_COLOR,
+        |c| *c,
+        |c| *c,
+    )?;
+    if args.analyze() {
+        print_model_analysis(model.sampler(), model.constraints(), output_size);
+    }
+    if let Some(graph_path) = &args.graph {
+        save_constraint_graph(graph_path, model.constraints(), |_| None)?;
+    }
+    if args.stage != Stage::Generate {
+        return Ok(());
+    }
+
+    let ground = ground_constraint(&args, &input_lattice, tile_size, &pattern_shape)?;
+    let coarse = coarse_patterns(
+        &args,
+        &input_lattice,
+        tile_size,
+        &pattern_shape,
+        seed,
+        output_size,
+    )?;
+    let checkpoints = Checkpoints::from_args(&args);
+
+    let save_variant = |result: &VecLatticeMap<PatternId>, path: &Path| {
+        let colors = color_final_patterns_vox(result, model.tiles());
+
+        Ok(save_vox(path, colors, &color_palette)?)
+    };
+    if generate_variants(&args, seed, &model, output_size, save_variant)? {
+        return Ok(());
+    }
+
+    let mut animation = args.gif.clone().map(|prefix| {
+        let tiles = model.tiles().clone();
+        let palette = color_palette.clone();
+        let (extension, write_frame): (_, Box<WriteFrame>) = match args.anim_format {
+            AnimFormat::Vox => (
+                "vox",
+                Box::new(move |slots: &VecLatticeMap<PatternSet>, path: &Path| {
+                    let colors = color_collapsed_patterns_vox(slots, &tiles);
+
+                    Ok(write_vox_scene(
+                        &mut BufWriter::new(File::create(path)?),
+                        &colors,
+                        &palette.colors,
+                        &palette.materials,
+                    )?)
+                }),
+            ),
+            AnimFormat::Montage => (
+                "png",
+                Box::new(move |slots: &VecLatticeMap<PatternSet>, path: &Path| {
+                    let superposition = color_superposition_vox(slots, &tiles, &palette.colors);
+                    let montage_img: RgbaImage =
+                        (&slice_montage(&superposition, Rgba([0; 4]))).into();
+
+                    Ok(montage_img.save(path)?)
+                }),
+            ),
+            AnimFormat::Gif | AnimFormat::Apng | AnimFormat::Webp => {
+                unreachable!("Animated images were rejected for VOX input")
+            }
+        };
+
+        FrameSequence::new(prefix, extension, args.skip_frames, write_frame)
+    });
+
+    let result = match args.race {
+        Some(num_racers) => race(seed, num_racers, &model, output_size, cancel),
+        None => generate(
+            seed,
+            args.max_attempts,
+            &model,
+            output_size,
+            coarse.as_ref(),
+            ground.as_ref(),
+            edges.as_ref(),
+            &checkpoints,
+            &mut animation,
+            entropy_heatmaps(&args, model.constraints().num_patterns()),
+            contradiction_writer(
+                &args,
+                model.tiles().tile_size,
+                Some(Box::new(|region| {
+                    color_superposition_vox(region, model.tiles(), &color_palette.colors)
+                })),
+            ),
+            cancel,
+        )?,
+    };
+    if let Some(result) = result {
+        save_assignment(&args, &result, model.tiles())?;
+        if !save_schematic(&args, &result, model.constraints())? {
+            let colors = color_final_patterns_vox(&result, model.tiles());
+            save_vox(args.output(), colors, &color_palette)?;
+        }
+    }
+    // Finish the animation even if generation failed, since it shows where the contradiction was.
+    if let Some(animation) = animation {
+        animation.finish()?;
+    }
+
+    Ok(())
+}
+
+fn generate_tiled(
+    args: Args,
+    seed: [u8; 16],
+    tile_size: lat::Point,
+    pattern_shape: PatternShape,
+    input_lattice: VecLatticeMap<u32, PeriodicYLevelsIndexer>,
+    output_size: lat::Point,
+    map: TiledMap,
+    cancel: &CancellationToken,
+) -> Result<(), CliError> {
+    if args.stage == Stage::Render {
+        return Err(CliError::Usage(
+            "Tiled maps can't be rendered; use --graph with `analyze`".to_string(),
+        ));
+    }
+    println!(
+        "Input size in tiles = {}",
+        input_lattice.get_extent().get_local_supremum()
+    );
+
+    let mut extra_inputs = Vec::with_capacity(args.extra_input.len());
+    for path in args.extra_input.iter() {
+        let (extra_input, _) = read_tmx(&std::fs::read_to_string(path)?)?;
+        extra_inputs.push(fit_input(&args, extra_input, &tile_size, 0, |s| {
+            s.parse().ok()
+        })?);
+    }
+
+    let (model, edges) = extract_or_load_model(
+        &args,
+        &input_lattice,
+        &extra_inputs,
+        tile_size,
+        &pattern_shape,
+        |gid| *gid == 0,
+        |gid| *gid,
+        |gid| *gid,
+    )?;
+    if args.analyze() {
+        print_model_analysis(model.sampler(), model.constraints(), output_size);
+    }
+    if let Some(graph_path) = &args.graph {
+        save_constraint_graph(graph_path, model.constraints(), |_| None)?;
+    }
+    if args.stage != Stage::Generate {
+        return Ok(());
+    }
+
+    let ground = ground_constraint(&args, &input_lattice, tile_size, &pattern_shape)?;
+    let coarse = coarse_patterns(
+        &args,
+        &input_lattice,
+        tile_size,
+        &pattern_shape,
+        seed,
+        output_size,
+    )?;
+    let checkpoints = Checkpoints::from_args(&args);
+
+    let save_variant = |result: &VecLatticeMap<PatternId>, path: &Path| {
+        save_tmx(path, &final_pattern_gids(result, model.tiles()), &map)
+    };
+    if generate_variants(&args, seed, &model, output_size, save_variant)? {
+        return Ok(());
+    }
+
+    let result = match args.race {
+        Some(num_racers) => race(seed, num_racers, &model, output_size, cancel),
+        None => generate(
+            seed,
+            args.max_attempts,
+            &model,
+            output_size,
+            coarse.as_ref(),
+            ground.as_ref(),
+            edges.as_ref(),
+            &checkpoints,
+            &mut None::<NilFrameConsumer>,
+            entropy_heatmaps(&args, model.constraints().num_patterns()),
+            contradiction_writer(&args, model.tiles().tile_size, None),
+            cancel,
+        )?,
+    };
+    if let Some(result) = result {
+        save_assignment(&args, &result, model.tiles())?;
+        if !save_schematic(&args, &result, model.constraints())? {
+            save_tmx(
+                args.output(),
+                &final_pattern_gids(&result, model.tiles()),
+                &map,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Generates with the tiles and adjacency rules of a tileset file instead of an example, rendering
+/// the output with the tiles' images.
+fn generate_from_tileset(args: Args, cancel: &CancellationToken) -> Result<(), CliError> {
+    if !tile_size_is_valid(&args.output_size) {
+        panic!("Output size must specify 3 positive dimensions");
+    }
+    let output_size = lat::Point::from(get_three_elements(&args.output_size));
+    assert_eq!(output_size.z, 1, "Tilesets are 2D, use --output-size x y 1");
+    if args.gif.is_some() {
+        panic!("Animation not supported for tilesets");
+    }
+    let seed = parse_seed(&args);
+
+    let tileset = TilesetFile::load(&args.input_path)?;
+    let TilesetModel {
+        sampler,
+        constraints,
+        variants,
+    } = tileset.build()?;
+    println!(
+        "Built {} patterns from {} tiles",
+        constraints.num_patterns(),
+        tileset.tiles.len()
+    );
+
+    let base_dir = args.input_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut images = Vec::with_capacity(tileset.tiles.len());
+    for tile in tileset.tiles.iter() {
+        let path = tile.path.as_ref().ok_or_else(|| {
+            CliError::Rules(format!("Tile {:?} has no image to render", tile.name))
+        })?;
+        let img = image::open(base_dir.join(path))?;
+        let lattice: VecLatticeMap<_, PeriodicYLevelsIndexer> =
+            (&img.to_rgba(), PeriodicYLevelsIndexer {}).into();
+        images.push(lattice);
+    }
+    let tile_extent = images[0].get_extent();
+    if images.iter().any(|img| img.get_extent() != tile_extent) {
+        return Err(CliError::Rules(
+            "Tile images must all be the same size".to_string(),
+        ));
+    }
+    let tiles = variants
+        .iter()
+        .map(|(_, (tile_index, transform))| {
+            let transformed = transform.apply_to_tile(&images[*tile_index]);
+
+            Tile::get_from_map(&transformed, &tile_extent)
+        })
+        .collect();
+    let model = Model::new(
+        sampler,
+        constraints,
+        PatternTileSet {
+            tiles: PatternMap::new(tiles),
+            tile_size: *tile_extent.get_local_supremum(),
+        },
+    );
+
+    let save_variant = |result: &VecLatticeMap<PatternId>, path: &Path| {
+        let colors = color_final_patterns_rgba(result, model.tiles());
+        let variant_img: RgbaImage = (&colors).into();
+        println!("Writing {:?}", path);
+
+        Ok(variant_img.save(path)?)
+    };
+    if generate_variants(&args, seed, &model, output_size, save_variant)? {
+        return Ok(());
+    }
+
+    let result = match args.race {
+        Some(num_racers) => race(seed, num_racers, &model, output_size, cancel),
+        None => generate(
+            seed,
+            args.max_attempts,
+            &model,
+            output_size,
+            None,
+            None,
+            None,
+            &Checkpoints::from_args(&args),
+            &mut None::<NilFrameConsumer>,
+            entropy_heatmaps(&args, model.constraints().num_patterns()),
+            contradiction_writer(
+                &args,
+                model.tiles().tile_size,
+                Some(Box::new(|region| {
+                    color_superposition(region, model.tiles())
+                })),
+            ),
+            cancel,
+        )?,
+    };
+    if let Some(result) = result {
+        save_assignment(&args, &result, &model.tiles().map_voxels(|c| c.0))?;
+        if !save_schematic(&args, &result, model.constraints())? {
+            let colors = color_final_patterns_rgba(&result, model.tiles());
+            let final_img: RgbaImage = (&colors).into();
+            println!("Writing {:?}", args.output());
+            final_img.save(args.output())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Either extracts patterns from the input (and any extra examples) or loads a saved model, then
+/// optionally saves the model. Also returns the `EdgeConstraint` if requested with --edges. Voxels are converted with `to_file` and `from_file` since not every voxel type is serializable.
+fn extract_or_load_model<T, S, F, G>(
+    args: &Args,
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    extra_inputs: &[VecLatticeMap<T, PeriodicYLevelsIndexer>],
+    tile_size: lat::Point,
+    pattern_shape: &PatternShape,
+    is_empty: fn(&T) -> bool,
+    to_file: F,
+    from_file: G,
+) -> Result<(Model<T>, Option<EdgeConstraint>), CliError>
+where
+    T: Clone + Copy + std::fmt::Debug + Eq + std::hash::Hash,
+    S: Clone + serde::Serialize + serde::de::DeserializeOwned,
+    F: Fn(&T) -> S,
+    G: Fn(&S) -> T,
+{
+    let mut edges = None;
+    let (sampler, constraints, tiles) = if let Some(model_path) = &args.load_model {
+        println!("Loading model from {:?}", model_path);
+        let (sampler, constraints, file_tiles) = load_model::<S, _>(open_model(model_path)?)?;
+
+        (sampler, constraints, file_tiles.map_voxels(from_file))
+    } else if args.overlapping {
+        process_overlapping_patterns_in_lattice(input_lattice, pattern_shape)?
+    } else {
+        let mut trainer = ModelTrainer::new(tile_size, pattern_shape.clone())?;
+        if args.mask_empty {
+            trainer = trainer.mask_empty(is_empty);
+        }
+        trainer.add_example(input_lattice)?;
+        for extra_input in extra_inputs.iter() {
+            trainer.add_example(extra_input)?;
+        }
+
+        let (sampler, constraints, tiles, input_edges) = trainer.into_parts_with_edges()?;
+        if args.edges {
+            edges = Some(input_edges);
+        }
+
+        (sampler, constraints, tiles)
+    };
+    println!(
+        "Found {} patterns in input lattice",
+        constraints.num_patterns()
+    );
+    let (sampler, mut constraints, tiles) = if let Some(min_weight) = args.min_pattern_weight {
+        let num_found = constraints.num_patterns();
+        let pruned = prune_rare_patterns(sampler, constraints, tiles, min_weight)?;
+        println!(
+            "Pruned {} patterns with weight less than {}",
+            num_found - pruned.1.num_patterns(),
+            min_weight
+        );
+
+        pruned
+    } else {
+        (sampler, constraints, tiles)
+    };
+    if let Some(rules_path) = &args.forbid {
+        let num_removed = forbid_adjacencies(rules_path, &mut constraints)?;
+        println!("Forbade {} adjacencies from {:?}", num_removed, rules_path);
+    }
+    let model = Model::new(sampler, constraints, tiles);
+    println!("Weights = {:?}", model.summary().sorted_weights);
+
+    if let Some(model_path) = args.model_output() {
+        println!("Writing {:?}", model_path);
+        let mut writer = BufWriter::new(File::create(model_path)?);
+        if args.stage == Stage::Extract {
+            ModelSource::new(args)?.write(&mut writer)?;
+        }
+        save_model(
+            writer,
+            model.sampler(),
+            model.constraints(),
+            &model.tiles().map_voxels(to_file),
+        )?;
+    }
+
+    Ok((model, edges))
+}
+
+/// Removes the adjacencies listed in the rules file at `path` (see --forbid) and returns how many
+/// were removed.
+fn forbid_adjacencies(
+    path: &Path,
+    constraints: &mut PatternConstraints,
+) -> Result<usize, CliError> {
+    let rules = std::fs::read_to_string(path)?;
+    let bad_rule = |line_num: usize, msg: &str| {
+        CliError::Rules(format!("{:?} line {}: {}", path, line_num + 1, msg))
+    };
+
+    let mut num_removed = 0;
+    for (line_num, line) in rules.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line
+            .split_whitespace()
+            .map(|f| f.parse::<i32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| bad_rule(line_num, &e.to_string()))?;
+        let (a, b) = match fields.as_slice() {
+            [a, b] | [a, b, _, _, _] => (*a, *b),
+            _ => return Err(bad_rule(line_num, "Expected \"A B\" or \"A B DX DY DZ\"")),
+        };
+        for p in [a, b].iter() {
+            if *p < 0 || *p as PatternIndex >= constraints.num_patterns() {
+                return Err(bad_rule(line_num, &format!("No pattern {}", p)));
+            }
+        }
+        let (a, b) = (PatternId(a as PatternIndex), PatternId(b as PatternIndex));
+
+        let offsets: Vec<lat::Point> = if let [_, _, dx, dy, dz] = fields.as_slice() {
+            let offset = lat::Point::from([*dx, *dy, *dz]);
+            if !constraints.get_offset_group().contains(&offset) {
+                return Err(bad_rule(line_num, &format!("No offset {}", offset)));
+            }
+
+            vec![offset]
+        } else {
+            constraints
+                .get_offset_group()
+                .iter()
+                .map(|(_, offset)| *offset)
+                .collect()
+        };
+        for offset in offsets.iter() {
+            let offset_id = constraints.get_offset_group().offset_id(offset);
+            if constraints.are_compatible(a, b, offset_id) {
+                constraints.remove_compatible_patterns(offset, a, b);
+                num_removed += 1;
+            }
+        }
+    }
+    constraints.validate()?;
+
+    Ok(num_removed)
+}
+
+/// Writes `result` as a schematic if requested with --output-format. Returns `false` if not.
+fn save_schematic(
+    args: &Args,
+    result: &VecLatticeMap<PatternId>,
+    constraints: &PatternConstraints,
+) -> Result<bool, CliError> {
+    let (format, blocks_path) = match (args.output_format, &args.blocks) {
+        (Some(format), Some(blocks_path)) => (format, blocks_path),
+        _ => return Ok(false),
+    };
+    let blocks = read_block_table(blocks_path, constraints.num_patterns())?;
+
+    println!("Writing {:?}", args.output());
+    write_schematic(
+        BufWriter::new(File::create(args.output())?),
+        format,
+        result,
+        |p: &PatternId| blocks[p.0 as usize].as_deref(),
+    )?;
+
+    Ok(true)
+}
+
+/// Writes the raw pattern assignment if requested with --assignment.
+fn save_assignment<T, I>(
+    args: &Args,
+    result: &VecLatticeMap<PatternId>,
+    tiles: &PatternTileSet<T, I>,
+) -> Result<(), CliError>
+where
+    T: Clone + serde::Serialize,
+    I: Clone + lat::Indexer,
+{
+    let path = match &args.assignment {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    println!("Writing {:?}", path);
+    PatternAssignment::new(result, tiles).write(
+        BufWriter::new(File::create(path)?),
+        AssignmentFormat::from_path(path),
+    )?;
+
+    Ok(())
+}
+
+/// Reads the block of each pattern from the file at `path` (see --blocks).
+fn read_block_table(
+    path: &Path,
+    num_patterns: PatternIndex,
+) -> Result<Vec<Option<String>>, CliError> {
+    let table = std::fs::read_to_string(path)?;
+    let bad_line = |line_num: usize, msg: &str| {
+        CliError::Rules(format!("{:?} line {}: {}", path, line_num + 1, msg))
+    };
+
+    let mut blocks = vec![None; num_patterns as usize];
+    for (line_num, line) in table.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (pattern, block) = match fields.as_slice() {
+            [pattern, block] => (*pattern, *block),
+            _ => return Err(bad_line(line_num, "Expected \"A BLOCK\"")),
+        };
+        let pattern = pattern
+            .parse::<PatternIndex>()
+            .map_err(|e| bad_line(line_num, &e.to_string()))?;
+        if pattern >= num_patterns {
+            return Err(bad_line(line_num, &format!("No pattern {}", pattern)));
+        }
+        blocks[pattern as usize] = Some(block.to_string());
+    }
+
+    Ok(blocks)
+}
+
+fn ground_constraint<T>(
+    args: &Args,
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    tile_size: lat::Point,
+    pattern_shape: &PatternShape,
+) -> Result<Option<GroundConstraint>, CliError>
+where
+    T: Clone + Copy + std::fmt::Debug + Eq + std::hash::Hash,
+{
+    if !args.ground {
+        return Ok(None);
+    }
+
+    let ground = GroundConstraint::from_input(
+        input_lattice,
+        &tile_size,
+        pattern_shape,
+        args.ground_exclusive,
+    )?;
+    println!("Found {} ground patterns", ground.ground_patterns.len());
+
+    Ok(Some(ground))
+}
+
+/// With --coarse-scale, generates the coarse output and returns the patterns that each slot of the
+/// full output may start with.
+fn coarse_patterns<T>(
+    args: &Args,
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    tile_size: lat::Point,
+    pattern_shape: &PatternShape,
+    seed: [u8; NUM_SEED_BYTES],
+    output_size: lat::Point,
+) -> Result<Option<VecLatticeMap<PatternSet>>, CliError>
+where
+    T: Clone + Copy + std::fmt::Debug + Eq + std::hash::Hash,
+{
+    if args.coarse_scale.is_empty() {
+        return Ok(None);
+    }
+    if !tile_size_is_valid(&args.coarse_scale) {
+        panic!("Coarse scale must specify 3 positive dimensions");
+    }
+    let scale = lat::Point::from(get_three_elements(&args.coarse_scale));
+    let coarse_size: lat::Point = [
+        output_size.x / scale.x,
+        output_size.y / scale.y,
+        output_size.z / scale.z,
+    ]
+    .into();
+    if coarse_size * scale != output_size {
+        panic!("Output size must be a multiple of the coarse scale");
+    }
+
+    let (coarse_model, refinement) =
+        Refinement::learn(input_lattice, tile_size, pattern_shape, scale)?;
+    println!(
+        "Found {} coarse patterns; generating coarse output of size {}",
+        coarse_model.constraints().num_patterns(),
+        coarse_size
+    );
+    let (result, stats) = generate_with_retries(
+        seed,
+        args.max_attempts,
+        coarse_size,
+        coarse_model.sampler(),
+        coarse_model.constraints(),
+        |_| UpdateResult::Continue,
+        |_| true,
+    );
+    println!("Ran {} coarse attempt(s)", stats.num_attempts);
+
+    match result {
+        Some(coarse_result) => Ok(Some(refinement.initial_patterns(&coarse_result))),
+        None => Err(CliError::Coarse(match stats.last_error {
+            Some(e) => format!("Failed to generate the coarse output: {}", e),
+            None => "Failed to generate the coarse output".to_string(),
+        })),
+    }
+}
+
+/// With --variants, generates all of the variants and writes each successful one with `save`.
+/// Returns `false` iff --variants wasn't given.
+fn generate_variants<T, S>(
+    args: &Args,
+    seed: [u8; NUM_SEED_BYTES],
+    model: &Model<T>,
+    output_size: lat::Point,
+    mut save: S,
+) -> Result<bool, CliError>
+where
+    T: 'static + Send + Sync,
+    S: FnMut(&VecLatticeMap<PatternId>, &Path) -> Result<(), CliError>,
+{
+    let num_variants = match args.variants {
+        Some(num_variants) => num_variants,
+        None => return Ok(false),
+    };
+
+    let seeds = derived_seeds(seed, num_variants);
+    println!(
+        "Generating {} variants on {} threads",
+        num_variants, args.threads
+    );
+    let results = generate_batch(
+        model,
+        &seeds,
+        output_size,
+        &GeneratorConfig::default(),
+        args.threads,
+    )?;
+
+    for (i, result) in results.iter().enumerate() {
+        match result {
+            Ok(assignment) => {
+                assert!(
+                    model.constraints().assignment_is_valid(assignment),
+                    "BUG: produced output that doesn't satisfy constraints"
+                );
+                save(assignment, &variant_path(args.output(), i))?;
+            }
+            Err(e) => println!("Variant {} with seed {:?} failed: {}", i, seeds[i], e),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Races generators for --race.
+fn race<T>(
+    seed: [u8; NUM_SEED_BYTES],
+    num_racers: usize,
+    model: &Model<T>,
+    output_size: lat::Point,
+    cancel: &CancellationToken,
+) -> Option<VecLatticeMap<PatternId>>
+where
+    T: 'static + Send + Sync,
+{
+    let seeds = derived_seeds(seed, num_racers);
+    println!("Racing {} generators", num_racers);
+    let winner = race_seeds(
+        model,
+        &seeds,
+        output_size,
+        &GeneratorConfig::default(),
+        cancel,
+    )
+    .expect("The default config is valid");
+
+    match winner {
+        Some((i, result)) => {
+            println!("Succeeded with seed {:?}", seeds[i]);
+
+            Some(result)
+        }
+        None => {
+            println!("Failed to generate");
+
+            None
+        }
+    }
+}
+
+/// `seed` followed by the seeds that `generate_with_retries` would try after it.
+fn derived_seeds(seed: [u8; NUM_SEED_BYTES], count: usize) -> Vec<[u8; NUM_SEED_BYTES]> {
+    let mut seeds = Vec::with_capacity(count);
+    let mut next_seed = seed;
+    for _ in 0..count {
+        seeds.push(next_seed);
+        next_seed = mutate_seed(next_seed);
+    }
+
+    seeds
+}
+
+/// The output path of variant `i`, e.g. "out_3.png" for "out.png".
+fn variant_path(path: &Path, i: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, i, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, i),
+    };
+
+    path.with_file_name(file_name)
+}
+
+/// Possibly chooses a larger `skip_frames` so the GIF fits in --gif-max-frames.
+fn gif_skip_frames(args: &Args, output_size: lat::Point) -> usize {
+    let max_frames = match args.gif_max_frames {
+        Some(max_frames) if args.auto_skip_frames => max_frames,
+        _ => return args.skip_frames,
+    };
+    let min_skip_frames = skip_frames_for_max_frames(output_size, max_frames);
+    if min_skip_frames <= args.skip_frames {
+        return args.skip_frames;
+    }
+    println!(
+        "Increasing --skip-frames to {} to fit --gif-max-frames",
+        min_skip_frames
+    );
+
+    min_skip_frames
+}
+
+fn print_model_analysis(
+    sampler: &PatternSampler,
+    constraints: &PatternConstraints,
+    output_size: lat::Point,
+) {
+    println!("Patterns = {}", constraints.num_patterns());
+    for (min_weight, count) in weight_histogram(sampler) {
+        println!(
+            "Patterns with weight in [{}, {}) = {}",
+            min_weight,
+            (2 * min_weight).max(1),
+            count
+        );
+    }
+
+    let summary = summarize_constraints(constraints, 10);
+    for (offset_id, offset) in constraints.get_offset_group().iter() {
+        println!(
+            "Mean compatible patterns at offset {} = {:.2}",
+            offset, summary.mean_branching[offset_id.0]
+        );
+    }
+    println!("Tightest patterns = {:?}", summary.tightest_patterns);
+    println!(
+        "Articulation patterns = {:?}",
+        summary.articulation_patterns
+    );
+    for (pattern, offset) in summary.dead_patterns.iter() {
+        println!(
+            "Dead pattern: {:?} has no compatible patterns at offset {}",
+            pattern, offset
+        );
+    }
+    println!("Health score = {:.3}", summary.health);
+
+    let memory = estimate_generator_memory(constraints, output_size);
+    println!(
+        "Estimated memory for a {} output = {:.1} MiB",
+        output_size,
+        memory as f64 / (1 << 20) as f64
+    );
+}
+
+fn save_constraint_graph<F>(
+    path: &Path,
+    constraints: &PatternConstraints,
+    thumbnail: F,
+) -> Result<(), CliError>
+where
+    F: Fn(PatternId) -> Option<String>,
+{
+    write_constraint_dot(BufWriter::new(File::create(path)?), constraints, thumbnail)?;
+    println!("Wrote the pattern graph to {}", path.display());
+
+    Ok(())
+}
+
+/// The smallest width or height of a pattern thumbnail for --graph. Tiles are often a single
+/// pixel, so they're scaled up to be visible.
+const MIN_THUMBNAIL_SIZE: u32 = 32;
+
+/// Saves the tile of each pattern as "<graph name>_patterns/<pattern>.png", returning the
+/// directory.
+fn save_pattern_thumbnails(
+    graph_path: &Path,
+    tiles: &PatternTileSet<Rgba<u8>, PeriodicYLevelsIndexer>,
+) -> Result<PathBuf, CliError> {
+    let mut dir_name = graph_path.file_stem().unwrap_or_default().to_os_string();
+    dir_name.push("_patterns");
+    let dir = graph_path.with_file_name(dir_name);
+    std::fs::create_dir_all(&dir)?;
+
+    let tile_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), tiles.tile_size);
+    for (pattern, tile) in tiles.tiles.iter() {
+        let tile_img: RgbaImage = (&tile.clone().put_in_extent(tile_extent)).into();
+        let (width, height) = tile_img.dimensions();
+        let scale = (MIN_THUMBNAIL_SIZE / width.min(height).max(1)).max(1);
+        let thumbnail = imageops::resize(
+            &tile_img,
+            width * scale,
+            height * scale,
+            FilterType::Nearest,
+        );
+        thumbnail.save(dir.join(format!("{}.png", pattern.0)))?;
+    }
+
+    Ok(dir)
+}
+
+/// The color of heightmap voxels, in MagicaVoxel's default palette.
+const HEIGHTMAP_COLOR: VoxColor = 0;
+
+/// Reads the model chosen by --input-model from the VOX file at `path`, or all of its models with
+/// --merge-models, along with the palette and materials. With --input-mode heightmap, voxelizes
+/// the image at `path` instead, with the default palette.
+fn load_vox_input(
+    args: &Args,
+    path: &Path,
+) -> Result<
+    (
+        VecLatticeMap<VoxColor, PeriodicYLevelsIndexer>,
+        VoxColorPalette,
+    ),
+    CliError,
+> {
+    if args.input_mode == InputMode::Heightmap {
+        let heightmap = image::open(path)?.to_luma();
+        let lattice = voxelize_heightmap(&heightmap, args.heightmap_height, HEIGHTMAP_COLOR);
+
+        return Ok((
+            lattice,
+            VoxColorPalette {
+                colors: dot_vox::DEFAULT_PALETTE.clone(),
+                materials: Vec::new(),
+            },
+        ));
+    }
+
+    let bytes = std::fs::read(path)?;
+    let vox = dot_vox::load_bytes(&bytes).expect("Failed to load VOX file");
+    let indexer = PeriodicYLevelsIndexer {};
+
+    let lattice = if args.merge_models {
+        let positions = vox_model_positions(&bytes)?;
+        let models: Vec<_> = (0..vox.models.len())
+            .map(|i| VecLatticeMap::from_vox_with_indexer(indexer, &vox, i))
+            .collect();
+        let mut merged = merge_vox_models(&models, &positions);
+        merged.set_minimum(&[0, 0, 0].into());
+
+        merged
+    } else {
+        let model_index = args.input_model.unwrap_or(0);
+        assert!(
+            model_index < vox.models.len(),
+            "{:?} has {} models, so there's no model {}",
+            path,
+            vox.models.len(),
+            model_index
+        );
+
+        VecLatticeMap::from_vox_with_indexer(indexer, &vox, model_index)
+    };
+
+    Ok((
+        lattice,
+        VoxColorPalette {
+            colors: vox.palette,
+            materials: vox.materials,
+        },
+    ))
 }
 
 fn save_vox<I: lat::Indexer>(
-    path: &PathBuf,
+    path: &Path,
     colors: VecLatticeMap<VoxColor, I>,
     color_palette: &VoxColorPalette,
 ) -> Result<(), std::io::Error> {
-    let mut vox_data: DotVoxData = colors.into();
-    vox_data.palette = color_palette.colors.clone();
     println!("Writing {:?}", path);
-    let mut out_file = File::create(path)?;
+    let mut out_file = BufWriter::new(File::create(path)?);
+
+    write_vox_scene(
+        &mut out_file,
+        &colors,
+        &color_palette.colors,
+        &color_palette.materials,
+    )
+}
+
+fn save_tmx(path: &Path, gids: &VecLatticeMap<u32>, map: &TiledMap) -> Result<(), CliError> {
+    println!("Writing {:?}", path);
+
+    Ok(write_tmx(
+        &mut BufWriter::new(File::create(path)?),
+        gids,
+        map,
+    )?)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AnimFormat {
+    Gif,
+    Apng,
+    Webp,
+    Vox,
+    Montage,
+}
+
+impl AnimFormat {
+    /// The format of a single animated image file, if that's what this is.
+    fn animation_format(self) -> Option<AnimationFormat> {
+        match self {
+            AnimFormat::Gif => Some(AnimationFormat::Gif),
+            AnimFormat::Apng => Some(AnimationFormat::Apng),
+            AnimFormat::Webp => Some(AnimationFormat::Webp),
+            AnimFormat::Vox | AnimFormat::Montage => None,
+        }
+    }
+}
+
+impl FromStr for AnimFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gif" => Ok(AnimFormat::Gif),
+            "apng" => Ok(AnimFormat::Apng),
+            "webp" => Ok(AnimFormat::Webp),
+            "vox" => Ok(AnimFormat::Vox),
+            "montage" | "png-seq" => Ok(AnimFormat::Montage),
+            _ => Err(format!("Unknown animation format {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+enum InputMode {
+    Auto,
+    Heightmap,
+}
+
+impl FromStr for InputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(InputMode::Auto),
+            "heightmap" => Ok(InputMode::Heightmap),
+            _ => Err(format!("Unknown input mode {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PartialTilesPolicy {
+    Error,
+    Crop,
+    Pad,
+}
+
+impl FromStr for PartialTilesPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(PartialTilesPolicy::Error),
+            "crop" => Ok(PartialTilesPolicy::Crop),
+            "pad" => Ok(PartialTilesPolicy::Pad),
+            _ => Err(format!("Unknown partial tiles policy {:?}", s)),
+        }
+    }
+}
 
-    vox_data.write_vox(&mut out_file)
+/// The part of the input chosen by --input-min and --input-size, or all of it.
+fn select_input<T: Clone>(
+    args: &Args,
+    input_lattice: VecLatticeMap<T, PeriodicYLevelsIndexer>,
+) -> Result<VecLatticeMap<T, PeriodicYLevelsIndexer>, CliError> {
+    if args.input_min.is_empty() && args.input_size.is_empty() {
+        return Ok(input_lattice);
+    }
+    let input_extent = input_lattice.get_extent();
+    let min = if args.input_min.is_empty() {
+        [0, 0, 0].into()
+    } else {
+        lat::Point::from(get_three_elements(&args.input_min))
+    };
+    let size = if args.input_size.is_empty() {
+        *input_extent.get_local_supremum() - min
+    } else {
+        lat::Point::from(get_three_elements(&args.input_size))
+    };
+    let extent = lat::Extent::from_min_and_local_supremum(*input_extent.get_minimum() + min, size);
+
+    Ok(crop_input(&input_lattice, &extent)?)
+}
+
+/// Applies --partial-tiles to an input of voxels `T`, padding with the --pad-value read by
+/// `parse_pad`, or with `empty` by default. A loaded model was already extracted, so its input is
+/// left as it is.
+fn fit_input<T: Clone>(
+    args: &Args,
+    input_lattice: VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    tile_size: &lat::Point,
+    empty: T,
+    parse_pad: fn(&str) -> Option<T>,
+) -> Result<VecLatticeMap<T, PeriodicYLevelsIndexer>, CliError> {
+    if args.load_model.is_some() {
+        return Ok(input_lattice);
+    }
+    let partial_tiles = match args.partial_tiles {
+        PartialTilesPolicy::Error => PartialTiles::Error,
+        PartialTilesPolicy::Crop => PartialTiles::Crop,
+        PartialTilesPolicy::Pad => match &args.pad_value {
+            Some(s) => PartialTiles::Pad(parse_pad(s).ok_or_else(|| {
+                CliError::Usage(format!("Invalid --pad-value {:?} for this input", s))
+            })?),
+            None => PartialTiles::Pad(empty),
+        },
+    };
+
+    let input_size = *input_lattice.get_extent().get_local_supremum();
+    let fitted = fit_to_tiles(input_lattice, tile_size, &partial_tiles)
+        .map_err(|e| CliError::Usage(format!("{}; see --partial-tiles", e)))?;
+    let fitted_size = *fitted.get_extent().get_local_supremum();
+    if fitted_size != input_size {
+        println!(
+            "Resized input from {} to {} to fit tiles of size {}",
+            input_size, fitted_size, tile_size
+        );
+    }
+
+    Ok(fitted)
+}
+
+/// Parses an RRGGBB or RRGGBBAA hex color, with an optional leading '#'.
+fn parse_hex_color(s: &str) -> Option<Rgba<u8>> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if (hex.len() != 6 && hex.len() != 8) || !hex.is_ascii() {
+        return None;
+    }
+    let mut color = Rgba([0, 0, 0, std::u8::MAX]);
+    for (i, c) in color.0.iter_mut().take(hex.len() / 2).enumerate() {
+        *c = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+
+    Some(color)
+}
+
+fn parse_input_boundary(s: &str) -> Result<InputBoundary, String> {
+    match s {
+        "periodic" => Ok(InputBoundary::Periodic),
+        "clamp" => Ok(InputBoundary::Clamp),
+        "skip" => Ok(InputBoundary::Skip),
+        _ => Err(format!("Unknown input boundary {:?}", s)),
+    }
+}
+
+fn parse_symmetry(s: &str) -> Result<Symmetry, String> {
+    match s {
+        "none" => Ok(Symmetry::None),
+        "mirror-x" => Ok(Symmetry::MirrorX),
+        "rotate-z" => Ok(Symmetry::RotateZ),
+        "dihedral" => Ok(Symmetry::Dihedral),
+        "full" => Ok(Symmetry::Full),
+        _ => Err(format!("Unknown symmetry {:?}", s)),
+    }
+}
+
+fn parse_schematic_format(s: &str) -> Result<SchematicFormat, String> {
+    match s {
+        "schem" => Ok(SchematicFormat::Schem),
+        "litematic" => Ok(SchematicFormat::Litematic),
+        _ => Err(format!("Unknown output format {:?}", s)),
+    }
+}
+
+enum Animation<I> {
+    Gif(Box<GifMaker<I>>),
+    Frames(FrameSequence),
+    Terminal(Box<TerminalRenderer<I>>),
 }
 
-fn generate<F>(
+impl<I: Clone + lat::Indexer> FrameConsumer for Animation<I> {
+    fn use_frame(&mut self, slots: &VecLatticeMap<PatternSet>) {
+        match self {
+            Animation::Gif(maker) => maker.use_frame(slots),
+            Animation::Frames(frames) => frames.use_frame(slots),
+            Animation::Terminal(renderer) => renderer.use_frame(slots),
+        }
+    }
+
+    fn use_frame_with_modified(
+        &mut self,
+        slots: &VecLatticeMap<PatternSet>,
+        modified_slots: &[lat::Point],
+    ) {
+        match self {
+            Animation::Gif(maker) => maker.use_frame_with_modified(slots, modified_slots),
+            Animation::Frames(frames) => frames.use_frame(slots),
+            Animation::Terminal(renderer) => {
+                renderer.use_frame_with_modified(slots, modified_slots)
+            }
+        }
+    }
+
+    fn use_frame_with_changes(
+        &mut self,
+        slots: &VecLatticeMap<PatternSet>,
+        modified_slots: &[lat::Point],
+        changed_slots: &[lat::Point],
+    ) {
+        match self {
+            Animation::Gif(maker) => {
+                maker.use_frame_with_changes(slots, modified_slots, changed_slots)
+            }
+            Animation::Frames(frames) => frames.use_frame(slots),
+            Animation::Terminal(renderer) => {
+                renderer.use_frame_with_changes(slots, modified_slots, changed_slots)
+            }
+        }
+    }
+}
+
+/// The tint of --highlight-changes.
+const HIGHLIGHT_COLOR: Rgba<u8> = Rgba([255, 0, 255, 255]);
+
+impl<I: lat::Indexer> Animation<I> {
+    fn finish(self) -> Result<(), CliError> {
+        match self {
+            Animation::Gif(maker) => {
+                let path = maker.path().to_owned();
+                let num_dropped_frames = maker.save()?;
+                if num_dropped_frames > 0 {
+                    println!(
+                        "Dropped the last {} frames of {:?} to stay within its limits",
+                        num_dropped_frames, path
+                    );
+                }
+
+                Ok(())
+            }
+            Animation::Frames(frames) => frames.finish(),
+            Animation::Terminal(renderer) => {
+                renderer.finish()?;
+
+                Ok(())
+            }
+        }
+    }
+}
+
+type WriteFrame<T = VecLatticeMap<PatternSet>> = dyn Fn(&T, &Path) -> Result<(), CliError>;
+
+/// Writes a numbered file for every `skip_frames` updates, e.g. "anim_00042.vox" for the prefix
+/// "anim". Frames are made from the superposition, or from any other `T` given to `add_frame`.
+struct FrameSequence<T = VecLatticeMap<PatternSet>> {
+    prefix: PathBuf,
+    extension: &'static str,
+    write_frame: Box<WriteFrame<T>>,
+    num_updates: usize,
+    num_frames: usize,
+    skip_frames: usize,
+    /// The first error from writing a frame, reported by `finish`.
+    error: Option<CliError>,
+}
+
+impl<T> FrameSequence<T> {
+    fn new(
+        prefix: PathBuf,
+        extension: &'static str,
+        skip_frames: usize,
+        write_frame: Box<WriteFrame<T>>,
+    ) -> Self {
+        FrameSequence {
+            prefix: prefix.with_extension(""),
+            extension,
+            write_frame,
+            num_updates: 0,
+            num_frames: 0,
+            skip_frames,
+            error: None,
+        }
+    }
+
+    fn finish(self) -> Result<(), CliError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        println!(
+            "Wrote {} frames to {:?}",
+            self.num_frames,
+            self.frame_path("*")
+        );
+
+        Ok(())
+    }
+
+    fn frame_path(&self, number: &str) -> PathBuf {
+        let mut file_name = self.prefix.file_name().unwrap_or_default().to_owned();
+        file_name.push(format!("_{}.{}", number, self.extension));
+
+        self.prefix.with_file_name(file_name)
+    }
+
+    fn add_frame(&mut self, frame: &T) {
+        if self.num_updates % self.skip_frames == 0 && self.error.is_none() {
+            let path = self.frame_path(&format!("{:05}", self.num_frames));
+            if let Err(e) = (self.write_frame)(frame, &path) {
+                self.error = Some(e);
+            }
+            self.num_frames += 1;
+        }
+        self.num_updates += 1;
+    }
+}
+
+impl FrameConsumer for FrameSequence {
+    fn use_frame(&mut self, slots: &VecLatticeMap<PatternSet>) {
+        self.add_frame(slots);
+    }
+}
+
+/// The entropy heatmaps requested with --debug-entropy.
+fn entropy_heatmaps(args: &Args, num_patterns: PatternIndex) -> Option<FrameSequence<Generator>> {
+    let max_entropy = (num_patterns as f32).log2();
+
+    args.debug_entropy.clone().map(|prefix| {
+        let write_frame = move |generator: &Generator, path: &Path| {
+            let heatmap = color_entropy(&generator.get_entropy_lattice(), max_entropy);
+            let heatmap_img: RgbaImage = (&slice_montage(&heatmap, Rgba([0; 4]))).into();
+
+            Ok(heatmap_img.save(path)?)
+        };
+
+        FrameSequence::new(prefix, "png", args.skip_frames, Box::new(write_frame))
+    })
+}
+
+/// Renders the superposition of part of the wave, for --contradiction-report.
+type RenderRegion<'a> = dyn Fn(&VecLatticeMap<PatternSet>) -> VecLatticeMap<Rgba<u8>> + 'a;
+
+/// How many slots around the failed slot to draw for --contradiction-report.
+const CONTRADICTION_RADIUS: i32 = 4;
+
+const CONTRADICTION_COLOR: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+/// The smallest width or height of the --contradiction-report image, which is scaled up to it.
+const MIN_CONTRADICTION_IMAGE_SIZE: u32 = 256;
+
+/// Writes the --contradiction-report of the last failed attempt.
+struct ContradictionWriter<'a> {
+    path: PathBuf,
+    tile_size: lat::Point,
+    /// `None` if the tiles can't be drawn.
+    render: Option<Box<RenderRegion<'a>>>,
+}
+
+impl ContradictionWriter<'_> {
+    fn write(
+        &self,
+        report: &ContradictionReport,
+        wave: &VecLatticeMap<PatternSet>,
+    ) -> Result<(), CliError> {
+        std::fs::write(&self.path, report.to_string())?;
+        println!("Wrote a contradiction report to {:?}", self.path);
+
+        if let (Some(render), Some((region, slot))) =
+            (&self.render, report.region(wave, CONTRADICTION_RADIUS))
+        {
+            let mut colors = render(&region);
+            tint_slots(&mut colors, &[slot], self.tile_size, CONTRADICTION_COLOR);
+            let region_img: RgbaImage = (&slice_montage(&colors, Rgba([0; 4]))).into();
+            let (width, height) = region_img.dimensions();
+            let scale = (MIN_CONTRADICTION_IMAGE_SIZE / width.min(height).max(1)).max(1);
+            let region_img = imageops::resize(
+                &region_img,
+                width * scale,
+                height * scale,
+                FilterType::Nearest,
+            );
+            let img_path = self.path.with_extension("png");
+            region_img.save(&img_path)?;
+            println!("Wrote the contradiction region to {:?}", img_path);
+        }
+
+        Ok(())
+    }
+}
+
+fn contradiction_writer<'a>(
+    args: &Args,
+    tile_size: lat::Point,
+    render: Option<Box<RenderRegion<'a>>>,
+) -> Option<ContradictionWriter<'a>> {
+    args.contradiction_report
+        .clone()
+        .map(|path| ContradictionWriter {
+            path,
+            tile_size,
+            render,
+        })
+}
+
+struct Checkpoints {
+    path: Option<PathBuf>,
+    every: usize,
+    resume: Option<PathBuf>,
+}
+
+impl Checkpoints {
+    fn from_args(args: &Args) -> Self {
+        Checkpoints {
+            path: args.checkpoint.clone(),
+            every: args.checkpoint_every,
+            resume: args.resume.clone(),
+        }
+    }
+
+    fn save(&self, generator: &Generator, num_updates: usize, interrupted: bool) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        if !interrupted && (self.every == 0 || num_updates % self.every != 0) {
+            return;
+        }
+
+        // Write to a temporary file first so a crash mid-write doesn't clobber the last checkpoint.
+        let tmp_path = path.with_extension("tmp");
+        let saved = File::create(&tmp_path)
+            .map_err(CliError::from)
+            .and_then(|file| Ok(save_snapshot(BufWriter::new(file), generator)?))
+            .and_then(|()| Ok(std::fs::rename(&tmp_path, path)?));
+        match saved {
+            Ok(()) if interrupted => println!("Saved checkpoint to {:?}", path),
+            Ok(()) => (),
+            Err(e) => println!("Failed to save checkpoint to {:?}: {}", path, e),
+        }
+    }
+}
+
+fn generate<T, F>(
     seed: [u8; 16],
-    sampler: &PatternSampler,
-    constraints: &PatternConstraints,
+    max_attempts: usize,
+    model: &Model<T>,
     output_size: lat::Point,
+    coarse: Option<&VecLatticeMap<PatternSet>>,
+    ground: Option<&GroundConstraint>,
+    edges: Option<&EdgeConstraint>,
+    checkpoints: &Checkpoints,
     frame_consumer: &mut Option<F>,
-    running: Arc<AtomicBool>,
-) -> Option<VecLatticeMap<PatternId>>
+    mut entropy_heatmaps: Option<FrameSequence<Generator>>,
+    contradictions: Option<ContradictionWriter<'_>>,
+    cancel: &CancellationToken,
+) -> Result<Option<VecLatticeMap<PatternId>>, CliError>
 where
     F: FrameConsumer,
 {
-    println!("Trying to generate with seed {:?}", seed);
+    // Frames can highlight the slots that changed in each update.
+    let track_modified = frame_consumer.is_some();
+    let track_removals = contradictions.is_some();
+    let resumed = match &checkpoints.resume {
+        Some(path) => {
+            println!("Resuming from {:?}", path);
+            let mut generator = load_snapshot(
+                BufReader::new(File::open(path)?),
+                model.constraints(),
+                &GeneratorConfig::default(),
+            )?;
+            if track_modified {
+                generator.track_modified_slots();
+                generator.track_changes();
+            }
+            if track_removals {
+                generator.track_removals();
+            }
+
+            Some(generator)
+        }
+        None => {
+            println!("Trying to generate with seed {:?}", seed);
+
+            None
+        }
+    };
 
     let volume = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), output_size).volume();
     let progress_bar = ProgressBar::new(volume as u64);
 
-    let mut generator = Generator::new(seed, output_size, sampler, constraints);
-    let mut success = true;
+    let mut num_updates = 0;
+    // The report and wave of the last failed attempt.
+    let mut failure = None;
+    // When the last frame was taken, so the next one only redraws what changed.
+    let mut change_stamp = None;
+    let on_update = |generator: &Generator| {
+        progress_bar.set_position(generator.num_collapsed() as u64);
+        if let Some(report) = generator.contradiction_report() {
+            failure = Some((report.clone(), generator.get_wave_lattice().clone()));
+        }
+
+        if let Some(consumer) = frame_consumer.as_mut() {
+            consumer.use_frame_with_changes(
+                generator.get_wave_lattice(),
+                &generator.modified_slots(),
+                &generator.changed_slots_since(change_stamp),
+            );
+            change_stamp = Some(generator.change_stamp());
+        }
+        if let Some(heatmaps) = entropy_heatmaps.as_mut() {
+            heatmaps.add_frame(generator);
+        }
+
+        num_updates += 1;
+        // Can be interrupted by other threads.
+        let interrupted = cancel.is_cancelled();
+        checkpoints.save(generator, num_updates, interrupted);
+
+        !interrupted
+    };
+
     println!("Generating...");
+    let (result, stats) = match resumed {
+        Some(generator) => resume(generator, model, on_update),
+        None => generate_with_retries(
+            seed,
+            max_attempts,
+            output_size,
+            model.sampler(),
+            model.constraints(),
+            |generator| {
+                if track_modified {
+                    generator.track_modified_slots();
+                    generator.track_changes();
+                }
+                if track_removals {
+                    generator.track_removals();
+                }
+                if let Some(coarse) = coarse {
+                    let restrictions = coarse
+                        .get_extent()
+                        .into_iter()
+                        .map(|slot| (slot, coarse.get_world_ref(&slot)));
+                    let result = generator
+                        .constrain_slots(model.sampler(), model.constraints(), restrictions)
+                        .expect("Coarse patterns cover the output");
+                    if !matches!(result, UpdateResult::Continue) {
+                        return result;
+                    }
+                }
+                if let Some(ground) = ground {
+                    let result = ground.apply(generator, model.sampler(), model.constraints());
+                    if !matches!(result, UpdateResult::Continue) {
+                        return result;
+                    }
+                }
+                match edges {
+                    Some(edges) => edges.apply(generator, model.sampler(), model.constraints()),
+                    None => UpdateResult::Continue,
+                }
+            },
+            on_update,
+        ),
+    };
+
+    progress_bar.finish_at_current_pos();
+    if let Some(heatmaps) = entropy_heatmaps {
+        heatmaps.finish()?;
+    }
+
+    println!(
+        "Ran {} attempt(s) with {} total updates",
+        stats.num_attempts, stats.total_updates
+    );
+    if result.is_some() {
+        if stats.num_attempts > 1 {
+            println!("Succeeded with seed {:?}", stats.last_seed);
+        }
+    } else if let Some(e) = &stats.last_error {
+        println!("Failed to generate: {}", e);
+    } else {
+        println!("Failed to generate");
+    }
+    if let (Some(writer), Some((report, wave)), None) = (&contradictions, &failure, &result) {
+        writer.write(report, wave)?;
+    }
+
+    Ok(result)
+}
+
+/// Finishes a generator loaded from a checkpoint. There's no seed to retry with, so this is a
+/// single attempt.
+fn resume<T, U>(
+    mut generator: Generator,
+    model: &Model<T>,
+    mut on_update: U,
+) -> (Option<VecLatticeMap<PatternId>>, RetryStats)
+where
+    U: FnMut(&Generator) -> bool,
+{
+    let mut stats = RetryStats {
+        num_attempts: 1,
+        last_seed: [0; NUM_SEED_BYTES],
+        total_updates: 0,
+        last_error: None,
+    };
     loop {
-        let state = generator.update(sampler, constraints);
-        progress_bar.set_position(generator.num_collapsed() as u64);
+        let state = generator.update(model.sampler(), model.constraints());
+        stats.total_updates += 1;
+        let keep_going = on_update(&generator);
         match state {
-            UpdateResult::Success => break,
-            UpdateResult::Failure => {
-                success = false;
-                break;
+            UpdateResult::Success => return (Some(generator.result()), stats),
+            UpdateResult::Failure(e) => {
+                stats.last_error = Some(e);
+
+                return (None, stats);
             }
-            UpdateResult::Continue => (),
+            _ if !keep_going => return (None, stats),
+            _ => (),
         }
+    }
+}
 
-        // Can be interrupted by other threads.
-        if !running.load(Ordering::SeqCst) {
-            success = false;
-            break;
+#[derive(Debug)]
+enum CliError {
+    Image(ImageError),
+    Io(io::Error),
+    ModelFile(ModelFileError),
+    Snapshot(SnapshotError),
+    Tiled(TiledError),
+    Tileset(TilesetFileError),
+    Assignment(AssignmentError),
+    Wfc(WfcError),
+    /// A malformed --forbid or --blocks file.
+    Rules(String),
+    /// The first pass of --coarse-scale failed.
+    Coarse(String),
+    /// Arguments that don't fit the subcommand.
+    Usage(String),
+    /// A malformed --config file.
+    Config(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Image(e) => write!(f, "{}", e),
+            CliError::Io(e) => write!(f, "{}", e),
+            CliError::ModelFile(e) => write!(f, "{}", e),
+            CliError::Snapshot(e) => write!(f, "{}", e),
+            CliError::Tiled(e) => write!(f, "{}", e),
+            CliError::Tileset(e) => write!(f, "{}", e),
+            CliError::Assignment(e) => write!(f, "{}", e),
+            CliError::Wfc(e) => write!(f, "{}", e),
+            CliError::Rules(msg) => write!(f, "{}", msg),
+            CliError::Coarse(msg) => write!(f, "{}", msg),
+            CliError::Usage(msg) => write!(f, "{}", msg),
+            CliError::Config(msg) => write!(f, "{}", msg),
         }
+    }
+}
 
-        if let Some(consumer) = frame_consumer {
-            consumer.use_frame(generator.get_wave_lattice());
+impl error::Error for CliError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CliError::Image(e) => e.source(),
+            CliError::Io(e) => e.source(),
+            CliError::ModelFile(e) => e.source(),
+            CliError::Snapshot(e) => e.source(),
+            CliError::Tiled(e) => e.source(),
+            CliError::Tileset(e) => e.source(),
+            CliError::Assignment(e) => e.source(),
+            CliError::Wfc(e) => e.source(),
+            CliError::Rules(_) | CliError::Coarse(_) | CliError::Usage(_) | CliError::Config(_) => {
+                None
+            }
         }
     }
+}
 
-    progress_bar.finish_at_current_pos();
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Io(e)
+    }
+}
 
-    if success {
-        Some(generator.result())
-    } else {
-        println!("Failed to generate");
+impl From<ModelFileError> for CliError {
+    fn from(e: ModelFileError) -> Self {
+        CliError::ModelFile(e)
+    }
+}
+
+impl From<SnapshotError> for CliError {
+    fn from(e: SnapshotError) -> Self {
+        CliError::Snapshot(e)
+    }
+}
+
+impl From<TiledError> for CliError {
+    fn from(e: TiledError) -> Self {
+        CliError::Tiled(e)
+    }
+}
+
+impl From<TilesetFileError> for CliError {
+    fn from(e: TilesetFileError) -> Self {
+        CliError::Tileset(e)
+    }
+}
+
+impl From<AssignmentError> for CliError {
+    fn from(e: AssignmentError) -> Self {
+        CliError::Assignment(e)
+    }
+}
+
+impl From<ImageError> for CliError {
+    fn from(e: ImageError) -> Self {
+        CliError::Image(e)
+    }
+}
 
-        None
+impl From<WfcError> for CliError {
+    fn from(e: WfcError) -> Self {
+        CliError::Wfc(e)
     }
 }
@@ -0,0 +1,706 @@
+//! The `serve` subcommand: a small HTTP API that learns models from uploaded samples and generates
+//! outputs from them in the background.
+//!
+//! ```text
+//! POST   /samples?pattern_size=3,3,1&tile_size=1,1,1    body: a PNG (or other image) or VOX file
+//! POST   /models?kind=image                             body: a model saved by `extract`
+//! POST   /models/<model>/jobs                           body: generation parameters, as JSON
+//! GET    /jobs/<job>                                    the progress of the job
+//! GET    /jobs/<job>/result.png                         the output, once the job is done
+//! DELETE /jobs/<job>                                    cancels the job and forgets it
+//! ```
+//!
+//! Model uploads return `{ "model": 0, "kind": "image", "num_patterns": 52 }`. Samples are
+//! extracted in the background, like generation, so they return `{ "job": 0 }`, and the job's
+//! status has the `model` once it's done. `tile_size` defaults to 1,1,1, and `kind` ("image" or
+//! "vox") to what the model was extracted from, if it was saved by `extract`, or "image". Models
+//! uploaded without their sample write VOX outputs with the default palette. The generation
+//! parameters look like:
+//!
+//! ```text
+//! {
+//!     "size": [64, 64, 1],
+//!     "seed": "flowers",
+//!     "max_attempts": 3,
+//!     "constraints": [{ "slot": [0, 0, 0], "patterns": [4, 7] }]
+//! }
+//! ```
+//!
+//! Only `size` is required. Results can be downloaded as "result.png" (image models), "result.vox"
+//! (VOX models), or "result.json", which has the pattern of every slot. Everything is kept in
+//! memory, so models and jobs are lost when the server stops. Only the latest finished jobs are
+//! kept; see `--max-finished-jobs`.
+
+use super::http_api::{json_output, read_body, GenerateRequest, RequestError};
+use super::{skip_model_source, CliError, InputMode, VoxColorPalette};
+
+use ilattice3_wfc::*;
+
+use ilattice3 as lat;
+use ilattice3::{GetExtent, PeriodicYLevelsIndexer, VecLatticeMap, VoxColor};
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(structopt::StructOpt)]
+pub struct ServeArgs {
+    #[structopt(long, default_value = "127.0.0.1:8080")]
+    address: String,
+
+    /// Number of requests handled concurrently. Jobs generate on threads of their own.
+    #[structopt(long, default_value = "4")]
+    threads: usize,
+
+    /// Number of jobs that may generate or extract at once. Further jobs are refused until one
+    /// finishes.
+    #[structopt(long, default_value = "4")]
+    max_jobs: usize,
+
+    /// Number of finished jobs whose status and result are kept. The oldest are forgotten first.
+    #[structopt(long, default_value = "256")]
+    max_finished_jobs: usize,
+
+    /// A job is abandoned after this many seconds, summed over all attempts.
+    #[structopt(long, default_value = "300")]
+    timeout_secs: u64,
+
+    /// The largest output (in slots) that a job may ask for.
+    #[structopt(long, default_value = "1048576")]
+    max_volume: usize,
+
+    /// The largest upload (in MiB) that will be read.
+    #[structopt(long, default_value = "16")]
+    max_upload_mb: u64,
+
+    /// The largest pattern or tile size, on any axis, that a sample may be extracted with.
+    #[structopt(long, default_value = "8")]
+    max_pattern_size: i32,
+}
+
+/// How often the request threads check whether the server was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Serves requests until `cancel` is cancelled, e.g. by Ctrl-C.
+pub fn serve(args: ServeArgs, cancel: &CancellationToken) -> Result<(), CliError> {
+    let server =
+        Server::http(&args.address).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let server = Arc::new(server);
+    println!("Serving on {}", args.address);
+
+    let state = Arc::new(ServeState {
+        models: Arc::new(RwLock::new(Vec::new())),
+        jobs: Mutex::new(HashMap::new()),
+        next_job_id: AtomicUsize::new(0),
+        num_running: Arc::new(AtomicUsize::new(0)),
+        timeout: Duration::from_secs(args.timeout_secs),
+        max_jobs: args.max_jobs,
+        max_finished_jobs: args.max_finished_jobs,
+        max_volume: args.max_volume,
+        max_upload_bytes: args.max_upload_mb * 1024 * 1024,
+        max_pattern_size: args.max_pattern_size,
+    });
+    let workers: Vec<_> = (0..args.threads.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let state = state.clone();
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                while !cancel.is_cancelled() {
+                    match server.recv_timeout(POLL_INTERVAL) {
+                        Ok(Some(request)) => state.handle(request),
+                        Ok(None) => (),
+                        Err(e) => println!("Failed to receive request: {}", e),
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers.into_iter() {
+        worker.join().expect("Worker thread panicked");
+    }
+
+    // Stop the jobs that are still generating.
+    for job in state.jobs.lock().unwrap().values() {
+        job.cancel.cancel();
+    }
+
+    Ok(())
+}
+
+enum ServedModel {
+    Image(Model<Rgba<u8>>),
+    Vox(Model<VoxColor>, VoxColorPalette),
+}
+
+impl ServedModel {
+    fn kind(&self) -> &'static str {
+        match self {
+            ServedModel::Image(_) => "image",
+            ServedModel::Vox(..) => "vox",
+        }
+    }
+
+    fn num_patterns(&self) -> PatternIndex {
+        match self {
+            ServedModel::Image(model) => model.constraints().num_patterns(),
+            ServedModel::Vox(model, _) => model.constraints().num_patterns(),
+        }
+    }
+}
+
+type HttpResponse = Response<io::Cursor<Vec<u8>>>;
+
+/// Each model's ID is its index.
+type Models = RwLock<Vec<Arc<ServedModel>>>;
+
+struct ServeState {
+    models: Arc<Models>,
+    jobs: Mutex<HashMap<usize, Arc<Job>>>,
+    next_job_id: AtomicUsize,
+    num_running: Arc<AtomicUsize>,
+    timeout: Duration,
+    max_jobs: usize,
+    max_finished_jobs: usize,
+    max_volume: usize,
+    max_upload_bytes: u64,
+    max_pattern_size: i32,
+}
+
+impl ServeState {
+    fn handle(&self, mut request: Request) {
+        let response = self.respond(&mut request).unwrap_or_else(|e| {
+            Response::from_string(e.to_string()).with_status_code(e.status_code())
+        });
+        if let Err(e) = request.respond(response) {
+            println!("Failed to send response: {}", e);
+        }
+    }
+
+    fn respond(&self, request: &mut Request) -> Result<HttpResponse, RequestError> {
+        let url = request.url().to_string();
+        let (path, query) = split_query(&url);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (request.method().clone(), segments.as_slice()) {
+            (Method::Post, ["samples"]) => {
+                let body = read_body(request, self.max_upload_bytes)?;
+                self.add_sample(body, &query)
+            }
+            (Method::Post, ["models"]) => {
+                let body = read_body(request, self.max_upload_bytes)?;
+                self.add_model(&body, &query)
+            }
+            (Method::Post, ["models", model_id, "jobs"]) => {
+                let model = self.model(model_id)?;
+                let body = read_body(request, self.max_upload_bytes)?;
+                self.start_job(model, &body)
+            }
+            (Method::Get, ["jobs", job_id]) => {
+                let job = self.job(job_id)?;
+                let status = job.status.lock().unwrap();
+
+                Ok(json_response(200, &*status))
+            }
+            (Method::Get, ["jobs", job_id, file_name]) => self.job(job_id)?.result(file_name),
+            (Method::Delete, ["jobs", job_id]) => {
+                let job = self.job(job_id)?;
+                job.cancel.cancel();
+                self.jobs.lock().unwrap().remove(&parse_id(job_id)?);
+
+                Ok(Response::from_data(Vec::new()).with_status_code(204))
+            }
+            _ => Err(RequestError::NotFound),
+        }
+    }
+
+    fn model(&self, id: &str) -> Result<Arc<ServedModel>, RequestError> {
+        self.models
+            .read()
+            .unwrap()
+            .get(parse_id(id)?)
+            .cloned()
+            .ok_or(RequestError::NotFound)
+    }
+
+    fn job(&self, id: &str) -> Result<Arc<Job>, RequestError> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&parse_id(id)?)
+            .cloned()
+            .ok_or(RequestError::NotFound)
+    }
+
+    /// Starts extracting a model from an image or VOX file.
+    fn add_sample(&self, bytes: Vec<u8>, query: &Query) -> Result<HttpResponse, RequestError> {
+        let pattern_size = query
+            .point("pattern_size")?
+            .ok_or_else(|| RequestError::BadRequest("Missing pattern_size".to_string()))?;
+        let tile_size = query.point("tile_size")?.unwrap_or([1, 1, 1].into());
+        let [px, py, pz]: [i32; 3] = pattern_size.into();
+        let [tx, ty, tz]: [i32; 3] = tile_size.into();
+        if [px, py, pz, tx, ty, tz]
+            .iter()
+            .any(|s| *s > self.max_pattern_size)
+        {
+            return Err(RequestError::BadRequest(format!(
+                "Pattern and tile sizes must be at most {} on every axis",
+                self.max_pattern_size
+            )));
+        }
+
+        self.reserve_job()?;
+        let job = Arc::new(Job::new(None, 0));
+        let job_id = self.insert_job(job.clone());
+        println!("Started extracting job {}", job_id);
+
+        let models = self.models.clone();
+        let max_volume = self.max_volume;
+        let num_running = self.num_running.clone();
+        thread::spawn(move || {
+            let model = extract_sample(&bytes, pattern_size, tile_size, max_volume);
+            let mut status = job.status.lock().unwrap();
+            match model {
+                Ok(model) => {
+                    status.model = Some(insert_model(&models, model).model);
+                    status.state = JobState::Done;
+                }
+                Err(e) => {
+                    status.state = JobState::Failed;
+                    status.error = Some(e.to_string());
+                }
+            }
+            drop(status);
+            num_running.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        Ok(json_response(202, &JobCreated { job: job_id }))
+    }
+
+    /// Loads a model saved by `extract` or --save-model.
+    fn add_model(&self, mut bytes: &[u8], query: &Query) -> Result<HttpResponse, RequestError> {
+        let source =
+            skip_model_source(&mut bytes).map_err(|e| RequestError::BadRequest(e.to_string()))?;
+        let source_is_vox = match source {
+            Some(s) => {
+                s.input_mode == InputMode::Heightmap
+                    || s.input_path.extension() == Some(OsStr::new("vox"))
+            }
+            None => false,
+        };
+        let is_vox = match query.get("kind") {
+            Some("image") => false,
+            Some("vox") => true,
+            Some(kind) => {
+                return Err(RequestError::BadRequest(format!(
+                    "Unknown kind {:?}; expected \"image\" or \"vox\"",
+                    kind
+                )))
+            }
+            None => source_is_vox,
+        };
+
+        let bad_model = |e: ModelFileError| RequestError::BadRequest(e.to_string());
+        let model = if is_vox {
+            let (sampler, constraints, tiles) =
+                load_model::<VoxColor, _>(bytes).map_err(bad_model)?;
+            let palette = VoxColorPalette {
+                colors: dot_vox::DEFAULT_PALETTE.clone(),
+                materials: Vec::new(),
+            };
+
+            ServedModel::Vox(Model::new(sampler, constraints, tiles), palette)
+        } else {
+            let (sampler, constraints, tiles) =
+                load_model::<[u8; 4], _>(bytes).map_err(bad_model)?;
+
+            ServedModel::Image(Model::new(
+                sampler,
+                constraints,
+                tiles.map_voxels(|c| Rgba(*c)),
+            ))
+        };
+
+        Ok(json_response(201, &insert_model(&self.models, model)))
+    }
+
+    /// Counts a new job against `max_jobs`, or fails with `Busy` if there's no room. The job must
+    /// decrement `num_running` when it finishes.
+    fn reserve_job(&self) -> Result<(), RequestError> {
+        if self.num_running.fetch_add(1, Ordering::SeqCst) >= self.max_jobs {
+            self.num_running.fetch_sub(1, Ordering::SeqCst);
+            return Err(RequestError::Busy);
+        }
+
+        Ok(())
+    }
+
+    /// Adds `job`, forgetting the oldest finished jobs beyond `max_finished_jobs`. Returns its ID.
+    fn insert_job(&self, job: Arc<Job>) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut finished: Vec<usize> = jobs
+            .iter()
+            .filter(|(_, job)| job.status.lock().unwrap().state != JobState::Running)
+            .map(|(id, _)| *id)
+            .collect();
+        if finished.len() > self.max_finished_jobs {
+            finished.sort_unstable();
+            for id in finished[..finished.len() - self.max_finished_jobs].iter() {
+                jobs.remove(id);
+            }
+        }
+
+        let job_id = self.next_job_id.fetch_add(1, Ordering::SeqCst);
+        jobs.insert(job_id, job);
+
+        job_id
+    }
+
+    fn start_job(
+        &self,
+        model: Arc<ServedModel>,
+        body: &[u8],
+    ) -> Result<HttpResponse, RequestError> {
+        let request: GenerateRequest =
+            serde_json::from_slice(body).map_err(|e| RequestError::BadRequest(e.to_string()))?;
+        let (output_size, slot_constraints) =
+            request.validate(model.num_patterns(), self.max_volume)?;
+        if let ServedModel::Image(_) = *model {
+            if output_size.z != 1 {
+                return Err(RequestError::BadRequest(
+                    "Image models need a size of 1 in Z".to_string(),
+                ));
+            }
+        }
+
+        self.reserve_job()?;
+        let num_slots =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), output_size).volume();
+        let job = Arc::new(Job::new(Some(model), num_slots));
+        let job_id = self.insert_job(job.clone());
+        println!("Started job {}", job_id);
+
+        let params = JobParams {
+            output_size,
+            seed: seed_from_str(&request.seed),
+            max_attempts: request.max_attempts.max(1),
+            slot_constraints,
+            deadline: Instant::now() + self.timeout,
+        };
+        let num_running = self.num_running.clone();
+        thread::spawn(move || {
+            job.run(&params);
+            num_running.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        Ok(json_response(202, &JobCreated { job: job_id }))
+    }
+}
+
+/// A generation or extraction running in the background, or finished.
+struct Job {
+    /// The model to generate from, or `None` if the job extracts a model from a sample.
+    model: Option<Arc<ServedModel>>,
+    cancel: CancellationToken,
+    status: Mutex<JobStatus>,
+}
+
+struct JobParams {
+    output_size: lat::Point,
+    seed: [u8; NUM_SEED_BYTES],
+    max_attempts: usize,
+    slot_constraints: Vec<(lat::Point, PatternSet)>,
+    deadline: Instant,
+}
+
+impl Job {
+    fn new(model: Option<Arc<ServedModel>>, num_slots: usize) -> Self {
+        Job {
+            model,
+            cancel: CancellationToken::new(),
+            status: Mutex::new(JobStatus {
+                state: JobState::Running,
+                attempt: 0,
+                num_collapsed: 0,
+                num_slots,
+                error: None,
+                model: None,
+                result: None,
+            }),
+        }
+    }
+
+    fn run(&self, params: &JobParams) {
+        let outcome = match self.model.as_deref() {
+            Some(ServedModel::Image(model)) => self.generate(model, params),
+            Some(ServedModel::Vox(model, _)) => self.generate(model, params),
+            None => return,
+        };
+
+        let mut status = self.status.lock().unwrap();
+        match outcome {
+            UpdateResult::Success => status.state = JobState::Done,
+            UpdateResult::Failure(e) => {
+                status.state = JobState::Failed;
+                status.error = Some(e.to_string());
+            }
+            UpdateResult::TimedOut => status.state = JobState::TimedOut,
+            _ => status.state = JobState::Cancelled,
+        }
+    }
+
+    /// Generates until an attempt succeeds or they run out, storing the result in the status.
+    fn generate<T>(&self, model: &Model<T>, params: &JobParams) -> UpdateResult {
+        let mut seed = params.seed;
+        let mut outcome = UpdateResult::Continue;
+        for attempt in 1..=params.max_attempts {
+            self.status.lock().unwrap().attempt = attempt;
+            let mut generator = model.generator(seed, params.output_size);
+            outcome = generator
+                .constrain_slots(
+                    params
+                        .slot_constraints
+                        .iter()
+                        .map(|(slot, allowed)| (*slot, allowed)),
+                )
+                .expect("Slot constraints are validated when the job is submitted");
+            if outcome == UpdateResult::Continue {
+                for progress in generator.frames() {
+                    self.status.lock().unwrap().num_collapsed = progress.num_collapsed;
+                    outcome = progress.result;
+                    if outcome == UpdateResult::Continue {
+                        if self.cancel.is_cancelled() {
+                            return UpdateResult::Cancelled;
+                        }
+                        if Instant::now() >= params.deadline {
+                            return UpdateResult::TimedOut;
+                        }
+                    }
+                }
+            }
+
+            if outcome == UpdateResult::Success {
+                self.status.lock().unwrap().result = Some(generator.result());
+                break;
+            }
+            seed = mutate_seed(seed);
+        }
+
+        outcome
+    }
+
+    /// Encodes the result as the format of `file_name`.
+    fn result(&self, file_name: &str) -> Result<HttpResponse, RequestError> {
+        let status = self.status.lock().unwrap();
+        let result = status.result.as_ref().ok_or(RequestError::NotDone)?;
+
+        let (content_type, bytes) = match (self.model.as_deref(), file_name) {
+            (Some(ServedModel::Image(model)), "result.png") => {
+                let colors = color_final_patterns_rgba(result, model.tiles());
+                let img: RgbaImage = (&colors).into();
+                let mut bytes = Vec::new();
+                DynamicImage::ImageRgba8(img)
+                    .write_to(&mut bytes, ImageOutputFormat::Png)
+                    .map_err(|e| RequestError::Internal(e.to_string()))?;
+
+                ("image/png", bytes)
+            }
+            (Some(ServedModel::Vox(model, palette)), "result.vox") => {
+                let colors = color_final_patterns_vox(result, model.tiles());
+                let mut bytes = Vec::new();
+                write_vox_scene(&mut bytes, &colors, &palette.colors, &palette.materials)
+                    .map_err(|e| RequestError::Internal(e.to_string()))?;
+
+                ("application/octet-stream", bytes)
+            }
+            (_, "result.json") => ("application/json", json_output(result)),
+            _ => return Err(RequestError::NotFound),
+        };
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+
+        Ok(Response::from_data(bytes).with_header(header))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+/// Returned by `GET /jobs/<job>`.
+#[derive(Serialize)]
+struct JobStatus {
+    state: JobState,
+    /// Starts at 1.
+    attempt: usize,
+    /// In the current attempt.
+    num_collapsed: usize,
+    num_slots: usize,
+    /// Why the last attempt failed.
+    error: Option<String>,
+    /// The ID of the model extracted from a sample, once it's done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<usize>,
+    #[serde(skip)]
+    result: Option<VecLatticeMap<PatternId>>,
+}
+
+#[derive(Serialize)]
+struct ModelCreated {
+    model: usize,
+    kind: &'static str,
+    num_patterns: PatternIndex,
+}
+
+#[derive(Serialize)]
+struct JobCreated {
+    job: usize,
+}
+
+/// Extracts a model from an image or VOX file, as long as it has at most `max_volume` voxels.
+fn extract_sample(
+    bytes: &[u8],
+    pattern_size: lat::Point,
+    tile_size: lat::Point,
+    max_volume: usize,
+) -> Result<ServedModel, RequestError> {
+    let indexer = PeriodicYLevelsIndexer {};
+    let bad_sample = |e: WfcError| RequestError::BadRequest(e.to_string());
+    let check_volume = |extent: lat::Extent| {
+        if extent.volume() > max_volume {
+            return Err(RequestError::BadRequest(format!(
+                "Samples may have at most {} voxels",
+                max_volume
+            )));
+        }
+
+        Ok(())
+    };
+
+    let model = if bytes.starts_with(b"VOX ") {
+        let vox =
+            dot_vox::load_bytes(bytes).map_err(|e| RequestError::BadRequest(e.to_string()))?;
+        if vox.models.is_empty() {
+            return Err(RequestError::BadRequest(
+                "No models in VOX file".to_string(),
+            ));
+        }
+        let lattice = VecLatticeMap::from_vox_with_indexer(indexer, &vox, 0);
+        check_volume(lattice.get_extent())?;
+        let offset_group = OffsetGroup::new(&face_3d_offsets()).expect("Invalid built-in offsets");
+        let pattern_shape = PatternShape::with_full_overlap(pattern_size, offset_group);
+        let model =
+            Model::from_lattice(&lattice, &tile_size, &pattern_shape).map_err(bad_sample)?;
+        let palette = VoxColorPalette {
+            colors: vox.palette,
+            materials: vox.materials,
+        };
+
+        ServedModel::Vox(model, palette)
+    } else {
+        let img =
+            image::load_from_memory(bytes).map_err(|e| RequestError::BadRequest(e.to_string()))?;
+        if pattern_size.z != 1 || tile_size.z != 1 {
+            return Err(RequestError::BadRequest(
+                "Image samples need sizes of 1 in Z".to_string(),
+            ));
+        }
+        let lattice: VecLatticeMap<_, _> = (&img.to_rgba(), indexer).into();
+        check_volume(lattice.get_extent())?;
+        let offset_group = OffsetGroup::new(&edge_2d_offsets()).expect("Invalid built-in offsets");
+        let pattern_shape = PatternShape::with_full_overlap(pattern_size, offset_group);
+
+        ServedModel::Image(
+            Model::from_lattice(&lattice, &tile_size, &pattern_shape).map_err(bad_sample)?,
+        )
+    };
+
+    Ok(model)
+}
+
+fn insert_model(models: &Models, model: ServedModel) -> ModelCreated {
+    let mut models = models.write().unwrap();
+    let created = ModelCreated {
+        model: models.len(),
+        kind: model.kind(),
+        num_patterns: model.num_patterns(),
+    };
+    println!(
+        "Added {} model {} with {} patterns",
+        created.kind, created.model, created.num_patterns
+    );
+    models.push(Arc::new(model));
+
+    created
+}
+
+fn json_response<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+
+    Response::from_data(serde_json::to_vec(value).unwrap())
+        .with_header(header)
+        .with_status_code(status_code)
+}
+
+/// The parameters after the "?" of a URL. Values aren't percent-decoded, since none of them need
+/// to be.
+struct Query<'a>(HashMap<&'a str, &'a str>);
+
+impl<'a> Query<'a> {
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.0.get(key).copied()
+    }
+
+    /// Parses a value like "3,3,1".
+    fn point(&self, key: &str) -> Result<Option<lat::Point>, RequestError> {
+        let value = match self.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let bad_value = || RequestError::BadRequest(format!("Expected {}=X,Y,Z", key));
+        let coords = value
+            .split(',')
+            .map(|c| c.parse::<i32>().ok().filter(|c| *c > 0))
+            .collect::<Option<Vec<i32>>>()
+            .ok_or_else(bad_value)?;
+        if coords.len() != 3 {
+            return Err(bad_value());
+        }
+
+        Ok(Some([coords[0], coords[1], coords[2]].into()))
+    }
+}
+
+fn split_query(url: &str) -> (&str, Query<'_>) {
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap();
+    let params = parts
+        .next()
+        .unwrap_or("")
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut key_value = param.splitn(2, '=');
+
+            (key_value.next().unwrap(), key_value.next().unwrap_or(""))
+        })
+        .collect();
+
+    (path, Query(params))
+}
+
+fn parse_id(id: &str) -> Result<usize, RequestError> {
+    id.parse().map_err(|_| RequestError::NotFound)
+}
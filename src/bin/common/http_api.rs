@@ -0,0 +1,154 @@
+//! The requests, responses, and errors shared by the `server` binary and the `serve` subcommand of
+//! the CLI.
+
+use ilattice3_wfc::*;
+
+use ilattice3 as lat;
+use ilattice3::{GetExtent, GetWorld, VecLatticeMap};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use tiny_http::Request;
+
+/// The generation parameters of a request. Only `size` is required.
+#[derive(Deserialize)]
+pub struct GenerateRequest {
+    pub size: [i32; 3],
+    #[serde(default)]
+    pub seed: String,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+    #[serde(default)]
+    pub constraints: Vec<SlotConstraint>,
+}
+
+fn default_max_attempts() -> usize {
+    1
+}
+
+/// Restricts `slot` to `patterns`.
+#[derive(Deserialize)]
+pub struct SlotConstraint {
+    pub slot: [i32; 3],
+    pub patterns: Vec<PatternIndex>,
+}
+
+/// The patterns each constrained slot is restricted to.
+pub type SlotConstraints = Vec<(lat::Point, PatternSet)>;
+
+impl GenerateRequest {
+    /// Checks that the output is positive and at most `max_volume` slots, and that every
+    /// constrained slot is inside it. Returns the output size and the restriction of each slot.
+    pub fn validate(
+        &self,
+        num_patterns: PatternIndex,
+        max_volume: usize,
+    ) -> Result<(lat::Point, SlotConstraints), RequestError> {
+        let output_size = lat::Point::from(self.size);
+        let output_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), output_size);
+        if self.size.iter().any(|s| *s <= 0) || output_extent.volume() > max_volume {
+            return Err(RequestError::BadRequest(format!(
+                "Size must be positive with at most {} slots",
+                max_volume
+            )));
+        }
+        let mut slot_constraints = Vec::with_capacity(self.constraints.len());
+        for c in self.constraints.iter() {
+            let slot = lat::Point::from(c.slot);
+            if !output_extent.contains_world(&slot) {
+                return Err(RequestError::BadRequest(format!(
+                    "Slot {} is outside of the output",
+                    slot
+                )));
+            }
+            let mut allowed = PatternSet::all(num_patterns);
+            for p in (0..num_patterns).map(PatternId) {
+                if !c.patterns.contains(&p.0) {
+                    allowed.remove(p);
+                }
+            }
+            slot_constraints.push((slot, allowed));
+        }
+
+        Ok((output_size, slot_constraints))
+    }
+}
+
+/// Reads the body of `request`, or fails with `TooLarge` if it's longer than `max_bytes`.
+pub fn read_body(request: &mut Request, max_bytes: u64) -> Result<Vec<u8>, RequestError> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max_bytes + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| RequestError::BadRequest(e.to_string()))?;
+    if body.len() as u64 > max_bytes {
+        return Err(RequestError::TooLarge);
+    }
+
+    Ok(body)
+}
+
+/// The pattern of every slot, in extent iteration order.
+#[derive(Serialize)]
+struct JsonOutput {
+    size: [i32; 3],
+    patterns: Vec<PatternIndex>,
+}
+
+pub fn json_output(result: &VecLatticeMap<PatternId>) -> Vec<u8> {
+    let extent = result.get_extent();
+    let output = JsonOutput {
+        size: (*extent.get_local_supremum()).into(),
+        patterns: extent.into_iter().map(|p| result.get_world(&p).0).collect(),
+    };
+
+    serde_json::to_vec(&output).unwrap()
+}
+
+// Each server only returns some of these.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum RequestError {
+    NotFound,
+    BadRequest(String),
+    TooLarge,
+    /// Every attempt ended in a contradiction.
+    Contradiction(GenerationError),
+    TimedOut,
+    /// The job hasn't produced a result, at least not yet.
+    NotDone,
+    /// Too many jobs are running.
+    Busy,
+    Internal(String),
+}
+
+impl RequestError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            RequestError::NotFound => 404,
+            RequestError::BadRequest(_) => 400,
+            RequestError::TooLarge => 413,
+            RequestError::Contradiction(_) => 422,
+            RequestError::TimedOut => 503,
+            RequestError::NotDone => 409,
+            RequestError::Busy => 503,
+            RequestError::Internal(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::NotFound => write!(f, "Not found"),
+            RequestError::BadRequest(e) => write!(f, "Bad request: {}", e),
+            RequestError::TooLarge => write!(f, "Request body too large"),
+            RequestError::Contradiction(e) => write!(f, "Failed to generate: {}", e),
+            RequestError::TimedOut => write!(f, "Timed out"),
+            RequestError::NotDone => write!(f, "The job has no result; check its state"),
+            RequestError::Busy => write!(f, "Too many jobs running; try again later"),
+            RequestError::Internal(e) => write!(f, "Internal error: {}", e),
+        }
+    }
+}
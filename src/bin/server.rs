@@ -0,0 +1,312 @@
+//! A small HTTP server that generates outputs from models saved with `cli --save-model`.
+//!
+//! Each model is loaded once at startup and shared by all worker threads. Requests look like:
+//!
+//! ```text
+//! POST /generate/<model name>
+//! {
+//!     "size": [64, 64, 1],
+//!     "seed": "flowers",
+//!     "max_attempts": 3,
+//!     "format": "png",
+//!     "constraints": [{ "slot": [0, 0, 0], "patterns": [4, 7] }]
+//! }
+//! ```
+//!
+//! Only `size` is required. `format` is one of "png" (image models), "vox" (VOX models), or "json",
+//! which returns the pattern of every slot.
+
+#[path = "common/http_api.rs"]
+mod http_api;
+
+use http_api::{json_output, read_body, GenerateRequest, RequestError};
+
+use ilattice3_wfc::*;
+
+use dot_vox::DotVoxData;
+use ilattice3::{VecLatticeMap, VoxColor};
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(structopt::StructOpt)]
+struct Args {
+    /// Image models to serve, as NAME=PATH pairs.
+    #[structopt(long)]
+    image_model: Vec<String>,
+
+    /// VOX models to serve, as NAME=PATH pairs. Outputs use the default VOX palette.
+    #[structopt(long)]
+    vox_model: Vec<String>,
+
+    #[structopt(long, default_value = "127.0.0.1:8080")]
+    address: String,
+
+    /// Number of requests handled concurrently.
+    #[structopt(long, default_value = "4")]
+    threads: usize,
+
+    /// Generation is abandoned after this many seconds, summed over all attempts.
+    #[structopt(long, default_value = "30")]
+    timeout_secs: u64,
+
+    /// The largest output (in slots) that a request may ask for.
+    #[structopt(long, default_value = "1048576")]
+    max_volume: usize,
+
+    /// The largest request body (in KiB) that will be read.
+    #[structopt(long, default_value = "1024")]
+    max_request_kb: u64,
+}
+
+#[paw::main]
+fn main(args: Args) -> Result<(), ServerError> {
+    let mut models = HashMap::new();
+    for arg in args.image_model.iter() {
+        let (name, model) = load_named_model(arg, |c: &[u8; 4]| Rgba(*c))?;
+        models.insert(name, ServedModel::Image(model));
+    }
+    for arg in args.vox_model.iter() {
+        let (name, model) = load_named_model(arg, |c: &VoxColor| *c)?;
+        models.insert(name, ServedModel::Vox(model));
+    }
+    if models.is_empty() {
+        return Err(ServerError::NoModels);
+    }
+
+    let server = Arc::new(Server::http(&args.address).map_err(ServerError::Bind)?);
+    println!("Serving {} model(s) on {}", models.len(), args.address);
+
+    let state = Arc::new(ServerState {
+        models,
+        timeout: Duration::from_secs(args.timeout_secs),
+        max_volume: args.max_volume,
+        max_request_bytes: args.max_request_kb * 1024,
+    });
+    let workers: Vec<_> = (0..args.threads.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    state.handle(request);
+                }
+            })
+        })
+        .collect();
+    for worker in workers.into_iter() {
+        worker.join().expect("Worker thread panicked");
+    }
+
+    Ok(())
+}
+
+/// Parses a NAME=PATH argument and loads the model at PATH.
+fn load_named_model<T, S, F>(arg: &str, from_file: F) -> Result<(String, Model<T>), ServerError>
+where
+    T: Clone,
+    S: Clone + serde::de::DeserializeOwned,
+    F: Fn(&S) -> T,
+{
+    let mut parts = arg.splitn(2, '=');
+    let (name, path) = match (parts.next(), parts.next()) {
+        (Some(name), Some(path)) if !name.is_empty() => (name, path),
+        _ => return Err(ServerError::BadModelArg(arg.to_string())),
+    };
+
+    println!("Loading model {} from {:?}", name, path);
+    let (sampler, constraints, tiles) = load_model::<S, _>(BufReader::new(File::open(path)?))?;
+
+    Ok((
+        name.to_string(),
+        Model::new(sampler, constraints, tiles.map_voxels(from_file)),
+    ))
+}
+
+enum ServedModel {
+    Image(Model<Rgba<u8>>),
+    Vox(Model<VoxColor>),
+}
+
+struct ServerState {
+    models: HashMap<String, ServedModel>,
+    timeout: Duration,
+    max_volume: usize,
+    max_request_bytes: u64,
+}
+
+impl ServerState {
+    fn handle(&self, mut request: Request) {
+        let response = self.respond(&mut request).unwrap_or_else(|e| {
+            Response::from_string(e.to_string()).with_status_code(e.status_code())
+        });
+        if let Err(e) = request.respond(response) {
+            println!("Failed to send response: {}", e);
+        }
+    }
+
+    fn respond(
+        &self,
+        request: &mut Request,
+    ) -> Result<Response<io::Cursor<Vec<u8>>>, RequestError> {
+        if *request.method() != Method::Post {
+            return Err(RequestError::NotFound);
+        }
+        let model_name = request
+            .url()
+            .strip_prefix("/generate/")
+            .ok_or(RequestError::NotFound)?
+            .to_string();
+        let model = self.models.get(&model_name).ok_or(RequestError::NotFound)?;
+
+        let body = read_body(request, self.max_request_bytes)?;
+        let server_request: ServerRequest =
+            serde_json::from_slice(&body).map_err(|e| RequestError::BadRequest(e.to_string()))?;
+        let generate_request = &server_request.generate;
+
+        let (content_type, bytes) = match (model, server_request.format) {
+            (ServedModel::Image(model), OutputFormat::Png) => {
+                let result = self.generate(model, generate_request)?;
+                let colors = color_final_patterns_rgba(&result, model.tiles());
+                let img: RgbaImage = (&colors).into();
+                let mut bytes = Vec::new();
+                DynamicImage::ImageRgba8(img)
+                    .write_to(&mut bytes, ImageOutputFormat::Png)
+                    .map_err(|e| RequestError::Internal(e.to_string()))?;
+
+                ("image/png", bytes)
+            }
+            (ServedModel::Vox(model), OutputFormat::Vox) => {
+                let result = self.generate(model, generate_request)?;
+                let vox_data: DotVoxData = color_final_patterns_vox(&result, model.tiles()).into();
+                let mut bytes = Vec::new();
+                vox_data
+                    .write_vox(&mut bytes)
+                    .map_err(|e| RequestError::Internal(e.to_string()))?;
+
+                ("application/octet-stream", bytes)
+            }
+            (ServedModel::Image(model), OutputFormat::Json) => (
+                "application/json",
+                json_output(&self.generate(model, generate_request)?),
+            ),
+            (ServedModel::Vox(model), OutputFormat::Json) => (
+                "application/json",
+                json_output(&self.generate(model, generate_request)?),
+            ),
+            _ => {
+                return Err(RequestError::BadRequest(format!(
+                    "Model {} can't produce that format",
+                    model_name
+                )))
+            }
+        };
+
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+
+        Ok(Response::from_data(bytes).with_header(header))
+    }
+
+    fn generate<T>(
+        &self,
+        model: &Model<T>,
+        request: &GenerateRequest,
+    ) -> Result<VecLatticeMap<PatternId>, RequestError> {
+        let (output_size, slot_constraints) =
+            request.validate(model.constraints().num_patterns(), self.max_volume)?;
+
+        let mut seed = seed_from_str(&request.seed);
+
+        let cancel = CancellationToken::new();
+        let deadline = Instant::now() + self.timeout;
+        let mut last_error = None;
+        for _ in 0..request.max_attempts.max(1) {
+            let mut generator = model.generator(seed, output_size);
+            let mut state = UpdateResult::Continue;
+            for (slot, allowed) in slot_constraints.iter() {
+                state = generator
+                    .constrain_slot(slot, allowed)
+                    .map_err(|e| RequestError::BadRequest(e.to_string()))?;
+                if state != UpdateResult::Continue {
+                    break;
+                }
+            }
+            if state == UpdateResult::Continue {
+                let budget = deadline.saturating_duration_since(Instant::now());
+                state = generator.run(&cancel, Some(budget));
+            }
+
+            match state {
+                UpdateResult::Success => return Ok(generator.result()),
+                UpdateResult::Failure(e) => last_error = Some(e),
+                _ => return Err(RequestError::TimedOut),
+            }
+            seed = mutate_seed(seed);
+        }
+
+        Err(RequestError::Contradiction(last_error.unwrap()))
+    }
+}
+
+/// A `GenerateRequest` and the format to return the output in.
+#[derive(Deserialize)]
+struct ServerRequest {
+    #[serde(flatten)]
+    generate: GenerateRequest,
+    #[serde(default = "default_format")]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Png,
+    Vox,
+    Json,
+}
+
+fn default_format() -> OutputFormat {
+    OutputFormat::Json
+}
+
+#[derive(Debug)]
+enum ServerError {
+    IoError(io::Error),
+    ModelFileError(ModelFileError),
+    BadModelArg(String),
+    NoModels,
+    Bind(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<io::Error> for ServerError {
+    fn from(other: io::Error) -> Self {
+        ServerError::IoError(other)
+    }
+}
+
+impl From<ModelFileError> for ServerError {
+    fn from(other: ModelFileError) -> Self {
+        ServerError::ModelFileError(other)
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::IoError(e) => write!(f, "{}", e),
+            ServerError::ModelFileError(e) => write!(f, "{}", e),
+            ServerError::BadModelArg(arg) => write!(f, "Expected NAME=PATH, got {:?}", arg),
+            ServerError::NoModels => write!(f, "No models given"),
+            ServerError::Bind(e) => write!(f, "Failed to start server: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
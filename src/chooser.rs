@@ -0,0 +1,109 @@
+//! Strategies for choosing which pattern an observed slot collapses to.
+
+use crate::pattern::{sample_index, PatternId};
+
+use ilattice3 as lat;
+use rand::RngCore;
+
+/// Chooses the pattern for a slot being observed, from the patterns still possible there. By
+/// default, the `Generator` samples them by weight, like `WeightedChooser`.
+pub trait PatternChooser: Send + Sync {
+    /// Returns an index into `patterns`. `weights[i]` is the weight of `patterns[i]`, including any
+    /// guidance.
+    fn choose(
+        &self,
+        slot: &lat::Point,
+        patterns: &[PatternId],
+        weights: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> usize;
+}
+
+impl<F> PatternChooser for F
+where
+    F: Fn(&lat::Point, &[PatternId], &[f32], &mut dyn RngCore) -> usize + Send + Sync,
+{
+    fn choose(
+        &self,
+        slot: &lat::Point,
+        patterns: &[PatternId],
+        weights: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> usize {
+        self(slot, patterns, weights, rng)
+    }
+}
+
+/// Samples patterns by weight.
+pub struct WeightedChooser;
+
+impl PatternChooser for WeightedChooser {
+    fn choose(
+        &self,
+        _slot: &lat::Point,
+        _patterns: &[PatternId],
+        weights: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> usize {
+        sample_index(weights, rng)
+    }
+}
+
+/// Always chooses the most likely pattern, breaking ties by the lowest `PatternId`. Outputs only
+/// vary by the order that slots are observed in.
+pub struct ArgmaxChooser;
+
+impl PatternChooser for ArgmaxChooser {
+    fn choose(
+        &self,
+        _slot: &lat::Point,
+        _patterns: &[PatternId],
+        weights: &[f32],
+        _rng: &mut dyn RngCore,
+    ) -> usize {
+        let mut best = 0;
+        for (i, w) in weights.iter().enumerate() {
+            if *w > weights[best] {
+                best = i;
+            }
+        }
+
+        best
+    }
+}
+
+/// Samples patterns by their weights raised to the power `1 / temperature`. Temperatures below 1
+/// favor common patterns (approaching `ArgmaxChooser`), and temperatures above 1 flatten the
+/// distribution (approaching uniform).
+pub struct TemperatureChooser {
+    pub temperature: f32,
+}
+
+impl PatternChooser for TemperatureChooser {
+    fn choose(
+        &self,
+        _slot: &lat::Point,
+        _patterns: &[PatternId],
+        weights: &[f32],
+        rng: &mut dyn RngCore,
+    ) -> usize {
+        assert!(
+            self.temperature > 0.0,
+            "Temperature {} must be positive",
+            self.temperature
+        );
+
+        // Normalize first so large weights don't overflow at low temperatures.
+        let max_weight = weights.iter().cloned().fold(0.0, f32::max);
+        if max_weight <= 0.0 {
+            return sample_index(weights, rng);
+        }
+        let exponent = 1.0 / self.temperature;
+        let scaled: Vec<f32> = weights
+            .iter()
+            .map(|w| (w / max_weight).powf(exponent))
+            .collect();
+
+        sample_index(&scaled, rng)
+    }
+}
@@ -0,0 +1,270 @@
+//! Generation of outputs that are too large for a single `Wave`.
+
+use crate::{
+    generate::{mutate_seed, GenerationError, Generator, UpdateResult, NUM_SEED_BYTES},
+    pattern::{PatternConstraints, PatternId, PatternSampler},
+};
+
+use ilattice3 as lat;
+use ilattice3::{GetExtent, VecLatticeMap};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Receives each chunk of a `ChunkedGenerator`'s output as soon as it's finished.
+pub trait ChunkConsumer {
+    /// `assignment` holds the patterns of one chunk, with its extent placed where the chunk lies in
+    /// the full output. Chunks don't overlap, so every slot is consumed exactly once.
+    fn use_chunk(&mut self, assignment: &VecLatticeMap<PatternId>);
+}
+
+/// Generates the output one chunk at a time, so only a couple layers of chunks are ever held in
+/// memory.
+///
+/// Each chunk's wave covers more than the chunk itself. On the sides facing finished chunks, it
+/// includes a border of their slots, held fixed, so the new chunk fits against them. On the other
+/// sides, it includes `margin` extra slots that are generated and then thrown away; the chunks
+/// that get generated there later are only constrained by slots that are known to have at least
+/// one valid continuation.
+pub struct ChunkedGenerator {
+    pub seed: [u8; NUM_SEED_BYTES],
+    /// Size of the full output, in slots.
+    pub output_size: lat::Point,
+    /// Size of each chunk, in slots. Chunks at the far edges of the output may be smaller.
+    pub chunk_size: lat::Point,
+    /// Extra slots (per axis) generated past each chunk and discarded. More margin means fewer
+    /// contradictions along chunk borders. Must not exceed `chunk_size`.
+    pub margin: lat::Point,
+    /// Seeds to try for each chunk before giving up.
+    pub max_attempts_per_chunk: usize,
+}
+
+impl ChunkedGenerator {
+    /// Uses a margin of 2 slots, or the whole chunk size if that's smaller.
+    pub fn new(
+        seed: [u8; NUM_SEED_BYTES],
+        output_size: lat::Point,
+        chunk_size: lat::Point,
+    ) -> Self {
+        ChunkedGenerator {
+            seed,
+            output_size,
+            chunk_size,
+            margin: [
+                chunk_size.x.min(2),
+                chunk_size.y.min(2),
+                chunk_size.z.min(2),
+            ]
+            .into(),
+            max_attempts_per_chunk: 1,
+        }
+    }
+
+    /// Generates every chunk, in order of increasing Z, then Y, then X, and hands it to `consumer`.
+    /// Stops at the first chunk that fails on every attempt. The error's slot is in the coordinates
+    /// of the full output.
+    pub fn generate<C: ChunkConsumer>(
+        &self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        consumer: &mut C,
+    ) -> Result<(), GenerationError> {
+        let border = border_size(constraints);
+        self.assert_valid(&border);
+
+        let grid_size = self.output_size.div_ceil(&self.chunk_size);
+        let chunk_order = |c: &lat::Point| (c.z * grid_size.y + c.y) * grid_size.x + c.x;
+
+        // Finished chunks that some unfinished chunk still needs for its border.
+        let mut finished: HashMap<[i32; 3], VecLatticeMap<PatternId>> = HashMap::new();
+        for z in 0..grid_size.z {
+            for y in 0..grid_size.y {
+                for x in 0..grid_size.x {
+                    let chunk: lat::Point = [x, y, z].into();
+                    let assignment =
+                        self.generate_chunk(sampler, constraints, &border, &chunk, &finished)?;
+                    consumer.use_chunk(&assignment);
+                    finished.insert(chunk.into(), assignment);
+
+                    // Borders and margins are no larger than a chunk, so waves only reach the
+                    // chunks at most one step away on each axis.
+                    let current_order = chunk_order(&chunk);
+                    finished.retain(|c, _| {
+                        let c: lat::Point = (*c).into();
+                        neighbor_steps().any(|step| {
+                            let n = c + step;
+                            in_grid(&n, &grid_size) && chunk_order(&n) > current_order
+                        })
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn generate_chunk(
+        &self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        border: &lat::Point,
+        chunk: &lat::Point,
+        finished: &HashMap<[i32; 3], VecLatticeMap<PatternId>>,
+    ) -> Result<VecLatticeMap<PatternId>, GenerationError> {
+        let chunk_extent = self.chunk_extent(chunk);
+        let wave_extent = self.clip_to_output(
+            *chunk_extent.get_minimum() - *border,
+            chunk_extent.get_world_supremum() + self.margin,
+        );
+        let wave_min = *wave_extent.get_minimum();
+
+        // The patterns of finished chunks that fall in this wave, relative to the wave.
+        let fixed: Vec<(lat::Point, VecLatticeMap<PatternId>)> = neighbor_steps()
+            .filter_map(|step| {
+                let key: [i32; 3] = (*chunk + step).into();
+
+                finished.get(&key)
+            })
+            .filter_map(|neighbor| {
+                let shared = intersect_extents(&wave_extent, &neighbor.get_extent())?;
+
+                Some((
+                    *shared.get_minimum() - wave_min,
+                    neighbor.copy_extent_into_new_map(&shared),
+                ))
+            })
+            .collect();
+
+        let mut seed = chunk_seed(self.seed, chunk);
+        let mut last_error = None;
+        for attempt in 1..=self.max_attempts_per_chunk {
+            let mut generator = Generator::new(
+                seed,
+                *wave_extent.get_local_supremum(),
+                sampler,
+                constraints,
+            );
+            let mut state = UpdateResult::Continue;
+            for (stamp_min, stamp) in fixed.iter() {
+                state = generator
+                    .stamp(sampler, constraints, stamp_min, stamp)
+                    .expect("Finished chunks are valid and lie within the wave");
+                if state != UpdateResult::Continue {
+                    break;
+                }
+            }
+            while state == UpdateResult::Continue {
+                state = generator.update(sampler, constraints);
+            }
+
+            match state {
+                UpdateResult::Success => {
+                    let mut wave_assignment = generator.result();
+                    wave_assignment.set_minimum(&wave_min);
+
+                    return Ok(wave_assignment.copy_extent_into_new_map(&chunk_extent));
+                }
+                UpdateResult::Failure(mut e) => {
+                    info!("Chunk {} attempt {} failed: {}", chunk, attempt, e);
+                    e.slot = e.slot.map(|slot| slot + wave_min);
+                    last_error = Some(e);
+                }
+                _ => unreachable!("Generator::update can't be cancelled or time out"),
+            }
+            seed = mutate_seed(seed);
+        }
+
+        Err(last_error.unwrap())
+    }
+
+    fn chunk_extent(&self, chunk: &lat::Point) -> lat::Extent {
+        let min = *chunk * self.chunk_size;
+
+        self.clip_to_output(min, min + self.chunk_size)
+    }
+
+    fn clip_to_output(&self, min: lat::Point, sup: lat::Point) -> lat::Extent {
+        let output_extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), self.output_size);
+        let extent = lat::Extent::from_min_and_world_supremum(min, sup);
+
+        intersect_extents(&extent, &output_extent).unwrap()
+    }
+
+    fn assert_valid(&self, border: &lat::Point) {
+        assert!(self.max_attempts_per_chunk > 0);
+        for ((chunk, margin), border) in [self.chunk_size.x, self.chunk_size.y, self.chunk_size.z]
+            .iter()
+            .zip([self.margin.x, self.margin.y, self.margin.z].iter())
+            .zip([border.x, border.y, border.z].iter())
+        {
+            assert!(
+                *chunk > 0 && *margin >= 0 && margin <= chunk && border <= chunk,
+                "Chunk size {} must be positive and at least as large as the margin {} and the \
+                largest offset {}",
+                self.chunk_size,
+                self.margin,
+                border
+            );
+        }
+    }
+}
+
+/// The border that a wave needs around a chunk so that every slot adjacent to the chunk is in the
+/// wave.
+fn border_size(constraints: &PatternConstraints) -> lat::Point {
+    constraints
+        .get_offset_group()
+        .iter()
+        .fold([0, 0, 0].into(), |b: lat::Point, (_, offset)| {
+            [
+                b.x.max(offset.x.abs()),
+                b.y.max(offset.y.abs()),
+                b.z.max(offset.z.abs()),
+            ]
+            .into()
+        })
+}
+
+/// Steps from a chunk to every chunk adjacent to it.
+fn neighbor_steps() -> impl Iterator<Item = lat::Point> {
+    (0..27)
+        .filter(|i| *i != 13)
+        .map(|i| [i % 3 - 1, (i / 3) % 3 - 1, i / 9 - 1].into())
+}
+
+fn in_grid(chunk: &lat::Point, grid_size: &lat::Point) -> bool {
+    lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), *grid_size).contains_world(chunk)
+}
+
+fn intersect_extents(a: &lat::Extent, b: &lat::Extent) -> Option<lat::Extent> {
+    let (a_min, b_min) = (a.get_minimum(), b.get_minimum());
+    let (a_sup, b_sup) = (a.get_world_supremum(), b.get_world_supremum());
+    let min = lat::Point::from([
+        a_min.x.max(b_min.x),
+        a_min.y.max(b_min.y),
+        a_min.z.max(b_min.z),
+    ]);
+    let sup = lat::Point::from([
+        a_sup.x.min(b_sup.x),
+        a_sup.y.min(b_sup.y),
+        a_sup.z.min(b_sup.z),
+    ]);
+    if sup.x <= min.x || sup.y <= min.y || sup.z <= min.z {
+        return None;
+    }
+
+    Some(lat::Extent::from_min_and_world_supremum(min, sup))
+}
+
+/// Each chunk gets its own seed, derived from the generator's seed and the chunk's position.
+fn chunk_seed(seed: [u8; NUM_SEED_BYTES], chunk: &lat::Point) -> [u8; NUM_SEED_BYTES] {
+    let mut seed = seed;
+    let coords: [i32; 3] = (*chunk).into();
+    for (i, c) in coords.iter().enumerate() {
+        for (j, b) in c.to_le_bytes().iter().enumerate() {
+            seed[4 * i + j] ^= b;
+        }
+    }
+
+    mutate_seed(seed)
+}
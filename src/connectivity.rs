@@ -0,0 +1,109 @@
+//! Keeping two regions of the output connected by a path of walkable patterns.
+
+use crate::{
+    offset::OffsetGroup,
+    pattern::{PatternId, PatternSet},
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, VecLatticeMap};
+use std::collections::VecDeque;
+
+/// Requires some slot in `from` to be connected to some slot in `to` by a path of slots that are
+/// assigned `walkable` patterns, e.g. so every dungeon has a route from the entrance to the exit.
+/// Slots on the path are adjacent by the offsets of the model's `OffsetGroup`.
+///
+/// There's no backtracking, so the constraint is checked by flood fill through the slots that could
+/// still be walkable, and generation fails with an error as soon as no path is possible. Retry with
+/// another seed (e.g. with `generate_with_retries`) to find an output that satisfies it. The flood
+/// fill visits the whole output, so for large outputs, set `check_every` to only check after that
+/// many propagations. Every observation, constraint or ban is followed by a propagation, so an
+/// update can count more than once. The constraint is always checked when it's added and once the
+/// output is finished.
+#[derive(Clone, Debug)]
+pub struct ConnectivityConstraint {
+    pub walkable: PatternSet,
+    pub from: Vec<lat::Point>,
+    pub to: Vec<lat::Point>,
+    pub check_every: usize,
+}
+
+impl ConnectivityConstraint {
+    pub fn new(walkable: PatternSet, from: Vec<lat::Point>, to: Vec<lat::Point>) -> Self {
+        ConnectivityConstraint {
+            walkable,
+            from,
+            to,
+            check_every: 1,
+        }
+    }
+
+    /// Returns `true` iff a path from `from` to `to` is still possible through slots where any
+    /// walkable pattern is possible.
+    pub fn is_possible(&self, slots: &VecLatticeMap<PatternSet>, offsets: &OffsetGroup) -> bool {
+        let extent = slots.get_extent();
+        let can_walk = |slot: &lat::Point| {
+            extent.contains_world(slot)
+                && slots
+                    .get_world_ref(slot)
+                    .iter()
+                    .any(|p: PatternId| self.walkable.contains(p))
+        };
+
+        let mut visited: VecLatticeMap<bool> = VecLatticeMap::fill(extent, false);
+        let mut queue = VecDeque::new();
+        for slot in self.from.iter() {
+            if can_walk(slot) && !visited.get_world(slot) {
+                *visited.get_world_ref_mut(slot) = true;
+                queue.push_back(*slot);
+            }
+        }
+        while let Some(slot) = queue.pop_front() {
+            if self.to.contains(&slot) {
+                return true;
+            }
+            for (_, offset) in offsets.iter() {
+                let neighbor = slot + *offset;
+                if can_walk(&neighbor) && !visited.get_world(&neighbor) {
+                    *visited.get_world_ref_mut(&neighbor) = true;
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+
+        false
+    }
+}
+
+/// The `Wave`'s schedule for checking one `ConnectivityConstraint`.
+pub(crate) struct ConnectivityTracker {
+    pub constraint: ConnectivityConstraint,
+    pub propagations_since_check: usize,
+}
+
+impl ConnectivityTracker {
+    pub fn new(constraint: ConnectivityConstraint) -> Self {
+        ConnectivityTracker {
+            constraint,
+            propagations_since_check: 0,
+        }
+    }
+
+    /// Called after each propagation. Checks the constraint if it's due or `force` is set, e.g.
+    /// because the output is finished. Returns `false` iff the constraint was checked and can't be
+    /// met.
+    pub fn after_propagation(
+        &mut self,
+        slots: &VecLatticeMap<PatternSet>,
+        offsets: &OffsetGroup,
+        force: bool,
+    ) -> bool {
+        self.propagations_since_check += 1;
+        if !force && self.propagations_since_check < self.constraint.check_every {
+            return true;
+        }
+        self.propagations_since_check = 0;
+
+        self.constraint.is_possible(slots, offsets)
+    }
+}
@@ -0,0 +1,139 @@
+//! Limits on how many times patterns appear in the output.
+
+use crate::{
+    error::WfcError,
+    pattern::{PatternId, PatternIndex, PatternSet},
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, VecLatticeMap};
+
+/// Requires the number of slots assigned any pattern in `patterns` to be between `min` and `max`
+/// (inclusive), e.g. "exactly one throne room" or "at most 5 staircases".
+///
+/// Counts are tracked as slots collapse. Once `max` slots are assigned, the patterns are removed
+/// from every other slot. Once only `min` slots can still take the patterns, those slots are
+/// restricted to them. If the counts can no longer be met, generation fails with an error at the
+/// slot that most recently changed them, if any.
+#[derive(Clone, Debug)]
+pub struct CountConstraint {
+    pub patterns: PatternSet,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl CountConstraint {
+    pub fn exactly(patterns: PatternSet, count: usize) -> Self {
+        CountConstraint {
+            patterns,
+            min: count,
+            max: count,
+        }
+    }
+
+    pub fn at_least(patterns: PatternSet, min: usize) -> Self {
+        CountConstraint {
+            patterns,
+            min,
+            max: std::usize::MAX,
+        }
+    }
+
+    pub fn at_most(patterns: PatternSet, max: usize) -> Self {
+        CountConstraint {
+            patterns,
+            min: 0,
+            max,
+        }
+    }
+
+    pub(crate) fn validate(&self, num_slots: usize) -> Result<(), WfcError> {
+        if self.min > self.max || self.min > num_slots {
+            return Err(WfcError::InvalidConfig(format!(
+                "Count constraint minimum {} must be at most the maximum {} and the number of \
+                slots {}",
+                self.min, self.max, num_slots
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// The `Wave`'s running counts for one `CountConstraint`.
+pub(crate) struct CountTracker {
+    pub constraint: CountConstraint,
+    /// How many of the constraint's patterns are still possible in each slot, by linear index.
+    pub remaining: Vec<PatternIndex>,
+    /// Number of slots where any of the patterns is still possible.
+    pub possible: usize,
+    /// Number of slots collapsed to one of the patterns.
+    pub assigned: usize,
+    /// The slot that most recently changed `possible` or `assigned`, and the pattern removed from
+    /// it or assigned to it.
+    pub last_change: Option<(lat::Point, PatternId)>,
+    /// The patterns were removed from every slot besides the assigned ones.
+    pub max_enforced: bool,
+    /// Every slot that could take the patterns was restricted to them.
+    pub min_enforced: bool,
+}
+
+impl CountTracker {
+    /// `constraint` must already be validated.
+    pub fn new(constraint: CountConstraint, slots: &VecLatticeMap<PatternSet>) -> Self {
+        let num_slots = slots.get_extent().volume();
+        let remaining: Vec<PatternIndex> = (0..num_slots)
+            .map(|i| {
+                let patterns = slots.get_linear_ref(i).iter();
+
+                patterns
+                    .filter(|p| constraint.patterns.contains(*p))
+                    .count() as PatternIndex
+            })
+            .collect();
+        let possible = remaining.iter().filter(|r| **r > 0).count();
+        let assigned = (0..num_slots)
+            .filter(|i| slots.get_linear_ref(*i).len() == 1 && remaining[*i] > 0)
+            .count();
+
+        CountTracker {
+            constraint,
+            remaining,
+            possible,
+            assigned,
+            last_change: None,
+            max_enforced: false,
+            min_enforced: false,
+        }
+    }
+
+    /// Updates the counts after `pattern` is removed from `slot`. `last_pattern` is the only
+    /// pattern left in the slot, if it just collapsed.
+    pub fn remove_pattern(
+        &mut self,
+        slot_index: usize,
+        slot: &lat::Point,
+        pattern: PatternId,
+        last_pattern: Option<PatternId>,
+    ) {
+        if self.constraint.patterns.contains(pattern) {
+            let remaining = &mut self.remaining[slot_index];
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.possible -= 1;
+                self.last_change = Some((*slot, pattern));
+            }
+        }
+        if let Some(last_pattern) = last_pattern {
+            if self.constraint.patterns.contains(last_pattern) {
+                self.assigned += 1;
+                self.last_change = Some((*slot, last_pattern));
+            }
+        }
+    }
+
+    /// `true` iff the counts can no longer be met.
+    pub fn violated(&self) -> bool {
+        self.possible < self.constraint.min || self.assigned > self.constraint.max
+    }
+}
@@ -0,0 +1,78 @@
+//! Keeping the borders of the output like the borders of the input.
+
+use crate::{
+    generate::{Generator, UpdateResult},
+    offset::face_3d_offsets,
+    pattern::{PatternConstraints, PatternSampler, PatternSet},
+};
+
+use ilattice3 as lat;
+use ilattice3::prelude::*;
+
+/// Restricts the slots on each face of the output to the patterns that were observed on the same
+/// face of the input, e.g. so the output always ends in closed walls or open water. Get one from
+/// `ModelTrainer::into_parts_with_edges`.
+#[derive(Clone, Debug)]
+pub struct EdgeConstraint {
+    /// The outward normal of each face (e.g. `[-1, 0, 0]` for the minimum X face) and the patterns
+    /// allowed in the slots on it.
+    pub faces: Vec<(lat::Point, PatternSet)>,
+}
+
+impl EdgeConstraint {
+    /// No allowed patterns on any face yet. Filled in by the `ModelTrainer`.
+    pub(crate) fn empty() -> Self {
+        EdgeConstraint {
+            faces: face_3d_offsets()
+                .into_iter()
+                .map(|normal| (normal, PatternSet::empty()))
+                .collect(),
+        }
+    }
+
+    /// Keeps only the faces whose normal is in `normals`, e.g. to leave the top of the output open.
+    pub fn only_faces(mut self, normals: &[lat::Point]) -> Self {
+        self.faces.retain(|(normal, _)| normals.contains(normal));
+
+        self
+    }
+
+    /// Restricts the slots of `generator` and propagates. Meant to be called before the first
+    /// `update`.
+    pub fn apply(
+        &self,
+        generator: &mut Generator,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> UpdateResult {
+        let wave_extent = generator.get_wave_lattice().get_extent();
+        let restrictions = self.faces.iter().flat_map(|(normal, patterns)| {
+            wave_extent
+                .into_iter()
+                .filter(move |slot| on_face(&wave_extent, slot, normal))
+                .map(move |slot| (slot, patterns))
+        });
+
+        generator.restrict_slots(sampler, constraints, restrictions)
+    }
+}
+
+/// Returns `true` iff `p` is on the face of `extent` with the outward `normal`.
+pub(crate) fn on_face(extent: &lat::Extent, p: &lat::Point, normal: &lat::Point) -> bool {
+    let min = *extent.get_minimum();
+    let max = extent.get_world_supremum() - [1, 1, 1].into();
+    let [px, py, pz]: [i32; 3] = (*p).into();
+    let [nx, ny, nz]: [i32; 3] = (*normal).into();
+
+    [
+        (px, nx, min.x, max.x),
+        (py, ny, min.y, max.y),
+        (pz, nz, min.z, max.z),
+    ]
+    .iter()
+    .all(|(c, n, min, max)| match n {
+        n if *n < 0 => c == min,
+        n if *n > 0 => c == max,
+        _ => true,
+    })
+}
@@ -0,0 +1,62 @@
+//! Errors returned by the library, as opposed to the file and image errors of the binaries.
+
+use crate::{
+    generate::GenerationError,
+    offset::OffsetGroupError,
+    pattern::{PatternId, MAX_PATTERNS},
+};
+
+use ilattice3 as lat;
+use std::error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum WfcError {
+    /// The input has more unique patterns than a model can hold.
+    TooManyPatterns,
+    /// Generation reached a contradiction.
+    Contradiction(GenerationError),
+    InvalidOffsets(OffsetGroupError),
+    /// A pattern has no compatible patterns at `offset`, so it could never be placed.
+    NoCompatiblePatterns {
+        pattern: PatternId,
+        offset: lat::Point,
+    },
+    /// A size or other setting is out of range.
+    InvalidConfig(String),
+    /// A slot passed to the generator is outside of the output.
+    SlotOutOfBounds(lat::Point),
+}
+
+impl fmt::Display for WfcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WfcError::TooManyPatterns => {
+                write!(f, "Too many patterns, maximum is {}", MAX_PATTERNS)
+            }
+            WfcError::Contradiction(e) => write!(f, "{}", e),
+            WfcError::InvalidOffsets(e) => write!(f, "{}", e),
+            WfcError::NoCompatiblePatterns { pattern, offset } => write!(
+                f,
+                "Pattern {} has no compatible patterns at offset {}",
+                pattern.0, offset
+            ),
+            WfcError::InvalidConfig(msg) => write!(f, "{}", msg),
+            WfcError::SlotOutOfBounds(slot) => write!(f, "Slot {} is outside of the output", slot),
+        }
+    }
+}
+
+impl error::Error for WfcError {}
+
+impl From<GenerationError> for WfcError {
+    fn from(e: GenerationError) -> Self {
+        WfcError::Contradiction(e)
+    }
+}
+
+impl From<OffsetGroupError> for WfcError {
+    fn from(e: OffsetGroupError) -> Self {
+        WfcError::InvalidOffsets(e)
+    }
+}
@@ -0,0 +1,26 @@
+//! A structured stream of what the `Generator` is doing, for visualizers and debuggers.
+
+use crate::{generate::GenerationError, pattern::PatternId};
+
+use ilattice3 as lat;
+
+/// Sent by a `Generator` to the receiver returned from `Generator::observe_events`, in the order
+/// that things happen.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ObserverEvent {
+    /// `update` chose `slot`, with the least entropy, to observe.
+    SlotChosen { slot: lat::Point, entropy: f32 },
+    /// `slot` was left with only `pattern`, either by observation or by propagation.
+    PatternAssigned {
+        slot: lat::Point,
+        pattern: PatternId,
+    },
+    /// `pattern` is no longer possible at `slot`.
+    PatternRemoved {
+        slot: lat::Point,
+        pattern: PatternId,
+    },
+    /// Generation failed.
+    Contradiction(GenerationError),
+}
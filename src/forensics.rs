@@ -0,0 +1,202 @@
+//! Explaining contradictions, for debugging models and constraints that fail to generate.
+
+use crate::{
+    generate::GenerationError,
+    offset::OffsetGroup,
+    pattern::{PatternId, PatternIndex, PatternSet},
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, VecLatticeMap};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A pattern removed from a slot, as recorded after `Generator::track_removals`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Removal {
+    pub slot: lat::Point,
+    pub pattern: PatternId,
+    /// The removal that caused this one by propagation, as an index into the log. `None` if the
+    /// pattern was removed directly, e.g. by observing the slot or by a constraint.
+    pub cause: Option<usize>,
+}
+
+/// Every removal made by a `Wave`, in order.
+#[derive(Default)]
+pub(crate) struct RemovalLog {
+    removals: Vec<Removal>,
+    /// The latest removal of each (slot index, pattern). A pattern is only removed again if its
+    /// slot was reopened in between.
+    latest: HashMap<(usize, PatternIndex), usize>,
+}
+
+impl RemovalLog {
+    pub fn push(
+        &mut self,
+        slot_index: usize,
+        slot: lat::Point,
+        pattern: PatternId,
+        cause: Option<(usize, PatternId)>,
+    ) {
+        let cause = cause.and_then(|(i, p)| self.latest.get(&(i, p.0)).copied());
+        self.latest
+            .insert((slot_index, pattern.0), self.removals.len());
+        self.removals.push(Removal {
+            slot,
+            pattern,
+            cause,
+        });
+    }
+
+    /// The removals that led to the last removal of `pattern` from `slot_index`, starting with the
+    /// direct one at the root and ending with that removal.
+    fn cause_chain(&self, slot_index: usize, pattern: PatternId) -> Vec<Removal> {
+        let mut chain = Vec::new();
+        let mut next = self.latest.get(&(slot_index, pattern.0)).copied();
+        while let Some(i) = next {
+            let removal = self.removals[i];
+            chain.push(removal);
+            next = removal.cause;
+        }
+        chain.reverse();
+
+        chain
+    }
+}
+
+/// What the wave looked like when generation failed, and how it got there.
+#[derive(Clone, Debug)]
+pub struct ContradictionReport {
+    pub error: GenerationError,
+    /// The patterns left in each slot adjacent to `error.slot` (inside the output), along with its
+    /// offset from `error.slot`. Empty if the error has no slot.
+    pub neighbors: Vec<(lat::Point, Vec<PatternId>)>,
+    /// Every removal from `error.slot`, in order. The last one emptied it.
+    pub slot_removals: Vec<Removal>,
+    /// The chain of propagation that emptied `error.slot`, starting with the observation or
+    /// constraint that removed a pattern directly and ending with the removal of
+    /// `error.emptied_by`. Empty if the failure wasn't caused by a removal, e.g. a connectivity
+    /// constraint that can't be met.
+    pub cause_chain: Vec<Removal>,
+}
+
+impl ContradictionReport {
+    pub(crate) fn new(
+        error: GenerationError,
+        log: &RemovalLog,
+        slots: &VecLatticeMap<PatternSet>,
+        offset_group: &OffsetGroup,
+    ) -> Self {
+        let (slot, emptied_by) = match (error.slot, error.emptied_by) {
+            (Some(slot), Some(emptied_by)) => (slot, emptied_by),
+            _ => {
+                return ContradictionReport {
+                    error,
+                    neighbors: Vec::new(),
+                    slot_removals: Vec::new(),
+                    cause_chain: Vec::new(),
+                }
+            }
+        };
+
+        let extent = slots.get_extent();
+        let neighbors = offset_group
+            .iter()
+            .map(|(_, offset)| *offset)
+            .filter(|offset| extent.contains_world(&(slot + *offset)))
+            .map(|offset| {
+                let patterns = slots.get_world_ref(&(slot + offset)).iter().collect();
+
+                (offset, patterns)
+            })
+            .collect();
+        let slot_removals = log
+            .removals
+            .iter()
+            .filter(|r| r.slot == slot)
+            .copied()
+            .collect();
+        // Only an emptied slot has a removal to explain.
+        let cause_chain = if slots.get_world_ref(&slot).is_empty() {
+            log.cause_chain(slots.index_from_local_point(&slot), emptied_by)
+        } else {
+            Vec::new()
+        };
+
+        ContradictionReport {
+            error,
+            neighbors,
+            slot_removals,
+            cause_chain,
+        }
+    }
+
+    /// Copies the slots of `wave` within `radius` of `error.slot`, clipped to the wave, into a new
+    /// lattice that starts at the origin, e.g. to render the neighborhood of the contradiction.
+    /// Also returns where `error.slot` ends up. `None` if the error has no slot.
+    pub fn region(
+        &self,
+        wave: &VecLatticeMap<PatternSet>,
+        radius: i32,
+    ) -> Option<(VecLatticeMap<PatternSet>, lat::Point)> {
+        let slot = self.error.slot?;
+        let extent = wave.get_extent();
+        let [min_x, min_y, min_z]: [i32; 3] = (*extent.get_minimum()).into();
+        let [sup_x, sup_y, sup_z]: [i32; 3] = extent.get_world_supremum().into();
+        let [x, y, z]: [i32; 3] = slot.into();
+        let region_min: lat::Point = [
+            (x - radius).max(min_x),
+            (y - radius).max(min_y),
+            (z - radius).max(min_z),
+        ]
+        .into();
+        let region_sup: lat::Point = [
+            (x + radius + 1).min(sup_x),
+            (y + radius + 1).min(sup_y),
+            (z + radius + 1).min(sup_z),
+        ]
+        .into();
+
+        let region_extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), region_sup - region_min);
+        let mut region = VecLatticeMap::fill(region_extent, PatternSet::empty());
+        for p in region_extent {
+            *region.get_world_ref_mut(&p) = wave.get_world_ref(&(p + region_min)).clone();
+        }
+
+        Some((region, slot - region_min))
+    }
+}
+
+impl fmt::Display for ContradictionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        let slot = match self.error.slot {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        writeln!(f, "Remaining patterns of neighboring slots:")?;
+        for (offset, patterns) in self.neighbors.iter() {
+            writeln!(
+                f,
+                "    {} (offset {}): {:?}",
+                slot + *offset,
+                offset,
+                patterns
+            )?;
+        }
+        let removed: Vec<PatternIndex> = self.slot_removals.iter().map(|r| r.pattern.0).collect();
+        writeln!(
+            f,
+            "Patterns removed from slot {}, in order: {:?}",
+            slot, removed
+        )?;
+        writeln!(f, "Propagation that emptied slot {}:", slot)?;
+        for (i, removal) in self.cause_chain.iter().enumerate() {
+            let how = if i == 0 { "removed" } else { "unsupported" };
+            writeln!(f, "    {} {:?} at {}", how, removal.pattern, removal.slot)?;
+        }
+
+        Ok(())
+    }
+}
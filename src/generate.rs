@@ -1,19 +1,201 @@
 use crate::{
-    pattern::{PatternConstraints, PatternId, PatternSampler, PatternSet},
-    wave::Wave,
+    chooser::PatternChooser,
+    connectivity::ConnectivityConstraint,
+    count::CountConstraint,
+    error::WfcError,
+    event::ObserverEvent,
+    forensics::ContradictionReport,
+    guidance::WeightField,
+    pattern::{
+        PatternConstraints, PatternId, PatternIndex, PatternSampler, PatternSet, PatternTileSet,
+    },
+    progress::Frames,
+    wave::{ChangeStamp, PropagationStats, Wave},
 };
 
 use ilattice3 as lat;
-use ilattice3::VecLatticeMap;
-use log::debug;
+use ilattice3::{GetExtent, GetWorld, GetWorldRef, Indexer, VecLatticeMap};
 use rand::{prelude::*, rngs::SmallRng};
+use rand_pcg::Pcg64Mcg;
+use sha2::{Digest, Sha256};
+use std::fmt;
+#[cfg(feature = "mmap")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, info};
 
 pub const NUM_SEED_BYTES: usize = 16;
 
 /// Generates a `Lattice<PatternId>` using the overlapping "Wave Function Collapse" algorithm.
-pub struct Generator {
-    rng: SmallRng,
+///
+/// Any `Rng` can be used with `from_rng`. The default, `Pcg64Mcg`, is the same as `SmallRng` on
+/// 64-bit targets, but serializable, so only it supports `save_snapshot`.
+pub struct Generator<R = Pcg64Mcg> {
+    rng: R,
     wave: Wave,
+    /// The slot observed by the last `update`, if it observed one.
+    last_observed_slot: Option<lat::Point>,
+    /// See `set_observation_batch`.
+    batch: ObservationBatch,
+    /// Calls to `update` so far.
+    num_updates: u64,
+}
+
+#[derive(Clone, Copy)]
+struct ObservationBatch {
+    max_slots: usize,
+    min_distance: i32,
+}
+
+impl Default for ObservationBatch {
+    fn default() -> Self {
+        ObservationBatch {
+            max_slots: 1,
+            min_distance: 0,
+        }
+    }
+}
+
+/// Options that are fixed for the lifetime of a `Generator`.
+#[derive(Clone)]
+pub struct GeneratorConfig {
+    /// How to choose between slots with equal entropy.
+    pub tie_break: TieBreak,
+    /// Optional per-slot multipliers of the pattern weights. Every multiplier must be positive and
+    /// finite, or `Generator::with_config` returns an error.
+    pub guidance: Option<Arc<dyn WeightField>>,
+    /// Optional patterns that each slot starts with, instead of all of them, e.g. to paint coarse
+    /// regions of water or buildings before collapse. Must cover the whole output, and no slot may
+    /// start empty, or `Generator::with_config` returns an error.
+    pub initial_patterns: Option<Arc<VecLatticeMap<PatternSet>>>,
+    /// How the entropy of each slot is computed.
+    pub entropy: EntropyMode,
+    /// Where constraints are propagated.
+    pub propagation: Propagation,
+    /// Where the support counts are kept.
+    pub storage: WaveStorage,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            tie_break: TieBreak::Noise { amplitude: 0.1 },
+            guidance: None,
+            initial_patterns: None,
+            entropy: EntropyMode::Float,
+            propagation: Propagation::Cpu,
+            storage: WaveStorage::Memory,
+        }
+    }
+}
+
+impl GeneratorConfig {
+    pub(crate) fn validate(
+        &self,
+        output_size: lat::Point,
+        num_patterns: PatternIndex,
+    ) -> Result<(), WfcError> {
+        self.validate_guidance(output_size, num_patterns)?;
+        if let Some(initial_patterns) = &self.initial_patterns {
+            let extent = lat::Extent::from_min_and_world_supremum([0, 0, 0].into(), output_size);
+            let all_possible = PatternSet::all(num_patterns);
+            for slot in extent {
+                if !initial_patterns.get_extent().contains_world(&slot) {
+                    return Err(WfcError::InvalidConfig(format!(
+                        "Slot {} is outside of the initial patterns",
+                        slot
+                    )));
+                }
+                let mut possible = all_possible.clone();
+                possible.intersect_with(initial_patterns.get_world_ref(&slot));
+                if possible.is_empty() {
+                    return Err(WfcError::InvalidConfig(format!(
+                        "No initial patterns for {}",
+                        slot
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks every weight multiplier of the guidance once, since a NaN or non-positive one would
+    /// break entropy ordering and sampling later on.
+    pub(crate) fn validate_guidance(
+        &self,
+        output_size: lat::Point,
+        num_patterns: PatternIndex,
+    ) -> Result<(), WfcError> {
+        if let Some(guidance) = &self.guidance {
+            let extent = lat::Extent::from_min_and_world_supremum([0, 0, 0].into(), output_size);
+            for slot in extent {
+                for pattern in (0..num_patterns).map(PatternId) {
+                    let multiplier = guidance.weight_multiplier(&slot, pattern);
+                    if !(multiplier > 0.0 && multiplier.is_finite()) {
+                        return Err(WfcError::InvalidConfig(format!(
+                            "Weight multiplier {} for pattern {} at {} must be positive and finite",
+                            multiplier, pattern.0, slot
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How a `Generator` chooses between slots with equal entropy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TieBreak {
+    /// Adds random noise in `[0, amplitude)` to each slot's entropy. The noise is applied to all
+    /// slots, not just tied ones, so larger amplitudes can override real entropy differences.
+    Noise { amplitude: f32 },
+    /// Chooses the slot with the lowest linear index. Useful for comparing outputs, since the order
+    /// of observations doesn't depend on the seed.
+    LowestIndex,
+}
+
+/// How a `Generator` computes the entropy of each slot, which decides the order of observations.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntropyMode {
+    /// `f32` arithmetic. The logarithms come from the platform's math library, which can round
+    /// differently on other targets, so the same seed doesn't always choose the same slots there.
+    Float,
+    /// Integer sums and logarithms, so the same seed and model produce the same output on every
+    /// target, e.g. x86, ARM, and WASM. Weights, including guidance, are rounded down to multiples
+    /// of 1/256. The RNG must also be portable; `Pcg64Mcg` is, but `SmallRng` isn't.
+    FixedPoint,
+}
+
+/// Where a `Generator` propagates constraints.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Propagation {
+    /// One removal at a time, using support counts.
+    Cpu,
+    /// Experimental. Every slot at once in a wgpu compute shader, which pays off for large outputs,
+    /// e.g. 256^3 with hundreds of patterns. Needs the "gpu" feature; without it, or without a
+    /// usable GPU, this falls back to `Cpu`. Generators propagating on the GPU can't be saved with
+    /// `save_snapshot`, and their `ContradictionReport`s have no cause chains.
+    Gpu,
+}
+
+/// Where a `Generator` keeps its support counts, which take most of its memory: a count for every
+/// slot, pattern, and offset. See `estimate_generator_memory`. The possible patterns of each slot
+/// always stay in memory, since they only need a bit per pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WaveStorage {
+    Memory,
+    /// A file at this path, created or overwritten, and mapped into memory, so outputs bigger than
+    /// RAM can page their counts out to disk. Fastest when propagation stays local, e.g. with a
+    /// `ChunkedGenerator`. Falls back to `Memory` if the file can't be mapped. Needs the "mmap"
+    /// feature.
+    #[cfg(feature = "mmap")]
+    MappedFile(PathBuf),
 }
 
 impl Generator {
@@ -23,16 +205,169 @@ impl Generator {
         sampler: &PatternSampler,
         constraints: &PatternConstraints,
     ) -> Self {
+        Self::from_valid_config(
+            Pcg64Mcg::from_seed(seed),
+            output_size,
+            sampler,
+            constraints,
+            &GeneratorConfig::default(),
+        )
+    }
+
+    /// Returns an error iff `config` is invalid for the output, e.g. its initial patterns don't
+    /// cover every slot.
+    pub fn with_config(
+        seed: [u8; NUM_SEED_BYTES],
+        output_size: lat::Point,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        config: &GeneratorConfig,
+    ) -> Result<Self, WfcError> {
+        Self::from_rng_with_config(
+            Pcg64Mcg::from_seed(seed),
+            output_size,
+            sampler,
+            constraints,
+            config,
+        )
+    }
+
+    /// Like `new`, but the pattern weights are scaled by `guidance` wherever slots are chosen and
+    /// observed. Returns an error iff any multiplier is non-positive or not finite.
+    pub fn with_guidance(
+        seed: [u8; NUM_SEED_BYTES],
+        output_size: lat::Point,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        guidance: Arc<dyn WeightField>,
+    ) -> Result<Self, WfcError> {
+        let config = GeneratorConfig {
+            guidance: Some(guidance),
+            ..GeneratorConfig::default()
+        };
+
+        Self::with_config(seed, output_size, sampler, constraints, &config)
+    }
+
+    pub(crate) fn from_parts(rng: Pcg64Mcg, wave: Wave) -> Self {
         Generator {
-            wave: Wave::new(sampler, constraints, output_size),
-            rng: SmallRng::from_seed(seed),
+            rng,
+            wave,
+            last_observed_slot: None,
+            batch: ObservationBatch::default(),
+            num_updates: 0,
         }
     }
 
+    pub(crate) fn parts(&self) -> (&Pcg64Mcg, &Wave) {
+        (&self.rng, &self.wave)
+    }
+}
+
+impl<R: Rng + SeedableRng> Generator<R> {
+    /// Seeds `R` from a single number, which is easier to pass around than a byte array.
+    pub fn from_u64_seed(
+        seed: u64,
+        output_size: lat::Point,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> Self {
+        Self::from_rng(R::seed_from_u64(seed), output_size, sampler, constraints)
+    }
+}
+
+impl<R: Rng> Generator<R> {
+    /// Uses `rng` for every random choice, e.g. a ChaCha RNG for the same outputs on every
+    /// platform.
+    pub fn from_rng(
+        rng: R,
+        output_size: lat::Point,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> Self {
+        Self::from_valid_config(
+            rng,
+            output_size,
+            sampler,
+            constraints,
+            &GeneratorConfig::default(),
+        )
+    }
+
+    /// Like `with_config`, but with any `rng`.
+    pub fn from_rng_with_config(
+        rng: R,
+        output_size: lat::Point,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        config: &GeneratorConfig,
+    ) -> Result<Self, WfcError> {
+        config.validate(output_size, constraints.num_patterns())?;
+
+        Ok(Self::from_valid_config(
+            rng,
+            output_size,
+            sampler,
+            constraints,
+            config,
+        ))
+    }
+
+    fn from_valid_config(
+        mut rng: R,
+        output_size: lat::Point,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        config: &GeneratorConfig,
+    ) -> Self {
+        let wave = Wave::new(sampler, constraints, output_size, config, &mut rng);
+
+        Generator {
+            rng,
+            wave,
+            last_observed_slot: None,
+            batch: ObservationBatch::default(),
+            num_updates: 0,
+        }
+    }
+
+    /// Observes up to `max_slots` slots in each `update` instead of one: the slot with the least
+    /// entropy, then the next least, skipping any within `min_distance` (along every axis) of a
+    /// slot already chosen. Each observation is propagated before the next, so the output is still
+    /// consistent, but slots that are far apart rarely affect each other's entropy, so this mostly
+    /// saves searching for the least entropy in every update.
+    pub fn set_observation_batch(&mut self, max_slots: usize, min_distance: i32) {
+        self.batch = ObservationBatch {
+            max_slots: max_slots.max(1),
+            min_distance,
+        };
+    }
+
+    /// Replaces weighted sampling with `chooser` whenever a slot is observed.
+    pub fn set_chooser(&mut self, chooser: Arc<dyn PatternChooser>) {
+        self.wave.set_chooser(chooser);
+    }
+
+    /// Starts sending an `ObserverEvent` for each step of generation. Events are only queued, so
+    /// the receiver can be drained at any pace, e.g. once per frame. Calling this again replaces the
+    /// previous receiver.
+    pub fn observe_events(&mut self) -> Receiver<ObserverEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.wave.set_event_sender(sender);
+
+        receiver
+    }
+
     pub fn get_wave_lattice(&self) -> &VecLatticeMap<PatternSet> {
         self.wave.get_slots()
     }
 
+    /// The entropy of each slot, as used to choose the next slot to observe. Collapsed slots are
+    /// infinite.
+    pub fn get_entropy_lattice(&self) -> VecLatticeMap<f32> {
+        self.wave.get_entropies()
+    }
+
     /// Warning: undefined behavior if called before `update` returns `Success`.
     pub fn result(&self) -> VecLatticeMap<PatternId> {
         self.wave
@@ -44,25 +379,475 @@ impl Generator {
         self.wave.num_collapsed()
     }
 
+    pub fn num_slots(&self) -> usize {
+        self.wave.num_slots()
+    }
+
+    /// How many removals propagation has pushed, popped, and skipped as duplicates so far, and
+    /// how deep the removal stack got, e.g. to profile a model.
+    pub fn propagation_stats(&self) -> PropagationStats {
+        self.wave.propagation_stats()
+    }
+
+    /// A summary of the generator's progress and the work done so far, e.g. to log after a run or
+    /// to compare models.
+    pub fn stats(&self) -> GeneratorStats {
+        GeneratorStats {
+            num_collapsed: self.wave.num_collapsed(),
+            remaining_superposition: self.wave.remaining_superposition(),
+            num_removals: self.wave.num_removals(),
+            num_backtracks: self.wave.num_reopens(),
+            num_contradictions: self.wave.num_contradictions(),
+            num_updates: self.num_updates,
+            max_queue_depth: self.wave.propagation_stats().max_depth,
+        }
+    }
+
+    /// The slot observed by the last `update`, or the first of them, after `set_observation_batch`.
+    /// `None` if it didn't observe one, e.g. because it found a contradiction first.
+    pub fn last_observed_slot(&self) -> Option<lat::Point> {
+        self.last_observed_slot
+    }
+
+    /// Iterates over the updates until the output is fully assigned or a contradiction is found,
+    /// e.g. to drive a progress bar with a `for` loop.
+    pub fn frames<'a>(
+        &'a mut self,
+        sampler: &'a PatternSampler,
+        constraints: &'a PatternConstraints,
+    ) -> Frames<'a, R> {
+        Frames::new(self, sampler, constraints)
+    }
+
+    /// Frees the memory that collapsed slots no longer need, e.g. every so often during a long run
+    /// where most of the output is already determined. Returns how many slots had their support
+    /// counts freed, which is always 0 with `WaveStorage::MappedFile`.
+    pub fn compact(&mut self, constraints: &PatternConstraints) -> usize {
+        self.wave.compact(constraints)
+    }
+
+    /// Starts recording when each slot changes, for `changed_slots_since`. Off by default, since it
+    /// adds a little work to every removal and a `u64` per slot.
+    pub fn track_changes(&mut self) {
+        self.wave.track_changes();
+    }
+
+    /// The current point in this generator's history, to pass to `changed_slots_since` later.
+    pub fn change_stamp(&self) -> ChangeStamp {
+        self.wave.change_stamp()
+    }
+
+    /// The slots that lost or regained patterns after `since` was taken, e.g. to redraw only those
+    /// with a `SuperpositionRenderer`. Every slot if `since` is `None`, came from another
+    /// generator, or `track_changes` wasn't called before it.
+    pub fn changed_slots_since(&self, since: Option<ChangeStamp>) -> Vec<lat::Point> {
+        self.wave.changed_slots_since(since)
+    }
+
+    /// Starts recording which slots lose patterns, for `modified_slots`. Off by default, since it
+    /// adds a little work to every removal.
+    pub fn track_modified_slots(&mut self) {
+        self.wave.track_modified_slots();
+    }
+
+    /// The slots that lost patterns during the last `update`, or since then, e.g. from
+    /// `constrain_slot`. Only tracked after `track_modified_slots`, so empty before.
+    pub fn modified_slots(&self) -> Vec<lat::Point> {
+        self.wave.modified_slots()
+    }
+
+    /// Starts recording every pattern removal, so a failed update can be explained by
+    /// `contradiction_report`. Off by default, since the log grows with every removal.
+    pub fn track_removals(&mut self) {
+        self.wave.track_removals();
+    }
+
+    /// Explains the last contradiction: the neighbors of the slot that was emptied, and the
+    /// removals that emptied it. Only available if `track_removals` was called beforehand.
+    pub fn contradiction_report(&self) -> Option<&ContradictionReport> {
+        self.wave.contradiction_report()
+    }
+
     pub fn update(
         &mut self,
         sampler: &PatternSampler,
         constraints: &PatternConstraints,
     ) -> UpdateResult {
-        let (slot, entropy) = self.wave.choose_least_entropy_slot(&mut self.rng);
-        debug!(
-            "{} collapsed slots; chose slot {} with least entropy {}",
-            self.wave.num_collapsed(),
-            slot,
-            entropy
-        );
-
-        if !self
+        self.wave.clear_modified_slots();
+        self.last_observed_slot = None;
+        self.num_updates += 1;
+        if let Err(error) = self.wave.propagate_pending(sampler, constraints) {
+            return self.update_result(Err(error));
+        }
+
+        let choices = if self.batch.max_slots > 1 {
+            self.wave
+                .choose_separated_slots(self.batch.max_slots, self.batch.min_distance)
+        } else {
+            self.wave.choose_least_entropy_slot().into_iter().collect()
+        };
+        if choices.is_empty() {
+            // Every slot is already collapsed.
+            return self.update_result(Ok(()));
+        }
+
+        let mut propagated = Ok(());
+        for (slot, entropy) in choices.into_iter() {
+            // An earlier observation in the batch may have collapsed this slot already.
+            if self.last_observed_slot.is_some() && self.possible_patterns(&slot).len() == 1 {
+                continue;
+            }
+            let _span = debug_span!(
+                "observe",
+                %slot,
+                entropy,
+                num_collapsed = self.wave.num_collapsed()
+            )
+            .entered();
+            self.wave.emit(ObserverEvent::SlotChosen { slot, entropy });
+            self.last_observed_slot = self.last_observed_slot.or(Some(slot));
+
+            propagated = self
+                .wave
+                .observe_slot(&mut self.rng, sampler, constraints, &slot);
+            if propagated.is_err() {
+                break;
+            }
+        }
+
+        self.update_result(propagated)
+    }
+
+    /// Calls `update` until the output is fully assigned or a contradiction is found. Stops early
+    /// with `Cancelled` once `cancel` is triggered (from any thread), or with `TimedOut` once
+    /// `time_budget` has elapsed. The generator can be resumed after either.
+    pub fn run(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        cancel: &CancellationToken,
+        time_budget: Option<Duration>,
+    ) -> UpdateResult {
+        // Only read the clock when there's a budget, since `Instant` isn't available on every
+        // target, e.g. wasm32-unknown-unknown.
+        let start = time_budget.map(|budget| (Instant::now(), budget));
+        loop {
+            if cancel.is_cancelled() {
+                return UpdateResult::Cancelled;
+            }
+            if let Some((start, budget)) = start {
+                if start.elapsed() >= budget {
+                    return UpdateResult::TimedOut;
+                }
+            }
+
+            match self.update(sampler, constraints) {
+                UpdateResult::Continue => (),
+                result => return result,
+            }
+        }
+    }
+
+    /// Calls `update` until the output is fully assigned or a contradiction is found, so the result
+    /// is either `Success` or `Failure`.
+    pub fn run_to_completion(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> UpdateResult {
+        self.run(sampler, constraints, &CancellationToken::new(), None)
+    }
+
+    /// Calls `update` up to `num_steps` times, stopping early if the output is fully assigned or a
+    /// contradiction is found. Returns `Continue` if there's more to do.
+    pub fn run_steps(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        num_steps: usize,
+    ) -> UpdateResult {
+        for _ in 0..num_steps {
+            match self.update(sampler, constraints) {
+                UpdateResult::Continue => (),
+                result => return result,
+            }
+        }
+
+        UpdateResult::Continue
+    }
+
+    /// Like `run_to_completion`, but stops with `TimedOut` once `duration` has elapsed. The
+    /// generator can be resumed afterward. Not available on wasm32-unknown-unknown, which has no
+    /// clock.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run_for(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        duration: Duration,
+    ) -> UpdateResult {
+        self.run(
+            sampler,
+            constraints,
+            &CancellationToken::new(),
+            Some(duration),
+        )
+    }
+
+    /// Calls `update` as many times as fit in `budget`, for spreading a large output over the
+    /// frames of a game without going over the frame's time. Each update is assumed to take as long
+    /// as the slowest one so far, and one is skipped unless it's expected to fit, so only the first
+    /// update can overrun the budget. Not available on wasm32-unknown-unknown, which has no clock.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn update_for_budget(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        budget: Duration,
+    ) -> BudgetedUpdate {
+        let start = Instant::now();
+        let mut slowest_update = Duration::from_secs(0);
+        let mut num_updates = 0;
+        let result = loop {
+            let update_start = Instant::now();
+            let result = self.update(sampler, constraints);
+            num_updates += 1;
+            if result != UpdateResult::Continue {
+                break result;
+            }
+            slowest_update = slowest_update.max(update_start.elapsed());
+            if start.elapsed() + slowest_update > budget {
+                break result;
+            }
+        };
+
+        BudgetedUpdate {
+            result,
+            num_updates,
+            num_collapsed: self.num_collapsed(),
+        }
+    }
+
+    /// Restricts `slot` to the patterns in `allowed` and propagates the consequences. Meant to be
+    /// called before the first `update`, e.g. to match the border of a neighboring output. Returns
+    /// an error iff `slot` is outside of the output.
+    pub fn constrain_slot(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        allowed: &PatternSet,
+    ) -> Result<UpdateResult, WfcError> {
+        self.check_slot(slot)?;
+        let propagated = self
+            .wave
+            .constrain_slot(sampler, constraints, slot, allowed);
+
+        Ok(self.update_result(propagated))
+    }
+
+    /// Like `constrain_slot`, but for many slots at once, which only needs to propagate once. If
+    /// any slot is outside of the output, returns an error without restricting any of them.
+    pub fn constrain_slots<'a, A>(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        restrictions: A,
+    ) -> Result<UpdateResult, WfcError>
+    where
+        A: IntoIterator<Item = (lat::Point, &'a PatternSet)>,
+    {
+        let restrictions: Vec<_> = restrictions.into_iter().collect();
+        for (slot, _) in restrictions.iter() {
+            self.check_slot(slot)?;
+        }
+
+        Ok(self.restrict_slots(sampler, constraints, restrictions))
+    }
+
+    /// `constrain_slots` without the bounds check, for callers that take their slots from the
+    /// wave's own extent.
+    pub(crate) fn restrict_slots<'a, A>(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        restrictions: A,
+    ) -> UpdateResult
+    where
+        A: IntoIterator<Item = (lat::Point, &'a PatternSet)>,
+    {
+        let propagated = self
             .wave
-            .observe_slot(&mut self.rng, sampler, constraints, &slot)
+            .constrain_slots(sampler, constraints, restrictions);
+
+        self.update_result(propagated)
+    }
+
+    /// Limits how many slots may be assigned the patterns of `count`, then propagates. Meant to be
+    /// called before the first `update`. Returns an error iff the minimum is greater than the
+    /// maximum or the number of slots; a count that the remaining patterns can't meet is a
+    /// `Failure`.
+    pub fn add_count_constraint(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        count: CountConstraint,
+    ) -> Result<UpdateResult, WfcError> {
+        count.validate(self.num_slots())?;
+        let propagated = self.wave.add_count_constraint(sampler, constraints, count);
+
+        Ok(self.update_result(propagated))
+    }
+
+    /// Puts the slots in `region` back into superposition, keeping the rest of the output fixed,
+    /// e.g. to re-roll one room of a finished map. Call `update` or `run` afterwards to collapse
+    /// the region again; only its slots will be observed. The new patterns are still constrained
+    /// by the slots around the region, and by the initial patterns, `constrain_slot` and `ban`, but
+    /// not by `force_slot` or `stamp`.
+    pub fn reopen_region(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        region: &lat::Extent,
+    ) -> UpdateResult {
+        let propagated = self.wave.reopen_region(sampler, constraints, region);
+
+        self.update_result(propagated)
+    }
+
+    /// Requires a path of walkable patterns between two regions of the output, checked after each
+    /// propagation. Meant to be called before the first `update`.
+    pub fn add_connectivity_constraint(
+        &mut self,
+        constraints: &PatternConstraints,
+        connectivity: ConnectivityConstraint,
+    ) -> UpdateResult {
+        let checked = self
+            .wave
+            .add_connectivity_constraint(constraints, connectivity);
+
+        self.update_result(checked)
+    }
+
+    /// Requires the output voxel at `voxel` to be `value`, by restricting the slot that covers it to
+    /// patterns whose tile has `value` in the right place. This gives finer control than
+    /// `constrain_slot`, e.g. to put a door voxel at an exact position. Returns an error iff
+    /// `voxel` is outside of the output.
+    pub fn constrain_voxel<T, I>(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        tiles: &PatternTileSet<T, I>,
+        voxel: &lat::Point,
+        value: &T,
+    ) -> Result<UpdateResult, WfcError>
+    where
+        T: Clone + PartialEq,
+        I: Clone + Indexer,
+    {
+        let (slot, allowed) = tiles.patterns_with_voxel(voxel, value);
+
+        self.constrain_slot(sampler, constraints, &slot, &allowed)
+    }
+
+    /// Collapses `slot` to `pattern` and propagates the consequences, e.g. to place a door at a
+    /// specific location. Usually called before the first `update`, but it works between updates
+    /// too. Returns an error iff `slot` is outside of the output.
+    pub fn force_slot(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        pattern: PatternId,
+    ) -> Result<UpdateResult, WfcError> {
+        self.check_slot(slot)?;
+        let propagated = self.wave.force_slot(sampler, constraints, slot, pattern);
+
+        Ok(self.update_result(propagated))
+    }
+
+    /// Same as `force_slot`, for interactive tools where the user assigns patterns (e.g. by painting
+    /// a tile) between calls to `update`.
+    pub fn observe_at(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        pattern: PatternId,
+    ) -> Result<UpdateResult, WfcError> {
+        self.force_slot(sampler, constraints, slot, pattern)
+    }
+
+    /// Rules out `pattern` at `slot` and propagates the consequences. Can be called between any two
+    /// calls to `update`. Returns an error iff `slot` is outside of the output.
+    pub fn ban(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        pattern: PatternId,
+    ) -> Result<UpdateResult, WfcError> {
+        self.check_slot(slot)?;
+        let propagated = self.wave.ban(sampler, constraints, slot, pattern);
+
+        Ok(self.update_result(propagated))
+    }
+
+    /// The patterns that are still possible at `slot`.
+    pub fn possible_patterns(&self, slot: &lat::Point) -> &PatternSet {
+        self.wave.get_slots().get_world_ref(slot)
+    }
+
+    /// Pins a block of patterns into the output, with the minimum corner of `stamp` placed at
+    /// `extent_min`, and propagates the consequences. This is how authored set-pieces get mixed
+    /// into generated surroundings.
+    pub fn stamp<I: Indexer>(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        extent_min: &lat::Point,
+        stamp: &VecLatticeMap<PatternId, I>,
+    ) -> Result<UpdateResult, StampError> {
+        if !constraints.assignment_is_valid(stamp) {
+            return Err(StampError::InvalidAssignment);
+        }
+
+        let stamp_extent = stamp.get_extent();
+        let output_extent = self.wave.get_slots().get_extent();
+        let assignments: Vec<(lat::Point, PatternId)> = stamp_extent
+            .into_iter()
+            .map(|p| {
+                let slot = *extent_min + (p - *stamp_extent.get_minimum());
+
+                (slot, stamp.get_world(&p))
+            })
+            .collect();
+        if assignments
+            .iter()
+            .any(|(slot, _)| !output_extent.contains_world(slot))
         {
-            UpdateResult::Failure
+            return Err(StampError::OutOfBounds);
+        }
+
+        let propagated = self.wave.force_slots(sampler, constraints, assignments);
+
+        Ok(self.update_result(propagated))
+    }
+
+    fn check_slot(&self, slot: &lat::Point) -> Result<(), WfcError> {
+        if self.wave.get_slots().get_extent().contains_world(slot) {
+            Ok(())
+        } else {
+            Err(WfcError::SlotOutOfBounds(*slot))
+        }
+    }
+
+    fn update_result(&self, propagated: Result<(), GenerationError>) -> UpdateResult {
+        if let Err(e) = propagated {
+            UpdateResult::Failure(e)
         } else if self.wave.determined() {
+            debug!("{:?}", self.wave.propagation_stats());
+
             UpdateResult::Success
         } else {
             UpdateResult::Continue
@@ -70,12 +855,229 @@ impl Generator {
     }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+/// How far `Generator::update_for_budget` got.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BudgetedUpdate {
+    /// The result of the last update, so `Continue` if the budget ran out first.
+    pub result: UpdateResult,
+    pub num_updates: usize,
+    /// The number of collapsed slots afterward, out of `Generator::num_slots`.
+    pub num_collapsed: usize,
+}
+
+/// See `Generator::stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GeneratorStats {
+    pub num_collapsed: usize,
+    /// The patterns that could still be removed: the sum over all slots of one less than the
+    /// number of possible patterns.
+    pub remaining_superposition: u64,
+    /// Patterns removed from slots, by observation, propagation, or constraints.
+    pub num_removals: u64,
+    /// Regions put back into superposition by `reopen_region`. There's no automatic backtracking,
+    /// so this is the only way a generator revisits its choices.
+    pub num_backtracks: u64,
+    /// Contradictions found, including those of constraints that couldn't be met.
+    pub num_contradictions: u64,
+    /// Calls to `update`.
+    pub num_updates: u64,
+    /// The most removals waiting to be propagated at once.
+    pub max_queue_depth: usize,
+}
+
+/// Statistics from `generate_with_retries`.
+#[derive(Clone, Debug)]
+pub struct RetryStats {
+    /// Number of generators that were run, including the last one.
+    pub num_attempts: usize,
+    /// The seed of the last attempt. On success, this seed reproduces the output.
+    pub last_seed: [u8; NUM_SEED_BYTES],
+    /// Calls to `Generator::update`, summed over all attempts.
+    pub total_updates: usize,
+    /// The contradiction that ended the most recent failed attempt, if any.
+    pub last_error: Option<GenerationError>,
+}
+
+/// Runs generators until one succeeds or `max_attempts` have failed. Each retry uses a new seed
+/// derived deterministically from the previous one, so the whole sequence is reproducible from
+/// `seed`.
+///
+/// `prepare` is called on each new generator before its first update, e.g. to apply a
+/// `GroundConstraint`; an attempt fails if it returns `UpdateResult::Failure`. `on_update` is called
+/// after every update. Returning `false` stops generation without retrying.
+pub fn generate_with_retries<P, F>(
+    seed: [u8; NUM_SEED_BYTES],
+    max_attempts: usize,
+    output_size: lat::Point,
+    sampler: &PatternSampler,
+    constraints: &PatternConstraints,
+    mut prepare: P,
+    mut on_update: F,
+) -> (Option<VecLatticeMap<PatternId>>, RetryStats)
+where
+    P: FnMut(&mut Generator) -> UpdateResult,
+    F: FnMut(&Generator) -> bool,
+{
+    let mut stats = RetryStats {
+        num_attempts: 0,
+        last_seed: seed,
+        total_updates: 0,
+        last_error: None,
+    };
+
+    let mut attempt_seed = seed;
+    while stats.num_attempts < max_attempts {
+        stats.num_attempts += 1;
+        stats.last_seed = attempt_seed;
+
+        let mut generator = Generator::new(attempt_seed, output_size, sampler, constraints);
+        let mut state = prepare(&mut generator);
+        loop {
+            if state == UpdateResult::Continue {
+                state = generator.update(sampler, constraints);
+                stats.total_updates += 1;
+            }
+            let keep_going = on_update(&generator);
+            match state {
+                UpdateResult::Success => return (Some(generator.result()), stats),
+                _ if !keep_going => return (None, stats),
+                UpdateResult::Continue => (),
+                UpdateResult::Failure(e) => {
+                    info!(
+                        "Attempt {} with seed {:?} failed: {}",
+                        stats.num_attempts, attempt_seed, e
+                    );
+                    stats.last_error = Some(e);
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        attempt_seed = mutate_seed(attempt_seed);
+    }
+
+    (None, stats)
+}
+
+/// Turns a seed string into the seed bytes of a `Generator`. Strings of up to `NUM_SEED_BYTES`
+/// bytes are used as-is, padded with zeros, so seeds from before hashing still reproduce their
+/// outputs. Longer strings are hashed with SHA-256, truncated, so every byte of them matters.
+pub fn seed_from_str(s: &str) -> [u8; NUM_SEED_BYTES] {
+    let mut seed = [0; NUM_SEED_BYTES];
+    let seed_bytes = s.as_bytes();
+    if seed_bytes.len() <= NUM_SEED_BYTES {
+        seed[..seed_bytes.len()].copy_from_slice(seed_bytes);
+    } else {
+        seed.copy_from_slice(&Sha256::digest(seed_bytes)[..NUM_SEED_BYTES]);
+    }
+
+    seed
+}
+
+/// The seed that `generate_with_retries` tries after `seed` fails.
+pub fn mutate_seed(seed: [u8; NUM_SEED_BYTES]) -> [u8; NUM_SEED_BYTES] {
+    let mut rng = SmallRng::from_seed(seed);
+    let mut new_seed = [0; NUM_SEED_BYTES];
+    rng.fill_bytes(&mut new_seed);
+
+    new_seed
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StampError {
+    /// Part of the stamp lies outside of the output.
+    OutOfBounds,
+    /// The stamp contains adjacent patterns that aren't compatible.
+    InvalidAssignment,
+}
+
+/// A cheaply cloneable flag for stopping generation from another thread.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Which constraint ended generation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailureReason {
+    /// A slot was left with no possible patterns.
+    EmptySlot,
+    /// A `CountConstraint` can't be met by the remaining patterns.
+    CountUnsatisfiable,
+    /// A `ConnectivityConstraint` can't be met by the remaining patterns.
+    ConnectivityUnsatisfiable,
+}
+
+/// Describes the contradiction that ended generation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GenerationError {
+    pub reason: FailureReason,
+    /// The slot that was left with no possible patterns, or for a count constraint, the slot whose
+    /// change made it unsatisfiable. `None` if no one slot is to blame, e.g. for a connectivity
+    /// constraint.
+    pub slot: Option<lat::Point>,
+    /// The last pattern removed from `slot`, i.e. the one whose removal emptied it.
+    pub emptied_by: Option<PatternId>,
+    /// Number of removals propagated before the contradiction was found. Zero means the slot was
+    /// emptied directly, e.g. by `Generator::force_slot`.
+    pub chain_length: usize,
+    /// Patterns that are still compatible with every slot adjacent to `slot`. If this isn't empty,
+    /// the contradiction was caused by an earlier choice (likely a collapse) rather than by the
+    /// neighbors themselves.
+    pub recoverable_patterns: Vec<PatternId>,
+}
+
+impl fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            FailureReason::EmptySlot => write!(f, "No possible patterns")?,
+            FailureReason::CountUnsatisfiable => write!(f, "Count constraint can't be met")?,
+            FailureReason::ConnectivityUnsatisfiable => {
+                write!(f, "Connectivity constraint can't be met")?
+            }
+        }
+        if let Some(slot) = self.slot {
+            write!(f, " for slot {}", slot)?;
+        }
+        if let Some(pattern) = self.emptied_by {
+            write!(f, " after removing {:?}", pattern)?;
+        }
+        write!(
+            f,
+            " ({} propagated removals, {} patterns still compatible with neighbors)",
+            self.chain_length,
+            self.recoverable_patterns.len()
+        )
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum UpdateResult {
     /// The output lattice is fully assigned.
     Success,
     /// Further calls to `update` are required.
     Continue,
     /// The currently assigned patterns cannot satisfy the constraints.
-    Failure,
+    Failure(GenerationError),
+    /// `Generator::run` was stopped by its `CancellationToken`.
+    Cancelled,
+    /// `Generator::run` used up its time budget.
+    TimedOut,
 }
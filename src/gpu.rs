@@ -0,0 +1,328 @@
+//! Experimental constraint propagation on the GPU, for large outputs. See `Propagation::Gpu`.
+
+use crate::pattern::{PatternConstraints, PatternId, PatternSet};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, VecLatticeMap};
+use std::borrow::Cow;
+use tracing::{info, warn};
+use wgpu::util::DeviceExt;
+
+const WORKGROUP_SIZE: u32 = 64;
+const MAX_WORKGROUPS_PER_DIMENSION: u32 = 65535;
+/// Passes to run between checks for convergence, since each check waits for the GPU.
+const PASSES_PER_CHECK: usize = 8;
+
+/// Keeps a copy of the wave's pattern sets on the GPU. Each pass removes, from every slot at once,
+/// the patterns with no compatible pattern in some neighboring slot, and passes repeat until one
+/// removes nothing.
+///
+/// Support counts aren't needed for this, so only the pattern sets are stored, one bit per pattern
+/// with X varying fastest, then Y, then Z. Between propagations, only the slots changed on the CPU
+/// are uploaded, but the whole wave is read back to apply the removals.
+pub(crate) struct GpuPropagator {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+    slot_buffer: wgpu::Buffer,
+    slot_readback: wgpu::Buffer,
+    status_buffer: wgpu::Buffer,
+    status_readback: wgpu::Buffer,
+    size: lat::Point,
+    /// The number of `u32`s in each pattern set.
+    num_words: usize,
+    /// The 2D dispatch that covers every slot.
+    workgroups: (u32, u32),
+    /// Every slot must be uploaded before the next propagation.
+    needs_upload: bool,
+}
+
+impl GpuPropagator {
+    /// Returns `None` if there's no GPU, or the wave doesn't fit in its buffers.
+    pub fn new(constraints: &PatternConstraints, output_size: lat::Point) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+        }))?;
+        let limits = adapter.limits();
+        let (device, queue) = match pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: wgpu::Features::empty(),
+                limits: limits.clone(),
+            },
+            None,
+        )) {
+            Ok(device) => device,
+            Err(e) => {
+                warn!("Failed to open the GPU: {}", e);
+                return None;
+            }
+        };
+
+        let num_slots = (output_size.x * output_size.y * output_size.z) as usize;
+        let num_words = (constraints.num_patterns() as usize + 31) / 32;
+        let slot_bytes = (num_slots * num_words * 4) as u64;
+        if slot_bytes > limits.max_storage_buffer_binding_size as u64 {
+            warn!(
+                "The wave needs {} bytes, but GPU buffers are limited to {}",
+                slot_bytes, limits.max_storage_buffer_binding_size
+            );
+            return None;
+        }
+        info!("Propagating on {}", adapter.get_info().name);
+
+        let offset_group = constraints.get_offset_group();
+        let mut compatible = Vec::new();
+        for pattern in (0..constraints.num_patterns()).map(PatternId) {
+            for (offset_id, _) in offset_group.iter() {
+                let mut words = vec![0u32; num_words];
+                for other in constraints.iter_compatible(pattern, offset_id) {
+                    add_pattern(&mut words, other);
+                }
+                compatible.extend_from_slice(&words);
+            }
+        }
+        let offsets: Vec<i32> = offset_group
+            .iter()
+            .flat_map(|(_, offset)| vec![offset.x, offset.y, offset.z])
+            .collect();
+
+        let num_workgroups = (num_slots as u32 + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+        let workgroups_x = num_workgroups.min(MAX_WORKGROUPS_PER_DIMENSION);
+        let workgroups_y = (num_workgroups + workgroups_x - 1) / workgroups_x;
+        let params = [
+            output_size.x as u32,
+            output_size.y as u32,
+            output_size.z as u32,
+            num_words as u32,
+            offset_group.num_offsets() as u32,
+            workgroups_x * WORKGROUP_SIZE,
+            0,
+            0,
+        ];
+
+        let storage = wgpu::BufferUsage::STORAGE;
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("params"),
+            contents: bytemuck::cast_slice(&params),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+        let compatible_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("compatible"),
+            contents: bytemuck::cast_slice(&compatible),
+            usage: storage,
+        });
+        let offset_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("offsets"),
+            contents: bytemuck::cast_slice(&offsets),
+            usage: storage,
+        });
+        let copyable = storage | wgpu::BufferUsage::COPY_SRC | wgpu::BufferUsage::COPY_DST;
+        let slot_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("slots"),
+            size: slot_bytes,
+            usage: copyable,
+            mapped_at_creation: false,
+        });
+        let status_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("status"),
+            size: 8,
+            usage: copyable,
+            mapped_at_creation: false,
+        });
+        let readback = wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST;
+        let slot_readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("slot readback"),
+            size: slot_bytes,
+            usage: readback,
+            mapped_at_creation: false,
+        });
+        let status_readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("status readback"),
+            size: 8,
+            usage: readback,
+            mapped_at_creation: false,
+        });
+
+        let module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("propagate"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("gpu_propagate.wgsl"))),
+            flags: wgpu::ShaderFlags::all(),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("propagate"),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: slot_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: compatible_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: offset_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: status_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        Some(GpuPropagator {
+            device,
+            queue,
+            pipeline,
+            bind_group,
+            slot_buffer,
+            slot_readback,
+            status_buffer,
+            status_readback,
+            size: output_size,
+            num_words,
+            workgroups: (workgroups_x, workgroups_y),
+            needs_upload: true,
+        })
+    }
+
+    /// Uploads every slot before the next propagation, e.g. after slots were reopened.
+    pub fn invalidate(&mut self) {
+        self.needs_upload = true;
+    }
+
+    /// Uploads the slots in `changed` (or every slot, if invalidated), then propagates until
+    /// nothing changes or a slot is emptied. Returns the patterns that were removed.
+    pub fn propagate(
+        &mut self,
+        slots: &VecLatticeMap<PatternSet>,
+        changed: &[lat::Point],
+    ) -> Vec<(lat::Point, PatternId)> {
+        if self.needs_upload {
+            let mut words = Vec::with_capacity(slots.get_extent().volume() * self.num_words);
+            for i in 0..slots.get_extent().volume() {
+                words.extend_from_slice(&self.set_words(slots.get_world_ref(&self.slot_point(i))));
+            }
+            self.queue
+                .write_buffer(&self.slot_buffer, 0, bytemuck::cast_slice(&words));
+            self.needs_upload = false;
+        } else {
+            for slot in changed.iter() {
+                let words = self.set_words(slots.get_world_ref(slot));
+                let offset = (self.slot_index(slot) * self.num_words * 4) as u64;
+                self.queue
+                    .write_buffer(&self.slot_buffer, offset, bytemuck::cast_slice(&words));
+            }
+        }
+
+        loop {
+            self.queue
+                .write_buffer(&self.status_buffer, 0, bytemuck::cast_slice(&[0u32; 2]));
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            {
+                let mut pass =
+                    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+                pass.set_pipeline(&self.pipeline);
+                pass.set_bind_group(0, &self.bind_group, &[]);
+                for _ in 0..PASSES_PER_CHECK {
+                    pass.dispatch(self.workgroups.0, self.workgroups.1, 1);
+                }
+            }
+            encoder.copy_buffer_to_buffer(&self.status_buffer, 0, &self.status_readback, 0, 8);
+            self.queue.submit(Some(encoder.finish()));
+
+            let status = self.read(&self.status_readback);
+            let (changed, emptied) = (status[0] != 0, status[1] != 0);
+            if !changed || emptied {
+                break;
+            }
+        }
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(
+            &self.slot_buffer,
+            0,
+            &self.slot_readback,
+            0,
+            self.slot_readback.size(),
+        );
+        self.queue.submit(Some(encoder.finish()));
+        let words = self.read(&self.slot_readback);
+
+        let mut removed = Vec::new();
+        for (i, slot_words) in words.chunks(self.num_words).enumerate() {
+            let slot = self.slot_point(i);
+            removed.extend(
+                slots
+                    .get_world_ref(&slot)
+                    .iter()
+                    .filter(|p| !has_pattern(slot_words, *p))
+                    .map(|p| (slot, p)),
+            );
+        }
+
+        removed
+    }
+
+    fn read(&self, buffer: &wgpu::Buffer) -> Vec<u32> {
+        let slice = buffer.slice(..);
+        let mapped = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        pollster::block_on(mapped).expect("Failed to read from the GPU");
+        let words = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        buffer.unmap();
+
+        words
+    }
+
+    fn set_words(&self, set: &PatternSet) -> Vec<u32> {
+        let mut words = vec![0; self.num_words];
+        for pattern in set.iter() {
+            add_pattern(&mut words, pattern);
+        }
+
+        words
+    }
+
+    fn slot_index(&self, slot: &lat::Point) -> usize {
+        ((slot.z * self.size.y + slot.y) * self.size.x + slot.x) as usize
+    }
+
+    fn slot_point(&self, index: usize) -> lat::Point {
+        let index = index as i32;
+
+        [
+            index % self.size.x,
+            (index / self.size.x) % self.size.y,
+            index / (self.size.x * self.size.y),
+        ]
+        .into()
+    }
+}
+
+fn add_pattern(words: &mut [u32], pattern: PatternId) {
+    words[pattern.0 as usize / 32] |= 1 << (pattern.0 % 32);
+}
+
+fn has_pattern(words: &[u32], pattern: PatternId) -> bool {
+    words[pattern.0 as usize / 32] & (1 << (pattern.0 % 32)) != 0
+}
@@ -0,0 +1,126 @@
+// One pass of arc consistency: each invocation removes the patterns of one slot that have no
+// compatible pattern in some neighboring slot. Patterns are only ever removed, so reading a
+// neighbor while another invocation updates it can only remove more, and repeated passes still
+// converge to the same result as propagating on the CPU.
+
+[[block]]
+struct Params {
+    size_x: u32;
+    size_y: u32;
+    size_z: u32;
+    // The number of u32s in each slot's pattern set.
+    num_words: u32;
+    num_offsets: u32;
+    // Invocations per row of the 2D dispatch.
+    row_length: u32;
+    padding0: u32;
+    padding1: u32;
+};
+
+[[block]]
+struct Words {
+    data: [[stride(4)]] array<u32>;
+};
+
+[[block]]
+struct Offsets {
+    data: [[stride(4)]] array<i32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> params: Params;
+
+// The pattern set of each slot, with X varying fastest, then Y, then Z.
+[[group(0), binding(1)]]
+var<storage> slots: [[access(read_write)]] Words;
+
+// For each pattern and offset, the set of patterns allowed at that offset.
+[[group(0), binding(2)]]
+var<storage> compatible: [[access(read)]] Words;
+
+// X, Y, and Z of each offset.
+[[group(0), binding(3)]]
+var<storage> offsets: [[access(read)]] Offsets;
+
+// [0] is set to 1 when any pattern is removed. [1] is set to one more than the index of a slot
+// that was emptied.
+[[group(0), binding(4)]]
+var<storage> status: [[access(read_write)]] Words;
+
+fn is_supported(x: u32, y: u32, z: u32, pattern: u32) -> bool {
+    var offset: u32 = 0u;
+    loop {
+        if (offset >= params.num_offsets) {
+            break;
+        }
+        let nx: i32 = i32(x) + offsets.data[3u * offset];
+        let ny: i32 = i32(y) + offsets.data[3u * offset + 1u];
+        let nz: i32 = i32(z) + offsets.data[3u * offset + 2u];
+        let inside: bool = nx >= 0 && ny >= 0 && nz >= 0
+            && nx < i32(params.size_x) && ny < i32(params.size_y) && nz < i32(params.size_z);
+        if (inside) {
+            let neighbor: u32 = (u32(nz) * params.size_y + u32(ny)) * params.size_x + u32(nx);
+            let allowed: u32 = (pattern * params.num_offsets + offset) * params.num_words;
+            var found: bool = false;
+            var word: u32 = 0u;
+            loop {
+                if (word >= params.num_words) {
+                    break;
+                }
+                let possible: u32 = slots.data[neighbor * params.num_words + word];
+                if ((possible & compatible.data[allowed + word]) != 0u) {
+                    found = true;
+                    break;
+                }
+                word = word + 1u;
+            }
+            if (!found) {
+                return false;
+            }
+        }
+        offset = offset + 1u;
+    }
+
+    return true;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn main([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let slot: u32 = id.y * params.row_length + id.x;
+    if (slot >= params.size_x * params.size_y * params.size_z) {
+        return;
+    }
+    let x: u32 = slot % params.size_x;
+    let y: u32 = (slot / params.size_x) % params.size_y;
+    let z: u32 = slot / (params.size_x * params.size_y);
+
+    var remaining: u32 = 0u;
+    var word: u32 = 0u;
+    loop {
+        if (word >= params.num_words) {
+            break;
+        }
+        let index: u32 = slot * params.num_words + word;
+        let old: u32 = slots.data[index];
+        var kept: u32 = old;
+        var bit: u32 = 0u;
+        loop {
+            if (bit >= 32u) {
+                break;
+            }
+            if ((old & (1u << bit)) != 0u && !is_supported(x, y, z, word * 32u + bit)) {
+                kept = kept & ~(1u << bit);
+            }
+            bit = bit + 1u;
+        }
+        if (kept != old) {
+            slots.data[index] = kept;
+            status.data[0] = 1u;
+        }
+        remaining = remaining | kept;
+        word = word + 1u;
+    }
+    if (remaining == 0u) {
+        status.data[1] = slot + 1u;
+    }
+}
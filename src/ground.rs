@@ -0,0 +1,105 @@
+//! Keeping the ground of terrain-like outputs on the bottom layer.
+
+use crate::{
+    error::WfcError,
+    generate::{Generator, UpdateResult},
+    pattern::{
+        index_patterns, InputBoundary, PatternConstraints, PatternId, PatternIndex, PatternSampler,
+        PatternSet, PatternShape,
+    },
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, PeriodicYLevelsIndexer, VecLatticeMap};
+use std::hash::Hash;
+
+/// Restricts the bottom Z layer of the output to the patterns that were observed on the bottom
+/// layer of the input. Without this, inputs like terrain can generate floating chunks of ground.
+pub struct GroundConstraint {
+    /// Patterns allowed in the bottom layer of slots.
+    pub ground_patterns: PatternSet,
+    /// Patterns allowed above the bottom layer. If `None`, any pattern is allowed there.
+    pub upper_patterns: Option<PatternSet>,
+}
+
+impl GroundConstraint {
+    /// Finds the patterns on the bottom layer of `input_lattice`. The input must be processed with
+    /// the same `tile_size` and `pattern_shape` as the model, so the `PatternId`s match, and the
+    /// shape's boundary must be periodic, since other boundaries renumber the patterns.
+    ///
+    /// If `exclusive`, patterns that only occur on the bottom layer of the input are also barred
+    /// from appearing higher up in the output.
+    pub fn from_input<T>(
+        input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+        tile_size: &lat::Point,
+        pattern_shape: &PatternShape,
+        exclusive: bool,
+    ) -> Result<Self, WfcError>
+    where
+        T: Clone + Copy + std::fmt::Debug + Eq + Hash,
+    {
+        if pattern_shape.boundary != InputBoundary::Periodic {
+            return Err(WfcError::InvalidConfig(
+                "The ground constraint requires a periodic input boundary".to_string(),
+            ));
+        }
+        let (pattern_lattice, first_occurrences) = index_patterns(
+            input_lattice,
+            tile_size,
+            &pattern_shape.size,
+            pattern_shape.boundary,
+        )?;
+        let num_patterns = first_occurrences.len() as PatternIndex;
+
+        let mut ground_patterns = PatternSet::all(num_patterns);
+        let mut upper_patterns = PatternSet::all(num_patterns);
+        let mut seen_on_ground = vec![false; num_patterns as usize];
+        let mut seen_above = vec![false; num_patterns as usize];
+        for point in pattern_lattice.get_extent() {
+            let pattern = pattern_lattice.get_local(&point);
+            if point.z == 0 {
+                seen_on_ground[pattern.0 as usize] = true;
+            } else {
+                seen_above[pattern.0 as usize] = true;
+            }
+        }
+        for (i, (ground, above)) in seen_on_ground.iter().zip(seen_above.iter()).enumerate() {
+            let pattern = PatternId(i as PatternIndex);
+            if !ground {
+                ground_patterns.remove(pattern);
+            }
+            if !above {
+                upper_patterns.remove(pattern);
+            }
+        }
+
+        Ok(GroundConstraint {
+            ground_patterns,
+            upper_patterns: if exclusive {
+                Some(upper_patterns)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Restricts the slots of `generator` and propagates. Meant to be called before the first
+    /// `update`.
+    pub fn apply(
+        &self,
+        generator: &mut Generator,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> UpdateResult {
+        let wave_extent = generator.get_wave_lattice().get_extent();
+        let restrictions = wave_extent.into_iter().filter_map(|slot| {
+            if slot.z == wave_extent.get_minimum().z {
+                Some((slot, &self.ground_patterns))
+            } else {
+                self.upper_patterns.as_ref().map(|upper| (slot, upper))
+            }
+        });
+
+        generator.restrict_slots(sampler, constraints, restrictions)
+    }
+}
@@ -0,0 +1,32 @@
+//! Spatially varying pattern weights.
+
+use crate::pattern::{PatternId, PatternMap};
+
+use ilattice3 as lat;
+use ilattice3::{GetWorldRef, VecLatticeMap};
+
+/// Multiplies the weight of each pattern, depending on the slot it would go in. This guides
+/// generation without forbidding anything, e.g. to make "sky" patterns more likely near the top of
+/// the output.
+///
+/// Multipliers must be positive and finite; the generator checks them all when it's created. To
+/// rule patterns out entirely, use `Generator::constrain_slot`.
+pub trait WeightField: Send + Sync {
+    fn weight_multiplier(&self, slot: &lat::Point, pattern: PatternId) -> f32;
+}
+
+impl<F> WeightField for F
+where
+    F: Fn(&lat::Point, PatternId) -> f32 + Send + Sync,
+{
+    fn weight_multiplier(&self, slot: &lat::Point, pattern: PatternId) -> f32 {
+        self(slot, pattern)
+    }
+}
+
+/// A multiplier for every pattern at every slot. The extent must cover the output.
+impl WeightField for VecLatticeMap<PatternMap<f32>> {
+    fn weight_multiplier(&self, slot: &lat::Point, pattern: PatternId) -> f32 {
+        *self.get_world_ref(slot).get(pattern)
+    }
+}
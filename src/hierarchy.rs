@@ -0,0 +1,167 @@
+//! Generating the large-scale structure of the output first, then filling in the details.
+
+use crate::{
+    error::WfcError,
+    generate::{Generator, GeneratorConfig, NUM_SEED_BYTES},
+    model::Model,
+    pattern::{
+        InputBoundary, PatternConstraints, PatternId, PatternMap, PatternSampler, PatternSet,
+        PatternShape,
+    },
+    trainer::ModelTrainer,
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, PeriodicYLevelsIndexer, VecLatticeMap};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// Links a coarse model, trained on a downsampled input, to the full-resolution model of the same
+/// input. A single overlapping pass can't see structures much larger than a pattern, so generate a
+/// small output with the coarse model first, then let each coarse slot restrict the fine slots
+/// under it to the fine patterns that were found within the same coarse pattern in the input.
+pub struct Refinement {
+    /// The number of fine slots under each coarse slot along each axis.
+    pub scale: lat::Point,
+    /// The fine patterns allowed under each coarse pattern, i.e. those found anywhere in its
+    /// window.
+    pub allowed: PatternMap<PatternSet>,
+}
+
+impl Refinement {
+    /// Trains the coarse model on `input` downsampled by `scale`, and learns the fine patterns
+    /// under each coarse pattern. The fine `PatternId`s are those of a `ModelTrainer` whose first
+    /// example is `input`, with the same `tile_size` and `pattern_shape`. The boundary must be
+    /// periodic, so the IDs aren't renumbered.
+    pub fn learn<T>(
+        input: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+        tile_size: lat::Point,
+        pattern_shape: &PatternShape,
+        scale: lat::Point,
+    ) -> Result<(Model<T>, Self), WfcError>
+    where
+        T: Clone + Copy + std::fmt::Debug + Eq + Hash,
+    {
+        if pattern_shape.boundary != InputBoundary::Periodic {
+            return Err(WfcError::InvalidConfig(
+                "Hierarchical generation requires a periodic input boundary".to_string(),
+            ));
+        }
+
+        let coarse_input = downsample(input, scale)?;
+        let mut coarse_trainer = ModelTrainer::new(tile_size, pattern_shape.clone())?;
+        let coarse_patterns = coarse_trainer.add_example_with_patterns(&coarse_input)?;
+        let mut fine_trainer = ModelTrainer::new(tile_size, pattern_shape.clone())?;
+        let fine_patterns = fine_trainer.add_example_with_patterns(input)?;
+
+        let mut allowed =
+            PatternMap::fill(PatternSet::empty(), coarse_trainer.num_patterns() as usize);
+        // A coarse pattern describes its whole window, so it allows every fine pattern in there.
+        // The input is periodic, so windows wrap around.
+        let window =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), pattern_shape.size * scale);
+        for coarse_point in coarse_patterns.get_extent() {
+            let coarse_pattern = allowed.get_mut(coarse_patterns.get_local(&coarse_point));
+            for window_point in window {
+                let fine_point = coarse_point * scale + window_point;
+                coarse_pattern.insert(fine_patterns.get_local(&fine_point));
+            }
+        }
+
+        Ok((coarse_trainer.finish()?, Refinement { scale, allowed }))
+    }
+
+    /// The patterns that each fine slot may start with, given the output of the coarse model. The
+    /// fine output is `scale` times the size of the coarse output.
+    pub fn initial_patterns(
+        &self,
+        coarse_result: &VecLatticeMap<PatternId>,
+    ) -> VecLatticeMap<PatternSet> {
+        let coarse_size = *coarse_result.get_extent().get_local_supremum();
+        let fine_extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), coarse_size * self.scale);
+        let mut initial = VecLatticeMap::fill(fine_extent, PatternSet::empty());
+        for fine_point in fine_extent {
+            let coarse_pattern = coarse_result.get_local(&divide(fine_point, self.scale));
+            *initial.get_world_ref_mut(&fine_point) = self.allowed.get(coarse_pattern).clone();
+        }
+
+        initial
+    }
+
+    /// A generator for the fine pass, which starts from `initial_patterns`. Other options come
+    /// from `config`. Returns an error iff `config` is invalid for the fine output.
+    pub fn fine_generator(
+        &self,
+        seed: [u8; NUM_SEED_BYTES],
+        coarse_result: &VecLatticeMap<PatternId>,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        config: &GeneratorConfig,
+    ) -> Result<Generator, WfcError> {
+        let initial_patterns = self.initial_patterns(coarse_result);
+        let output_size = *initial_patterns.get_extent().get_local_supremum();
+        let config = GeneratorConfig {
+            initial_patterns: Some(Arc::new(initial_patterns)),
+            ..config.clone()
+        };
+
+        Generator::with_config(seed, output_size, sampler, constraints, &config)
+    }
+}
+
+/// Shrinks `input` by `scale` along each axis, keeping the most common voxel in each block. Ties go
+/// to the voxel that reached the count first. Blocks that hang over the maximum edge of the input
+/// only count the voxels inside it.
+pub fn downsample<T>(
+    input: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    scale: lat::Point,
+) -> Result<VecLatticeMap<T, PeriodicYLevelsIndexer>, WfcError>
+where
+    T: Clone + Copy + Eq + Hash,
+{
+    let input_size = *input.get_extent().get_local_supremum();
+    let [sx, sy, sz]: [i32; 3] = scale.into();
+    if sx <= 0 || sy <= 0 || sz <= 0 {
+        return Err(WfcError::InvalidConfig(format!(
+            "Scale {} must be positive",
+            scale
+        )));
+    }
+    let coarse_size = input_size.div_ceil(&scale);
+    let input_local_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), input_size);
+    let coarse_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), coarse_size);
+    let block_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), scale);
+    let mut coarse = VecLatticeMap::<_, PeriodicYLevelsIndexer>::fill(
+        coarse_extent,
+        input.get_local(&[0, 0, 0].into()),
+    );
+    let mut counts = HashMap::new();
+    for coarse_point in coarse_extent {
+        counts.clear();
+        let mut most_common = None;
+        let mut max_count = 0;
+        for block_point in block_extent {
+            let fine_point = coarse_point * scale + block_point;
+            if !input_local_extent.contains_world(&fine_point) {
+                continue;
+            }
+            let voxel = input.get_local(&fine_point);
+            let count = counts.entry(voxel).or_insert(0);
+            *count += 1;
+            if *count > max_count {
+                max_count = *count;
+                most_common = Some(voxel);
+            }
+        }
+        *coarse.get_local_ref_mut(&coarse_point) = most_common.unwrap();
+    }
+
+    Ok(coarse)
+}
+
+/// Componentwise division, rounding down. Both points are non-negative.
+fn divide(p: lat::Point, divisor: lat::Point) -> lat::Point {
+    [p.x / divisor.x, p.y / divisor.y, p.z / divisor.z].into()
+}
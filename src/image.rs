@@ -1,15 +1,22 @@
 //! Utilities for using images. Mostly for testing the algorithms on 2D images.
 
 use crate::{
-    pattern::{PatternId, PatternSet, PatternTileSet, TileSet},
-    CliError, FrameConsumer,
+    apng::ApngEncoder,
+    pattern::{PatternId, PatternIndex, PatternSet, PatternTileSet, TileSet},
+    webp::WebpEncoder,
+    FrameConsumer,
 };
 
 use ilattice3 as lat;
-use ilattice3::{copy_extent, prelude::*, Indexer, Tile, VecLatticeMap, VoxColor, EMPTY_VOX_COLOR};
-use image::{self, gif, Delay, Frame, Rgba, RgbaImage};
+use ilattice3::{copy_extent, prelude::*, Indexer, VecLatticeMap, VoxColor, EMPTY_VOX_COLOR};
+use image::{
+    self, gif::GifEncoder, Delay, Frame, GrayImage, ImageError, ImageResult, Rgba, RgbaImage,
+};
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 pub fn make_palette_lattice<T: Clone, I: Clone + Indexer>(
     tiles: &TileSet<T, I>,
@@ -46,40 +53,219 @@ pub fn color_superposition<I: Clone + Indexer>(
     pattern_lattice: &VecLatticeMap<PatternSet>,
     tiles: &PatternTileSet<Rgba<u8>, I>,
 ) -> VecLatticeMap<Rgba<u8>> {
-    let PatternTileSet { tiles, tile_size } = tiles;
+    color_superposition_with(pattern_lattice, tiles, |c| *c)
+}
 
-    let full_size = *pattern_lattice.get_extent().get_local_supremum() * *tile_size;
+/// Like `color_superposition`, but for VOX tiles, which are looked up in `palette` (as stored in a
+/// VOX file). Empty voxels are transparent.
+pub fn color_superposition_vox<I: Clone + Indexer>(
+    pattern_lattice: &VecLatticeMap<PatternSet>,
+    tiles: &PatternTileSet<VoxColor, I>,
+    palette: &[u32],
+) -> VecLatticeMap<Rgba<u8>> {
+    color_superposition_with(pattern_lattice, tiles, |c| {
+        if *c == EMPTY_VOX_COLOR {
+            Rgba([0; 4])
+        } else {
+            Rgba(palette[*c as usize].to_le_bytes())
+        }
+    })
+}
+
+fn color_superposition_with<C, I, F>(
+    pattern_lattice: &VecLatticeMap<PatternSet>,
+    tiles: &PatternTileSet<C, I>,
+    to_rgba: F,
+) -> VecLatticeMap<Rgba<u8>>
+where
+    C: Clone,
+    I: Clone + Indexer,
+    F: Fn(&C) -> Rgba<u8>,
+{
+    let colors = TileColors::new(tiles, to_rgba);
+    let full_size = *pattern_lattice.get_extent().get_local_supremum() * tiles.tile_size;
     let full_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), full_size);
 
     let mut color_lattice = VecLatticeMap::fill(full_extent, Rgba([0; 4]));
-    for pattern_p in pattern_lattice.get_extent() {
-        let output_extent =
-            lat::Extent::from_min_and_local_supremum(pattern_p * *tile_size, *tile_size);
-        for p in output_extent {
-            let mut num_patterns = 0;
-            let patterns = pattern_lattice.get_world(&pattern_p);
-            let mut color_sum = [0.0; 4];
-            for pattern in patterns.iter() {
-                num_patterns += 1;
-                let tile: Tile<_, _> = tiles.get(pattern).clone();
-                let tile = tile.put_in_extent(output_extent);
-                let Rgba(p_color) = tile.get_world(&p);
+    for slot in pattern_lattice.get_extent() {
+        colors.color_slot(
+            pattern_lattice.get_world_ref(&slot),
+            &slot,
+            &mut color_lattice,
+        );
+    }
+
+    color_lattice
+}
+
+/// The color of each voxel of each pattern's tile, and their sums over every pattern. The mean
+/// color of a slot is summed over its possible patterns, or subtracted from the total over the
+/// impossible ones, whichever are fewer.
+struct TileColors {
+    tile_size: lat::Point,
+    num_voxels: usize,
+    num_patterns: usize,
+    /// By pattern, then voxel, in the order of the tile's extent.
+    colors: Vec<[u32; 4]>,
+    /// By voxel.
+    totals: Vec<[u32; 4]>,
+}
+
+impl TileColors {
+    fn new<C, I, F>(tiles: &PatternTileSet<C, I>, to_rgba: F) -> Self
+    where
+        C: Clone,
+        I: Clone + Indexer,
+        F: Fn(&C) -> Rgba<u8>,
+    {
+        let PatternTileSet { tiles, tile_size } = tiles;
+        let tile_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), *tile_size);
+        let num_voxels = tile_extent.volume();
+        let num_patterns = tiles.num_elements();
+
+        let mut colors = Vec::with_capacity(num_patterns * num_voxels);
+        let mut totals = vec![[0; 4]; num_voxels];
+        for (_, tile) in tiles.iter() {
+            let tile = tile.clone().put_in_extent(tile_extent);
+            for (p, total) in tile_extent.into_iter().zip(totals.iter_mut()) {
+                let Rgba(color) = to_rgba(&tile.get_world(&p));
+                let color = [
+                    color[0] as u32,
+                    color[1] as u32,
+                    color[2] as u32,
+                    color[3] as u32,
+                ];
                 for i in 0..4 {
-                    color_sum[i] += p_color[i] as f32;
+                    total[i] += color[i];
                 }
+                colors.push(color);
+            }
+        }
+
+        TileColors {
+            tile_size: *tile_size,
+            num_voxels,
+            num_patterns,
+            colors,
+            totals,
+        }
+    }
+
+    /// Colors the tile of `slot` in `color_lattice` with the mean of `patterns`.
+    fn color_slot(
+        &self,
+        patterns: &PatternSet,
+        slot: &lat::Point,
+        color_lattice: &mut VecLatticeMap<Rgba<u8>>,
+    ) {
+        let num_possible = patterns.len() as usize;
+        let mut sums = vec![[0; 4]; self.num_voxels];
+        if 2 * num_possible <= self.num_patterns {
+            for pattern in patterns.iter() {
+                self.add_pattern(pattern, &mut sums, |sum, color| *sum += color);
+            }
+        } else {
+            sums.copy_from_slice(&self.totals);
+            let impossible = patterns.complement(self.num_patterns as PatternIndex);
+            for pattern in impossible.iter() {
+                self.add_pattern(pattern, &mut sums, |sum, color| *sum -= color);
             }
+        }
+
+        let output_extent =
+            lat::Extent::from_min_and_local_supremum(*slot * self.tile_size, self.tile_size);
+        for (p, sum) in output_extent.into_iter().zip(sums.iter()) {
             let mut mean_color = [0; 4];
-            for i in 0..4 {
-                mean_color[i] = (color_sum[i] / num_patterns as f32).floor() as u8;
+            if num_possible > 0 {
+                for i in 0..4 {
+                    mean_color[i] = (sum[i] / num_possible as u32) as u8;
+                }
             }
             *color_lattice.get_local_ref_mut(&p) = Rgba(mean_color);
         }
     }
 
-    color_lattice
+    fn add_pattern<F: Fn(&mut u32, u32)>(&self, pattern: PatternId, sums: &mut [[u32; 4]], f: F) {
+        let start = pattern.0 as usize * self.num_voxels;
+        for (sum, color) in sums
+            .iter_mut()
+            .zip(&self.colors[start..start + self.num_voxels])
+        {
+            for i in 0..4 {
+                f(&mut sum[i], color[i]);
+            }
+        }
+    }
 }
 
-fn color_final_patterns<C, I: Clone + Indexer>(
+/// Like `color_superposition`, but keeps the image between frames, so only the slots that changed
+/// are drawn again.
+pub struct SuperpositionRenderer<I> {
+    pattern_tiles: PatternTileSet<Rgba<u8>, I>,
+    /// Computed on the first `render`.
+    colors: Option<TileColors>,
+    frame: Option<VecLatticeMap<Rgba<u8>>>,
+}
+
+impl<I> SuperpositionRenderer<I> {
+    pub fn new(pattern_tiles: PatternTileSet<Rgba<u8>, I>) -> Self {
+        SuperpositionRenderer {
+            pattern_tiles,
+            colors: None,
+            frame: None,
+        }
+    }
+
+    pub fn tile_size(&self) -> lat::Point {
+        self.pattern_tiles.tile_size
+    }
+
+    /// Draws every slot on the next `render`, e.g. when the changed slots aren't known.
+    pub fn invalidate(&mut self) {
+        self.frame = None;
+    }
+}
+
+impl<I: Clone + Indexer> SuperpositionRenderer<I> {
+    /// Draws `changed_slots` over the previous frame, e.g. from `Generator::changed_slots_since`.
+    /// The first frame, and any after `invalidate` or a change of size, draws every slot.
+    pub fn render(
+        &mut self,
+        pattern_lattice: &VecLatticeMap<PatternSet>,
+        changed_slots: &[lat::Point],
+    ) -> &VecLatticeMap<Rgba<u8>> {
+        let pattern_tiles = &self.pattern_tiles;
+        let colors = self
+            .colors
+            .get_or_insert_with(|| TileColors::new(pattern_tiles, |c| *c));
+
+        let full_size = *pattern_lattice.get_extent().get_local_supremum() * colors.tile_size;
+        let reuse = self
+            .frame
+            .as_ref()
+            .is_some_and(|frame| *frame.get_extent().get_local_supremum() == full_size);
+        let frame = if reuse {
+            let frame = self.frame.as_mut().unwrap();
+            for slot in changed_slots.iter() {
+                colors.color_slot(pattern_lattice.get_world_ref(slot), slot, frame);
+            }
+
+            frame
+        } else {
+            let full_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), full_size);
+            let mut frame = VecLatticeMap::fill(full_extent, Rgba([0; 4]));
+            for slot in pattern_lattice.get_extent() {
+                colors.color_slot(pattern_lattice.get_world_ref(&slot), &slot, &mut frame);
+            }
+
+            self.frame.insert(frame)
+        };
+
+        frame
+    }
+}
+
+pub(crate) fn color_final_patterns<C, I: Clone + Indexer>(
     pattern_lattice: &VecLatticeMap<PatternId>,
     tiles: &PatternTileSet<C, I>,
     fill_value: C,
@@ -117,51 +303,399 @@ pub fn color_final_patterns_vox<I: Clone + Indexer>(
     color_final_patterns(pattern_lattice, tiles, EMPTY_VOX_COLOR)
 }
 
+/// Colors the slots that have collapsed to a single pattern, leaving the rest empty, so a 3D
+/// output can be watched as it's built up.
+pub fn color_collapsed_patterns_vox<I: Clone + Indexer>(
+    pattern_lattice: &VecLatticeMap<PatternSet>,
+    tiles: &PatternTileSet<VoxColor, I>,
+) -> VecLatticeMap<VoxColor> {
+    let PatternTileSet { tiles, tile_size } = tiles;
+
+    let full_size = *pattern_lattice.get_extent().get_local_supremum() * *tile_size;
+    let full_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), full_size);
+
+    let mut color_lattice = VecLatticeMap::fill(full_extent, EMPTY_VOX_COLOR);
+    for p in pattern_lattice.get_extent() {
+        let patterns = pattern_lattice.get_world_ref(&p);
+        if patterns.len() != 1 {
+            continue;
+        }
+        let pattern = patterns.iter().next().unwrap();
+        let output_extent = lat::Extent::from_min_and_local_supremum(p * *tile_size, *tile_size);
+        let tile = tiles.get(pattern).clone().put_in_extent(output_extent);
+        copy_extent(&tile, &mut color_lattice, &output_extent);
+    }
+
+    color_lattice
+}
+
+/// Renders the entropy of each slot (see `Generator::get_entropy_lattice`) as a heatmap, from blue
+/// at zero through green to red at `max_entropy`, to show where the generator is most and least
+/// constrained. Collapsed slots are transparent. For comparable frames, use a fixed `max_entropy`,
+/// like the log2 of the number of patterns.
+pub fn color_entropy(entropy: &VecLatticeMap<f32>, max_entropy: f32) -> VecLatticeMap<Rgba<u8>> {
+    entropy.map(|e| {
+        if e.is_infinite() {
+            Rgba([0; 4])
+        } else {
+            heat_color(*e / max_entropy)
+        }
+    })
+}
+
+/// Blends the tiles of `slots` halfway to `tint`. Tiles are tinted once, even if repeated.
+pub fn tint_slots(
+    colors: &mut VecLatticeMap<Rgba<u8>>,
+    slots: &[lat::Point],
+    tile_size: lat::Point,
+    tint: Rgba<u8>,
+) {
+    let mut slots = slots.to_vec();
+    slots.sort_by_key(|p| (p.z, p.y, p.x));
+    slots.dedup();
+    for slot in slots.into_iter() {
+        let tile_extent = lat::Extent::from_min_and_local_supremum(slot * tile_size, tile_size);
+        for p in tile_extent {
+            let color = colors.get_world_ref_mut(&p);
+            for c in 0..4 {
+                color.0[c] = ((color.0[c] as u16 + tint.0[c] as u16) / 2) as u8;
+            }
+        }
+    }
+}
+
+/// Interpolates blue, cyan, green, yellow, and red over `[0, 1]`.
+fn heat_color(t: f32) -> Rgba<u8> {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 255.0],
+        [0.0, 255.0, 255.0],
+        [0.0, 255.0, 0.0],
+        [255.0, 255.0, 0.0],
+        [255.0, 0.0, 0.0],
+    ];
+
+    let x = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (x as usize).min(STOPS.len() - 2);
+    let f = x - i as f32;
+    let channel = |c: usize| (STOPS[i][c] + f * (STOPS[i + 1][c] - STOPS[i][c])).round() as u8;
+
+    Rgba([channel(0), channel(1), channel(2), 255])
+}
+
+/// Voxelizes a grayscale heightmap into terrain, so it can be used as a 3D example. The column at
+/// each pixel is filled with `color` from Z = 0 up to the pixel's brightness, scaled so white is
+/// `max_height` voxels tall, and the rest is empty.
+pub fn voxelize_heightmap<I: Indexer>(
+    heightmap: &GrayImage,
+    max_height: i32,
+    color: VoxColor,
+) -> VecLatticeMap<VoxColor, I> {
+    let (width, height) = heightmap.dimensions();
+    let extent = lat::Extent::from_min_and_local_supremum(
+        [0, 0, 0].into(),
+        [width as i32, height as i32, max_height].into(),
+    );
+
+    let mut lattice = VecLatticeMap::fill(extent, EMPTY_VOX_COLOR);
+    for (x, y, pixel) in heightmap.enumerate_pixels() {
+        let column_height = (pixel.0[0] as i32 * max_height + 127) / 255;
+        for z in 0..column_height {
+            *lattice.get_local_ref_mut(&[x as i32, y as i32, z].into()) = color;
+        }
+    }
+
+    lattice
+}
+
+/// Lays the Z slices of `lattice` side by side along X, separated by a column of `background`, so
+/// a 3D lattice can be viewed as an image.
+pub fn slice_montage<T: Clone>(lattice: &VecLatticeMap<T>, background: T) -> VecLatticeMap<T> {
+    let extent = lattice.get_extent();
+    let size = *extent.get_local_supremum();
+    let montage_size = [size.x * size.z + size.z - 1, size.y, 1].into();
+    let montage_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), montage_size);
+
+    let mut montage = VecLatticeMap::fill(montage_extent, background);
+    for p in extent {
+        let local_p = p - *extent.get_minimum();
+        let dst = [local_p.z * (size.x + 1) + local_p.x, local_p.y, 0].into();
+        *montage.get_local_ref_mut(&dst) = lattice.get_world_ref(&p).clone();
+    }
+
+    montage
+}
+
+/// The smallest `skip_frames` for which a GIF of the whole generation has at most `max_frames`
+/// frames. Every update collapses at least one slot, so there are at most `num_slots / skip_frames`
+/// frames.
+pub fn skip_frames_for_max_frames(output_size: lat::Point, max_frames: usize) -> usize {
+    let num_slots =
+        lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), output_size).volume();
+
+    num_slots.div_ceil(max_frames.max(1)).max(1)
+}
+
+/// Limits on the size of the animation written by a `GifMaker`. Once either limit is reached, the
+/// remaining frames are dropped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GifLimits {
+    pub max_frames: Option<usize>,
+    /// The file may exceed this by at most one frame. The first frame is always kept.
+    pub max_bytes: Option<u64>,
+}
+
+/// The file formats a `GifMaker` can write. GIF is limited to 256 colors per frame, which blurs
+/// the gradients of a superposition, while APNG and WebP frames are lossless.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+    Webp,
+}
+
+/// How long each APNG or WebP frame is shown. Most viewers slow down anything shorter.
+const FRAME_DELAY_MS: u16 = 10;
+
+enum AnimationEncoder<W: Write + Seek> {
+    Gif(GifEncoder<W>),
+    Apng(ApngEncoder<W>),
+    Webp(WebpEncoder<W>),
+}
+
+/// Writes an animation of the superposition, encoding each frame to the file as soon as it
+/// arrives, so memory use doesn't grow with the length of the run.
 pub struct GifMaker<I> {
     path: PathBuf,
-    pattern_tiles: PatternTileSet<Rgba<u8>, I>,
-    frames: Vec<Frame>,
+    renderer: SuperpositionRenderer<I>,
+    /// The slots that changed since the last frame, unless some frames came without them.
+    changed_slots: Option<Vec<lat::Point>>,
+    encoder: AnimationEncoder<CountingWriter<BufWriter<File>>>,
+    bytes_written: Arc<AtomicU64>,
+    limits: GifLimits,
+    num_frames: usize,
+    num_dropped_frames: usize,
     num_updates: usize,
     skip_frames: usize,
+    /// The first encoding error, reported by `save`.
+    error: Option<ImageError>,
+    /// The tint of slots that lost patterns since the last frame, if they're highlighted.
+    highlight: Option<Rgba<u8>>,
+    /// The slots that lost patterns since the last frame.
+    modified_slots: Vec<lat::Point>,
 }
 
 impl<I: Clone + Indexer> FrameConsumer for GifMaker<I> {
     fn use_frame(&mut self, slots: &VecLatticeMap<PatternSet>) {
-        if self.num_updates % self.skip_frames == 0 {
-            let superposition = color_superposition(slots, &self.pattern_tiles);
-            let superposition_img: RgbaImage = (&superposition).into();
-            self.frames.push(Frame::from_parts(
-                superposition_img,
-                0,
-                0,
-                Delay::from_numer_denom_ms(1, 1),
-            ));
+        self.use_frame_with_modified(slots, &[]);
+    }
+
+    fn use_frame_with_modified(
+        &mut self,
+        slots: &VecLatticeMap<PatternSet>,
+        modified_slots: &[lat::Point],
+    ) {
+        // Without the changed slots, the next frame is drawn from scratch.
+        self.changed_slots = None;
+        self.add_frame(slots, modified_slots);
+    }
+
+    fn use_frame_with_changes(
+        &mut self,
+        slots: &VecLatticeMap<PatternSet>,
+        modified_slots: &[lat::Point],
+        changed_slots: &[lat::Point],
+    ) {
+        if let Some(changed) = &mut self.changed_slots {
+            changed.extend_from_slice(changed_slots);
         }
-        self.num_updates += 1;
+        self.add_frame(slots, modified_slots);
     }
 }
 
 impl<I: Indexer> GifMaker<I> {
+    /// Creates the file at `path` right away, so a bad path fails before generation starts.
     pub fn new(
         path: PathBuf,
         pattern_tiles: PatternTileSet<Rgba<u8>, I>,
         skip_frames: usize,
-    ) -> Self {
-        GifMaker {
+        limits: GifLimits,
+    ) -> ImageResult<Self> {
+        Self::with_format(
             path,
             pattern_tiles,
-            frames: Vec::new(),
+            skip_frames,
+            limits,
+            AnimationFormat::Gif,
+        )
+    }
+
+    /// Like `new`, but writes an APNG or WebP instead if requested.
+    pub fn with_format(
+        path: PathBuf,
+        pattern_tiles: PatternTileSet<Rgba<u8>, I>,
+        skip_frames: usize,
+        limits: GifLimits,
+        format: AnimationFormat,
+    ) -> ImageResult<Self> {
+        let file_out = File::create(&path)?;
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        let writer = CountingWriter {
+            inner: BufWriter::new(file_out),
+            bytes_written: bytes_written.clone(),
+        };
+        let encoder = match format {
+            AnimationFormat::Gif => AnimationEncoder::Gif(GifEncoder::new(writer)),
+            AnimationFormat::Apng => {
+                AnimationEncoder::Apng(ApngEncoder::new(writer, FRAME_DELAY_MS))
+            }
+            AnimationFormat::Webp => {
+                AnimationEncoder::Webp(WebpEncoder::new(writer, FRAME_DELAY_MS as u32))
+            }
+        };
+
+        Ok(GifMaker {
+            path,
+            renderer: SuperpositionRenderer::new(pattern_tiles),
+            changed_slots: None,
+            encoder,
+            bytes_written,
+            limits,
+            num_frames: 0,
+            num_dropped_frames: 0,
             num_updates: 0,
             skip_frames,
+            error: None,
+            highlight: None,
+            modified_slots: Vec::new(),
+        })
+    }
+
+    fn add_frame(&mut self, slots: &VecLatticeMap<PatternSet>, modified_slots: &[lat::Point])
+    where
+        I: Clone,
+    {
+        if self.highlight.is_some() {
+            self.modified_slots.extend_from_slice(modified_slots);
+        }
+        if self.num_updates % self.skip_frames == 0 {
+            if self.error.is_some() || (self.num_frames > 0 && self.limit_reached()) {
+                self.num_dropped_frames += 1;
+                self.changed_slots = None;
+            } else {
+                let changed = match self.changed_slots.take() {
+                    Some(mut changed) => {
+                        changed.sort_by_key(|p| (p.z, p.y, p.x));
+                        changed.dedup();
+
+                        changed
+                    }
+                    None => {
+                        self.renderer.invalidate();
+
+                        Vec::new()
+                    }
+                };
+                let tile_size = self.renderer.tile_size();
+                let superposition = self.renderer.render(slots, &changed);
+                let superposition_img: RgbaImage = match self.highlight {
+                    Some(highlight) => {
+                        // The tint is only for this frame, so it goes on a copy.
+                        let mut tinted = superposition.clone();
+                        tint_slots(&mut tinted, &self.modified_slots, tile_size, highlight);
+
+                        (&tinted).into()
+                    }
+                    None => superposition.into(),
+                };
+                if let Err(e) = self.encode_frame(superposition_img) {
+                    self.error = Some(e);
+                }
+                self.num_frames += 1;
+                self.changed_slots = Some(Vec::new());
+            }
+            self.modified_slots.clear();
         }
+        self.num_updates += 1;
     }
 
-    pub fn save(self) -> Result<(), CliError> {
-        println!("Writing {:?}", self.path);
-        let file_out = File::create(&self.path)?;
+    /// Tints the slots that lost patterns since the previous frame with `highlight`, to show
+    /// propagation. Only works if frames are given with `use_frame_with_modified`.
+    pub fn set_highlight(&mut self, highlight: Option<Rgba<u8>>) {
+        self.highlight = highlight;
+    }
 
-        gif::Encoder::new(file_out).encode_frames(self.frames.into_iter())?;
+    fn encode_frame(&mut self, img: RgbaImage) -> ImageResult<()> {
+        match &mut self.encoder {
+            AnimationEncoder::Gif(encoder) => encoder.encode_frame(Frame::from_parts(
+                img,
+                0,
+                0,
+                Delay::from_numer_denom_ms(1, 1),
+            )),
+            AnimationEncoder::Apng(encoder) => Ok(encoder.encode_frame(&img)?),
+            AnimationEncoder::Webp(encoder) => Ok(encoder.encode_frame(&img)?),
+        }
+    }
+
+    fn limit_reached(&self) -> bool {
+        let too_many_frames = self
+            .limits
+            .max_frames
+            .is_some_and(|max| self.num_frames >= max);
+        let too_many_bytes = self
+            .limits
+            .max_bytes
+            .is_some_and(|max| self.bytes_written.load(Ordering::Relaxed) >= max);
+
+        too_many_frames || too_many_bytes
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Finishes the file, returning the first error from encoding any frame. Otherwise returns how
+    /// many frames at the end were dropped to stay within the `GifLimits`.
+    pub fn save(self) -> ImageResult<usize> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        match self.encoder {
+            // Dropping the encoder writes the GIF trailer and flushes the file.
+            AnimationEncoder::Gif(encoder) => drop(encoder),
+            AnimationEncoder::Apng(encoder) => {
+                encoder.finish()?;
+            }
+            AnimationEncoder::Webp(encoder) => {
+                encoder.finish()?;
+            }
+        }
+
+        Ok(self.num_dropped_frames)
+    }
+}
+
+/// Counts the bytes that make it to the inner writer.
+struct CountingWriter<W> {
+    inner: W,
+    bytes_written: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-        Ok(())
+impl<W: Seek> Seek for CountingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
     }
 }
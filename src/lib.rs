@@ -1,80 +1,164 @@
 //! Implementation of Max Gumin's "Wave Function Collapse" algorithm for voxel maps.
 
-// TODO: mirror and rotational symmetries
-
 // TODO: backtracking
 // The plan is to keep a log of collapse choices and for each one, a log of removals. Then the
 // remove_pattern operation needs to be made reversible. Then to reverse a collapse, we reverse all
 // of the removals that happened since, then choose a new collapse.
 
-#![feature(map_first_last)]
-
+mod analysis;
+#[cfg(feature = "io")]
+mod apng;
+#[cfg(feature = "io")]
+mod assignment;
+#[cfg(feature = "async")]
+mod async_generate;
+mod batch;
+#[cfg(feature = "bevy")]
+mod bevy_plugin;
+mod chooser;
+mod chunk;
+mod connectivity;
+mod count;
+mod edge;
+mod error;
+mod event;
+mod forensics;
 mod generate;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod ground;
+mod guidance;
+mod hierarchy;
+#[cfg(feature = "io")]
 mod image;
+mod model;
+mod model_file;
 mod offset;
 mod pattern;
+mod progress;
+#[cfg(feature = "io")]
+mod quantize;
+#[cfg(feature = "io")]
+mod schematic;
+mod snapshot;
+mod socket;
 mod static_vec;
+mod strategy;
+#[cfg(feature = "io")]
+mod terminal;
+#[cfg(feature = "io")]
+mod tiled;
+#[cfg(feature = "io")]
+mod tileset_file;
+mod trainer;
+#[cfg(feature = "io")]
+mod vox;
+#[cfg(feature = "wasm")]
+mod wasm;
 mod wave;
+#[cfg(feature = "io")]
+mod webp;
 
+#[cfg(feature = "io")]
 pub use crate::image::{
-    color_final_patterns_rgba, color_final_patterns_vox, color_superposition, make_palette_lattice,
-    GifMaker,
+    color_collapsed_patterns_vox, color_entropy, color_final_patterns_rgba,
+    color_final_patterns_vox, color_superposition, color_superposition_vox, make_palette_lattice,
+    skip_frames_for_max_frames, slice_montage, tint_slots, voxelize_heightmap, AnimationFormat,
+    GifLimits, GifMaker, SuperpositionRenderer,
+};
+pub use analysis::{
+    estimate_generator_memory, summarize_constraints, weight_histogram, write_constraint_dot,
+    ConstraintGraphSummary,
+};
+#[cfg(feature = "io")]
+pub use apng::ApngEncoder;
+#[cfg(feature = "io")]
+pub use assignment::{AssignmentError, AssignmentFormat, PatternAssignment};
+pub use batch::{generate_batch, race_seeds};
+#[cfg(feature = "bevy")]
+pub use bevy_plugin::{voxel_mesh, WfcFinished, WfcGeneration, WfcOutput, WfcPlugin};
+pub use chooser::{ArgmaxChooser, PatternChooser, TemperatureChooser, WeightedChooser};
+pub use chunk::{ChunkConsumer, ChunkedGenerator};
+pub use connectivity::ConnectivityConstraint;
+pub use count::CountConstraint;
+pub use edge::EdgeConstraint;
+pub use error::WfcError;
+pub use event::ObserverEvent;
+pub use forensics::{ContradictionReport, Removal};
+pub use generate::{
+    generate_with_retries, mutate_seed, seed_from_str, BudgetedUpdate, CancellationToken,
+    EntropyMode, FailureReason, GenerationError, Generator, GeneratorConfig, GeneratorStats,
+    Propagation, RetryStats, StampError, TieBreak, UpdateResult, WaveStorage, NUM_SEED_BYTES,
+};
+pub use ground::GroundConstraint;
+pub use guidance::WeightField;
+pub use hierarchy::{downsample, Refinement};
+pub use model::{Model, ModelGenerator, ModelSummary};
+pub use model_file::{load_model, save_model, ModelFileError};
+pub use offset::{
+    corner_2d_offsets, corner_3d_offsets, edge_2d_offsets, edge_3d_offsets, face_3d_offsets,
+    OffsetGroup, OffsetGroupError,
 };
-pub use generate::{Generator, UpdateResult, NUM_SEED_BYTES};
-pub use offset::{edge_2d_offsets, face_3d_offsets, OffsetGroup};
 pub use pattern::{
-    find_unique_tiles, process_patterns_in_lattice, PatternConstraints, PatternId, PatternMap,
-    PatternSampler, PatternSet, PatternShape,
+    crop_input, find_unique_tiles, fit_to_tiles, process_overlapping_patterns_in_lattice,
+    process_patterns_in_lattice, prune_rare_patterns, AdjacencyRule, InputBoundary, PartialTiles,
+    PatternConstraints, PatternId, PatternIndex, PatternMap, PatternPositions, PatternSampler,
+    PatternSet, PatternShape, PatternTileSet, Symmetry, TileCanonicalize, TileOccurrence, TileSet,
 };
+pub use progress::{Frames, Progress};
+#[cfg(feature = "io")]
+pub use quantize::{map_to_palette, quantize_colors};
+#[cfg(feature = "io")]
+pub use schematic::{write_schematic, SchematicFormat};
+pub use snapshot::{load_snapshot, save_snapshot, SnapshotError};
+pub use socket::{Socket, SocketRules, TileSymmetry, TileTransform};
+pub use strategy::{race_strategies, Strategy, StrategyOutcome, StrategyResult, StrategyStats};
+#[cfg(feature = "io")]
+pub use terminal::TerminalRenderer;
+#[cfg(feature = "io")]
+pub use tiled::{final_pattern_gids, read_tmx, write_tmx, TiledError, TiledMap};
+#[cfg(feature = "io")]
+pub use tileset_file::{NeighborEntry, TileEntry, TilesetFile, TilesetFileError, TilesetModel};
+pub use trainer::ModelTrainer;
+#[cfg(feature = "io")]
+pub use vox::{merge_vox_models, vox_model_positions, write_vox_scene, MAX_VOX_MODEL_SIZE};
+#[cfg(feature = "wasm")]
+pub use wasm::{StepResult, WasmGenerator};
+pub use wave::{ChangeStamp, PropagationStats};
+#[cfg(feature = "io")]
+pub use webp::WebpEncoder;
 
-use ::image::ImageError;
+use ilattice3 as lat;
 use ilattice3::VecLatticeMap;
-use std::error;
-use std::fmt;
-use std::io;
 
 pub trait FrameConsumer {
     fn use_frame(&mut self, frame: &VecLatticeMap<PatternSet>);
-}
 
-pub struct NilFrameConsumer;
-
-impl FrameConsumer for NilFrameConsumer {
-    fn use_frame(&mut self, _frame: &VecLatticeMap<PatternSet>) {}
-}
-
-#[derive(Debug)]
-pub enum CliError {
-    ImageError(ImageError),
-    IoError(io::Error),
-}
-
-impl fmt::Display for CliError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            CliError::ImageError(e) => write!(f, "{}", e),
-            CliError::IoError(e) => write!(f, "{}", e),
-        }
+    /// Like `use_frame`, but also given the slots that lost patterns in the last update (see
+    /// `Generator::modified_slots`), to show what propagation is doing. Ignores them by default.
+    fn use_frame_with_modified(
+        &mut self,
+        frame: &VecLatticeMap<PatternSet>,
+        _modified_slots: &[lat::Point],
+    ) {
+        self.use_frame(frame);
     }
-}
 
-impl error::Error for CliError {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match self {
-            CliError::ImageError(e) => e.source(),
-            CliError::IoError(e) => e.source(),
-        }
+    /// Like `use_frame_with_modified`, but also given every slot that changed since the previous
+    /// frame (see `Generator::changed_slots_since`), so a consumer that keeps its last image only
+    /// has to redraw those. Ignores them by default.
+    fn use_frame_with_changes(
+        &mut self,
+        frame: &VecLatticeMap<PatternSet>,
+        modified_slots: &[lat::Point],
+        _changed_slots: &[lat::Point],
+    ) {
+        self.use_frame_with_modified(frame, modified_slots);
     }
 }
 
-impl From<io::Error> for CliError {
-    fn from(e: io::Error) -> Self {
-        CliError::IoError(e)
-    }
-}
+pub struct NilFrameConsumer;
 
-impl From<ImageError> for CliError {
-    fn from(e: ImageError) -> Self {
-        CliError::ImageError(e)
-    }
+impl FrameConsumer for NilFrameConsumer {
+    fn use_frame(&mut self, _frame: &VecLatticeMap<PatternSet>) {}
 }
@@ -0,0 +1,273 @@
+use crate::{
+    connectivity::ConnectivityConstraint,
+    count::CountConstraint,
+    error::WfcError,
+    generate::{CancellationToken, Generator, UpdateResult, NUM_SEED_BYTES},
+    pattern::{
+        process_patterns_in_lattice, PatternConstraints, PatternId, PatternIndex, PatternSampler,
+        PatternSet, PatternShape, PatternTileSet,
+    },
+    progress::Frames,
+};
+
+use ilattice3 as lat;
+use ilattice3::{PeriodicYLevelsIndexer, VecLatticeMap};
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// See `Model::summary`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModelSummary {
+    pub num_patterns: PatternIndex,
+    /// The compatible (pattern, offset, pattern) triples.
+    pub num_adjacencies: usize,
+    /// The weight of every pattern, from least to greatest.
+    pub sorted_weights: Vec<u32>,
+}
+
+/// Everything learned from an input: the pattern weights, the compatibility tables used for
+/// propagation, and the tiles used to color the output.
+///
+/// The tables are immutable and shared behind an `Arc`, so cloning a `Model` is cheap, and it's
+/// `Send + Sync` whenever the voxel type is. Many `Generator`s, on any number of threads, can
+/// borrow the same model.
+pub struct Model<T> {
+    parts: Arc<ModelParts<T>>,
+}
+
+struct ModelParts<T> {
+    sampler: PatternSampler,
+    constraints: PatternConstraints,
+    tiles: PatternTileSet<T, PeriodicYLevelsIndexer>,
+}
+
+impl<T> Clone for Model<T> {
+    fn clone(&self) -> Self {
+        Model {
+            parts: self.parts.clone(),
+        }
+    }
+}
+
+impl<T> Model<T> {
+    pub fn new(
+        sampler: PatternSampler,
+        constraints: PatternConstraints,
+        tiles: PatternTileSet<T, PeriodicYLevelsIndexer>,
+    ) -> Self {
+        Model {
+            parts: Arc::new(ModelParts {
+                sampler,
+                constraints,
+                tiles,
+            }),
+        }
+    }
+
+    /// Extracts the patterns of `input_lattice`.
+    pub fn from_lattice(
+        input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+        tile_size: &lat::Point,
+        pattern_shape: &PatternShape,
+    ) -> Result<Self, WfcError>
+    where
+        T: Clone + Copy + std::fmt::Debug + Eq + Hash,
+    {
+        let (sampler, constraints, tiles, _) =
+            process_patterns_in_lattice(input_lattice, tile_size, pattern_shape, false, None)?;
+
+        Ok(Self::new(sampler, constraints, tiles))
+    }
+
+    pub fn sampler(&self) -> &PatternSampler {
+        &self.parts.sampler
+    }
+
+    pub fn constraints(&self) -> &PatternConstraints {
+        &self.parts.constraints
+    }
+
+    pub fn tiles(&self) -> &PatternTileSet<T, PeriodicYLevelsIndexer> {
+        &self.parts.tiles
+    }
+
+    /// What was learned, in numbers, e.g. to print after extracting a model.
+    pub fn summary(&self) -> ModelSummary {
+        let constraints = self.constraints();
+        let patterns = (0..constraints.num_patterns()).map(PatternId);
+        let mut sorted_weights: Vec<u32> = patterns
+            .clone()
+            .map(|p| self.sampler().get_weight(p))
+            .collect();
+        sorted_weights.sort_unstable();
+        let num_adjacencies = patterns
+            .flat_map(|p| {
+                constraints
+                    .get_offset_group()
+                    .iter()
+                    .map(move |(offset_id, _)| constraints.num_compatible(p, offset_id) as usize)
+            })
+            .sum();
+
+        ModelSummary {
+            num_patterns: constraints.num_patterns(),
+            num_adjacencies,
+            sorted_weights,
+        }
+    }
+
+    /// Starts generating an output from this model. The generator keeps a clone of the model, so
+    /// its methods don't need the tables passed in.
+    pub fn generator(
+        &self,
+        seed: [u8; NUM_SEED_BYTES],
+        output_size: lat::Point,
+    ) -> ModelGenerator<T> {
+        ModelGenerator {
+            generator: Generator::new(seed, output_size, self.sampler(), self.constraints()),
+            model: self.clone(),
+        }
+    }
+}
+
+/// A `Generator` tied to the `Model` it was made from, so it can't be given the tables of another
+/// model by mistake. Made by `Model::generator`. The methods that take no tables are on
+/// `generator()`.
+pub struct ModelGenerator<T> {
+    model: Model<T>,
+    generator: Generator,
+}
+
+impl<T> ModelGenerator<T> {
+    pub fn model(&self) -> &Model<T> {
+        &self.model
+    }
+
+    pub fn generator(&self) -> &Generator {
+        &self.generator
+    }
+
+    /// For the methods not wrapped here. Only pass it this generator's `model()` tables.
+    pub fn generator_mut(&mut self) -> &mut Generator {
+        &mut self.generator
+    }
+
+    pub fn into_generator(self) -> Generator {
+        self.generator
+    }
+
+    /// See `Generator::result`.
+    pub fn result(&self) -> VecLatticeMap<PatternId> {
+        self.generator.result()
+    }
+
+    /// See `Generator::update`.
+    pub fn update(&mut self) -> UpdateResult {
+        self.generator
+            .update(self.model.sampler(), self.model.constraints())
+    }
+
+    /// See `Generator::run`.
+    pub fn run(
+        &mut self,
+        cancel: &CancellationToken,
+        time_budget: Option<Duration>,
+    ) -> UpdateResult {
+        self.generator.run(
+            self.model.sampler(),
+            self.model.constraints(),
+            cancel,
+            time_budget,
+        )
+    }
+
+    /// See `Generator::run_to_completion`.
+    pub fn run_to_completion(&mut self) -> UpdateResult {
+        self.generator
+            .run_to_completion(self.model.sampler(), self.model.constraints())
+    }
+
+    /// See `Generator::run_steps`.
+    pub fn run_steps(&mut self, num_steps: usize) -> UpdateResult {
+        self.generator
+            .run_steps(self.model.sampler(), self.model.constraints(), num_steps)
+    }
+
+    /// See `Generator::frames`.
+    pub fn frames(&mut self) -> Frames<'_> {
+        self.generator
+            .frames(self.model.sampler(), self.model.constraints())
+    }
+
+    /// See `Generator::constrain_slot`.
+    pub fn constrain_slot(
+        &mut self,
+        slot: &lat::Point,
+        allowed: &PatternSet,
+    ) -> Result<UpdateResult, WfcError> {
+        self.generator.constrain_slot(
+            self.model.sampler(),
+            self.model.constraints(),
+            slot,
+            allowed,
+        )
+    }
+
+    /// See `Generator::constrain_slots`.
+    pub fn constrain_slots<'a, A>(&mut self, restrictions: A) -> Result<UpdateResult, WfcError>
+    where
+        A: IntoIterator<Item = (lat::Point, &'a PatternSet)>,
+    {
+        self.generator
+            .constrain_slots(self.model.sampler(), self.model.constraints(), restrictions)
+    }
+
+    /// See `Generator::force_slot`.
+    pub fn force_slot(
+        &mut self,
+        slot: &lat::Point,
+        pattern: PatternId,
+    ) -> Result<UpdateResult, WfcError> {
+        self.generator.force_slot(
+            self.model.sampler(),
+            self.model.constraints(),
+            slot,
+            pattern,
+        )
+    }
+
+    /// See `Generator::ban`.
+    pub fn ban(&mut self, slot: &lat::Point, pattern: PatternId) -> Result<UpdateResult, WfcError> {
+        self.generator.ban(
+            self.model.sampler(),
+            self.model.constraints(),
+            slot,
+            pattern,
+        )
+    }
+
+    /// See `Generator::reopen_region`.
+    pub fn reopen_region(&mut self, region: &lat::Extent) -> UpdateResult {
+        self.generator
+            .reopen_region(self.model.sampler(), self.model.constraints(), region)
+    }
+
+    /// See `Generator::add_count_constraint`.
+    pub fn add_count_constraint(
+        &mut self,
+        count: CountConstraint,
+    ) -> Result<UpdateResult, WfcError> {
+        self.generator
+            .add_count_constraint(self.model.sampler(), self.model.constraints(), count)
+    }
+
+    /// See `Generator::add_connectivity_constraint`.
+    pub fn add_connectivity_constraint(
+        &mut self,
+        connectivity: ConnectivityConstraint,
+    ) -> UpdateResult {
+        self.generator
+            .add_connectivity_constraint(self.model.constraints(), connectivity)
+    }
+}
@@ -0,0 +1,202 @@
+//! Saving and loading trained models (`.wfc` files), so patterns only need to be extracted once.
+
+use crate::{
+    offset::{OffsetGroup, OffsetGroupError},
+    pattern::{
+        PatternConstraints, PatternId, PatternIndex, PatternMap, PatternSampler, PatternTileSet,
+    },
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, PeriodicYLevelsIndexer, Tile, VecLatticeMap};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::error;
+use std::fmt;
+use std::io::{Read, Write};
+
+const MODEL_FILE_MAGIC: [u8; 4] = *b"WFC\0";
+const MODEL_FILE_VERSION: u32 = 2;
+
+/// The on-disk representation of a model. Neither `BitSet` nor `Tile` can be serialized directly,
+/// so everything is flattened into plain vectors.
+#[derive(Deserialize, Serialize)]
+struct ModelFile<T> {
+    magic: [u8; 4],
+    version: u32,
+    weights: Vec<u32>,
+    offsets: Vec<[i32; 3]>,
+    /// For each pattern and offset, the compatible patterns.
+    compatible: Vec<Vec<Vec<PatternIndex>>>,
+    tile_size: [i32; 3],
+    /// The voxels of each pattern's tile, in extent iteration order.
+    tiles: Vec<Vec<T>>,
+}
+
+/// Writes the output of `process_patterns_in_lattice` to `writer`.
+pub fn save_model<T, W>(
+    writer: W,
+    sampler: &PatternSampler,
+    constraints: &PatternConstraints,
+    tiles: &PatternTileSet<T, PeriodicYLevelsIndexer>,
+) -> Result<(), ModelFileError>
+where
+    T: Clone + Serialize,
+    W: Write,
+{
+    let offset_group = constraints.get_offset_group();
+    let patterns = (0..constraints.num_patterns()).map(PatternId);
+
+    let weights = patterns.clone().map(|p| sampler.get_weight(p)).collect();
+    let offsets = offset_group.iter().map(|(_, o)| (*o).into()).collect();
+    let compatible = patterns
+        .map(|p| {
+            offset_group
+                .iter()
+                .map(|(offset_id, _)| {
+                    constraints
+                        .iter_compatible(p, offset_id)
+                        .map(|c| c.0)
+                        .collect()
+                })
+                .collect()
+        })
+        .collect();
+
+    let tile_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), tiles.tile_size);
+    let tile_voxels = tiles
+        .tiles
+        .get_raw()
+        .iter()
+        .map(|tile| {
+            let tile_lattice = tile.clone().put_in_extent(tile_extent);
+
+            tile_extent
+                .into_iter()
+                .map(|p| tile_lattice.get_world(&p))
+                .collect()
+        })
+        .collect();
+
+    let file = ModelFile {
+        magic: MODEL_FILE_MAGIC,
+        version: MODEL_FILE_VERSION,
+        weights,
+        offsets,
+        compatible,
+        tile_size: tiles.tile_size.into(),
+        tiles: tile_voxels,
+    };
+
+    bincode::serialize_into(writer, &file).map_err(ModelFileError::Encoding)
+}
+
+/// Reads a model written by `save_model`.
+pub fn load_model<T, R>(
+    reader: R,
+) -> Result<
+    (
+        PatternSampler,
+        PatternConstraints,
+        PatternTileSet<T, PeriodicYLevelsIndexer>,
+    ),
+    ModelFileError,
+>
+where
+    T: Clone + DeserializeOwned,
+    R: Read,
+{
+    let file: ModelFile<T> = bincode::deserialize_from(reader).map_err(ModelFileError::Encoding)?;
+    if file.magic != MODEL_FILE_MAGIC {
+        return Err(ModelFileError::NotAModelFile);
+    }
+    if file.version != MODEL_FILE_VERSION {
+        return Err(ModelFileError::UnsupportedVersion(file.version));
+    }
+    let num_patterns = file.weights.len();
+    if file.compatible.len() != num_patterns || file.tiles.len() != num_patterns {
+        return Err(ModelFileError::Inconsistent);
+    }
+
+    let offsets: Vec<lat::Point> = file.offsets.iter().map(|o| (*o).into()).collect();
+    let offset_group = OffsetGroup::new(&offsets).map_err(ModelFileError::InvalidOffsets)?;
+
+    let mut constraints = PatternConstraints::new(offset_group);
+    for _ in 0..num_patterns {
+        constraints.add_pattern();
+    }
+    for (pattern, compatible) in file.compatible.iter().enumerate() {
+        if compatible.len() != offsets.len() {
+            return Err(ModelFileError::Inconsistent);
+        }
+        for (offset, offset_compatible) in offsets.iter().zip(compatible.iter()) {
+            for other in offset_compatible.iter() {
+                if *other as usize >= num_patterns {
+                    return Err(ModelFileError::Inconsistent);
+                }
+                constraints.add_compatible_patterns(
+                    offset,
+                    PatternId::from(pattern),
+                    PatternId(*other),
+                );
+            }
+        }
+    }
+
+    let tile_size = lat::Point::from(file.tile_size);
+    let tile_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), tile_size);
+    let mut tiles = Vec::with_capacity(num_patterns);
+    for voxels in file.tiles.into_iter() {
+        if voxels.len() != tile_extent.volume() {
+            return Err(ModelFileError::Inconsistent);
+        }
+        let mut tile_lattice =
+            VecLatticeMap::<_, PeriodicYLevelsIndexer>::fill(tile_extent, voxels[0].clone());
+        for (p, voxel) in tile_extent.into_iter().zip(voxels) {
+            *tile_lattice.get_world_ref_mut(&p) = voxel;
+        }
+        tiles.push(Tile::get_from_map(&tile_lattice, &tile_extent));
+    }
+
+    Ok((
+        PatternSampler::new(PatternMap::new(file.weights)),
+        constraints,
+        PatternTileSet {
+            tiles: PatternMap::new(tiles),
+            tile_size,
+        },
+    ))
+}
+
+#[derive(Debug)]
+pub enum ModelFileError {
+    Encoding(bincode::Error),
+    NotAModelFile,
+    UnsupportedVersion(u32),
+    InvalidOffsets(OffsetGroupError),
+    /// The file's sections disagree about the number of patterns, offsets, or voxels.
+    Inconsistent,
+}
+
+impl fmt::Display for ModelFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelFileError::Encoding(e) => write!(f, "{}", e),
+            ModelFileError::NotAModelFile => write!(f, "Not a model file"),
+            ModelFileError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported model file version {}", v)
+            }
+            ModelFileError::InvalidOffsets(e) => write!(f, "{}", e),
+            ModelFileError::Inconsistent => write!(f, "Model file is inconsistent"),
+        }
+    }
+}
+
+impl error::Error for ModelFileError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ModelFileError::Encoding(e) => e.source(),
+            ModelFileError::InvalidOffsets(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -2,49 +2,82 @@ use crate::static_vec::{Id, StaticVec};
 
 use ilattice3 as lat;
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
 
 #[derive(Clone)]
 pub struct OffsetGroup {
     offsets: OffsetMap<lat::Point>,
     offset_index: HashMap<lat::Point, OffsetId>,
+    /// The ID of each offset's negation.
+    opposites: OffsetMap<OffsetId>,
 }
 
 impl OffsetGroup {
-    /// `offsets` must be in order of the `OffsetId` assignments.
-    pub fn new(offsets: &[lat::Point]) -> Self {
+    /// `offsets` must be in order of the `OffsetId` assignments. Fails if `offsets` contains the
+    /// zero offset, any duplicates, or an offset without its negation, since any of those would
+    /// break the symmetry of the constraints.
+    pub fn new(offsets: &[lat::Point]) -> Result<Self, OffsetGroupError> {
         // Build the index so users can provide `lat::Point` offsets instead of `OffsetId`s when
         // convenient.
-        let offset_index: HashMap<lat::Point, OffsetId> = offsets
-            .iter()
-            .enumerate()
-            .map(|(i, offset)| (*offset, OffsetId(i)))
-            .collect();
+        let mut offset_index: HashMap<lat::Point, OffsetId> = HashMap::new();
+        for (i, offset) in offsets.iter().enumerate() {
+            if *offset == lat::Point::from([0, 0, 0]) {
+                return Err(OffsetGroupError::ZeroOffset);
+            }
+            if offset_index.insert(*offset, OffsetId(i)).is_some() {
+                return Err(OffsetGroupError::DuplicateOffset(*offset));
+            }
+        }
+        let mut opposites = Vec::with_capacity(offsets.len());
+        for offset in offsets.iter() {
+            match offset_index.get(&-*offset) {
+                Some(opposite) => opposites.push(*opposite),
+                None => return Err(OffsetGroupError::MissingOpposite(*offset)),
+            }
+        }
         let offsets = OffsetMap::new(offsets.to_vec());
 
-        OffsetGroup {
+        Ok(OffsetGroup {
             offsets,
             offset_index,
-        }
+            opposites: OffsetMap::new(opposites),
+        })
+    }
+
+    /// Every offset (in slots) at which two patterns of `pattern_size` tiles, placed with full
+    /// overlap, still overlap. Constraining only the immediate neighbors can let patterns larger
+    /// than 2 disagree with the slots further away that they overlap; this group checks all of
+    /// them.
+    pub fn within_radius(pattern_size: &lat::Point) -> Self {
+        let [sx, sy, sz]: [i32; 3] = (*pattern_size).into();
+        let offsets =
+            neighborhood_offsets(&[[1 - sx, sx - 1], [1 - sy, sy - 1], [1 - sz, sz - 1]], 3);
+
+        OffsetGroup::new(&offsets).expect("Invalid neighborhood offsets")
     }
 
     pub fn num_offsets(&self) -> usize {
         self.offsets.num_elements()
     }
 
+    pub fn contains(&self, offset: &lat::Point) -> bool {
+        self.offset_index.contains_key(offset)
+    }
+
+    /// Like `offset_id`, but returns `None` if `offset` is not in the group.
+    pub fn get_offset_id(&self, offset: &lat::Point) -> Option<OffsetId> {
+        self.offset_index.get(offset).copied()
+    }
+
     pub fn offset_id(&self, offset: &lat::Point) -> OffsetId {
-        *self
-            .offset_index
-            .get(offset)
+        self.get_offset_id(offset)
             .unwrap_or_else(|| panic!("Got offset {}", offset))
     }
 
+    /// The ID of the negation of `offset`.
     pub fn opposite(&self, offset: OffsetId) -> OffsetId {
-        let num_offsets = self.offsets.num_elements();
-        debug_assert!(num_offsets > 0);
-        let max_index = num_offsets - 1;
-        let opposite = max_index - offset.0;
-
-        opposite.into()
+        *self.opposites.get(offset)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (OffsetId, &lat::Point)> {
@@ -52,6 +85,32 @@ impl OffsetGroup {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OffsetGroupError {
+    /// The zero offset would make every pattern constrain itself.
+    ZeroOffset,
+    /// The same offset appears more than once.
+    DuplicateOffset(lat::Point),
+    /// The offset is present but its negation isn't.
+    MissingOpposite(lat::Point),
+}
+
+impl fmt::Display for OffsetGroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OffsetGroupError::ZeroOffset => write!(f, "Offset group contains the zero offset"),
+            OffsetGroupError::DuplicateOffset(offset) => {
+                write!(f, "Offset group contains {} more than once", offset)
+            }
+            OffsetGroupError::MissingOpposite(offset) => {
+                write!(f, "Offset group contains {} but not {}", offset, -*offset)
+            }
+        }
+    }
+}
+
+impl error::Error for OffsetGroupError {}
+
 /// Represents one of the possible offsets.
 #[derive(Clone, Copy, Debug)]
 pub struct OffsetId(pub usize);
@@ -70,7 +129,6 @@ impl From<usize> for OffsetId {
 
 impl Id for OffsetId {}
 
-// Must be ordered so opposites have mirror indices.
 const FACE_3D_OFFSETS: [[i32; 3]; 6] = [
     [-1, 0, 0],
     [0, -1, 0],
@@ -87,7 +145,6 @@ pub fn face_3d_offsets() -> Vec<lat::Point> {
         .collect()
 }
 
-// Must be ordered so opposites have mirror indices.
 const EDGE_2D_OFFSETS: [[i32; 3]; 4] = [[-1, 0, 0], [0, -1, 0], [0, 1, 0], [1, 0, 0]];
 
 pub fn edge_2d_offsets() -> Vec<lat::Point> {
@@ -97,4 +154,39 @@ pub fn edge_2d_offsets() -> Vec<lat::Point> {
         .collect()
 }
 
+/// All offsets in the Z = 0 plane with each coordinate in [-1, 1], except zero. Includes diagonal
+/// neighbors.
+pub fn corner_2d_offsets() -> Vec<lat::Point> {
+    neighborhood_offsets(&[[-1, 1], [-1, 1], [0, 0]], 2)
+}
+
+/// The face and edge neighbors of a voxel, i.e. offsets with one or two nonzero coordinates in
+/// [-1, 1].
+pub fn edge_3d_offsets() -> Vec<lat::Point> {
+    neighborhood_offsets(&[[-1, 1], [-1, 1], [-1, 1]], 2)
+}
+
+/// The full 26-neighborhood of a voxel: face, edge, and corner neighbors.
+pub fn corner_3d_offsets() -> Vec<lat::Point> {
+    neighborhood_offsets(&[[-1, 1], [-1, 1], [-1, 1]], 3)
+}
+
+/// The nonzero offsets with each coordinate in the given inclusive range and at most
+/// `max_nonzero` nonzero coordinates.
+fn neighborhood_offsets(ranges: &[[i32; 2]; 3], max_nonzero: usize) -> Vec<lat::Point> {
+    let mut offsets = Vec::new();
+    for x in ranges[0][0]..=ranges[0][1] {
+        for y in ranges[1][0]..=ranges[1][1] {
+            for z in ranges[2][0]..=ranges[2][1] {
+                let num_nonzero = [x, y, z].iter().filter(|c| **c != 0).count();
+                if num_nonzero > 0 && num_nonzero <= max_nonzero {
+                    offsets.push([x, y, z].into());
+                }
+            }
+        }
+    }
+
+    offsets
+}
+
 pub type OffsetMap<T> = StaticVec<OffsetId, T>;
@@ -1,64 +1,561 @@
 use crate::{
+    error::WfcError,
     offset::{OffsetGroup, OffsetId, OffsetMap},
     static_vec::{Id, StaticVec},
+    trainer::ModelTrainer,
 };
 
-use hibitset::{BitSet, BitSetLike};
+use hibitset::{BitIter, BitSet, BitSetLike, BitSetOr};
 use ilattice3 as lat;
 use ilattice3::{
-    prelude::*, Indexer, PeriodicYLevelsIndexer, Tile, Transform, VecLatticeMap,
+    copy_extent, prelude::*, Indexer, PeriodicYLevelsIndexer, Tile, Transform, VecLatticeMap,
     Z_STATIONARY_OCTAHEDRAL_GROUP,
 };
 use rand::prelude::*;
-use rand_distr::weighted::WeightedIndex;
-use std::collections::{HashMap, HashSet};
+use rand_distr::{
+    weighted::{WeightedError, WeightedIndex},
+    Uniform,
+};
+use std::collections::HashMap;
+#[cfg(feature = "mmap")]
+use std::fs::OpenOptions;
 use std::hash::Hash;
+#[cfg(feature = "mmap")]
+use std::io;
+#[cfg(feature = "mmap")]
+use std::path::Path;
+use tracing::{info, info_span};
 
+#[derive(Clone)]
 pub struct PatternShape {
     pub size: lat::Point,
+    /// How many tiles (per axis) the patterns in adjacent slots share. Must be less than `size`.
+    /// Full overlap (`size - 1`) is the strictest; less overlap allows more variety.
+    pub overlap: lat::Point,
     pub offset_group: OffsetGroup,
+    pub adjacency: AdjacencyRule,
+    pub boundary: InputBoundary,
+    pub symmetry: Symmetry,
+}
+
+/// How `process_patterns_in_lattice` decides which patterns may be adjacent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AdjacencyRule {
+    /// Patterns are compatible only if they were adjacent somewhere in the input.
+    Observed,
+    /// Patterns are compatible whenever their voxels agree where they overlap. This includes every
+    /// observed adjacency and usually many more, which gives much more variety for small inputs.
+    Agreement,
+}
+
+/// How pattern extraction treats the edges of the input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputBoundary {
+    /// The input wraps around on every axis, so patterns at one edge continue at the opposite edge.
+    Periodic,
+    /// Voxels past the edge repeat the nearest voxel on the edge.
+    Clamp,
+    /// Only patterns that fit entirely inside the input are extracted.
+    Skip,
+}
+
+/// What to do with the tiles at the far edges of an input whose size isn't a multiple of the tile
+/// size. Extraction needs whole tiles; otherwise, the periodic indexer would fill out the partial
+/// tiles with voxels from the opposite edge.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PartialTiles<T> {
+    /// Return an error.
+    Error,
+    /// Drop the partial tiles.
+    Crop,
+    /// Fill out the partial tiles with this value.
+    Pad(T),
+}
+
+/// Resizes `input_lattice` to a whole number of tiles of `tile_size`, according to
+/// `partial_tiles`. An input that's already made of whole tiles is returned as it is.
+pub fn fit_to_tiles<T: Clone>(
+    input_lattice: VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    tile_size: &lat::Point,
+    partial_tiles: &PartialTiles<T>,
+) -> Result<VecLatticeMap<T, PeriodicYLevelsIndexer>, WfcError> {
+    let input_extent = input_lattice.get_extent();
+    let input_size = *input_extent.get_local_supremum();
+    let min = *input_extent.get_minimum();
+    let fitted_size = match partial_tiles {
+        PartialTiles::Error => {
+            check_whole_tiles(&input_size, tile_size)?;
+
+            return Ok(input_lattice);
+        }
+        PartialTiles::Crop => {
+            let whole_tiles: lat::Point = [
+                input_size.x / tile_size.x,
+                input_size.y / tile_size.y,
+                input_size.z / tile_size.z,
+            ]
+            .into();
+
+            whole_tiles * *tile_size
+        }
+        PartialTiles::Pad(_) => input_size.div_ceil(tile_size) * *tile_size,
+    };
+    if fitted_size == input_size {
+        return Ok(input_lattice);
+    }
+    let [x, y, z]: [i32; 3] = fitted_size.into();
+    if x <= 0 || y <= 0 || z <= 0 {
+        return Err(WfcError::InvalidConfig(format!(
+            "Input of size {} is smaller than a tile of size {}",
+            input_size, tile_size
+        )));
+    }
+
+    let fitted_extent = lat::Extent::from_min_and_local_supremum(min, fitted_size);
+    match partial_tiles {
+        PartialTiles::Pad(value) => {
+            let mut padded = VecLatticeMap::fill(fitted_extent, value.clone());
+            copy_extent(&input_lattice, &mut padded, &input_extent);
+
+            Ok(padded)
+        }
+        _ => Ok(input_lattice.copy_extent_into_new_map(&fitted_extent)),
+    }
+}
+
+/// The part of `input_lattice` in `extent`, e.g. to learn from one region of a large scene. The
+/// result is moved to the origin, like a freshly loaded input.
+pub fn crop_input<T: Clone>(
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    extent: &lat::Extent,
+) -> Result<VecLatticeMap<T, PeriodicYLevelsIndexer>, WfcError> {
+    let input_extent = input_lattice.get_extent();
+    let [x, y, z]: [i32; 3] = (*extent.get_local_supremum()).into();
+    let max = extent.get_world_supremum() - [1, 1, 1].into();
+    if x <= 0
+        || y <= 0
+        || z <= 0
+        || !input_extent.contains_world(extent.get_minimum())
+        || !input_extent.contains_world(&max)
+    {
+        return Err(WfcError::InvalidConfig(format!(
+            "Extent with minimum {} and size {} isn't inside the input, with minimum {} and size \
+            {}",
+            extent.get_minimum(),
+            extent.get_local_supremum(),
+            input_extent.get_minimum(),
+            input_extent.get_local_supremum()
+        )));
+    }
+
+    let mut cropped = input_lattice.copy_extent_into_new_map(extent);
+    cropped.set_minimum(&[0, 0, 0].into());
+
+    Ok(cropped)
+}
+
+/// Returns an error unless an input of `input_size` is made of whole tiles of `tile_size`.
+pub(crate) fn check_whole_tiles(
+    input_size: &lat::Point,
+    tile_size: &lat::Point,
+) -> Result<(), WfcError> {
+    if input_size.div_ceil(tile_size) * *tile_size != *input_size {
+        return Err(WfcError::InvalidConfig(format!(
+            "Input of size {} isn't a whole number of tiles of size {}",
+            input_size, tile_size
+        )));
+    }
+
+    Ok(())
+}
+
+/// Which rotations and reflections of the input are also learned from, so the output can use
+/// transformed versions of what the input shows. Z is up, so every option but `Full` keeps the
+/// ground on the bottom, e.g. for buildings that must not be turned onto their sides.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Symmetry {
+    /// Only the input as it is.
+    None,
+    /// The input and its mirror image along X.
+    MirrorX,
+    /// The 4 quarter turns of the input about Z.
+    RotateZ,
+    /// The quarter turns about Z and their mirror images, which are all of the rotations and
+    /// reflections of a 2D input.
+    Dihedral,
+    /// All 48 rotations and reflections of a cube.
+    Full,
+}
+
+impl Symmetry {
+    /// The transforms applied to the input, starting with the identity.
+    pub fn transforms(&self) -> Vec<Transform> {
+        let keeps_z = |m: &[[i32; 3]; 3]| m[2] == [0, 0, 1];
+        let matrices: Vec<[[i32; 3]; 3]> = match self {
+            Symmetry::None => vec![IDENTITY_MATRIX],
+            Symmetry::MirrorX => vec![IDENTITY_MATRIX, [[-1, 0, 0], [0, 1, 0], [0, 0, 1]]],
+            Symmetry::RotateZ => octahedral_group()
+                .into_iter()
+                .filter(|m| keeps_z(m) && determinant(m) == 1)
+                .collect(),
+            Symmetry::Dihedral => octahedral_group().into_iter().filter(keeps_z).collect(),
+            Symmetry::Full => octahedral_group(),
+        };
+
+        matrices
+            .into_iter()
+            .map(|matrix| Transform { matrix })
+            .collect()
+    }
+}
+
+const IDENTITY_MATRIX: [[i32; 3]; 3] = [[1, 0, 0], [0, 1, 0], [0, 0, 1]];
+
+/// Every signed permutation of the axes, starting with the identity.
+fn octahedral_group() -> Vec<[[i32; 3]; 3]> {
+    const AXIS_PERMUTATIONS: [[usize; 3]; 6] = [
+        [0, 1, 2],
+        [0, 2, 1],
+        [1, 0, 2],
+        [1, 2, 0],
+        [2, 0, 1],
+        [2, 1, 0],
+    ];
+
+    let mut group = Vec::with_capacity(48);
+    for permutation in AXIS_PERMUTATIONS.iter() {
+        for signs in 0..8 {
+            let mut matrix = [[0; 3]; 3];
+            for (row, column) in permutation.iter().enumerate() {
+                matrix[row][*column] = if signs & (1 << row) == 0 { 1 } else { -1 };
+            }
+            group.push(matrix);
+        }
+    }
+
+    group
+}
+
+fn determinant(m: &[[i32; 3]; 3]) -> i32 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/// Whether a box of `size` has the same size after `transform`.
+fn preserves_shape(transform: &Transform, size: &lat::Point) -> bool {
+    let s: [i32; 3] = (*size).into();
+    let row_size = |row: &[i32; 3]| row.iter().zip(s.iter()).map(|(m, s)| m.abs() * s).sum();
+    let m = &transform.matrix;
+    let transformed: lat::Point = [row_size(&m[0]), row_size(&m[1]), row_size(&m[2])].into();
+
+    transformed == *size
+}
+
+/// `input_lattice` after `transform`, with the same minimum.
+pub(crate) fn transform_example<T>(
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    transform: &Transform,
+) -> VecLatticeMap<T, PeriodicYLevelsIndexer>
+where
+    T: Clone,
+{
+    let mut transformed = input_lattice.apply_octahedral_transform(transform);
+    transformed.set_minimum(input_lattice.get_extent().get_minimum());
+
+    transformed
+}
+
+impl InputBoundary {
+    /// The number of pattern positions (one per tile) along each axis of an input with
+    /// `input_extent`.
+    pub(crate) fn pattern_lattice_size(
+        &self,
+        input_extent: &lat::Extent,
+        tile_size: &lat::Point,
+        pattern_size: &lat::Point,
+    ) -> Result<lat::Point, WfcError> {
+        let num_tiles = input_extent.get_local_supremum().div_ceil(tile_size);
+        if *self != InputBoundary::Skip {
+            return Ok(num_tiles);
+        }
+
+        let size = num_tiles - *pattern_size + [1, 1, 1].into();
+        let [x, y, z]: [i32; 3] = size.into();
+        if x <= 0 || y <= 0 || z <= 0 {
+            return Err(WfcError::InvalidConfig(format!(
+                "Input of {} tiles is smaller than a pattern of {} tiles",
+                num_tiles, pattern_size
+            )));
+        }
+
+        Ok(size)
+    }
+
+    /// Reads the voxels of `input_lattice` in `extent`, which may cross the edge of the input.
+    pub(crate) fn get_window<T>(
+        &self,
+        input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+        extent: &lat::Extent,
+    ) -> Tile<T, PeriodicYLevelsIndexer>
+    where
+        T: Clone + Copy,
+    {
+        let input_extent = input_lattice.get_extent();
+        let min = *input_extent.get_minimum();
+        let max = input_extent.get_world_supremum() - [1, 1, 1].into();
+        let window_max = extent.get_world_supremum() - [1, 1, 1].into();
+        if *self != InputBoundary::Clamp
+            || (input_extent.contains_world(extent.get_minimum())
+                && input_extent.contains_world(&window_max))
+        {
+            return Tile::get_from_map(input_lattice, extent);
+        }
+
+        let mut window = VecLatticeMap::<_, PeriodicYLevelsIndexer>::fill(
+            *extent,
+            input_lattice.get_world(&min),
+        );
+        for p in *extent {
+            let clamped: lat::Point = [
+                p.x.max(min.x).min(max.x),
+                p.y.max(min.y).min(max.y),
+                p.z.max(min.z).min(max.z),
+            ]
+            .into();
+            *window.get_world_ref_mut(&p) = input_lattice.get_world(&clamped);
+        }
+
+        Tile::get_from_map(&window, extent)
+    }
+}
+
+impl PatternShape {
+    /// A shape where adjacent patterns overlap as much as possible.
+    pub fn with_full_overlap(size: lat::Point, offset_group: OffsetGroup) -> Self {
+        PatternShape {
+            size,
+            overlap: size - [1, 1, 1].into(),
+            offset_group,
+            adjacency: AdjacencyRule::Observed,
+            boundary: InputBoundary::Periodic,
+            symmetry: Symmetry::None,
+        }
+    }
+
+    /// The distance (in tiles, per axis) between the minimum corners of patterns in adjacent
+    /// slots. Each output slot is rendered with this many tiles.
+    pub fn stride(&self) -> lat::Point {
+        self.size - self.overlap
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), WfcError> {
+        let size: [i32; 3] = self.size.into();
+        let overlap: [i32; 3] = self.overlap.into();
+        for (s, o) in size.iter().zip(overlap.iter()) {
+            if *o < 0 || *o >= *s {
+                return Err(WfcError::InvalidConfig(format!(
+                    "Pattern overlap {} must be in [0, size) for pattern size {}",
+                    self.overlap, self.size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The transforms of `symmetry` that keep the shape of the tiles and patterns, so the
+    /// transformed input can be divided the same way as the input. E.g. a quarter turn about Z
+    /// would turn 2x1x3 tiles into 1x2x3 tiles, so it's left out.
+    pub(crate) fn symmetry_transforms(&self, tile_size: &lat::Point) -> Vec<Transform> {
+        let sizes = [*tile_size, self.size, self.overlap];
+        let transforms = self.symmetry.transforms();
+        let num_transforms = transforms.len();
+        let kept: Vec<Transform> = transforms
+            .into_iter()
+            .filter(|t| sizes.iter().all(|s| preserves_shape(t, s)))
+            .collect();
+        if kept.len() < num_transforms {
+            info!(
+                "Using {} of the {} transforms of {:?} symmetry, since the rest change the shape \
+                of tile size {}, pattern size {}, or overlap {}",
+                kept.len(),
+                num_transforms,
+                self.symmetry,
+                tile_size,
+                self.size,
+                self.overlap
+            );
+        }
+
+        kept
+    }
 }
 
 pub struct PatternSampler {
-    /// Count of each pattern in the source lattice. Equivalently, a prior distribution of patterns.
+    /// Count of each pattern in the source lattice, unless overridden. Equivalently, a prior
+    /// distribution of patterns. A pattern with zero weight is only chosen when nothing else is
+    /// possible.
     weights: PatternMap<u32>,
+    /// `weight * log2(weight)` of each pattern, kept in sync with `weights`, since entropy updates
+    /// need it for every removal.
+    weight_log_weights: PatternMap<f32>,
 }
 
 impl PatternSampler {
     pub fn new(weights: PatternMap<u32>) -> Self {
-        PatternSampler { weights }
+        let weight_log_weights = weights.map(|w| weight_log_weight(*w as f32));
+
+        PatternSampler {
+            weights,
+            weight_log_weights,
+        }
     }
 
-    /// Returns the number of occurences of `pattern` in the source data.
+    /// Returns the number of occurences of `pattern` in the source data, unless it was overridden.
     pub fn get_weight(&self, pattern: PatternId) -> u32 {
         *self.weights.get(pattern)
     }
 
-    pub fn num_patterns(&self) -> u16 {
-        self.weights.num_elements() as u16
+    /// Overrides the weight of `pattern`, e.g. to make a rare structure more common.
+    pub fn set_weight(&mut self, pattern: PatternId, weight: u32) {
+        *self.weights.get_mut(pattern) = weight;
+        *self.weight_log_weights.get_mut(pattern) = weight_log_weight(weight as f32);
+    }
+
+    /// `weight * log2(weight)` of `pattern`, or 0 for a zero weight.
+    pub fn get_weight_log_weight(&self, pattern: PatternId) -> f32 {
+        *self.weight_log_weights.get(pattern)
+    }
+
+    /// Multiplies the weights of `patterns` by `factor`, rounding to the nearest integer.
+    pub fn scale_weights(&mut self, patterns: &PatternSet, factor: f32) {
+        assert!(factor >= 0.0, "Weight scale factor {} is negative", factor);
+        for pattern in patterns.iter() {
+            let weight = (*self.weights.get(pattern) as f32 * factor).round() as u32;
+            self.set_weight(pattern, weight);
+        }
+    }
+
+    /// Adds `pseudocount` to every weight (Laplace smoothing), so rarely observed patterns aren't
+    /// drowned out by common ones.
+    pub fn add_pseudocount(&mut self, pseudocount: u32) {
+        for (pattern, weight) in self.weights.iter_mut() {
+            *weight = weight.saturating_add(pseudocount);
+            *self.weight_log_weights.get_mut(pattern) = weight_log_weight(*weight as f32);
+        }
+    }
+
+    pub fn num_patterns(&self) -> PatternIndex {
+        self.weights.num_elements() as PatternIndex
     }
 
     /// Sample the possible patterns by their probability (weights) in the source data.
     pub fn sample_pattern<R: Rng>(&self, possible_patterns: &PatternSet, rng: &mut R) -> PatternId {
-        let mut possible_weights = Vec::new();
-        let mut possible_patterns_vec = Vec::new();
-        for pattern in possible_patterns.iter() {
-            possible_weights.push(*self.weights.get(pattern));
-            possible_patterns_vec.push(pattern);
+        sample_pattern_by(possible_patterns, |p| *self.weights.get(p), rng)
+    }
+
+    /// Like `sample_pattern`, but each pattern's weight is scaled by `multiplier`, which is called
+    /// twice for each pattern.
+    pub fn sample_pattern_scaled<R, F>(
+        &self,
+        possible_patterns: &PatternSet,
+        rng: &mut R,
+        multiplier: F,
+    ) -> PatternId
+    where
+        R: Rng,
+        F: Fn(PatternId) -> f32,
+    {
+        sample_pattern_by(
+            possible_patterns,
+            |p| *self.weights.get(p) as f32 * multiplier(p),
+            rng,
+        )
+    }
+}
+
+/// Weights aren't necessarily counts; zero contributes nothing, as in the limit.
+pub(crate) fn weight_log_weight(weight: f32) -> f32 {
+    if weight > 0.0 {
+        weight * weight.log2()
+    } else {
+        0.0
+    }
+}
+
+/// Chooses the same pattern as `sample_index` over the weights of `possible_patterns`, for the same
+/// RNG state, but without allocating. This runs for every observation, so rather than building a
+/// `WeightedIndex`, it walks the set twice: once to sum the weights, and once to find where the
+/// cumulative weight passes the sampled value.
+fn sample_pattern_by<X, W, R>(possible_patterns: &PatternSet, weight: W, rng: &mut R) -> PatternId
+where
+    X: rand_distr::uniform::SampleUniform + PartialOrd + Default + Copy,
+    X: for<'a> std::ops::AddAssign<&'a X>,
+    W: Fn(PatternId) -> X,
+    R: Rng + ?Sized,
+{
+    let zero = X::default();
+    let mut total = zero;
+    let mut num_possible = 0;
+    for pattern in possible_patterns.iter() {
+        let w = weight(pattern);
+        assert!(
+            w >= zero,
+            "Invalid pattern weights: A weight is invalid in distribution"
+        );
+        total += &w;
+        num_possible += 1;
+    }
+    assert!(
+        num_possible > 0,
+        "Invalid pattern weights: No weights provided."
+    );
+
+    if total == zero {
+        let choice = rng.gen_range(0, num_possible);
+
+        return possible_patterns.iter().nth(choice).unwrap();
+    }
+
+    let chosen = Uniform::new(zero, total).sample(rng);
+    let mut cumulative = zero;
+    let mut last = None;
+    for pattern in possible_patterns.iter() {
+        cumulative += &weight(pattern);
+        if cumulative > chosen {
+            return pattern;
         }
-        let dist = WeightedIndex::new(&possible_weights).unwrap();
-        let choice = dist.sample(rng);
+        last = Some(pattern);
+    }
 
-        possible_patterns_vec[choice]
+    // Rounding can leave the sum just short of the sample.
+    last.unwrap()
+}
+
+/// Samples an index by weight, or uniformly if every weight is zero.
+pub(crate) fn sample_index<X, R>(weights: &[X], rng: &mut R) -> usize
+where
+    X: rand_distr::uniform::SampleUniform + PartialOrd + Default + Clone + Copy,
+    X: for<'a> std::ops::AddAssign<&'a X>,
+    R: Rng + ?Sized,
+{
+    match WeightedIndex::new(weights) {
+        Ok(dist) => dist.sample(rng),
+        Err(WeightedError::AllWeightsZero) => rng.gen_range(0, weights.len()),
+        Err(e) => panic!("Invalid pattern weights: {}", e),
     }
 }
 
+/// The integer type behind `PatternId`.
+pub type PatternIndex = u32;
+
 /// Represents one of the possible patterns.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub struct PatternId(pub u16);
+pub struct PatternId(pub PatternIndex);
 
-/// Limited by the support counts, which use i16.
-pub const MAX_PATTERNS: u16 = std::i16::MAX as u16;
+/// Limited by the capacity of `PatternSet`'s bit set on 32-bit targets.
+pub const MAX_PATTERNS: PatternIndex = 1 << 20;
 
 impl Into<usize> for PatternId {
     fn into(self) -> usize {
@@ -69,21 +566,41 @@ impl Into<usize> for PatternId {
 impl From<usize> for PatternId {
     fn from(other: usize) -> PatternId {
         debug_assert!(other <= MAX_PATTERNS as usize);
-        PatternId(other as u16)
+        PatternId(other as PatternIndex)
     }
 }
 
 impl Id for PatternId {}
 
-const EMPTY_PATTERN_ID: PatternId = PatternId(std::u16::MAX);
+const EMPTY_PATTERN_ID: PatternId = PatternId(std::u32::MAX);
 
+/// Normalizes every voxel of a tile or pattern before it's compared with others, so near-duplicates
+/// merge instead of each getting their own tile, e.g. by ignoring alpha, quantizing colors, or
+/// mapping palette indices through a table. Tiles keep the voxels of their first occurrence as
+/// they were.
+///
+/// Any `Fn(&T) -> T` is a `TileCanonicalize<T>`.
+pub trait TileCanonicalize<T> {
+    fn canonicalize(&self, voxel: &T) -> T;
+}
+
+impl<T, F: Fn(&T) -> T> TileCanonicalize<T> for F {
+    fn canonicalize(&self, voxel: &T) -> T {
+        self(voxel)
+    }
+}
+
+/// Divides `input_lattice` into tiles of `tile_size` and keeps one representative of each tile that
+/// is unique up to the Z-stationary symmetries that keep the tile's shape, and up to `canonicalize`
+/// if given. Every tile of the input is recorded as a `TileOccurrence` of its representative.
 pub fn find_unique_tiles<T, I>(
     input_lattice: &VecLatticeMap<T, I>,
     tile_size: &lat::Point,
+    canonicalize: Option<&dyn TileCanonicalize<T>>,
 ) -> TileSet<T, I>
 where
     T: Clone + Copy + std::fmt::Debug + Eq + Hash,
-    I: Eq + Hash + lat::Indexer,
+    I: Clone + Eq + Hash + lat::Indexer,
 {
     let input_extent = input_lattice.get_extent();
     let index_extent = lat::Extent::from_min_and_local_supremum(
@@ -91,151 +608,437 @@ where
         input_extent.get_local_supremum().div_ceil(tile_size),
     );
 
-    let mut tiles: HashSet<Tile<T, _>> = HashSet::new();
+    let mut tiles = Vec::new();
+    // The canonical voxels of each tile, to recognize its other occurrences.
+    let mut tile_indices: HashMap<Tile<T, _>, usize> = HashMap::new();
+    let mut occurrences = Vec::new();
 
     for p in index_extent {
         let tile_min = p * *tile_size;
         let tile_extent = lat::Extent::from_min_and_local_supremum(tile_min, *tile_size);
         let tile_lattice = input_lattice.copy_extent_into_new_map(&tile_extent);
 
-        // Identify any symmetric configurations of a tile.
+        // Identify any symmetric configurations of a tile. Transforms that change the shape of the
+        // tile, like a quarter turn of a 2x1x1 tile, can't be compared.
+        let mut occurrence = None;
         let mut add_tile = None;
-        for symmetry in Z_STATIONARY_OCTAHEDRAL_GROUP.iter() {
-            let transform = Transform {
+        let transforms = Z_STATIONARY_OCTAHEDRAL_GROUP
+            .iter()
+            .map(|symmetry| Transform {
                 matrix: symmetry.clone(),
-            };
+            })
+            .filter(|transform| preserves_shape(transform, tile_size));
+        for transform in transforms {
             let mut transformed_tile_lattice = tile_lattice.apply_octahedral_transform(&transform);
             transformed_tile_lattice.set_minimum(&[0, 0, 0].into()); // normalize
             let normalized_extent =
                 lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), *tile_size);
             let transformed_tile =
                 Tile::get_from_map(&transformed_tile_lattice, &normalized_extent);
+            let key = match canonicalize {
+                Some(c) => Tile::get_from_map(
+                    &transformed_tile_lattice.map(|v| c.canonicalize(v)),
+                    &normalized_extent,
+                ),
+                None => transformed_tile.clone(),
+            };
 
             // Only add the tile if a symmetry of it doesn't already exist.
-            if tiles.contains(&transformed_tile) {
-                add_tile = None;
+            if let Some(&tile) = tile_indices.get(&key) {
+                occurrence = Some((tile, transform));
                 break;
             }
 
-            add_tile = Some(transformed_tile);
+            add_tile = Some((transformed_tile, key, transform));
         }
 
-        if let Some(tile) = add_tile {
-            tiles.insert(tile);
-        }
+        let (tile, transform) = match (occurrence, add_tile) {
+            (Some(occurrence), _) => occurrence,
+            (None, Some((new_tile, key, transform))) => {
+                let tile = tiles.len();
+                tile_indices.insert(key, tile);
+                tiles.push(new_tile);
+
+                (tile, transform)
+            }
+            (None, None) => unreachable!("The identity always keeps the tile's shape"),
+        };
+        occurrences.push(TileOccurrence {
+            tile,
+            min: tile_min,
+            transform,
+        });
     }
 
     TileSet {
-        tiles: tiles.into_iter().collect(),
+        tiles,
         tile_size: *tile_size,
+        occurrences,
     }
 }
 
 /// For each unique (up to translation) sublattice of `input_lattice`, create a `PatternId`, count
 /// the occurences of the pattern, and record the set of patterns that overlap with that pattern at
-/// each possible offset.
+/// each possible offset. Adjacent patterns are one `PatternShape::stride` apart, and the returned
+/// tiles are the stride-sized corner of each pattern. Which patterns count as compatible depends on
+/// `PatternShape::adjacency`.
+///
+/// With `record_positions`, also returns the minimum voxel of every occurrence of each pattern in
+/// `input_lattice`, to find where a pattern came from. With `canonicalize`, patterns are identified
+/// by their canonical voxels, as in `ModelTrainer::with_equivalence`.
+#[allow(clippy::type_complexity)]
 pub fn process_patterns_in_lattice<T>(
     input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
     tile_size: &lat::Point,
     pattern_shape: &PatternShape,
-) -> (
-    PatternSampler,
-    PatternConstraints,
-    PatternTileSet<T, PeriodicYLevelsIndexer>,
-)
+    record_positions: bool,
+    canonicalize: Option<Box<dyn TileCanonicalize<T> + Send + Sync>>,
+) -> Result<
+    (
+        PatternSampler,
+        PatternConstraints,
+        PatternTileSet<T, PeriodicYLevelsIndexer>,
+        Option<PatternPositions>,
+    ),
+    WfcError,
+>
 where
     T: Clone + Copy + std::fmt::Debug + Eq + Hash,
 {
-    let input_extent = input_lattice.get_extent();
-    let pattern_size = pattern_shape.size * *tile_size;
-    let pattern_lattice_size = input_extent.get_local_supremum().div_ceil(tile_size);
+    let _span = info_span!("extract_patterns", %tile_size).entered();
 
-    let mut num_patterns = 0;
-    // Map sublattice data to pattern ID.
-    let mut patterns: HashMap<Tile<T, _>, PatternId> = HashMap::new();
-    // Min corner tile of each pattern.
-    let mut pattern_min_tiles = Vec::new();
-    // Map from pattern ID to # of occurrences.
-    let mut pattern_weights = PatternMap::new(Vec::new());
+    let mut trainer = ModelTrainer::new(*tile_size, pattern_shape.clone())?;
+    if record_positions {
+        trainer = trainer.record_positions();
+    }
+    if let Some(canonicalize) = canonicalize {
+        trainer = trainer.with_equivalence(canonicalize);
+    }
+    trainer.add_example(input_lattice)?;
 
-    let mut constraints = PatternConstraints::new(pattern_shape.offset_group.clone());
+    trainer.into_parts_with_positions()
+}
 
+/// Identifies the pattern (`pattern_size` tiles) starting at every tile of the input. Returns a
+/// lattice with the `PatternId` at each tile position, and for each pattern, the position where it
+/// first occurs. IDs are assigned in order of first occurrence.
+pub(crate) fn index_patterns<T>(
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    tile_size: &lat::Point,
+    pattern_size: &lat::Point,
+    boundary: InputBoundary,
+) -> Result<
+    (
+        VecLatticeMap<PatternId, PeriodicYLevelsIndexer>,
+        Vec<lat::Point>,
+    ),
+    WfcError,
+>
+where
+    T: Clone + Copy + std::fmt::Debug + Eq + Hash,
+{
+    let input_extent = input_lattice.get_extent();
+    let pattern_voxel_size = *pattern_size * *tile_size;
+    let pattern_lattice_size =
+        boundary.pattern_lattice_size(&input_extent, tile_size, pattern_size)?;
     let pattern_lattice_extent =
         lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), pattern_lattice_size);
 
-    // Map pattern center to pattern ID.
+    // Map sublattice data to pattern ID.
+    let mut patterns: HashMap<Tile<T, _>, PatternId> = HashMap::new();
+    let mut first_occurrences = Vec::new();
     let mut pattern_lattice =
         VecLatticeMap::<_, PeriodicYLevelsIndexer>::fill(pattern_lattice_extent, EMPTY_PATTERN_ID);
-
-    // Index the patterns.
     for pattern_point in pattern_lattice_extent.into_iter() {
         // Identify the pattern with the serialized values.
         let pattern_min = pattern_point * *tile_size;
-        let pattern_extent = lat::Extent::from_min_and_local_supremum(pattern_min, pattern_size);
-        let tile_extent = lat::Extent::from_min_and_local_supremum(pattern_min, *tile_size);
+        let pattern_extent =
+            lat::Extent::from_min_and_local_supremum(pattern_min, pattern_voxel_size);
+        let pattern = boundary.get_window(input_lattice, &pattern_extent);
 
-        let pattern = Tile::get_from_map(input_lattice, &pattern_extent);
-        let pattern_min_tile = Tile::get_from_map(input_lattice, &tile_extent);
+        let pattern_id = *patterns.entry(pattern).or_insert_with(|| {
+            let this_pattern_id = PatternId(first_occurrences.len() as PatternIndex);
+            first_occurrences.push(pattern_point);
 
-        let pattern_id = patterns.entry(pattern).or_insert_with(|| {
-            let this_pattern_id = PatternId(num_patterns);
+            this_pattern_id
+        });
+        if first_occurrences.len() > MAX_PATTERNS as usize {
+            return Err(WfcError::TooManyPatterns);
+        }
+        *pattern_lattice.get_local_ref_mut(&pattern_point) = pattern_id;
+    }
 
-            num_patterns += 1;
-            if num_patterns > MAX_PATTERNS {
-                panic!(
-                    "Too many patterns ({}), maximum is {}",
-                    num_patterns, MAX_PATTERNS
-                );
+    Ok((pattern_lattice, first_occurrences))
+}
+
+/// Like `process_patterns_in_lattice`, but in the style of Gumin's original overlapping model. A
+/// pattern is extracted at every voxel of the input, regardless of tile alignment, and two patterns
+/// are compatible at an offset whenever their voxels agree where they overlap, whether or not they
+/// were ever adjacent in the input. `pattern_shape` is measured in voxels, so each output slot is
+/// `pattern_shape.stride()` voxels.
+pub fn process_overlapping_patterns_in_lattice<T>(
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    pattern_shape: &PatternShape,
+) -> Result<
+    (
+        PatternSampler,
+        PatternConstraints,
+        PatternTileSet<T, PeriodicYLevelsIndexer>,
+    ),
+    WfcError,
+>
+where
+    T: Clone + Copy + std::fmt::Debug + Eq + Hash,
+{
+    pattern_shape.validate()?;
+
+    let stride = pattern_shape.stride();
+    let pattern_extent =
+        lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), pattern_shape.size);
+    let tile_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), stride);
+
+    // Map sublattice data to pattern ID.
+    let mut patterns: HashMap<Tile<T, _>, PatternId> = HashMap::new();
+    // The voxels of each pattern, with the pattern's minimum at the origin.
+    let mut pattern_lattices = Vec::new();
+    // Min corner tile of each pattern.
+    let mut pattern_min_tiles = Vec::new();
+    // Map from pattern ID to # of occurrences.
+    let mut pattern_weights = PatternMap::new(Vec::new());
+
+    let mut constraints = PatternConstraints::new(pattern_shape.offset_group.clone());
+
+    // Index the patterns, one window per voxel of the input and each of its transforms.
+    let transforms = pattern_shape.symmetry_transforms(&[1, 1, 1].into());
+    for (i, transform) in transforms.iter().enumerate() {
+        let transformed;
+        let example = if i == 0 {
+            input_lattice
+        } else {
+            transformed = transform_example(input_lattice, transform);
+            &transformed
+        };
+        let input_extent = example.get_extent();
+        let window_lattice_size = pattern_shape.boundary.pattern_lattice_size(
+            &input_extent,
+            &[1, 1, 1].into(),
+            &pattern_shape.size,
+        )?;
+        let window_lattice_extent = lat::Extent::from_min_and_local_supremum(
+            *input_extent.get_minimum(),
+            window_lattice_size,
+        );
+        for pattern_min in window_lattice_extent {
+            let window_extent =
+                lat::Extent::from_min_and_local_supremum(pattern_min, pattern_shape.size);
+            let pattern = pattern_shape.boundary.get_window(example, &window_extent);
+
+            let next_pattern_id = PatternId(pattern_lattices.len() as PatternIndex);
+            let pattern_id = *patterns.entry(pattern.clone()).or_insert_with(|| {
+                let pattern_lattice = pattern.put_in_extent(pattern_extent);
+                pattern_min_tiles.push(Tile::get_from_map(&pattern_lattice, &tile_extent));
+                pattern_lattices.push(pattern_lattice);
+                constraints.add_pattern();
+                pattern_weights.push(0);
+
+                next_pattern_id
+            });
+            if pattern_lattices.len() > MAX_PATTERNS as usize {
+                return Err(WfcError::TooManyPatterns);
             }
+            *pattern_weights.get_mut(pattern_id) += 1;
+        }
+    }
 
-            constraints.add_pattern();
-            pattern_weights.push(0);
-            pattern_min_tiles.push(pattern_min_tile);
+    constraints.add_agreeing_patterns(&pattern_lattices, &stride);
 
-            this_pattern_id
-        });
-        *pattern_lattice.get_local_ref_mut(&pattern_point) = *pattern_id;
+    let sampler = PatternSampler::new(pattern_weights);
+    let tiles = PatternTileSet {
+        tiles: PatternMap::new(pattern_min_tiles),
+        tile_size: stride,
+    };
+    if pattern_shape.boundary == InputBoundary::Periodic {
+        constraints.assert_valid();
+
+        Ok((sampler, constraints, tiles))
+    } else {
+        // Patterns at the edge may not agree with anything past it.
+        prune_rare_patterns(sampler, constraints, tiles, 0)
     }
+}
 
-    // Set the constraints and count pattern occurences.
-    for pattern_point in pattern_lattice_extent.into_iter() {
-        let pattern = pattern_lattice.get_local(&pattern_point);
-        debug_assert!(pattern != EMPTY_PATTERN_ID);
-        for (_, offset) in pattern_shape.offset_group.iter() {
-            let offset_point = pattern_point + *offset;
-            let offset_pattern = pattern_lattice.get_local(&offset_point);
-            debug_assert!(offset_pattern != EMPTY_PATTERN_ID);
+/// Removes every pattern with a weight less than `min_weight`, along with all of its adjacencies.
+/// Patterns that are left with no compatible pattern at some offset are removed as well. The
+/// remaining patterns are renumbered in order, so `PatternId`s from before pruning are not valid
+/// afterward.
+///
+/// Noisy inputs can have thousands of patterns that occur only once; they slow down propagation
+/// and rarely make the output any better.
+pub fn prune_rare_patterns<T, I>(
+    sampler: PatternSampler,
+    constraints: PatternConstraints,
+    tiles: PatternTileSet<T, I>,
+    min_weight: u32,
+) -> Result<(PatternSampler, PatternConstraints, PatternTileSet<T, I>), WfcError> {
+    let new_ids = pruned_pattern_ids(&sampler, &constraints, min_weight)?;
+
+    Ok(renumber_patterns(sampler, constraints, tiles, &new_ids))
+}
+
+/// The new ID of each pattern kept by `prune_rare_patterns`, or `None` if it's removed.
+pub(crate) fn pruned_pattern_ids(
+    sampler: &PatternSampler,
+    constraints: &PatternConstraints,
+    min_weight: u32,
+) -> Result<PatternMap<Option<PatternId>>, WfcError> {
+    let num_patterns = constraints.num_patterns();
+    let mut keep: PatternMap<bool> = sampler.weights.map(|w| *w >= min_weight);
+
+    // Removing a pattern can leave others without support, so repeat until nothing changes.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pattern in (0..num_patterns).map(PatternId) {
+            if !*keep.get(pattern) {
+                continue;
+            }
+            let unsupported = constraints
+                .constraints
+                .get(pattern)
+                .iter()
+                .any(|(_, set)| !set.iter().any(|other| *keep.get(PatternId(other))));
+            if unsupported {
+                *keep.get_mut(pattern) = false;
+                changed = true;
+            }
+        }
+    }
 
-            constraints.add_compatible_patterns(&offset, pattern, offset_pattern);
+    let mut new_ids = PatternMap::fill(None, num_patterns as usize);
+    let mut num_kept = 0;
+    for (pattern, k) in keep.iter() {
+        if *k {
+            *new_ids.get_mut(pattern) = Some(PatternId(num_kept));
+            num_kept += 1;
         }
-        *pattern_weights.get_mut(pattern) += 1;
+    }
+    if num_kept == 0 {
+        return Err(WfcError::InvalidConfig(format!(
+            "No patterns are left after pruning patterns with weight less than {}",
+            min_weight
+        )));
     }
 
-    constraints.assert_valid();
+    Ok(new_ids)
+}
 
-    let mut sorted_weights = pattern_weights.get_raw().clone();
-    sorted_weights.sort();
-    println!("Weights = {:?}", sorted_weights);
+/// Keeps only the patterns with a new ID in `new_ids`, and renumbers them.
+pub(crate) fn renumber_patterns<T, I>(
+    sampler: PatternSampler,
+    constraints: PatternConstraints,
+    tiles: PatternTileSet<T, I>,
+    new_ids: &PatternMap<Option<PatternId>>,
+) -> (PatternSampler, PatternConstraints, PatternTileSet<T, I>) {
+    let PatternSampler { weights, .. } = sampler;
+    let PatternConstraints {
+        constraints: old_constraints,
+        offset_group,
+    } = constraints;
+    let mut new_weights = PatternMap::new(Vec::new());
+    let mut new_tiles = PatternMap::new(Vec::new());
+    let mut new_constraints = PatternConstraints::new(offset_group);
+    for ((pattern, tile), weight) in tiles
+        .tiles
+        .into_raw()
+        .into_iter()
+        .enumerate()
+        .zip(weights.into_raw().into_iter())
+    {
+        let pattern = PatternId::from(pattern);
+        if new_ids.get(pattern).is_none() {
+            continue;
+        }
+        new_weights.push(weight);
+        new_tiles.push(tile);
+        new_constraints
+            .constraints
+            .push(old_constraints.get(pattern).map(|set| {
+                let mut new_set = BitSet::new();
+                for other in set.iter() {
+                    if let Some(new_other) = new_ids.get(PatternId(other)) {
+                        new_set.add(new_other.0);
+                    }
+                }
+
+                new_set
+            }));
+    }
 
     (
-        PatternSampler::new(pattern_weights),
-        constraints,
+        PatternSampler::new(new_weights),
+        new_constraints,
         PatternTileSet {
-            tiles: PatternMap::new(pattern_min_tiles),
-            tile_size: *tile_size,
+            tiles: new_tiles,
+            tile_size: tiles.tile_size,
         },
     )
 }
 
+/// Returns `true` iff `a` and `b` have the same voxels where they overlap, with `b`'s minimum at
+/// `displacement` relative to `a`'s. Both lattices must have the same extent.
+fn patterns_agree<T: Clone + Eq, I: Indexer>(
+    a: &VecLatticeMap<T, I>,
+    b: &VecLatticeMap<T, I>,
+    displacement: &lat::Point,
+) -> bool {
+    let extent = a.get_extent();
+    for p in extent {
+        let p_in_b = p - *displacement;
+        if extent.contains_world(&p_in_b) && a.get_world_ref(&p) != b.get_world_ref(&p_in_b) {
+            return false;
+        }
+    }
+
+    true
+}
+
 #[derive(Clone)]
 pub struct TileSet<T, I> {
     pub tiles: Vec<Tile<T, I>>,
     pub tile_size: lat::Point,
+    /// Every tile of the input that `tiles` came from. Empty if the tiles weren't found by
+    /// `find_unique_tiles`.
+    pub occurrences: Vec<TileOccurrence>,
+}
+
+impl<T, I> TileSet<T, I> {
+    /// The number of occurrences of each tile, in the same order as `tiles`.
+    pub fn counts(&self) -> Vec<u32> {
+        let mut counts = vec![0; self.tiles.len()];
+        for occurrence in self.occurrences.iter() {
+            counts[occurrence.tile] += 1;
+        }
+
+        counts
+    }
+}
+
+/// One tile of the input, as found by `find_unique_tiles`.
+#[derive(Clone, Debug)]
+pub struct TileOccurrence {
+    /// The index of the tile's representative in `TileSet::tiles`.
+    pub tile: usize,
+    /// The minimum of the tile in the input.
+    pub min: lat::Point,
+    /// Maps the tile as found in the input onto its representative, up to translation.
+    pub transform: Transform,
 }
 
 impl<T, I> From<TileSet<T, I>> for PatternTileSet<T, I> {
     fn from(other: TileSet<T, I>) -> Self {
-        let TileSet { tiles, tile_size } = other;
+        let TileSet {
+            tiles, tile_size, ..
+        } = other;
 
         PatternTileSet {
             tiles: PatternMap::new(tiles),
@@ -251,6 +1054,7 @@ impl<T, I> From<PatternTileSet<T, I>> for TileSet<T, I> {
         TileSet {
             tiles: tiles.into_raw(),
             tile_size,
+            occurrences: Vec::new(),
         }
     }
 }
@@ -261,6 +1065,49 @@ pub struct PatternTileSet<T, I> {
     pub tile_size: lat::Point,
 }
 
+impl<T: Clone, I: Clone + Indexer> PatternTileSet<T, I> {
+    /// Converts every voxel of every tile with `f`, e.g. to get a serializable voxel type.
+    pub fn map_voxels<S: Clone, F: Fn(&T) -> S>(&self, f: F) -> PatternTileSet<S, I> {
+        let tile_extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), self.tile_size);
+        let tiles = self.tiles.map(|tile| {
+            let mapped_lattice = tile.clone().put_in_extent(tile_extent).map(&f);
+
+            Tile::get_from_map(&mapped_lattice, &tile_extent)
+        });
+
+        PatternTileSet {
+            tiles,
+            tile_size: self.tile_size,
+        }
+    }
+
+    /// Finds the output slot that covers `voxel`, along with every pattern whose tile has `value`
+    /// at the corresponding position. Constraining that slot to those patterns pins down a single
+    /// voxel of the output.
+    pub fn patterns_with_voxel(&self, voxel: &lat::Point, value: &T) -> (lat::Point, PatternSet)
+    where
+        T: PartialEq,
+    {
+        let [vx, vy, vz]: [i32; 3] = (*voxel).into();
+        let [sx, sy, sz]: [i32; 3] = self.tile_size.into();
+        let slot: lat::Point = [vx.div_euclid(sx), vy.div_euclid(sy), vz.div_euclid(sz)].into();
+        let voxel_in_tile = *voxel - slot * self.tile_size;
+
+        let tile_extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), self.tile_size);
+        let mut matching = PatternSet::all(self.tiles.num_elements() as PatternIndex);
+        for (pattern, tile) in self.tiles.iter() {
+            let tile_lattice = tile.clone().put_in_extent(tile_extent);
+            if tile_lattice.get_world_ref(&voxel_in_tile) != value {
+                matching.remove(pattern);
+            }
+        }
+
+        (slot, matching)
+    }
+}
+
 /// Used to build the set of pattern relations. Enforces symmetry of the `compatible` relation.
 pub struct PatternConstraints {
     constraints: PatternMap<OffsetMap<BitSet>>,
@@ -286,16 +1133,31 @@ impl PatternConstraints {
         &self.offset_group
     }
 
-    fn assert_valid(&self) {
-        for (_, c) in self.constraints.iter() {
-            for i in 0..self.offset_group.num_offsets() {
-                assert!(!c.get(OffsetId(i)).is_empty());
+    pub(crate) fn assert_valid(&self) {
+        if let Err(e) = self.validate() {
+            panic!("{}", e);
+        }
+    }
+
+    /// Checks that every pattern is compatible with some pattern at every offset. A pattern that
+    /// isn't could never be placed, which usually means too many adjacencies were removed.
+    pub fn validate(&self) -> Result<(), WfcError> {
+        for (pattern, c) in self.constraints.iter() {
+            for (offset_id, offset) in self.offset_group.iter() {
+                if c.get(offset_id).is_empty() {
+                    return Err(WfcError::NoCompatiblePatterns {
+                        pattern,
+                        offset: *offset,
+                    });
+                }
             }
         }
+
+        Ok(())
     }
 
-    pub fn num_patterns(&self) -> u16 {
-        self.constraints.num_elements() as u16
+    pub fn num_patterns(&self) -> PatternIndex {
+        self.constraints.num_elements() as PatternIndex
     }
 
     pub fn iter_compatible(
@@ -307,7 +1169,7 @@ impl PatternConstraints {
             .get(pattern)
             .get(offset)
             .iter()
-            .map(|i| PatternId(i as u16))
+            .map(PatternId)
     }
 
     pub fn are_compatible(
@@ -319,11 +1181,11 @@ impl PatternConstraints {
         self.constraints
             .get(pattern)
             .get(offset)
-            .contains(offset_pattern.0 as u32)
+            .contains(offset_pattern.0)
     }
 
-    pub fn num_compatible(&self, pattern: PatternId, offset: OffsetId) -> u16 {
-        self.iter_compatible(pattern, offset).count() as u16
+    pub fn num_compatible(&self, pattern: PatternId, offset: OffsetId) -> PatternIndex {
+        self.iter_compatible(pattern, offset).count() as PatternIndex
     }
 
     pub fn add_compatible_patterns(
@@ -336,32 +1198,87 @@ impl PatternConstraints {
         self.constraints
             .get_mut(pattern)
             .get_mut(offset_id)
-            .add(offset_pattern.0 as u32);
+            .add(offset_pattern.0);
 
-        let opposite_id = self.offset_group.offset_id(&-*offset);
+        let opposite_id = self.offset_group.opposite(offset_id);
         self.constraints
             .get_mut(offset_pattern)
             .get_mut(opposite_id)
-            .add(pattern.0 as u32);
+            .add(pattern.0);
     }
 
-    /// For a fully undetermined `Wave`, return the support map for one slot.
-    pub fn get_initial_support(&self) -> PatternMap<PatternSupport> {
-        let mut pattern_supports = PatternMap::fill(
-            PatternSupport {
-                counts: OffsetMap::fill(0, self.offset_group.num_offsets()),
-            },
-            self.num_patterns() as usize,
-        );
+    /// The inverse of `add_compatible_patterns`, e.g. to forbid an adjacency that was observed in
+    /// the input. Call `validate` afterward.
+    pub fn remove_compatible_patterns(
+        &mut self,
+        offset: &lat::Point,
+        pattern: PatternId,
+        offset_pattern: PatternId,
+    ) {
+        let offset_id = self.offset_group.offset_id(offset);
+        self.constraints
+            .get_mut(pattern)
+            .get_mut(offset_id)
+            .remove(offset_pattern.0);
+
+        let opposite_id = self.offset_group.opposite(offset_id);
+        self.constraints
+            .get_mut(offset_pattern)
+            .get_mut(opposite_id)
+            .remove(pattern.0);
+    }
+
+    /// Makes every pair of patterns compatible at each offset where their voxels agree on the
+    /// overlap. `pattern_voxels` holds the voxels of each pattern (by ID), all with the same extent,
+    /// and adjacent slots are `stride` voxels apart.
+    pub fn add_agreeing_patterns<T, I>(
+        &mut self,
+        pattern_voxels: &[VecLatticeMap<T, I>],
+        stride: &lat::Point,
+    ) where
+        T: Clone + Eq,
+        I: Indexer,
+    {
+        // Agreement is symmetric, and `add_compatible_patterns` also sets the opposite offset, so
+        // only half of the offsets need to be checked.
+        let mut offsets: Vec<lat::Point> = Vec::new();
+        for (_, offset) in self.offset_group.iter() {
+            if !offsets.contains(&-*offset) {
+                offsets.push(*offset);
+            }
+        }
+        for offset in offsets.iter() {
+            let displacement = *offset * *stride;
+            for (a, a_voxels) in pattern_voxels.iter().enumerate() {
+                for (b, b_voxels) in pattern_voxels.iter().enumerate() {
+                    if patterns_agree(a_voxels, b_voxels, &displacement) {
+                        self.add_compatible_patterns(
+                            offset,
+                            PatternId::from(a),
+                            PatternId::from(b),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// For a fully undetermined `Wave`, return the support counts of one slot, by pattern and then
+    /// offset, as laid out in `SupportCounts`.
+    pub(crate) fn get_initial_support(&self) -> Vec<SupportCount> {
+        let mut counts =
+            Vec::with_capacity(self.num_patterns() as usize * self.offset_group.num_offsets());
         for pattern in (0..self.num_patterns()).map(PatternId) {
             for offset in (0..self.offset_group.num_offsets()).map(OffsetId) {
                 // If P1 allows P2 to be at offset, then P2 allows P1 to be at -offset.
-                *pattern_supports.get_mut(pattern).counts.get_mut(offset) =
-                    self.num_compatible(pattern, self.offset_group.opposite(offset)) as i16;
+                counts.push(
+                    self.num_compatible(pattern, self.offset_group.opposite(offset))
+                        as SupportCount,
+                );
             }
         }
 
-        pattern_supports
+        counts
     }
 
     pub fn assignment_is_valid<I: Indexer>(
@@ -387,44 +1304,325 @@ impl PatternConstraints {
     }
 }
 
-/// A dynamic structure that tracks, for a pattern P (in some slot), how many patterns are
-/// compatible with P at each offset. Once no patterns are compatible with P at some offset, P
-/// is not possible.
-#[derive(Clone)]
-pub struct PatternSupport {
-    counts: OffsetMap<i16>,
+/// Tracks, for each pattern P in each slot, how many patterns are compatible with P at each offset.
+/// Once no patterns are compatible with P at some offset, P is not possible.
+///
+/// Propagation decrements these counts more than anything else, so they're stored in one flat
+/// buffer, indexed by slot, then pattern, then offset, rather than allocated separately per slot.
+pub(crate) struct SupportCounts {
+    counts: SupportStorage,
+    /// Where the counts of each slot start, once `retain_slots` has dropped some. Until then, every
+    /// slot has counts, in order.
+    slot_starts: Option<Vec<usize>>,
+    num_patterns: usize,
+    num_offsets: usize,
+}
+
+/// The start of a slot dropped by `SupportCounts::retain_slots`.
+const DROPPED_SLOT: usize = usize::MAX;
+
+enum SupportStorage {
+    Heap(Vec<SupportCount>),
+    /// A file mapped into memory, so the OS can page counts out to disk.
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::MmapMut),
 }
 
-impl PatternSupport {
-    /// Returns `true` iff `pattern` no longer gives any support.
-    pub fn remove(&mut self, offset: OffsetId) -> bool {
-        let count = self.counts.get_mut(offset);
+impl SupportStorage {
+    fn as_slice(&self) -> &[SupportCount] {
+        match self {
+            SupportStorage::Heap(counts) => counts,
+            #[cfg(feature = "mmap")]
+            SupportStorage::Mapped(map) => bytemuck::cast_slice(map),
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [SupportCount] {
+        match self {
+            SupportStorage::Heap(counts) => counts,
+            #[cfg(feature = "mmap")]
+            SupportStorage::Mapped(map) => bytemuck::cast_slice_mut(map),
+        }
+    }
+}
+
+/// Supports can't exceed the number of patterns, which `MAX_PATTERNS` keeps well within an `i32`.
+pub(crate) type SupportCount = i32;
+
+impl SupportCounts {
+    /// Starts every slot with the supports of `PatternConstraints::get_initial_support`.
+    pub fn new(num_slots: usize, constraints: &PatternConstraints) -> Self {
+        let initial = constraints.get_initial_support();
+
+        SupportCounts {
+            counts: SupportStorage::Heap(initial.repeat(num_slots)),
+            slot_starts: None,
+            num_patterns: constraints.num_patterns() as usize,
+            num_offsets: constraints.get_offset_group().num_offsets(),
+        }
+    }
+
+    /// Like `new`, but the counts are kept in the file at `path`, which is created or overwritten.
+    #[cfg(feature = "mmap")]
+    pub fn mapped(
+        num_slots: usize,
+        constraints: &PatternConstraints,
+        path: &Path,
+    ) -> io::Result<Self> {
+        let initial = constraints.get_initial_support();
+        let num_bytes = num_slots * initial.len() * std::mem::size_of::<SupportCount>();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(num_bytes as u64)?;
+        // The mapping is only unsound if the file is changed by someone else while it's mapped.
+        let mut map = unsafe { memmap2::MmapOptions::new().len(num_bytes).map_mut(&file)? };
+        let counts: &mut [SupportCount] = bytemuck::cast_slice_mut(&mut map);
+        for slot_counts in counts.chunks_mut(initial.len()) {
+            slot_counts.copy_from_slice(&initial);
+        }
+
+        Ok(SupportCounts {
+            counts: SupportStorage::Mapped(map),
+            slot_starts: None,
+            num_patterns: constraints.num_patterns() as usize,
+            num_offsets: constraints.get_offset_group().num_offsets(),
+        })
+    }
+
+    /// `counts` must be laid out like `to_vec`.
+    pub fn from_counts(counts: Vec<SupportCount>, num_patterns: usize, num_offsets: usize) -> Self {
+        debug_assert_eq!(counts.len() % (num_patterns * num_offsets), 0);
+
+        SupportCounts {
+            counts: SupportStorage::Heap(counts),
+            slot_starts: None,
+            num_patterns,
+            num_offsets,
+        }
+    }
+
+    pub fn num_patterns(&self) -> usize {
+        self.num_patterns
+    }
+
+    pub fn num_offsets(&self) -> usize {
+        self.num_offsets
+    }
+
+    /// Every count, by slot, then pattern, then offset. Dropped slots are all zeros.
+    pub fn to_vec(&self) -> Vec<SupportCount> {
+        let slot_starts = match &self.slot_starts {
+            Some(starts) => starts,
+            None => return self.counts.as_slice().to_vec(),
+        };
+        let counts = self.counts.as_slice();
+        let slot_len = self.num_patterns * self.num_offsets;
+        let mut all = Vec::with_capacity(slot_starts.len() * slot_len);
+        for &start in slot_starts.iter() {
+            if start == DROPPED_SLOT {
+                all.resize(all.len() + slot_len, 0);
+            } else {
+                all.extend_from_slice(&counts[start..start + slot_len]);
+            }
+        }
+
+        all
+    }
+
+    /// Frees the counts of every slot where `keep` is `false`. Their counts must not be used until
+    /// `restore_slot` is called. Mapped counts aren't compacted, since the OS already pages out the
+    /// ones that aren't used. Returns how many slots were freed by this call.
+    pub fn retain_slots(&mut self, num_slots: usize, keep: impl Fn(usize) -> bool) -> usize {
+        let old_counts = match &self.counts {
+            SupportStorage::Heap(counts) => counts,
+            #[cfg(feature = "mmap")]
+            SupportStorage::Mapped(_) => return 0,
+        };
+        let slot_len = self.num_patterns * self.num_offsets;
+        let mut counts = Vec::new();
+        let mut slot_starts = Vec::with_capacity(num_slots);
+        let mut num_freed = 0;
+        for slot_index in 0..num_slots {
+            let old_start = self.slot_start(slot_index);
+            if old_start == DROPPED_SLOT {
+                slot_starts.push(DROPPED_SLOT);
+            } else if keep(slot_index) {
+                slot_starts.push(counts.len());
+                counts.extend_from_slice(&old_counts[old_start..old_start + slot_len]);
+            } else {
+                slot_starts.push(DROPPED_SLOT);
+                num_freed += 1;
+            }
+        }
+        self.counts = SupportStorage::Heap(counts);
+        self.slot_starts = Some(slot_starts);
+
+        num_freed
+    }
+
+    /// Gives a slot dropped by `retain_slots` counts again, all zero.
+    pub fn restore_slot(&mut self, slot_index: usize) {
+        if let (Some(starts), SupportStorage::Heap(counts)) =
+            (&mut self.slot_starts, &mut self.counts)
+        {
+            if starts[slot_index] == DROPPED_SLOT {
+                starts[slot_index] = counts.len();
+                counts.resize(counts.len() + self.num_patterns * self.num_offsets, 0);
+            }
+        }
+    }
+
+    /// The counts of `pattern` in `slot_index`, by offset.
+    pub fn get(&self, slot_index: usize, pattern: PatternId) -> &[SupportCount] {
+        let start = self.pattern_start(slot_index, pattern);
+
+        &self.counts.as_slice()[start..start + self.num_offsets]
+    }
+
+    pub fn get_mut(&mut self, slot_index: usize, pattern: PatternId) -> &mut [SupportCount] {
+        let start = self.pattern_start(slot_index, pattern);
+
+        &mut self.counts.as_mut_slice()[start..start + self.num_offsets]
+    }
+
+    /// Returns `true` iff `pattern` in `slot_index` no longer has any support at `offset`.
+    pub fn remove(&mut self, slot_index: usize, pattern: PatternId, offset: OffsetId) -> bool {
+        let index = self.pattern_start(slot_index, pattern) + offset.0;
+        let count = &mut self.counts.as_mut_slice()[index];
         *count -= 1;
 
         *count == 0
     }
 
-    pub fn clear(&mut self) {
-        self.counts
-            .iter_mut()
-            .for_each(|(_offset, count)| *count = 0);
+    pub fn clear(&mut self, slot_index: usize, pattern: PatternId) {
+        for count in self.get_mut(slot_index, pattern) {
+            *count = 0;
+        }
+    }
+
+    fn pattern_start(&self, slot_index: usize, pattern: PatternId) -> usize {
+        let slot_start = self.slot_start(slot_index);
+        debug_assert_ne!(slot_start, DROPPED_SLOT);
+
+        slot_start + pattern.0 as usize * self.num_offsets
+    }
+
+    fn slot_start(&self, slot_index: usize) -> usize {
+        match &self.slot_starts {
+            Some(starts) => starts[slot_index],
+            None => slot_index * self.num_patterns * self.num_offsets,
+        }
     }
 }
 
 pub type PatternMap<T> = StaticVec<PatternId, T>;
 
-#[derive(Clone)]
+/// The minimum voxel of every occurrence of each pattern in the input.
+pub type PatternPositions = PatternMap<Vec<lat::Point>>;
+
+/// Models with at most this many patterns store each `PatternSet` in a single `u128`.
+pub(crate) const SMALL_SET_CAPACITY: PatternIndex = 128;
+
+/// A set of patterns, e.g. the ones still possible in a slot. Small sets of patterns (see
+/// `SMALL_SET_CAPACITY`) are stored inline, so most models don't allocate a set per slot. A set
+/// switches to a `BitSet` the first time it gets a pattern that doesn't fit.
+#[derive(Clone, Debug)]
 pub struct PatternSet {
-    bits: BitSet,
-    size: u16,
+    bits: PatternBits,
+    size: PatternIndex,
+}
+
+#[derive(Clone, Debug)]
+enum PatternBits {
+    Small(SmallPatternSet),
+    Large(BitSet),
+}
+
+/// The bits of up to `SMALL_SET_CAPACITY` patterns.
+#[derive(Clone, Copy, Debug, Default)]
+struct SmallPatternSet(u128);
+
+impl SmallPatternSet {
+    fn fits(pattern: PatternIndex) -> bool {
+        pattern < SMALL_SET_CAPACITY
+    }
+
+    /// Returns `true` iff `pattern` was already in the set.
+    fn add(&mut self, pattern: PatternIndex) -> bool {
+        let was_present = self.contains(pattern);
+        self.0 |= 1 << pattern;
+
+        was_present
+    }
+
+    /// Returns `true` iff `pattern` was in the set.
+    fn remove(&mut self, pattern: PatternIndex) -> bool {
+        let was_present = self.contains(pattern);
+        self.0 &= !(1 << pattern);
+
+        was_present
+    }
+
+    fn contains(&self, pattern: PatternIndex) -> bool {
+        Self::fits(pattern) && self.0 & (1 << pattern) != 0
+    }
+}
+
+/// Iterates over a `PatternSet` in increasing order.
+enum PatternSetIter<'a> {
+    Small(u128),
+    Large(BitIter<&'a BitSet>),
+}
+
+impl<'a> Iterator for PatternSetIter<'a> {
+    type Item = PatternId;
+
+    fn next(&mut self) -> Option<PatternId> {
+        match self {
+            PatternSetIter::Small(bits) => {
+                if *bits == 0 {
+                    return None;
+                }
+                let pattern = bits.trailing_zeros();
+                // Clear the lowest set bit.
+                *bits &= *bits - 1;
+
+                Some(PatternId(pattern))
+            }
+            PatternSetIter::Large(iter) => iter.next().map(PatternId),
+        }
+    }
 }
 
 impl PatternSet {
-    pub fn all(num_patterns: u16) -> Self {
-        let mut bits = BitSet::with_capacity(num_patterns as u32);
-        for i in 0..num_patterns {
-            bits.add(i as u32);
+    pub fn empty() -> Self {
+        PatternSet {
+            bits: PatternBits::Small(SmallPatternSet::default()),
+            size: 0,
         }
+    }
+
+    pub fn all(num_patterns: PatternIndex) -> Self {
+        let bits = if num_patterns <= SMALL_SET_CAPACITY {
+            let mut small = SmallPatternSet::default();
+            for i in 0..num_patterns {
+                small.add(i);
+            }
+
+            PatternBits::Small(small)
+        } else {
+            let mut large = BitSet::with_capacity(num_patterns);
+            for i in 0..num_patterns {
+                large.add(i);
+            }
+
+            PatternBits::Large(large)
+        };
 
         PatternSet {
             size: num_patterns,
@@ -432,20 +1630,221 @@ impl PatternSet {
         }
     }
 
-    pub fn len(&self) -> u16 {
+    pub fn len(&self) -> PatternIndex {
         self.size
     }
 
+    /// Returns `true` iff `pattern` was not already in the set.
+    pub fn insert(&mut self, pattern: PatternId) -> bool {
+        if let PatternBits::Small(small) = self.bits {
+            if !SmallPatternSet::fits(pattern.0) {
+                let mut large = BitSet::with_capacity(pattern.0 + 1);
+                for p in PatternSetIter::Small(small.0) {
+                    large.add(p.0);
+                }
+                self.bits = PatternBits::Large(large);
+            }
+        }
+        let was_present = match &mut self.bits {
+            PatternBits::Small(small) => small.add(pattern.0),
+            PatternBits::Large(large) => large.add(pattern.0),
+        };
+        if !was_present {
+            self.size += 1;
+        }
+
+        !was_present
+    }
+
     pub fn remove(&mut self, pattern: PatternId) {
-        self.bits.remove(pattern.0 as u32);
-        self.size -= 1;
+        let was_present = match &mut self.bits {
+            PatternBits::Small(small) => {
+                SmallPatternSet::fits(pattern.0) && small.remove(pattern.0)
+            }
+            PatternBits::Large(large) => large.remove(pattern.0),
+        };
+        if was_present {
+            self.size -= 1;
+        }
+    }
+
+    pub fn contains(&self, pattern: PatternId) -> bool {
+        match &self.bits {
+            PatternBits::Small(small) => small.contains(pattern.0),
+            PatternBits::Large(large) => large.contains(pattern.0),
+        }
     }
 
     pub fn iter(&self) -> impl Iterator<Item = PatternId> + '_ {
-        (&self.bits).iter().map(|i| PatternId(i as u16))
+        match &self.bits {
+            PatternBits::Small(small) => PatternSetIter::Small(small.0),
+            PatternBits::Large(large) => PatternSetIter::Large(large.iter()),
+        }
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Rebuilds a `BitSet` to fit only the patterns left, inline if they all fit, e.g. once a slot
+    /// has collapsed.
+    pub fn shrink_to_fit(&mut self) {
+        if let PatternBits::Large(_) = self.bits {
+            *self = self.iter().collect();
+        }
+    }
+
+    /// The patterns in either set.
+    pub fn union(&self, other: &PatternSet) -> PatternSet {
+        match (&self.bits, &other.bits) {
+            (PatternBits::Small(a), PatternBits::Small(b)) => {
+                let bits = a.0 | b.0;
+
+                PatternSet {
+                    bits: PatternBits::Small(SmallPatternSet(bits)),
+                    size: bits.count_ones(),
+                }
+            }
+            (PatternBits::Large(a), PatternBits::Large(b)) => {
+                BitSetOr(a, b).iter().map(PatternId).collect()
+            }
+            _ => self.iter().chain(other.iter()).collect(),
+        }
+    }
+
+    /// Removes the patterns that aren't in `other`.
+    pub fn intersect_with(&mut self, other: &PatternSet) {
+        if let (PatternBits::Small(a), PatternBits::Small(b)) = (&mut self.bits, &other.bits) {
+            a.0 &= b.0;
+            self.size = a.0.count_ones();
+
+            return;
+        }
+
+        let removed: Vec<PatternId> = self.iter().filter(|p| !other.contains(*p)).collect();
+        for pattern in removed.into_iter() {
+            self.remove(pattern);
+        }
+    }
+
+    /// The patterns in this set but not in `other`.
+    pub fn difference(&self, other: &PatternSet) -> PatternSet {
+        self.iter().filter(|p| !other.contains(*p)).collect()
+    }
+
+    /// The patterns (out of `num_patterns`) that aren't in this set.
+    pub fn complement(&self, num_patterns: PatternIndex) -> PatternSet {
+        (0..num_patterns)
+            .map(PatternId)
+            .filter(|p| !self.contains(*p))
+            .collect()
+    }
+}
+
+impl std::iter::FromIterator<PatternId> for PatternSet {
+    fn from_iter<It: IntoIterator<Item = PatternId>>(iter: It) -> Self {
+        let mut set = PatternSet::empty();
+        for pattern in iter {
+            set.insert(pattern);
+        }
+
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Model sizes on either side of the switch from inline bits to a `BitSet`.
+    const BOUNDARY_SIZES: [PatternIndex; 3] = [
+        SMALL_SET_CAPACITY - 1,
+        SMALL_SET_CAPACITY,
+        SMALL_SET_CAPACITY + 1,
+    ];
+
+    fn indices(set: &PatternSet) -> Vec<PatternIndex> {
+        set.iter().map(|p| p.0).collect()
+    }
+
+    fn set_of(patterns: impl Iterator<Item = PatternIndex>) -> PatternSet {
+        patterns.map(PatternId).collect()
+    }
+
+    #[test]
+    fn all_holds_every_pattern() {
+        for &n in BOUNDARY_SIZES.iter() {
+            let all = PatternSet::all(n);
+            assert_eq!(all.len(), n);
+            assert!(!all.is_empty());
+            assert_eq!(indices(&all), (0..n).collect::<Vec<_>>());
+            assert!(all.contains(PatternId(n - 1)));
+            assert!(!all.contains(PatternId(n)));
+        }
+    }
+
+    #[test]
+    fn union_and_intersection_at_capacity_boundary() {
+        for &n in BOUNDARY_SIZES.iter() {
+            let evens = set_of((0..n).filter(|i| i % 2 == 0));
+            let odds = set_of((0..n).filter(|i| i % 2 == 1));
+
+            let union = evens.union(&odds);
+            assert_eq!(union.len(), n);
+            assert_eq!(indices(&union), (0..n).collect::<Vec<_>>());
+
+            let mut intersection = evens.clone();
+            intersection.intersect_with(&odds);
+            assert!(intersection.is_empty());
+            assert_eq!(intersection.len(), 0);
+            assert_eq!(intersection.iter().next(), None);
+
+            let mut evens_of_all = PatternSet::all(n);
+            evens_of_all.intersect_with(&evens);
+            assert_eq!(evens_of_all.len(), evens.len());
+            assert_eq!(indices(&evens_of_all), indices(&evens));
+        }
+    }
+
+    #[test]
+    fn inline_and_bitset_sets_mix() {
+        let last_inline = PatternId(SMALL_SET_CAPACITY - 1);
+        let first_large = PatternId(SMALL_SET_CAPACITY);
+        let small = set_of([0, SMALL_SET_CAPACITY - 1].iter().cloned());
+        let large = set_of([1, SMALL_SET_CAPACITY].iter().cloned());
+
+        let union = small.union(&large);
+        assert_eq!(union.len(), 4);
+        assert_eq!(
+            indices(&union),
+            vec![0, 1, SMALL_SET_CAPACITY - 1, SMALL_SET_CAPACITY]
+        );
+        assert_eq!(indices(&large.union(&small)), indices(&union));
+
+        let mut small_of_union = union.clone();
+        small_of_union.intersect_with(&small);
+        assert_eq!(indices(&small_of_union), indices(&small));
+
+        let mut large_of_union = union;
+        large_of_union.intersect_with(&large);
+        assert_eq!(indices(&large_of_union), indices(&large));
+
+        let mut disjoint = small.clone();
+        disjoint.intersect_with(&large);
+        assert!(disjoint.is_empty());
+
+        // Inserting past the inline capacity keeps the patterns already there.
+        let mut grown = small;
+        assert!(grown.insert(first_large));
+        assert!(!grown.insert(first_large));
+        assert_eq!(
+            indices(&grown),
+            vec![0, SMALL_SET_CAPACITY - 1, SMALL_SET_CAPACITY]
+        );
+        grown.remove(first_large);
+        grown.shrink_to_fit();
+        assert_eq!(indices(&grown), vec![0, SMALL_SET_CAPACITY - 1]);
+        assert!(grown.contains(last_inline));
+        assert!(!grown.contains(first_large));
+    }
 }
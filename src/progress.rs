@@ -0,0 +1,77 @@
+//! Driving a `Generator` with an iterator, for `for` loops, progress bars, and channels.
+
+use crate::{
+    generate::{Generator, UpdateResult},
+    pattern::{PatternConstraints, PatternSampler},
+};
+
+use ilattice3 as lat;
+use rand::Rng;
+use rand_pcg::Pcg64Mcg;
+
+/// The state of a `Generator` after one update, yielded by `Frames`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// What the update returned. Only the last item isn't `Continue`.
+    pub result: UpdateResult,
+    pub num_collapsed: usize,
+    pub num_slots: usize,
+    /// The slot that the update observed, if any.
+    pub observed_slot: Option<lat::Point>,
+}
+
+/// Calls `Generator::update` once per item, until the output is fully assigned or a contradiction
+/// is found. Made by `Generator::frames`. Between items, the generator is available from
+/// `generator`, e.g. to draw the wave:
+///
+/// ```text
+/// let mut frames = generator.frames(&sampler, &constraints);
+/// while let Some(progress) = frames.next() {
+///     draw(frames.generator().get_wave_lattice());
+/// }
+/// ```
+pub struct Frames<'a, R = Pcg64Mcg> {
+    generator: &'a mut Generator<R>,
+    sampler: &'a PatternSampler,
+    constraints: &'a PatternConstraints,
+    done: bool,
+}
+
+impl<'a, R: Rng> Frames<'a, R> {
+    pub(crate) fn new(
+        generator: &'a mut Generator<R>,
+        sampler: &'a PatternSampler,
+        constraints: &'a PatternConstraints,
+    ) -> Self {
+        Frames {
+            generator,
+            sampler,
+            constraints,
+            done: false,
+        }
+    }
+
+    pub fn generator(&self) -> &Generator<R> {
+        self.generator
+    }
+}
+
+impl<R: Rng> Iterator for Frames<'_, R> {
+    type Item = Progress;
+
+    fn next(&mut self) -> Option<Progress> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.generator.update(self.sampler, self.constraints);
+        self.done = result != UpdateResult::Continue;
+
+        Some(Progress {
+            result,
+            num_collapsed: self.generator.num_collapsed(),
+            num_slots: self.generator.num_slots(),
+            observed_slot: self.generator.last_observed_slot(),
+        })
+    }
+}
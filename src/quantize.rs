@@ -0,0 +1,118 @@
+//! Reducing the colors of image inputs, so photographs and anti-aliased art don't turn every slight
+//! shade into patterns of their own.
+
+use ilattice3::{prelude::*, Indexer, VecLatticeMap};
+use image::Rgba;
+use std::collections::HashMap;
+
+/// Reduces the colors of `lattice` to at most `num_colors` by median cut: the colors are split in
+/// two at the median of the channel with the widest range, over and over, and each group becomes
+/// the mean of its colors, weighted by how many voxels have them.
+pub fn quantize_colors<I: Clone + Indexer>(
+    lattice: &VecLatticeMap<Rgba<u8>, I>,
+    num_colors: usize,
+) -> VecLatticeMap<Rgba<u8>, I> {
+    let mut counts: HashMap<Rgba<u8>, u32> = HashMap::new();
+    for p in lattice.get_extent() {
+        *counts.entry(*lattice.get_world_ref(&p)).or_insert(0) += 1;
+    }
+    if counts.len() <= num_colors {
+        return lattice.clone();
+    }
+
+    let mut boxes = vec![counts.into_iter().collect::<Vec<_>>()];
+    while boxes.len() < num_colors.max(1) {
+        // Split the box with the widest range on any channel.
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest_channel(b)))
+            .max_by_key(|(_, (_, range))| *range);
+        let (i, channel) = match widest {
+            Some((i, (channel, _))) => (i, channel),
+            None => break,
+        };
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_by_key(|(c, _)| c.0[channel]);
+        let total: u32 = colors.iter().map(|(_, n)| n).sum();
+        let mut below = 0;
+        let mut split = 1;
+        for (j, (_, n)) in colors.iter().enumerate().take(colors.len() - 1) {
+            below += n;
+            split = j + 1;
+            if 2 * below >= total {
+                break;
+            }
+        }
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    let mut quantized = HashMap::new();
+    for colors in boxes.iter() {
+        let mean = mean_color(colors);
+        for (c, _) in colors.iter() {
+            quantized.insert(*c, mean);
+        }
+    }
+
+    lattice.map(|c| quantized[c])
+}
+
+/// Replaces every color of `lattice` with the nearest color in `palette`, e.g. to bring more
+/// inputs to the colors of one made by `quantize_colors`. `palette` must not be empty.
+pub fn map_to_palette<I: Clone + Indexer>(
+    lattice: &VecLatticeMap<Rgba<u8>, I>,
+    palette: &[Rgba<u8>],
+) -> VecLatticeMap<Rgba<u8>, I> {
+    assert!(!palette.is_empty(), "Can't map colors to an empty palette");
+    let distance = |a: &Rgba<u8>, b: &Rgba<u8>| -> u32 {
+        a.0.iter()
+            .zip(b.0.iter())
+            .map(|(x, y)| (*x as i32 - *y as i32).pow(2) as u32)
+            .sum()
+    };
+    let mut nearest = HashMap::new();
+    for p in lattice.get_extent() {
+        let c = lattice.get_world_ref(&p);
+        nearest
+            .entry(*c)
+            .or_insert_with(|| *palette.iter().min_by_key(|p| distance(c, p)).unwrap());
+    }
+
+    lattice.map(|c| nearest[c])
+}
+
+/// The channel of `colors` with the widest range, and that range.
+fn widest_channel(colors: &[(Rgba<u8>, u32)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let values = colors.iter().map(|(c, _)| c.0[channel]);
+            let min = values.clone().min().unwrap_or(0);
+            let max = values.max().unwrap_or(0);
+
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+fn mean_color(colors: &[(Rgba<u8>, u32)]) -> Rgba<u8> {
+    let mut sum = [0u64; 4];
+    let mut total = 0u64;
+    for (c, n) in colors.iter() {
+        for (s, v) in sum.iter_mut().zip(c.0.iter()) {
+            *s += *v as u64 * *n as u64;
+        }
+        total += *n as u64;
+    }
+
+    let mut mean = [0; 4];
+    for (m, s) in mean.iter_mut().zip(sum.iter()) {
+        *m = ((*s + total / 2) / total) as u8;
+    }
+
+    Rgba(mean)
+}
@@ -0,0 +1,315 @@
+//! Exporting outputs as Minecraft schematics.
+
+use flate2::{write::GzEncoder, Compression};
+use ilattice3 as lat;
+use ilattice3::{prelude::*, Indexer, VecLatticeMap};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// The Minecraft version the schematics claim to be from (1.16.5). Newer versions upgrade the
+/// blocks when loading.
+const DATA_VERSION: i32 = 2586;
+
+const AIR: &str = "minecraft:air";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SchematicFormat {
+    /// Sponge schematic version 2 (.schem), as read by WorldEdit.
+    Schem,
+    /// Litematica (.litematic).
+    Litematic,
+}
+
+/// Writes `lattice` as a gzipped schematic, with one block per point. `block` gives the block state
+/// of each value, e.g. "minecraft:stone" or "minecraft:oak_log[axis=x]"; `None` is air.
+///
+/// The lattice's Z axis is up, like in MagicaVoxel, so it becomes Minecraft's Y axis, and the
+/// lattice's Y axis becomes Minecraft's Z axis.
+pub fn write_schematic<'a, W, T, I, F>(
+    writer: W,
+    format: SchematicFormat,
+    lattice: &VecLatticeMap<T, I>,
+    block: F,
+) -> io::Result<()>
+where
+    W: Write,
+    I: Indexer,
+    F: Fn(&T) -> Option<&'a str>,
+{
+    let extent = lattice.get_extent();
+    let size = *extent.get_local_supremum();
+    if size.x > 0xffff || size.y > 0xffff || size.z > 0xffff {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Schematics can't be larger than 65535 per axis, not {}",
+                size
+            ),
+        ));
+    }
+
+    // Air is always first in the palette, so it's the default for Litematica.
+    let mut palette = vec![AIR];
+    let mut palette_ids: HashMap<&str, usize> = HashMap::new();
+    palette_ids.insert(AIR, 0);
+    // In Minecraft's YZX order.
+    let mut block_ids = Vec::with_capacity(extent.volume());
+    for y in 0..size.z {
+        for z in 0..size.y {
+            for x in 0..size.x {
+                let p = *extent.get_minimum() + [x, z, y].into();
+                let name = block(lattice.get_world_ref(&p)).unwrap_or(AIR);
+                let id = *palette_ids.entry(name).or_insert_with(|| {
+                    palette.push(name);
+
+                    palette.len() - 1
+                });
+                block_ids.push(id);
+            }
+        }
+    }
+
+    let (root_name, root) = match format {
+        SchematicFormat::Schem => ("Schematic", schem(size, &palette, &block_ids)),
+        SchematicFormat::Litematic => ("", litematic(size, &palette, &block_ids)),
+    };
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    write_named_tag(&mut encoder, root_name, &root)?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+fn schem(size: lat::Point, palette: &[&str], block_ids: &[usize]) -> Tag {
+    let mut block_data = Vec::with_capacity(block_ids.len());
+    for id in block_ids.iter() {
+        // Unsigned LEB128.
+        let mut id = *id;
+        while id >= 0x80 {
+            block_data.push((id & 0x7f) as u8 | 0x80);
+            id >>= 7;
+        }
+        block_data.push(id as u8);
+    }
+
+    Tag::Compound(vec![
+        ("Version".to_string(), Tag::Int(2)),
+        ("DataVersion".to_string(), Tag::Int(DATA_VERSION)),
+        ("Width".to_string(), Tag::Short(size.x as u16 as i16)),
+        ("Height".to_string(), Tag::Short(size.z as u16 as i16)),
+        ("Length".to_string(), Tag::Short(size.y as u16 as i16)),
+        ("Offset".to_string(), Tag::IntArray(vec![0, 0, 0])),
+        ("PaletteMax".to_string(), Tag::Int(palette.len() as i32)),
+        (
+            "Palette".to_string(),
+            Tag::Compound(
+                palette
+                    .iter()
+                    .enumerate()
+                    .map(|(id, name)| (name.to_string(), Tag::Int(id as i32)))
+                    .collect(),
+            ),
+        ),
+        ("BlockData".to_string(), Tag::ByteArray(block_data)),
+        (
+            "BlockEntities".to_string(),
+            Tag::List(TAG_COMPOUND, Vec::new()),
+        ),
+    ])
+}
+
+fn litematic(size: lat::Point, palette: &[&str], block_ids: &[usize]) -> Tag {
+    // Each block takes the same number of bits, and may straddle two longs.
+    let mut bits = 2;
+    while (1 << bits) < palette.len() {
+        bits += 1;
+    }
+    let mut block_states = vec![0u64; (block_ids.len() * bits + 63) / 64];
+    for (i, id) in block_ids.iter().enumerate() {
+        let start = i * bits;
+        let (long, offset) = (start / 64, start % 64);
+        block_states[long] |= (*id as u64) << offset;
+        if offset + bits > 64 {
+            block_states[long + 1] |= (*id as u64) >> (64 - offset);
+        }
+    }
+
+    let xyz = |p: [i32; 3]| {
+        Tag::Compound(vec![
+            ("x".to_string(), Tag::Int(p[0])),
+            ("y".to_string(), Tag::Int(p[1])),
+            ("z".to_string(), Tag::Int(p[2])),
+        ])
+    };
+    let minecraft_size = [size.x, size.z, size.y];
+    let total_blocks = block_ids.iter().filter(|id| **id != 0).count();
+    let metadata = Tag::Compound(vec![
+        ("Name".to_string(), Tag::String("wfc".to_string())),
+        ("Author".to_string(), Tag::String(String::new())),
+        ("Description".to_string(), Tag::String(String::new())),
+        ("RegionCount".to_string(), Tag::Int(1)),
+        ("TotalBlocks".to_string(), Tag::Int(total_blocks as i32)),
+        ("TotalVolume".to_string(), Tag::Int(block_ids.len() as i32)),
+        ("EnclosingSize".to_string(), xyz(minecraft_size)),
+        ("TimeCreated".to_string(), Tag::Long(0)),
+        ("TimeModified".to_string(), Tag::Long(0)),
+    ]);
+    let region = Tag::Compound(vec![
+        ("Position".to_string(), xyz([0, 0, 0])),
+        ("Size".to_string(), xyz(minecraft_size)),
+        (
+            "BlockStatePalette".to_string(),
+            Tag::List(
+                TAG_COMPOUND,
+                palette.iter().map(|name| block_state(name)).collect(),
+            ),
+        ),
+        (
+            "BlockStates".to_string(),
+            Tag::LongArray(block_states.into_iter().map(|l| l as i64).collect()),
+        ),
+        ("Entities".to_string(), Tag::List(TAG_COMPOUND, Vec::new())),
+        (
+            "TileEntities".to_string(),
+            Tag::List(TAG_COMPOUND, Vec::new()),
+        ),
+        (
+            "PendingBlockTicks".to_string(),
+            Tag::List(TAG_COMPOUND, Vec::new()),
+        ),
+        (
+            "PendingFluidTicks".to_string(),
+            Tag::List(TAG_COMPOUND, Vec::new()),
+        ),
+    ]);
+
+    Tag::Compound(vec![
+        ("MinecraftDataVersion".to_string(), Tag::Int(DATA_VERSION)),
+        ("Version".to_string(), Tag::Int(5)),
+        ("Metadata".to_string(), metadata),
+        (
+            "Regions".to_string(),
+            Tag::Compound(vec![("wfc".to_string(), region)]),
+        ),
+    ])
+}
+
+/// Splits a block state like "minecraft:oak_log[axis=x]" into its name and properties.
+fn block_state(state: &str) -> Tag {
+    let (name, properties) = match state.find('[') {
+        Some(i) => (&state[..i], state[i + 1..].trim_end_matches(']')),
+        None => (state, ""),
+    };
+    let mut fields = vec![("Name".to_string(), Tag::String(name.to_string()))];
+    let properties: Vec<(String, Tag)> = properties
+        .split(',')
+        .filter_map(|property| {
+            let mut key_value = property.splitn(2, '=');
+            let key = key_value.next()?.trim();
+            let value = key_value.next()?.trim();
+
+            Some((key.to_string(), Tag::String(value.to_string())))
+        })
+        .collect();
+    if !properties.is_empty() {
+        fields.push(("Properties".to_string(), Tag::Compound(properties)));
+    }
+
+    Tag::Compound(fields)
+}
+
+const TAG_END: u8 = 0;
+const TAG_COMPOUND: u8 = 10;
+
+/// The subset of NBT that schematics need.
+enum Tag {
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    ByteArray(Vec<u8>),
+    String(String),
+    /// The type ID of the elements, and the elements.
+    List(u8, Vec<Tag>),
+    Compound(Vec<(String, Tag)>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    fn type_id(&self) -> u8 {
+        match self {
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_, _) => 9,
+            Tag::Compound(_) => TAG_COMPOUND,
+            Tag::IntArray(_) => 11,
+            Tag::LongArray(_) => 12,
+        }
+    }
+}
+
+fn write_named_tag<W: Write>(writer: &mut W, name: &str, tag: &Tag) -> io::Result<()> {
+    writer.write_all(&[tag.type_id()])?;
+    write_string(writer, name)?;
+
+    write_payload(writer, tag)
+}
+
+fn write_payload<W: Write>(writer: &mut W, tag: &Tag) -> io::Result<()> {
+    match tag {
+        Tag::Short(v) => writer.write_all(&v.to_be_bytes()),
+        Tag::Int(v) => writer.write_all(&v.to_be_bytes()),
+        Tag::Long(v) => writer.write_all(&v.to_be_bytes()),
+        Tag::ByteArray(bytes) => {
+            writer.write_all(&(bytes.len() as i32).to_be_bytes())?;
+            writer.write_all(bytes)
+        }
+        Tag::String(s) => write_string(writer, s),
+        Tag::List(element_type, elements) => {
+            let element_type = if elements.is_empty() {
+                TAG_END
+            } else {
+                *element_type
+            };
+            writer.write_all(&[element_type])?;
+            writer.write_all(&(elements.len() as i32).to_be_bytes())?;
+            for element in elements.iter() {
+                write_payload(writer, element)?;
+            }
+
+            Ok(())
+        }
+        Tag::Compound(fields) => {
+            for (name, field) in fields.iter() {
+                write_named_tag(writer, name, field)?;
+            }
+
+            writer.write_all(&[TAG_END])
+        }
+        Tag::IntArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            for v in values.iter() {
+                writer.write_all(&v.to_be_bytes())?;
+            }
+
+            Ok(())
+        }
+        Tag::LongArray(values) => {
+            writer.write_all(&(values.len() as i32).to_be_bytes())?;
+            for v in values.iter() {
+                writer.write_all(&v.to_be_bytes())?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(&(s.len() as u16).to_be_bytes())?;
+
+    writer.write_all(s.as_bytes())
+}
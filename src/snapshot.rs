@@ -0,0 +1,138 @@
+//! Saving and restoring a `Generator` mid-run, so long generations can be resumed after a crash or
+//! interruption.
+
+use crate::{
+    error::WfcError,
+    generate::{Generator, GeneratorConfig},
+    pattern::{PatternConstraints, PatternIndex, SupportCount},
+    wave::Wave,
+};
+
+use rand_pcg::Pcg64Mcg;
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fmt;
+use std::io::{Read, Write};
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"WFCS";
+const SNAPSHOT_VERSION: u32 = 5;
+
+#[derive(Deserialize, Serialize)]
+struct SnapshotFile {
+    magic: [u8; 4],
+    version: u32,
+    rng: Pcg64Mcg,
+    wave: WaveSnapshot,
+}
+
+/// The state of a `Wave` between updates, flattened into plain vectors.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct WaveSnapshot {
+    pub output_size: [i32; 3],
+    pub num_patterns: PatternIndex,
+    pub num_offsets: usize,
+    /// The possible patterns of each slot, by linear index.
+    pub slots: Vec<Vec<PatternIndex>>,
+    /// The cached sum of weights, sum of weight * log(weight), and entropy of each slot. These are
+    /// stored rather than recomputed so the resumed run makes exactly the same choices.
+    pub entropies: Vec<[f32; 3]>,
+    /// The fixed-point sums of each slot, for `EntropyMode::FixedPoint`. Empty otherwise.
+    pub fixed_entropies: Vec<[u128; 2]>,
+    pub entropy_noise: Vec<f32>,
+    /// The support counts of each slot, pattern, and offset, flattened in that order.
+    pub supports: Vec<SupportCount>,
+    /// The patterns each slot may hold after `reopen_region`, by linear index. Empty if every
+    /// pattern is allowed everywhere.
+    pub restrictions: Vec<Vec<PatternIndex>>,
+}
+
+/// Writes the state of `generator` to `writer`. Call this between updates.
+///
+/// Guidance, `PatternChooser`s, and `CountConstraint`s aren't saved, so they must be given again
+/// when loading. Generators propagating on the GPU can't be saved.
+pub fn save_snapshot<W: Write>(writer: W, generator: &Generator) -> Result<(), SnapshotError> {
+    let (rng, wave) = generator.parts();
+    if wave.uses_gpu() {
+        return Err(SnapshotError::GpuPropagation);
+    }
+    let file = SnapshotFile {
+        magic: SNAPSHOT_MAGIC,
+        version: SNAPSHOT_VERSION,
+        rng: rng.clone(),
+        wave: wave.to_snapshot(),
+    };
+
+    bincode::serialize_into(writer, &file).map_err(SnapshotError::Encoding)
+}
+
+/// Reads a generator written by `save_snapshot`. `constraints` must come from the same model as
+/// the saved generator. Only the guidance of `config` is used, since the tie-breaking
+/// noise and the entropy mode are restored from the snapshot.
+pub fn load_snapshot<R: Read>(
+    reader: R,
+    constraints: &PatternConstraints,
+    config: &GeneratorConfig,
+) -> Result<Generator, SnapshotError> {
+    let file: SnapshotFile = bincode::deserialize_from(reader).map_err(SnapshotError::Encoding)?;
+    if file.magic != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    if file.version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(file.version));
+    }
+    if file.wave.num_patterns != constraints.num_patterns()
+        || file.wave.num_offsets != constraints.get_offset_group().num_offsets()
+    {
+        return Err(SnapshotError::WrongModel);
+    }
+    config
+        .validate_guidance(file.wave.output_size.into(), constraints.num_patterns())
+        .map_err(SnapshotError::InvalidGuidance)?;
+    let wave =
+        Wave::from_snapshot(file.wave, constraints, config).ok_or(SnapshotError::Inconsistent)?;
+
+    Ok(Generator::from_parts(file.rng, wave))
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Encoding(bincode::Error),
+    NotASnapshot,
+    UnsupportedVersion(u32),
+    /// The snapshot's pattern or offset counts don't match the model.
+    WrongModel,
+    /// The snapshot's sections disagree about the number of slots or patterns.
+    Inconsistent,
+    /// The generator propagates on the GPU, so it has no support counts to save.
+    GpuPropagation,
+    /// The guidance of the config has a non-positive or non-finite weight multiplier.
+    InvalidGuidance(WfcError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Encoding(e) => write!(f, "{}", e),
+            SnapshotError::NotASnapshot => write!(f, "Not a generator snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported snapshot version {}", v)
+            }
+            SnapshotError::WrongModel => write!(f, "Snapshot was saved with a different model"),
+            SnapshotError::Inconsistent => write!(f, "Snapshot is inconsistent"),
+            SnapshotError::GpuPropagation => {
+                write!(f, "Generators propagating on the GPU can't be saved")
+            }
+            SnapshotError::InvalidGuidance(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SnapshotError::Encoding(e) => e.source(),
+            SnapshotError::InvalidGuidance(e) => Some(e),
+            _ => None,
+        }
+    }
+}
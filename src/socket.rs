@@ -0,0 +1,390 @@
+//! Authoring adjacency rules by labeling the faces of hand-made tiles, instead of extracting them
+//! from an example.
+
+use crate::{
+    error::WfcError,
+    offset::{OffsetGroup, OffsetId, OffsetMap},
+    pattern::{PatternConstraints, PatternId, PatternIndex, PatternMap, PatternSampler},
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, Indexer, VecLatticeMap};
+use serde::{Deserialize, Serialize};
+
+/// A connector label on one face of a tile. Two tiles fit together at a shared face when the
+/// sockets on either side fit (see `Socket::fits`).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Socket {
+    /// Looks the same when mirrored, so it fits the same socket.
+    Symmetric(u32),
+    /// An asymmetric connector. It only fits its mirror image, `Flipped` with the same ID.
+    Unflipped(u32),
+    /// The mirror image of `Unflipped` with the same ID.
+    Flipped(u32),
+    /// A connector on a face that's perpendicular to the rotation axis (e.g. top and bottom), in
+    /// one of 4 quarter turns. It only fits the same ID and turn. Rotating the tile changes the turn.
+    Rotated(u32, u8),
+}
+
+impl Socket {
+    pub fn fits(&self, other: &Socket) -> bool {
+        match (self, other) {
+            (Socket::Symmetric(a), Socket::Symmetric(b)) => a == b,
+            (Socket::Unflipped(a), Socket::Flipped(b)) => a == b,
+            (Socket::Flipped(a), Socket::Unflipped(b)) => a == b,
+            (Socket::Rotated(a, ra), Socket::Rotated(b, rb)) => a == b && ra == rb,
+            _ => false,
+        }
+    }
+
+    fn rotated(&self) -> Socket {
+        match *self {
+            Socket::Rotated(id, turn) => Socket::Rotated(id, (turn + 1) % 4),
+            other => other,
+        }
+    }
+
+    fn reflected(&self) -> Socket {
+        match *self {
+            Socket::Unflipped(id) => Socket::Flipped(id),
+            Socket::Flipped(id) => Socket::Unflipped(id),
+            Socket::Rotated(id, turn) => Socket::Rotated(id, (4 - turn) % 4),
+            other => other,
+        }
+    }
+}
+
+/// The symmetry of a tile's appearance, named after the letter with the same symmetry, as in
+/// Gumin's simple tiled model. It determines which rotations and reflections of the tile are
+/// distinct tiles.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum TileSymmetry {
+    /// Looks the same under every rotation and reflection. 1 variant.
+    X,
+    /// Looks the same after a half turn or a reflection. 2 variants.
+    I,
+    /// Looks the same after a half turn or a diagonal reflection, like `\`. 2 variants.
+    #[serde(alias = "\\")]
+    Backslash,
+    /// Looks the same after a diagonal reflection. 4 variants.
+    L,
+    /// Looks the same after a reflection. 4 variants.
+    T,
+    /// No symmetry. 8 variants.
+    F,
+}
+
+impl TileSymmetry {
+    /// The transforms that give each distinct variant of the tile.
+    pub fn variants(&self) -> Vec<TileTransform> {
+        let num_rotations = match self {
+            TileSymmetry::X => 1,
+            TileSymmetry::I | TileSymmetry::Backslash => 2,
+            TileSymmetry::L | TileSymmetry::T | TileSymmetry::F => 4,
+        };
+        let reflections: &[bool] = if *self == TileSymmetry::F {
+            &[false, true]
+        } else {
+            &[false]
+        };
+
+        reflections
+            .iter()
+            .flat_map(|reflected| {
+                (0..num_rotations).map(move |quarter_turns| TileTransform {
+                    quarter_turns,
+                    reflected: *reflected,
+                })
+            })
+            .collect()
+    }
+
+    /// The index in `variants` of the variant that looks the same as the untransformed tile after
+    /// `transform`.
+    pub fn variant_index(&self, transform: TileTransform) -> usize {
+        let stabilizer = self.stabilizer();
+
+        self.variants()
+            .iter()
+            .position(|variant| stabilizer.contains(&transform.then(variant.inverse())))
+            .expect("The variants cover every transform")
+    }
+
+    /// The transforms that leave the tile looking the same, for a tile drawn in its untransformed
+    /// orientation: `I` along the Y axis, `L` with arms toward -Y and +X, and `T` symmetric about
+    /// the X = 0 plane.
+    fn stabilizer(&self) -> Vec<TileTransform> {
+        let t = |quarter_turns, reflected| TileTransform {
+            quarter_turns,
+            reflected,
+        };
+        match self {
+            TileSymmetry::X => TileTransform::all(),
+            TileSymmetry::I => vec![t(0, false), t(2, false), t(0, true), t(2, true)],
+            TileSymmetry::Backslash => vec![t(0, false), t(2, false), t(1, true), t(3, true)],
+            TileSymmetry::L => vec![t(0, false), t(1, true)],
+            TileSymmetry::T => vec![t(0, false), t(0, true)],
+            TileSymmetry::F => vec![t(0, false)],
+        }
+    }
+}
+
+/// A reflection across the X = 0 plane (if `reflected`), followed by quarter turns
+/// counterclockwise about the Z axis. Apply the same transform to a tile's mesh or sprite.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TileTransform {
+    pub quarter_turns: u8,
+    pub reflected: bool,
+}
+
+impl TileTransform {
+    pub const IDENTITY: TileTransform = TileTransform {
+        quarter_turns: 0,
+        reflected: false,
+    };
+
+    /// All 8 rotations and reflections of a square.
+    pub fn all() -> Vec<TileTransform> {
+        [false, true]
+            .iter()
+            .flat_map(|reflected| {
+                (0..4).map(move |quarter_turns| TileTransform {
+                    quarter_turns,
+                    reflected: *reflected,
+                })
+            })
+            .collect()
+    }
+
+    /// This transform followed by `next`.
+    pub fn then(&self, next: TileTransform) -> TileTransform {
+        // Reflecting first reverses the direction of this transform's turns.
+        let turns = if next.reflected {
+            4 - self.quarter_turns
+        } else {
+            self.quarter_turns
+        };
+
+        TileTransform {
+            quarter_turns: (next.quarter_turns + turns) % 4,
+            reflected: self.reflected != next.reflected,
+        }
+    }
+
+    pub fn inverse(&self) -> TileTransform {
+        if self.reflected {
+            // A reflection followed by turns is its own inverse.
+            *self
+        } else {
+            TileTransform {
+                quarter_turns: (4 - self.quarter_turns) % 4,
+                reflected: false,
+            }
+        }
+    }
+
+    /// Transforms an offset or direction.
+    pub fn apply(&self, p: &lat::Point) -> lat::Point {
+        let mut p = *p;
+        if self.reflected {
+            p = [-p.x, p.y, p.z].into();
+        }
+        for _ in 0..self.quarter_turns {
+            p = [-p.y, p.x, p.z].into();
+        }
+
+        p
+    }
+
+    /// Transforms a tile's voxels about the center of its extent, which must be square in X and Y.
+    pub fn apply_to_tile<T, I>(&self, tile: &VecLatticeMap<T, I>) -> VecLatticeMap<T, I>
+    where
+        T: Clone,
+        I: Indexer,
+    {
+        let extent = tile.get_extent();
+        let min = *extent.get_minimum();
+        let size = *extent.get_local_supremum();
+        assert_eq!(size.x, size.y, "Only square tiles can be transformed");
+
+        // Doubled, so the center is on the lattice.
+        let center = lat::Point::from([size.x - 1, size.y - 1, 0]);
+        let mut transformed = VecLatticeMap::fill(extent, tile.get_world(&min));
+        for p in extent {
+            let doubled = lat::Point::from([2, 2, 1]) * (p - min);
+            let moved = self.apply(&(doubled - center)) + center;
+            let moved_p = min + [moved.x / 2, moved.y / 2, moved.z].into();
+            *transformed.get_world_ref_mut(&moved_p) = tile.get_world(&p);
+        }
+
+        transformed
+    }
+}
+
+/// Builds a model from tiles whose faces are labeled with `Socket`s, in the style of Oskar
+/// Stålberg's tilesets. Each tile becomes a pattern, and two patterns are compatible at an offset
+/// whenever the socket of the first at that offset fits the socket of the second at the opposite
+/// offset.
+///
+/// There are no voxels involved, so the output is just the assignment of `PatternId`s; pair it with
+/// whatever meshes or sprites the tiles stand for.
+pub struct SocketRules {
+    offset_group: OffsetGroup,
+    tiles: PatternMap<OffsetMap<Socket>>,
+    weights: PatternMap<u32>,
+}
+
+impl SocketRules {
+    pub fn new(offset_group: OffsetGroup) -> Self {
+        SocketRules {
+            offset_group,
+            tiles: PatternMap::new(Vec::new()),
+            weights: PatternMap::new(Vec::new()),
+        }
+    }
+
+    pub fn num_tiles(&self) -> PatternIndex {
+        self.tiles.num_elements() as PatternIndex
+    }
+
+    /// Adds a tile with one socket per offset, in the order of the `OffsetGroup`. `weight` is its
+    /// relative frequency, as if it had been counted in an example.
+    pub fn add_tile(&mut self, sockets: &[Socket], weight: u32) -> Result<PatternId, WfcError> {
+        self.check_num_sockets(sockets)?;
+        let id = PatternId(self.num_tiles());
+        self.tiles.push(OffsetMap::new(sockets.to_vec()));
+        self.weights.push(weight);
+
+        Ok(id)
+    }
+
+    /// Adds a tile and its rotations by quarter turns about the Z axis, skipping rotations that
+    /// have the same sockets as an earlier one. Every offset of the group must be in the group after
+    /// rotation. Each rotation gets the full `weight`, like the tiles of Gumin's simple tiled model.
+    pub fn add_tile_rotations(
+        &mut self,
+        sockets: &[Socket],
+        weight: u32,
+    ) -> Result<Vec<PatternId>, WfcError> {
+        let mut rotations: Vec<Vec<Socket>> = Vec::new();
+        for quarter_turns in 0..4 {
+            let transform = TileTransform {
+                quarter_turns,
+                reflected: false,
+            };
+            let rotation = self.transform_sockets(sockets, transform)?;
+            if rotations.contains(&rotation) {
+                break;
+            }
+            rotations.push(rotation);
+        }
+
+        rotations
+            .iter()
+            .map(|rotation| self.add_tile(rotation, weight))
+            .collect()
+    }
+
+    /// Adds every variant of a tile with the given `symmetry`, along with the transform to apply
+    /// to the tile's appearance for each one. `sockets` are those of the untransformed tile. Every
+    /// variant is added, even if its sockets are the same as another's, since it may look
+    /// different. Each variant gets the full `weight`.
+    pub fn add_tile_with_symmetry(
+        &mut self,
+        sockets: &[Socket],
+        symmetry: TileSymmetry,
+        weight: u32,
+    ) -> Result<Vec<(PatternId, TileTransform)>, WfcError> {
+        let mut added = Vec::new();
+        for transform in symmetry.variants().into_iter() {
+            let variant = self.transform_sockets(sockets, transform)?;
+            added.push((self.add_tile(&variant, weight)?, transform));
+        }
+
+        Ok(added)
+    }
+
+    /// The sockets of a tile after `transform`.
+    fn transform_sockets(
+        &self,
+        sockets: &[Socket],
+        transform: TileTransform,
+    ) -> Result<Vec<Socket>, WfcError> {
+        self.check_num_sockets(sockets)?;
+
+        let mut transformed = sockets.to_vec();
+        if transform.reflected {
+            let reflected_ids = self.offset_permutation(|o| [-o.x, o.y, o.z].into())?;
+            let prev = transformed.clone();
+            for (socket, reflected_id) in prev.iter().zip(reflected_ids.iter()) {
+                transformed[reflected_id.0] = socket.reflected();
+            }
+        }
+        if transform.quarter_turns > 0 {
+            let rotated_ids = self.offset_permutation(|o| [-o.y, o.x, o.z].into())?;
+            for _ in 0..transform.quarter_turns {
+                let prev = transformed.clone();
+                for (socket, rotated_id) in prev.iter().zip(rotated_ids.iter()) {
+                    transformed[rotated_id.0] = socket.rotated();
+                }
+            }
+        }
+
+        Ok(transformed)
+    }
+
+    /// The offset that each offset of the group turns into under `f`.
+    fn offset_permutation(
+        &self,
+        f: impl Fn(&lat::Point) -> lat::Point,
+    ) -> Result<Vec<OffsetId>, WfcError> {
+        self.offset_group
+            .iter()
+            .map(|(_, offset)| {
+                let transformed = f(offset);
+                self.offset_group
+                    .get_offset_id(&transformed)
+                    .ok_or_else(|| {
+                        WfcError::InvalidConfig(format!(
+                            "Offset {} turns into {}, which isn't in the group",
+                            offset, transformed
+                        ))
+                    })
+            })
+            .collect()
+    }
+
+    fn check_num_sockets(&self, sockets: &[Socket]) -> Result<(), WfcError> {
+        if sockets.len() != self.offset_group.num_offsets() {
+            return Err(WfcError::InvalidConfig(format!(
+                "Tile has {} sockets but there are {} offsets",
+                sockets.len(),
+                self.offset_group.num_offsets()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Derives the compatibility of every pair of tiles. Fails if some tile has nothing that fits
+    /// at some offset, since it could never be placed.
+    pub fn build(&self) -> Result<(PatternSampler, PatternConstraints), WfcError> {
+        let mut constraints = PatternConstraints::new(self.offset_group.clone());
+        for _ in self.tiles.iter() {
+            constraints.add_pattern();
+        }
+        for (offset_id, offset) in self.offset_group.iter() {
+            let opposite_id = self.offset_group.opposite(offset_id);
+            for (a, a_sockets) in self.tiles.iter() {
+                for (b, b_sockets) in self.tiles.iter() {
+                    if a_sockets.get(offset_id).fits(b_sockets.get(opposite_id)) {
+                        constraints.add_compatible_patterns(offset, a, b);
+                    }
+                }
+            }
+        }
+        constraints.validate()?;
+
+        Ok((PatternSampler::new(self.weights.clone()), constraints))
+    }
+}
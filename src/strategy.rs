@@ -0,0 +1,225 @@
+//! Run several generation strategies concurrently against the same input and keep the first output
+//! that satisfies its constraints.
+
+use crate::{
+    chooser::PatternChooser,
+    generate::{CancellationToken, Generator, GeneratorConfig, UpdateResult, NUM_SEED_BYTES},
+    pattern::{process_patterns_in_lattice, PatternId, PatternIndex, PatternShape, PatternTileSet},
+};
+
+use ilattice3 as lat;
+use ilattice3::{PeriodicYLevelsIndexer, VecLatticeMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// One configuration to try. Each strategy extracts its own patterns, since the pattern shape
+/// determines the model.
+#[derive(Clone)]
+pub struct Strategy {
+    /// Used to identify the strategy in the statistics.
+    pub name: String,
+    pub tile_size: lat::Point,
+    pub pattern_shape: PatternShape,
+    pub seed: [u8; NUM_SEED_BYTES],
+    /// Options for this strategy's generator. Initial patterns refer to the patterns extracted with
+    /// this strategy's shape.
+    pub config: GeneratorConfig,
+    /// Replaces weighted sampling whenever a slot is observed, if set.
+    pub chooser: Option<Arc<dyn PatternChooser>>,
+}
+
+impl Strategy {
+    /// A strategy with the default generator config and no chooser.
+    pub fn new(
+        name: String,
+        tile_size: lat::Point,
+        pattern_shape: PatternShape,
+        seed: [u8; NUM_SEED_BYTES],
+    ) -> Self {
+        Strategy {
+            name,
+            tile_size,
+            pattern_shape,
+            seed,
+            config: GeneratorConfig::default(),
+            chooser: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StrategyOutcome {
+    /// This strategy produced the returned output.
+    Success,
+    /// The generator hit a contradiction, or the input couldn't be processed with this strategy's
+    /// pattern shape.
+    Failure,
+    /// Another strategy succeeded first, or the caller stopped the race.
+    Cancelled,
+}
+
+#[derive(Clone, Debug)]
+pub struct StrategyStats {
+    pub name: String,
+    pub num_patterns: PatternIndex,
+    pub num_updates: usize,
+    pub elapsed: Duration,
+    pub outcome: StrategyOutcome,
+}
+
+/// The output of the winning strategy. Pattern IDs are only meaningful with respect to that
+/// strategy's tiles.
+pub struct StrategyResult<T> {
+    /// Index of the winning strategy in the input slice.
+    pub strategy: usize,
+    pub assignment: VecLatticeMap<PatternId>,
+    pub tiles: PatternTileSet<T, PeriodicYLevelsIndexer>,
+}
+
+/// Runs every strategy on its own thread and returns the first valid output, along with statistics
+/// for every strategy (in the same order as `strategies`). Triggering `cancel` stops all strategies.
+pub fn race_strategies<T>(
+    input_lattice: Arc<VecLatticeMap<T, PeriodicYLevelsIndexer>>,
+    strategies: &[Strategy],
+    output_size: lat::Point,
+    cancel: &CancellationToken,
+) -> (Option<StrategyResult<T>>, Vec<StrategyStats>)
+where
+    T: 'static + Clone + Copy + std::fmt::Debug + Eq + Hash + Send + Sync,
+{
+    let finished = Arc::new(AtomicBool::new(false));
+
+    let handles: Vec<_> = strategies
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(i, strategy)| {
+            let input_lattice = input_lattice.clone();
+            let cancel = cancel.clone();
+            let finished = finished.clone();
+
+            thread::spawn(move || {
+                run_strategy(i, strategy, &input_lattice, output_size, &cancel, &finished)
+            })
+        })
+        .collect();
+
+    let mut winner = None;
+    let mut all_stats = Vec::with_capacity(handles.len());
+    for handle in handles.into_iter() {
+        let (result, stats) = handle.join().expect("Strategy thread panicked");
+        info!("Strategy {} finished with {:?}", stats.name, stats.outcome);
+        if result.is_some() {
+            winner = result;
+        }
+        all_stats.push(stats);
+    }
+
+    (winner, all_stats)
+}
+
+fn run_strategy<T>(
+    index: usize,
+    strategy: Strategy,
+    input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    output_size: lat::Point,
+    cancel: &CancellationToken,
+    finished: &AtomicBool,
+) -> (Option<StrategyResult<T>>, StrategyStats)
+where
+    T: Clone + Copy + std::fmt::Debug + Eq + Hash,
+{
+    let start = Instant::now();
+
+    let (sampler, constraints, tiles, _) = match process_patterns_in_lattice(
+        input_lattice,
+        &strategy.tile_size,
+        &strategy.pattern_shape,
+        false,
+        None,
+    ) {
+        Ok(parts) => parts,
+        // This strategy's shape doesn't work for the input, so it can't win.
+        Err(_) => {
+            let stats = StrategyStats {
+                name: strategy.name,
+                num_patterns: 0,
+                num_updates: 0,
+                elapsed: start.elapsed(),
+                outcome: StrategyOutcome::Failure,
+            };
+
+            return (None, stats);
+        }
+    };
+
+    let mut generator = match Generator::with_config(
+        strategy.seed,
+        output_size,
+        &sampler,
+        &constraints,
+        &strategy.config,
+    ) {
+        Ok(generator) => generator,
+        // The config doesn't fit this strategy's patterns, so it can't win either.
+        Err(_) => {
+            let stats = StrategyStats {
+                name: strategy.name,
+                num_patterns: constraints.num_patterns(),
+                num_updates: 0,
+                elapsed: start.elapsed(),
+                outcome: StrategyOutcome::Failure,
+            };
+
+            return (None, stats);
+        }
+    };
+    if let Some(chooser) = strategy.chooser {
+        generator.set_chooser(chooser);
+    }
+    let mut num_updates = 0;
+    let outcome = loop {
+        if finished.load(Ordering::SeqCst) || cancel.is_cancelled() {
+            break StrategyOutcome::Cancelled;
+        }
+
+        num_updates += 1;
+        match generator.update(&sampler, &constraints) {
+            UpdateResult::Success => {
+                // Only the first successful strategy gets to claim the win.
+                if finished
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    break StrategyOutcome::Cancelled;
+                }
+                break StrategyOutcome::Success;
+            }
+            UpdateResult::Continue => (),
+            _ => break StrategyOutcome::Failure,
+        }
+    };
+
+    let stats = StrategyStats {
+        name: strategy.name,
+        num_patterns: constraints.num_patterns(),
+        num_updates,
+        elapsed: start.elapsed(),
+        outcome,
+    };
+    let result = if outcome == StrategyOutcome::Success {
+        Some(StrategyResult {
+            strategy: index,
+            assignment: generator.result(),
+            tiles,
+        })
+    } else {
+        None
+    };
+
+    (result, stats)
+}
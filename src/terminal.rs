@@ -0,0 +1,174 @@
+//! Watching generation in a terminal, without writing any files.
+
+use crate::{
+    image::SuperpositionRenderer,
+    pattern::{PatternSet, PatternTileSet},
+    FrameConsumer,
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, Indexer, VecLatticeMap};
+use image::Rgba;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// Draws the superposition in the terminal for every `skip_frames` updates, with one column per
+/// slot and two rows of slots per line, as half-block characters with 24-bit ANSI colors. Each
+/// slot gets the mean color of its tile. Only the first Z slice is drawn.
+///
+/// Each frame is drawn over the previous one, so anything else printed in between is overwritten.
+pub struct TerminalRenderer<I, W = io::Stdout> {
+    renderer: SuperpositionRenderer<I>,
+    writer: W,
+    /// The slots that changed since the last frame, unless some frames came without them.
+    changed_slots: Option<Vec<lat::Point>>,
+    num_frames: usize,
+    num_updates: usize,
+    skip_frames: usize,
+    /// The first error from writing a frame, reported by `finish`.
+    error: Option<io::Error>,
+}
+
+impl<I> TerminalRenderer<I> {
+    pub fn new(pattern_tiles: PatternTileSet<Rgba<u8>, I>, skip_frames: usize) -> Self {
+        Self::with_writer(pattern_tiles, io::stdout(), skip_frames)
+    }
+}
+
+impl<I, W: Write> TerminalRenderer<I, W> {
+    /// Writes the escape codes to `writer` instead of stdout.
+    pub fn with_writer(
+        pattern_tiles: PatternTileSet<Rgba<u8>, I>,
+        writer: W,
+        skip_frames: usize,
+    ) -> Self {
+        TerminalRenderer {
+            renderer: SuperpositionRenderer::new(pattern_tiles),
+            writer,
+            changed_slots: None,
+            num_frames: 0,
+            num_updates: 0,
+            skip_frames: skip_frames.max(1),
+            error: None,
+        }
+    }
+
+    /// Resets the colors and moves below the last frame. Returns the first error from writing any
+    /// frame.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.writer.write_all(b"\x1b[0m")?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn add_frame(&mut self, slots: &VecLatticeMap<PatternSet>)
+    where
+        I: Clone + Indexer,
+    {
+        self.num_updates += 1;
+        if (self.num_updates - 1) % self.skip_frames != 0 {
+            return;
+        }
+
+        let changed = match self.changed_slots.take() {
+            Some(mut changed) => {
+                changed.sort_by_key(|p| (p.z, p.y, p.x));
+                changed.dedup();
+
+                changed
+            }
+            None => {
+                self.renderer.invalidate();
+
+                Vec::new()
+            }
+        };
+        self.changed_slots = Some(Vec::new());
+
+        let tile_size = self.renderer.tile_size();
+        let frame = self.renderer.render(slots, &changed);
+        let text = draw_frame(frame, slots.get_extent(), tile_size, self.num_frames == 0);
+        self.num_frames += 1;
+
+        let written = self
+            .writer
+            .write_all(text.as_bytes())
+            .and_then(|()| self.writer.flush());
+        if let Err(e) = written {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+impl<I: Clone + Indexer, W: Write> FrameConsumer for TerminalRenderer<I, W> {
+    fn use_frame(&mut self, slots: &VecLatticeMap<PatternSet>) {
+        // Without the changed slots, the next frame is drawn from scratch.
+        self.changed_slots = None;
+        self.add_frame(slots);
+    }
+
+    fn use_frame_with_changes(
+        &mut self,
+        slots: &VecLatticeMap<PatternSet>,
+        _modified_slots: &[lat::Point],
+        changed_slots: &[lat::Point],
+    ) {
+        if let Some(changed) = &mut self.changed_slots {
+            changed.extend_from_slice(changed_slots);
+        }
+        self.add_frame(slots);
+    }
+}
+
+/// The escape codes that draw the first Z slice of `frame`. The first frame clears the screen, and
+/// the rest are drawn over it.
+fn draw_frame(
+    frame: &VecLatticeMap<Rgba<u8>>,
+    slot_extent: lat::Extent,
+    tile_size: lat::Point,
+    first: bool,
+) -> String {
+    let size = *slot_extent.get_local_supremum();
+    let slot_color = |x: i32, y: i32| {
+        let mut sum = [0u32; 3];
+        for ty in 0..tile_size.y {
+            for tx in 0..tile_size.x {
+                let voxel = [x * tile_size.x + tx, y * tile_size.y + ty, 0].into();
+                let color = frame.get_world_ref(&voxel);
+                for (s, c) in sum.iter_mut().zip(color.0.iter()) {
+                    *s += *c as u32;
+                }
+            }
+        }
+        let num_voxels = (tile_size.x * tile_size.y) as u32;
+
+        [
+            sum[0] / num_voxels,
+            sum[1] / num_voxels,
+            sum[2] / num_voxels,
+        ]
+    };
+
+    let mut text = String::new();
+    text.push_str(if first { "\x1b[2J\x1b[H" } else { "\x1b[H" });
+    for y in (0..size.y).step_by(2) {
+        for x in 0..size.x {
+            let [r, g, b] = slot_color(x, y);
+            write!(text, "\x1b[38;2;{};{};{}m", r, g, b).unwrap();
+            if y + 1 < size.y {
+                let [r, g, b] = slot_color(x, y + 1);
+                write!(text, "\x1b[48;2;{};{};{}m", r, g, b).unwrap();
+            } else {
+                text.push_str("\x1b[49m");
+            }
+            text.push('\u{2580}');
+        }
+        text.push_str("\x1b[0m\n");
+    }
+
+    text
+}
@@ -0,0 +1,252 @@
+//! Reading and writing maps made with the Tiled editor (.tmx).
+
+use crate::{
+    image::color_final_patterns,
+    pattern::{PatternId, PatternTileSet},
+};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use ilattice3 as lat;
+use ilattice3::{prelude::*, Indexer, VecLatticeMap};
+use std::io::{self, Read, Write};
+use std::{error, fmt};
+
+/// What a generated map needs from the example map to look the same in Tiled.
+#[derive(Clone, Debug)]
+pub struct TiledMap {
+    pub orientation: String,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// The map's `<tileset>` elements, verbatim, so written maps refer to the same tiles. External
+    /// tilesets (.tsx) are referred to by their paths relative to the example map.
+    pub tilesets: Vec<String>,
+}
+
+/// Reads the first tile layer of a .tmx map as a 2D lattice of global tile IDs (GIDs), along with
+/// the map's tilesets. Tile X and Y become lattice X and Y, and 0 is an empty tile. The flip bits
+/// of a GID are kept, so a flipped tile is a different voxel. Layer data can be CSV, XML, or
+/// base64, optionally compressed with zlib or gzip. Infinite maps aren't supported.
+pub fn read_tmx<I: Indexer>(text: &str) -> Result<(VecLatticeMap<u32, I>, TiledMap), TiledError> {
+    let document = roxmltree::Document::parse(text)?;
+    let map_node = document.root_element();
+    if !map_node.has_tag_name("map") {
+        return Err(TiledError::Format("Expected a <map> element".to_string()));
+    }
+    if map_node.attribute("infinite") == Some("1") {
+        return Err(TiledError::Format(
+            "Infinite maps aren't supported".to_string(),
+        ));
+    }
+
+    let map = TiledMap {
+        orientation: map_node
+            .attribute("orientation")
+            .unwrap_or("orthogonal")
+            .to_string(),
+        tile_width: number_attribute(&map_node, "tilewidth")?,
+        tile_height: number_attribute(&map_node, "tileheight")?,
+        tilesets: map_node
+            .children()
+            .filter(|n| n.has_tag_name("tileset"))
+            .map(|n| text[n.range()].to_string())
+            .collect(),
+    };
+
+    let layer = map_node
+        .children()
+        .find(|n| n.has_tag_name("layer"))
+        .ok_or_else(|| TiledError::Format("The map has no tile layers".to_string()))?;
+    let width: u32 = number_attribute(&layer, "width")?;
+    let height: u32 = number_attribute(&layer, "height")?;
+    let data = layer
+        .children()
+        .find(|n| n.has_tag_name("data"))
+        .ok_or_else(|| TiledError::Format("The layer has no <data>".to_string()))?;
+    let gids = read_layer_data(&data)?;
+    if gids.len() != (width * height) as usize {
+        return Err(TiledError::Format(format!(
+            "Expected {} tiles in the layer but found {}",
+            width * height,
+            gids.len()
+        )));
+    }
+
+    let extent = lat::Extent::from_min_and_local_supremum(
+        [0, 0, 0].into(),
+        [width as i32, height as i32, 1].into(),
+    );
+    let mut lattice = VecLatticeMap::fill(extent, 0);
+    for (i, gid) in gids.into_iter().enumerate() {
+        let p = [i as i32 % width as i32, i as i32 / width as i32, 0].into();
+        *lattice.get_world_ref_mut(&p) = gid;
+    }
+
+    Ok((lattice, map))
+}
+
+/// Writes `gids` as a .tmx map with the tilesets of `map`, and one tile layer per Z slice.
+pub fn write_tmx<W, I>(
+    writer: &mut W,
+    gids: &VecLatticeMap<u32, I>,
+    map: &TiledMap,
+) -> io::Result<()>
+where
+    W: Write,
+    I: Indexer,
+{
+    let extent = gids.get_extent();
+    let size = *extent.get_local_supremum();
+    let min = *extent.get_minimum();
+
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    write!(
+        writer,
+        r#"<map version="1.4" orientation="{}" renderorder="right-down""#,
+        map.orientation
+    )?;
+    write!(
+        writer,
+        r#" width="{}" height="{}" tilewidth="{}" tileheight="{}""#,
+        size.x, size.y, map.tile_width, map.tile_height
+    )?;
+    writeln!(
+        writer,
+        r#" infinite="0" nextlayerid="{}" nextobjectid="1">"#,
+        size.z + 1
+    )?;
+    for tileset in map.tilesets.iter() {
+        writeln!(writer, " {}", tileset)?;
+    }
+    for z in 0..size.z {
+        writeln!(
+            writer,
+            r#" <layer id="{}" name="Layer {}" width="{}" height="{}">"#,
+            z + 1,
+            z + 1,
+            size.x,
+            size.y
+        )?;
+        writeln!(writer, r#"  <data encoding="csv">"#)?;
+        for y in 0..size.y {
+            let row: Vec<String> = (0..size.x)
+                .map(|x| gids.get_world(&(min + [x, y, z].into())).to_string())
+                .collect();
+            let separator = if y + 1 < size.y { "," } else { "" };
+            writeln!(writer, "{}{}", row.join(","), separator)?;
+        }
+        writeln!(writer, "  </data>")?;
+        writeln!(writer, " </layer>")?;
+    }
+
+    writeln!(writer, "</map>")
+}
+
+/// The GID at each tile of the output, like `color_final_patterns_rgba` for images.
+pub fn final_pattern_gids<I: Clone + Indexer>(
+    pattern_lattice: &VecLatticeMap<PatternId>,
+    tiles: &PatternTileSet<u32, I>,
+) -> VecLatticeMap<u32> {
+    color_final_patterns(pattern_lattice, tiles, 0)
+}
+
+fn read_layer_data(data: &roxmltree::Node) -> Result<Vec<u32>, TiledError> {
+    let text = data.text().unwrap_or("");
+    match (data.attribute("encoding"), data.attribute("compression")) {
+        (Some("csv"), None) => text
+            .split(',')
+            .map(|gid| {
+                gid.trim()
+                    .parse()
+                    .map_err(|_| TiledError::Format(format!("Invalid GID {:?}", gid.trim())))
+            })
+            .collect(),
+        (Some("base64"), compression) => {
+            let bytes = base64::decode(text.trim())
+                .map_err(|e| TiledError::Format(format!("Invalid base64 layer data: {}", e)))?;
+            let bytes = match compression {
+                None => bytes,
+                Some("zlib") => decompress(ZlibDecoder::new(bytes.as_slice()))?,
+                Some("gzip") => decompress(GzDecoder::new(bytes.as_slice()))?,
+                Some(other) => {
+                    return Err(TiledError::Format(format!(
+                        "Unsupported layer compression {:?}",
+                        other
+                    )))
+                }
+            };
+            if bytes.len() % 4 != 0 {
+                return Err(TiledError::Format(
+                    "Layer data isn't a whole number of GIDs".to_string(),
+                ));
+            }
+
+            Ok(bytes
+                .chunks(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect())
+        }
+        (None, None) => data
+            .children()
+            .filter(|n| n.has_tag_name("tile"))
+            .map(|n| n.attribute("gid").map_or(Ok(0), |gid| gid.parse()))
+            .collect::<Result<_, std::num::ParseIntError>>()
+            .map_err(|e| TiledError::Format(format!("Invalid GID: {}", e))),
+        (encoding, compression) => Err(TiledError::Format(format!(
+            "Unsupported layer encoding {:?} with compression {:?}",
+            encoding, compression
+        ))),
+    }
+}
+
+fn decompress<R: Read>(mut decoder: R) -> Result<Vec<u8>, TiledError> {
+    let mut bytes = Vec::new();
+    decoder
+        .read_to_end(&mut bytes)
+        .map_err(|e| TiledError::Format(format!("Invalid compressed layer data: {}", e)))?;
+
+    Ok(bytes)
+}
+
+fn number_attribute<T: std::str::FromStr>(
+    node: &roxmltree::Node,
+    name: &str,
+) -> Result<T, TiledError> {
+    let value = node.attribute(name).ok_or_else(|| {
+        TiledError::Format(format!("<{}> has no {}", node.tag_name().name(), name))
+    })?;
+
+    value
+        .parse()
+        .map_err(|_| TiledError::Format(format!("Invalid {} {:?}", name, value)))
+}
+
+#[derive(Debug)]
+pub enum TiledError {
+    Xml(roxmltree::Error),
+    /// Valid XML, but not a map that can be read.
+    Format(String),
+}
+
+impl fmt::Display for TiledError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TiledError::Xml(e) => write!(f, "{}", e),
+            TiledError::Format(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for TiledError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TiledError::Xml(e) => Some(e),
+            TiledError::Format(_) => None,
+        }
+    }
+}
+
+impl From<roxmltree::Error> for TiledError {
+    fn from(e: roxmltree::Error) -> Self {
+        TiledError::Xml(e)
+    }
+}
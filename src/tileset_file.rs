@@ -0,0 +1,224 @@
+//! Describing a tileset for the simple tiled model in a RON or JSON file.
+
+use crate::{
+    error::WfcError,
+    offset::{edge_2d_offsets, OffsetGroup},
+    pattern::{PatternConstraints, PatternId, PatternMap, PatternSampler},
+    socket::{TileSymmetry, TileTransform},
+};
+
+use ilattice3 as lat;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{error, fmt, io};
+
+/// A 2D tileset with hand-written adjacency rules, like the data.xml files of Gumin's simple tiled
+/// model samples. Each variant of each tile (see `TileSymmetry::variants`) becomes a pattern.
+///
+/// In RON:
+///
+/// ```text
+/// (
+///     tiles: [
+///         (name: "empty", path: Some("empty.png"), symmetry: X),
+///         (name: "corner", path: Some("corner.png"), symmetry: L, weight: 2),
+///     ],
+///     neighbors: [
+///         (left: "empty", right: "corner 1"),
+///     ],
+/// )
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TilesetFile {
+    pub tiles: Vec<TileEntry>,
+    pub neighbors: Vec<NeighborEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TileEntry {
+    pub name: String,
+    /// The tile's image, relative to the tileset file. Only needed to render the output.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default = "default_symmetry")]
+    pub symmetry: TileSymmetry,
+    /// The relative frequency of each variant of the tile.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// Allows `right` directly to the +X side of `left`, and likewise for every rotation and
+/// reflection of the pair. Each side is a tile name, optionally followed by the index of a variant,
+/// e.g. "corner 1" (the default is 0).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NeighborEntry {
+    pub left: String,
+    pub right: String,
+}
+
+fn default_symmetry() -> TileSymmetry {
+    TileSymmetry::X
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// The model built from a `TilesetFile`.
+pub struct TilesetModel {
+    pub sampler: PatternSampler,
+    pub constraints: PatternConstraints,
+    /// The index of the tile of each pattern in `TilesetFile::tiles`, and the transform to apply
+    /// to its appearance.
+    pub variants: PatternMap<(usize, TileTransform)>,
+}
+
+impl TilesetFile {
+    /// Reads a RON file if the extension of `path` is "ron", otherwise JSON.
+    pub fn load(path: &Path) -> Result<Self, TilesetFileError> {
+        let text = std::fs::read_to_string(path)?;
+        if path.extension().and_then(|e| e.to_str()) == Some("ron") {
+            Ok(ron::de::from_str(&text)?)
+        } else {
+            Ok(serde_json::from_str(&text)?)
+        }
+    }
+
+    pub fn build(&self) -> Result<TilesetModel, TilesetFileError> {
+        let offset_group = OffsetGroup::new(&edge_2d_offsets()).expect("Invalid built-in offsets");
+        let mut constraints = PatternConstraints::new(offset_group);
+        let mut weights = Vec::new();
+        let mut variants = Vec::new();
+        // The pattern of each tile's first variant.
+        let mut first_patterns = HashMap::new();
+        for (tile_index, tile) in self.tiles.iter().enumerate() {
+            if first_patterns
+                .insert(tile.name.as_str(), variants.len())
+                .is_some()
+            {
+                return Err(TilesetFileError::Invalid(format!(
+                    "There are two tiles named {:?}",
+                    tile.name
+                )));
+            }
+            for transform in tile.symmetry.variants().into_iter() {
+                constraints.add_pattern();
+                weights.push(tile.weight);
+                variants.push((tile_index, transform));
+            }
+        }
+
+        let pattern_of = |tile_index: usize, transform: TileTransform| {
+            let tile = &self.tiles[tile_index];
+            let first = first_patterns[tile.name.as_str()];
+
+            PatternId((first + tile.symmetry.variant_index(transform)) as u32)
+        };
+        let right: lat::Point = [1, 0, 0].into();
+        for neighbor in self.neighbors.iter() {
+            let (left_tile, left_variant) = self.parse_variant(&neighbor.left)?;
+            let (right_tile, right_variant) = self.parse_variant(&neighbor.right)?;
+            for transform in TileTransform::all().into_iter() {
+                constraints.add_compatible_patterns(
+                    &transform.apply(&right),
+                    pattern_of(left_tile, left_variant.then(transform)),
+                    pattern_of(right_tile, right_variant.then(transform)),
+                );
+            }
+        }
+        constraints.validate()?;
+
+        Ok(TilesetModel {
+            sampler: PatternSampler::new(PatternMap::new(weights)),
+            constraints,
+            variants: PatternMap::new(variants),
+        })
+    }
+
+    /// Parses "name" or "name variant" into the tile's index and the variant's transform.
+    fn parse_variant(&self, s: &str) -> Result<(usize, TileTransform), TilesetFileError> {
+        let bad_neighbor = |msg: String| TilesetFileError::Invalid(format!("{:?}: {}", s, msg));
+
+        let mut words = s.split_whitespace();
+        let name = words
+            .next()
+            .ok_or_else(|| bad_neighbor("Expected a tile name".to_string()))?;
+        let variant: usize = match words.next() {
+            Some(variant) => variant
+                .parse()
+                .map_err(|_| bad_neighbor(format!("Invalid variant {:?}", variant)))?,
+            None => 0,
+        };
+        let tile_index = self
+            .tiles
+            .iter()
+            .position(|tile| tile.name == name)
+            .ok_or_else(|| bad_neighbor(format!("No tile named {:?}", name)))?;
+        let transform = *self.tiles[tile_index]
+            .symmetry
+            .variants()
+            .get(variant)
+            .ok_or_else(|| bad_neighbor(format!("No variant {}", variant)))?;
+
+        Ok((tile_index, transform))
+    }
+}
+
+#[derive(Debug)]
+pub enum TilesetFileError {
+    Io(io::Error),
+    Ron(ron::Error),
+    Json(serde_json::Error),
+    /// The file names tiles or variants that don't exist.
+    Invalid(String),
+    Wfc(WfcError),
+}
+
+impl fmt::Display for TilesetFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TilesetFileError::Io(e) => write!(f, "{}", e),
+            TilesetFileError::Ron(e) => write!(f, "{}", e),
+            TilesetFileError::Json(e) => write!(f, "{}", e),
+            TilesetFileError::Invalid(msg) => write!(f, "{}", msg),
+            TilesetFileError::Wfc(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for TilesetFileError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TilesetFileError::Io(e) => Some(e),
+            TilesetFileError::Ron(e) => Some(e),
+            TilesetFileError::Json(e) => Some(e),
+            TilesetFileError::Invalid(_) => None,
+            TilesetFileError::Wfc(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for TilesetFileError {
+    fn from(e: io::Error) -> Self {
+        TilesetFileError::Io(e)
+    }
+}
+
+impl From<ron::Error> for TilesetFileError {
+    fn from(e: ron::Error) -> Self {
+        TilesetFileError::Ron(e)
+    }
+}
+
+impl From<serde_json::Error> for TilesetFileError {
+    fn from(e: serde_json::Error) -> Self {
+        TilesetFileError::Json(e)
+    }
+}
+
+impl From<WfcError> for TilesetFileError {
+    fn from(e: WfcError) -> Self {
+        TilesetFileError::Wfc(e)
+    }
+}
@@ -0,0 +1,419 @@
+//! Learning one model from several example inputs.
+
+use crate::{
+    edge::{on_face, EdgeConstraint},
+    error::WfcError,
+    model::Model,
+    pattern::{
+        check_whole_tiles, index_patterns, pruned_pattern_ids, renumber_patterns,
+        transform_example, AdjacencyRule, InputBoundary, PatternConstraints, PatternId,
+        PatternIndex, PatternMap, PatternPositions, PatternSampler, PatternShape, PatternTileSet,
+        TileCanonicalize, MAX_PATTERNS,
+    },
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, PeriodicYLevelsIndexer, Tile, VecLatticeMap};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Extracts patterns from any number of example inputs into one model. A pattern that appears in
+/// several examples gets one `PatternId`, with its weight summed over all of them, and the
+/// adjacencies seen in every example are allowed.
+///
+/// Patterns never span two examples. The edges of each example are handled according to
+/// `PatternShape::boundary`; unless it's periodic, patterns that can't be placed because they were
+/// only seen at an edge are removed, which renumbers the `PatternId`s.
+pub struct ModelTrainer<T> {
+    tile_size: lat::Point,
+    pattern_shape: PatternShape,
+    /// The full voxels of each pattern, to recognize it in later examples.
+    pattern_ids: HashMap<Tile<T, PeriodicYLevelsIndexer>, PatternId>,
+    /// The voxels of each pattern, with the pattern's minimum at the origin. Only needed to check
+    /// for agreement.
+    pattern_voxels: Vec<VecLatticeMap<T, PeriodicYLevelsIndexer>>,
+    /// Min corner tile of each pattern.
+    pattern_min_tiles: Vec<Tile<T, PeriodicYLevelsIndexer>>,
+    /// Map from pattern ID to # of occurrences.
+    pattern_weights: PatternMap<u32>,
+    constraints: PatternConstraints,
+    /// Patterns seen on each face of any example.
+    edges: EdgeConstraint,
+    /// The minimum voxel of every occurrence of each pattern in the examples as given, if
+    /// `record_positions` was called.
+    pattern_positions: Option<PatternPositions>,
+    /// Which voxels don't count toward pattern weights, if `mask_empty` was called.
+    is_empty: Option<fn(&T) -> bool>,
+    /// The number of voxels of each pattern that aren't empty, when masking.
+    pattern_masses: PatternMap<u32>,
+    /// Maps each voxel to the part of it that identifies patterns, if `with_equivalence` was
+    /// called.
+    equivalence: Option<Box<dyn TileCanonicalize<T> + Send + Sync>>,
+}
+
+impl<T> ModelTrainer<T>
+where
+    T: Clone + Copy + std::fmt::Debug + Eq + Hash,
+{
+    pub fn new(tile_size: lat::Point, pattern_shape: PatternShape) -> Result<Self, WfcError> {
+        pattern_shape.validate()?;
+        let constraints = PatternConstraints::new(pattern_shape.offset_group.clone());
+
+        Ok(ModelTrainer {
+            tile_size,
+            pattern_shape,
+            pattern_ids: HashMap::new(),
+            pattern_voxels: Vec::new(),
+            pattern_min_tiles: Vec::new(),
+            pattern_weights: PatternMap::new(Vec::new()),
+            constraints,
+            edges: EdgeConstraint::empty(),
+            pattern_positions: None,
+            is_empty: None,
+            pattern_masses: PatternMap::new(Vec::new()),
+            equivalence: None,
+        })
+    }
+
+    /// Identifies patterns by their voxels after `equivalence`, rather than the voxels themselves,
+    /// in the examples added from now on. E.g. with voxels of (color, material, label), mapping
+    /// every color to the same value makes patterns of the same materials and labels one pattern,
+    /// whatever their colors. Each pattern's tile keeps the full voxels of its first occurrence, so
+    /// those are the colors in the output. With `AdjacencyRule::Agreement`, patterns agree where
+    /// their voxels agree after `equivalence`.
+    pub fn with_equivalence(
+        mut self,
+        equivalence: Box<dyn TileCanonicalize<T> + Send + Sync>,
+    ) -> Self {
+        self.equivalence = Some(equivalence);
+
+        self
+    }
+
+    /// Treats the voxels where `is_empty` is true, like the air of a sparse VOX model, as "don't
+    /// care" when weighing the patterns of the examples added from now on. Each occurrence of a
+    /// pattern counts for the number of its voxels that aren't empty instead of 1, so patterns
+    /// that are mostly empty don't dominate the weights. Adjacencies are kept as they are, and a
+    /// pattern that's entirely empty still gets a weight of 1, so it can be placed.
+    pub fn mask_empty(mut self, is_empty: fn(&T) -> bool) -> Self {
+        let pattern_volume = self.pattern_shape.size * self.tile_size;
+        let num_voxels = (pattern_volume.x * pattern_volume.y * pattern_volume.z) as u32;
+        let num_patterns = self.num_patterns() as usize;
+        self.pattern_masses = PatternMap::fill(num_voxels, num_patterns);
+        self.is_empty = Some(is_empty);
+
+        self
+    }
+
+    /// Also records where each pattern occurs in the examples added from now on, to be returned
+    /// by `into_parts_with_positions`. Positions are in the coordinates of each example, and
+    /// transformed copies from a `PatternShape::symmetry` aren't recorded.
+    pub fn record_positions(mut self) -> Self {
+        let num_patterns = self.num_patterns() as usize;
+        self.pattern_positions
+            .get_or_insert_with(|| PatternMap::fill(Vec::new(), num_patterns));
+
+        self
+    }
+
+    pub fn num_patterns(&self) -> PatternIndex {
+        self.constraints.num_patterns()
+    }
+
+    /// Adds the patterns and adjacencies of `input_lattice`, which must be made of whole tiles; see
+    /// `fit_to_tiles`.
+    pub fn add_example(
+        &mut self,
+        input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    ) -> Result<(), WfcError> {
+        self.add_example_with_patterns(input_lattice)?;
+
+        Ok(())
+    }
+
+    /// Like `add_example`, but also returns the pattern found at each tile of the example. Unless
+    /// the boundary is periodic, the finished model may renumber these `PatternId`s.
+    ///
+    /// With a `PatternShape::symmetry`, each transform of the example is added as an example of its
+    /// own, except those that would change the shape of the tiles or patterns. Only the patterns of
+    /// the example as given are returned.
+    pub fn add_example_with_patterns(
+        &mut self,
+        input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    ) -> Result<VecLatticeMap<PatternId, PeriodicYLevelsIndexer>, WfcError> {
+        check_whole_tiles(
+            input_lattice.get_extent().get_local_supremum(),
+            &self.tile_size,
+        )?;
+
+        let example_patterns = self.add_untransformed_example(input_lattice)?;
+        let transforms = self.pattern_shape.symmetry_transforms(&self.tile_size);
+        for transform in transforms.iter().skip(1) {
+            self.add_untransformed_example(&transform_example(input_lattice, transform))?;
+        }
+
+        let num_patterns = self.num_patterns() as usize;
+        if let Some(positions) = &mut self.pattern_positions {
+            while positions.num_elements() < num_patterns {
+                positions.push(Vec::new());
+            }
+            for pattern_point in example_patterns.get_extent() {
+                let pattern = *example_patterns.get_local_ref(&pattern_point);
+                positions
+                    .get_mut(pattern)
+                    .push(pattern_point * self.tile_size);
+            }
+        }
+
+        Ok(example_patterns)
+    }
+
+    fn add_untransformed_example(
+        &mut self,
+        input_lattice: &VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    ) -> Result<VecLatticeMap<PatternId, PeriodicYLevelsIndexer>, WfcError> {
+        let tile_size = self.tile_size;
+        let pattern_size = self.pattern_shape.size * tile_size;
+        let stride = self.pattern_shape.stride();
+        let slot_size = stride * tile_size;
+        let keep_voxels = self.pattern_shape.adjacency == AdjacencyRule::Agreement;
+        let pattern_voxels_extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), pattern_size);
+
+        let boundary = self.pattern_shape.boundary;
+        let (pattern_lattice, first_occurrences) = index_patterns(
+            input_lattice,
+            &tile_size,
+            &self.pattern_shape.size,
+            boundary,
+        )?;
+        let pattern_lattice_extent = pattern_lattice.get_extent();
+
+        // Translate the IDs local to this example into IDs of the whole model.
+        let mut model_ids = Vec::with_capacity(first_occurrences.len());
+        for pattern_point in first_occurrences.iter() {
+            let pattern_min = *pattern_point * tile_size;
+            let pattern_extent =
+                lat::Extent::from_min_and_local_supremum(pattern_min, pattern_size);
+            let pattern = boundary.get_window(input_lattice, &pattern_extent);
+            let pattern = match &self.equivalence {
+                Some(equivalence) => {
+                    let voxels = pattern
+                        .put_in_extent(pattern_voxels_extent)
+                        .map(|v| equivalence.canonicalize(v));
+
+                    Tile::get_from_map(&voxels, &pattern_voxels_extent)
+                }
+                None => pattern,
+            };
+            if let Some(id) = self.pattern_ids.get(&pattern) {
+                model_ids.push(*id);
+                continue;
+            }
+
+            if self.num_patterns() >= MAX_PATTERNS {
+                return Err(WfcError::TooManyPatterns);
+            }
+            let id = PatternId(self.num_patterns());
+            let tile_extent = lat::Extent::from_min_and_local_supremum(pattern_min, slot_size);
+            self.pattern_min_tiles
+                .push(boundary.get_window(input_lattice, &tile_extent));
+            if keep_voxels {
+                self.pattern_voxels
+                    .push(pattern.clone().put_in_extent(pattern_voxels_extent));
+            }
+            if let Some(is_empty) = self.is_empty {
+                // The mass counts the voxels as they are, not after any equivalence.
+                let voxels = boundary.get_window(input_lattice, &pattern_extent);
+                self.pattern_masses.push(pattern_mass(
+                    voxels.put_in_extent(pattern_voxels_extent),
+                    is_empty,
+                ));
+            }
+            self.pattern_ids.insert(pattern, id);
+            self.pattern_weights.push(0);
+            self.constraints.add_pattern();
+            model_ids.push(id);
+        }
+
+        // Set the constraints and count pattern occurences. Unless the input is periodic, patterns
+        // at the edge have no neighbor past it.
+        for pattern_point in pattern_lattice_extent {
+            let pattern = model_ids[pattern_lattice.get_local(&pattern_point).0 as usize];
+            for (_, offset) in self.pattern_shape.offset_group.iter() {
+                let offset_point = pattern_point + *offset * stride;
+                if boundary != InputBoundary::Periodic
+                    && !pattern_lattice_extent.contains_world(&offset_point)
+                {
+                    continue;
+                }
+                let offset_pattern = model_ids[pattern_lattice.get_local(&offset_point).0 as usize];
+
+                self.constraints
+                    .add_compatible_patterns(offset, pattern, offset_pattern);
+            }
+            *self.pattern_weights.get_mut(pattern) += match self.is_empty {
+                Some(_) => *self.pattern_masses.get(pattern),
+                None => 1,
+            };
+
+            for (normal, edge_patterns) in self.edges.faces.iter_mut() {
+                if on_face(&pattern_lattice_extent, &pattern_point, normal) {
+                    edge_patterns.insert(pattern);
+                }
+            }
+        }
+
+        let mut example_patterns = pattern_lattice;
+        for pattern_point in pattern_lattice_extent {
+            let pattern = example_patterns.get_local_ref_mut(&pattern_point);
+            *pattern = model_ids[pattern.0 as usize];
+        }
+
+        Ok(example_patterns)
+    }
+
+    /// The parts of the model, as returned by `process_patterns_in_lattice`.
+    pub fn into_parts(
+        self,
+    ) -> Result<
+        (
+            PatternSampler,
+            PatternConstraints,
+            PatternTileSet<T, PeriodicYLevelsIndexer>,
+        ),
+        WfcError,
+    > {
+        let (sampler, constraints, tiles, _) = self.into_parts_with_edges()?;
+
+        Ok((sampler, constraints, tiles))
+    }
+
+    /// Like `into_parts`, but also returns where each pattern occurred, if `record_positions` was
+    /// called.
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts_with_positions(
+        self,
+    ) -> Result<
+        (
+            PatternSampler,
+            PatternConstraints,
+            PatternTileSet<T, PeriodicYLevelsIndexer>,
+            Option<PatternPositions>,
+        ),
+        WfcError,
+    > {
+        let (sampler, constraints, tiles, _, positions) = self.into_all_parts()?;
+
+        Ok((sampler, constraints, tiles, positions))
+    }
+
+    /// Like `into_parts`, but also returns an `EdgeConstraint` with the patterns seen on each face
+    /// of the examples.
+    pub fn into_parts_with_edges(
+        self,
+    ) -> Result<
+        (
+            PatternSampler,
+            PatternConstraints,
+            PatternTileSet<T, PeriodicYLevelsIndexer>,
+            EdgeConstraint,
+        ),
+        WfcError,
+    > {
+        let (sampler, constraints, tiles, edges, _) = self.into_all_parts()?;
+
+        Ok((sampler, constraints, tiles, edges))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn into_all_parts(
+        mut self,
+    ) -> Result<
+        (
+            PatternSampler,
+            PatternConstraints,
+            PatternTileSet<T, PeriodicYLevelsIndexer>,
+            EdgeConstraint,
+            Option<PatternPositions>,
+        ),
+        WfcError,
+    > {
+        if self.num_patterns() == 0 {
+            return Err(WfcError::InvalidConfig(
+                "No examples were added to the model".to_string(),
+            ));
+        }
+
+        let slot_size = self.pattern_shape.stride() * self.tile_size;
+        if self.pattern_shape.adjacency == AdjacencyRule::Agreement {
+            self.constraints
+                .add_agreeing_patterns(&self.pattern_voxels, &slot_size);
+        }
+
+        if self.is_empty.is_some() {
+            for (_, weight) in self.pattern_weights.iter_mut() {
+                *weight = (*weight).max(1);
+            }
+        }
+        let sampler = PatternSampler::new(self.pattern_weights);
+        let tiles = PatternTileSet {
+            tiles: PatternMap::new(self.pattern_min_tiles),
+            tile_size: slot_size,
+        };
+        if self.pattern_shape.boundary == InputBoundary::Periodic {
+            self.constraints.assert_valid();
+
+            return Ok((
+                sampler,
+                self.constraints,
+                tiles,
+                self.edges,
+                self.pattern_positions,
+            ));
+        }
+
+        // Patterns at the edge may have been seen without a neighbor on some side, so remove any
+        // that can't be placed.
+        let new_ids = pruned_pattern_ids(&sampler, &self.constraints, 0)?;
+        let (sampler, constraints, tiles) =
+            renumber_patterns(sampler, self.constraints, tiles, &new_ids);
+        let mut edges = self.edges;
+        for (_, edge_patterns) in edges.faces.iter_mut() {
+            *edge_patterns = edge_patterns
+                .iter()
+                .filter_map(|pattern| *new_ids.get(pattern))
+                .collect();
+        }
+        let positions = self.pattern_positions.map(|positions| {
+            let mut new_positions = PatternMap::new(Vec::new());
+            for (pattern, pattern_positions) in positions.into_raw().into_iter().enumerate() {
+                if new_ids.get(PatternId::from(pattern)).is_some() {
+                    new_positions.push(pattern_positions);
+                }
+            }
+
+            new_positions
+        });
+
+        Ok((sampler, constraints, tiles, edges, positions))
+    }
+
+    pub fn finish(self) -> Result<Model<T>, WfcError> {
+        let (sampler, constraints, tiles) = self.into_parts()?;
+
+        Ok(Model::new(sampler, constraints, tiles))
+    }
+}
+
+/// The number of voxels of `pattern` that aren't empty.
+fn pattern_mass<T>(
+    pattern: VecLatticeMap<T, PeriodicYLevelsIndexer>,
+    is_empty: fn(&T) -> bool,
+) -> u32 {
+    pattern
+        .get_extent()
+        .into_iter()
+        .filter(|p| !is_empty(pattern.get_world_ref(p)))
+        .count() as u32
+}
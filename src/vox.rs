@@ -0,0 +1,421 @@
+//! Reading and writing VOX scenes with more than one model.
+
+use dot_vox::Material;
+use ilattice3 as lat;
+use ilattice3::{prelude::*, Indexer, VecLatticeMap, VoxColor, EMPTY_VOX_COLOR};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// MagicaVoxel can't open models larger than this along any axis.
+pub const MAX_VOX_MODEL_SIZE: i32 = 256;
+
+/// Writes `colors` as a VOX file, split into models of at most `MAX_VOX_MODEL_SIZE` along each
+/// axis, which the scene graph places side by side. Unlike the `DotVoxData` conversion, this works
+/// for lattices of any size, and it keeps the `materials` (e.g. emission and glass) of the palette
+/// colors. Chunks without any voxels are left out, unless they all are.
+pub fn write_vox_scene<W, I>(
+    writer: &mut W,
+    colors: &VecLatticeMap<VoxColor, I>,
+    palette: &[u32],
+    materials: &[Material],
+) -> io::Result<()>
+where
+    W: Write,
+    I: Indexer,
+{
+    let extent = colors.get_extent();
+    let size = *extent.get_local_supremum();
+    let max_chunk_size = lat::Point::from([MAX_VOX_MODEL_SIZE; 3]);
+    let num_chunks = size.div_ceil(&max_chunk_size);
+
+    let mut models = Vec::new();
+    let chunk_keys = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), num_chunks);
+    for chunk_key in chunk_keys {
+        let chunk_min = *extent.get_minimum() + chunk_key * max_chunk_size;
+        let chunk_max = min_point(chunk_min + max_chunk_size, extent.get_world_supremum());
+        let chunk_extent = lat::Extent::from_min_and_world_supremum(chunk_min, chunk_max);
+        let voxels: Vec<_> = chunk_extent
+            .into_iter()
+            .filter_map(|p| {
+                let color = *colors.get_world_ref(&p);
+                if color == EMPTY_VOX_COLOR {
+                    return None;
+                }
+                let local = p - chunk_min;
+
+                Some([local.x as u8, local.z as u8, local.y as u8, color + 1])
+            })
+            .collect();
+        if !voxels.is_empty() {
+            models.push((chunk_extent, voxels));
+        }
+    }
+    if models.is_empty() {
+        // MagicaVoxel needs at least one model.
+        let chunk_max = min_point(
+            *extent.get_minimum() + max_chunk_size,
+            extent.get_world_supremum(),
+        );
+        let chunk_extent =
+            lat::Extent::from_min_and_world_supremum(*extent.get_minimum(), chunk_max);
+        models.push((chunk_extent, Vec::new()));
+    }
+
+    let mut main = Vec::new();
+    for (chunk_extent, voxels) in models.iter() {
+        let s = *chunk_extent.get_local_supremum();
+        let mut size_chunk = Vec::new();
+        for c in [s.x, s.z, s.y].iter() {
+            write_i32(&mut size_chunk, *c);
+        }
+        write_chunk(&mut main, b"SIZE", &size_chunk);
+
+        let mut xyzi_chunk = Vec::new();
+        write_i32(&mut xyzi_chunk, voxels.len() as i32);
+        for voxel in voxels.iter() {
+            xyzi_chunk.extend_from_slice(voxel);
+        }
+        write_chunk(&mut main, b"XYZI", &xyzi_chunk);
+    }
+
+    // The root transform holds a group of one transform and shape per model. MagicaVoxel places
+    // models by their centers, rounded down, and swaps Y and Z relative to the lattice.
+    let group_id = 1;
+    let mut root = Vec::new();
+    write_transform(&mut root, 0, group_id, -1, None);
+    write_chunk(&mut main, b"nTRN", &root);
+
+    let child_ids: Vec<i32> = (0..models.len() as i32).map(|i| 2 + 2 * i).collect();
+    let mut group = Vec::new();
+    write_i32(&mut group, group_id);
+    write_dict(&mut group, &[]);
+    write_i32(&mut group, child_ids.len() as i32);
+    for id in child_ids.iter() {
+        write_i32(&mut group, *id);
+    }
+    write_chunk(&mut main, b"nGRP", &group);
+
+    for (model_id, ((chunk_extent, _), transform_id)) in
+        models.iter().zip(child_ids.iter()).enumerate()
+    {
+        let min = *chunk_extent.get_minimum() - *extent.get_minimum();
+        let s = *chunk_extent.get_local_supremum();
+        let center = [min.x + s.x / 2, min.z + s.z / 2, min.y + s.y / 2];
+        let mut transform = Vec::new();
+        write_transform(
+            &mut transform,
+            *transform_id,
+            transform_id + 1,
+            0,
+            Some(center),
+        );
+        write_chunk(&mut main, b"nTRN", &transform);
+
+        let mut shape = Vec::new();
+        write_i32(&mut shape, transform_id + 1);
+        write_dict(&mut shape, &[]);
+        write_i32(&mut shape, 1);
+        write_i32(&mut shape, model_id as i32);
+        write_dict(&mut shape, &[]);
+        write_chunk(&mut main, b"nSHP", &shape);
+    }
+
+    let mut rgba = Vec::with_capacity(256 * 4);
+    for i in 0..256 {
+        rgba.extend_from_slice(&palette.get(i).cloned().unwrap_or(0).to_le_bytes());
+    }
+    write_chunk(&mut main, b"RGBA", &rgba);
+
+    for material in materials.iter() {
+        let mut properties: Vec<(&str, &str)> = material
+            .properties
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        // For reproducible files.
+        properties.sort_unstable();
+        let mut matl = Vec::new();
+        write_i32(&mut matl, material.id as i32);
+        write_dict(&mut matl, &properties);
+        write_chunk(&mut main, b"MATL", &matl);
+    }
+
+    writer.write_all(b"VOX ")?;
+    writer.write_all(&150i32.to_le_bytes())?;
+    writer.write_all(b"MAIN")?;
+    writer.write_all(&0i32.to_le_bytes())?;
+    writer.write_all(&(main.len() as i32).to_le_bytes())?;
+
+    writer.write_all(&main)
+}
+
+/// Finds where the scene graph of the VOX file in `bytes` places each of its models, as the
+/// minimum lattice point of each model, in model order. Models that aren't in the scene graph,
+/// e.g. in files from before MagicaVoxel had one, are placed at the origin. Rotations are
+/// ignored.
+pub fn vox_model_positions(bytes: &[u8]) -> io::Result<Vec<lat::Point>> {
+    let bad_file = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    if bytes.len() < 20 || &bytes[0..4] != b"VOX " || &bytes[8..12] != b"MAIN" {
+        return Err(bad_file("Not a VOX file"));
+    }
+
+    let mut sizes = Vec::new();
+    let mut nodes = HashMap::new();
+    let mut reader = Reader {
+        bytes: &bytes[20..],
+        pos: 0,
+    };
+    while !reader.is_empty() {
+        let id = reader
+            .take(4)
+            .ok_or_else(|| bad_file("Truncated chunk ID"))?;
+        let content_len = reader.i32().ok_or_else(|| bad_file("Truncated chunk"))?;
+        let _children_len = reader.i32().ok_or_else(|| bad_file("Truncated chunk"))?;
+        let content = reader
+            .take(content_len.max(0) as usize)
+            .ok_or_else(|| bad_file("Truncated chunk"))?;
+        let mut content = Reader {
+            bytes: content,
+            pos: 0,
+        };
+        let node = match id {
+            b"SIZE" => {
+                let (x, y, z) = (content.i32(), content.i32(), content.i32());
+                let size = x
+                    .and_then(|x| Some([x, z?, y?]))
+                    .ok_or_else(|| bad_file("Truncated SIZE chunk"))?;
+                sizes.push(lat::Point::from(size));
+                continue;
+            }
+            b"nTRN" => content.transform(),
+            b"nGRP" => content.group(),
+            b"nSHP" => content.shape(),
+            _ => continue,
+        };
+        let (node_id, node) = node.ok_or_else(|| bad_file("Truncated scene graph node"))?;
+        nodes.insert(node_id, node);
+    }
+
+    let mut positions = vec![lat::Point::from([0, 0, 0]); sizes.len()];
+    // Depth-first from the root, accumulating translations.
+    let mut stack = vec![(0, lat::Point::from([0, 0, 0]))];
+    while let Some((node_id, translation)) = stack.pop() {
+        match nodes.get(&node_id) {
+            Some(SceneNode::Transform {
+                child,
+                translation: t,
+            }) => {
+                stack.push((*child, translation + *t));
+            }
+            Some(SceneNode::Group { children }) => {
+                stack.extend(children.iter().map(|child| (*child, translation)));
+            }
+            Some(SceneNode::Shape { models }) => {
+                for model in models.iter() {
+                    if let Some(size) = sizes.get(*model as usize) {
+                        let half_size = lat::Point::from([size.x / 2, size.y / 2, size.z / 2]);
+                        positions[*model as usize] = translation - half_size;
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+
+    Ok(positions)
+}
+
+/// Merges the models of a VOX scene into one lattice that covers all of them, with each model
+/// at its position from `vox_model_positions`. Where models overlap, the non-empty voxels of later
+/// models win.
+pub fn merge_vox_models<I: Indexer>(
+    models: &[VecLatticeMap<VoxColor, I>],
+    positions: &[lat::Point],
+) -> VecLatticeMap<VoxColor, I> {
+    assert_eq!(models.len(), positions.len());
+    assert!(!models.is_empty());
+
+    let mut min = positions[0];
+    let mut max = positions[0];
+    for (model, position) in models.iter().zip(positions.iter()) {
+        min = min_point(min, *position);
+        max = max_point(max, *position + *model.get_extent().get_local_supremum());
+    }
+    let mut merged = VecLatticeMap::fill(
+        lat::Extent::from_min_and_world_supremum(min, max),
+        EMPTY_VOX_COLOR,
+    );
+    for (model, position) in models.iter().zip(positions.iter()) {
+        let extent = model.get_extent();
+        for p in extent {
+            let color = *model.get_world_ref(&p);
+            if color != EMPTY_VOX_COLOR {
+                *merged.get_world_ref_mut(&(*position + p - *extent.get_minimum())) = color;
+            }
+        }
+    }
+
+    merged
+}
+
+fn min_point(a: lat::Point, b: lat::Point) -> lat::Point {
+    [a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)].into()
+}
+
+fn max_point(a: lat::Point, b: lat::Point) -> lat::Point {
+    [a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)].into()
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend_from_slice(id);
+    write_i32(out, content.len() as i32);
+    write_i32(out, 0);
+    out.extend_from_slice(content);
+}
+
+fn write_transform(
+    out: &mut Vec<u8>,
+    node_id: i32,
+    child_id: i32,
+    layer_id: i32,
+    translation: Option<[i32; 3]>,
+) {
+    write_i32(out, node_id);
+    write_dict(out, &[]);
+    write_i32(out, child_id);
+    // Reserved.
+    write_i32(out, -1);
+    write_i32(out, layer_id);
+    // One frame.
+    write_i32(out, 1);
+    match translation {
+        Some([x, y, z]) => write_dict(out, &[("_t", &format!("{} {} {}", x, y, z))]),
+        None => write_dict(out, &[]),
+    }
+}
+
+fn write_dict(out: &mut Vec<u8>, entries: &[(&str, &str)]) {
+    write_i32(out, entries.len() as i32);
+    for (key, value) in entries.iter() {
+        write_string(out, key);
+        write_string(out, value);
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_i32(out, s.len() as i32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_i32(out: &mut Vec<u8>, v: i32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+enum SceneNode {
+    /// The translation is in lattice coordinates.
+    Transform {
+        child: i32,
+        translation: lat::Point,
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        models: Vec<i32>,
+    },
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let taken = self.bytes.get(self.pos..self.pos + n)?;
+        self.pos += n;
+
+        Some(taken)
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        let mut le_bytes = [0; 4];
+        le_bytes.copy_from_slice(self.take(4)?);
+
+        Some(i32::from_le_bytes(le_bytes))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.i32()?;
+
+        Some(String::from_utf8_lossy(self.take(len.max(0) as usize)?).into_owned())
+    }
+
+    fn dict(&mut self) -> Option<HashMap<String, String>> {
+        let num_entries = self.i32()?;
+        let mut dict = HashMap::new();
+        for _ in 0..num_entries {
+            let key = self.string()?;
+            dict.insert(key, self.string()?);
+        }
+
+        Some(dict)
+    }
+
+    fn transform(&mut self) -> Option<(i32, SceneNode)> {
+        let node_id = self.i32()?;
+        self.dict()?;
+        let child = self.i32()?;
+        let _reserved = self.i32()?;
+        let _layer_id = self.i32()?;
+        let num_frames = self.i32()?;
+        // Only the first frame of an animation counts.
+        let mut translation = lat::Point::from([0, 0, 0]);
+        for frame in 0..num_frames {
+            let attributes = self.dict()?;
+            if frame > 0 {
+                continue;
+            }
+            if let Some(t) = attributes.get("_t") {
+                let t: Vec<i32> = t
+                    .split_whitespace()
+                    .filter_map(|c| c.parse().ok())
+                    .collect();
+                if let [x, y, z] = t.as_slice() {
+                    translation = [*x, *z, *y].into();
+                }
+            }
+        }
+
+        Some((node_id, SceneNode::Transform { child, translation }))
+    }
+
+    fn group(&mut self) -> Option<(i32, SceneNode)> {
+        let node_id = self.i32()?;
+        self.dict()?;
+        let num_children = self.i32()?;
+        let children = (0..num_children)
+            .map(|_| self.i32())
+            .collect::<Option<_>>()?;
+
+        Some((node_id, SceneNode::Group { children }))
+    }
+
+    fn shape(&mut self) -> Option<(i32, SceneNode)> {
+        let node_id = self.i32()?;
+        self.dict()?;
+        let num_models = self.i32()?;
+        let mut models = Vec::new();
+        for _ in 0..num_models {
+            models.push(self.i32()?);
+            self.dict()?;
+        }
+
+        Some((node_id, SceneNode::Shape { models }))
+    }
+}
@@ -0,0 +1,158 @@
+//! A thin `wasm-bindgen` wrapper around `Generator` for browser demos. Images go in and out as flat
+//! arrays of packed RGBA colors, one per pixel, in row-major order.
+
+use crate::{
+    generate::{UpdateResult, NUM_SEED_BYTES},
+    model::{Model, ModelGenerator},
+    offset::{edge_2d_offsets, OffsetGroup},
+    pattern::{PatternMap, PatternSet, PatternShape},
+};
+
+use ilattice3 as lat;
+use ilattice3::{prelude::*, PeriodicYLevelsIndexer, VecLatticeMap};
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StepResult {
+    Continue,
+    Success,
+    Failure,
+}
+
+/// Generates a 2D image from the patterns of an input image, one pixel per tile.
+#[wasm_bindgen]
+pub struct WasmGenerator {
+    /// The color of each pattern's tile.
+    pattern_colors: PatternMap<u32>,
+    generator: ModelGenerator<u32>,
+    output_size: lat::Point,
+    result: Option<StepResult>,
+}
+
+#[wasm_bindgen]
+impl WasmGenerator {
+    /// `input` holds `input_width * input_height` colors. Panics if the sizes don't match the
+    /// input.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        input: &[u32],
+        input_width: i32,
+        input_height: i32,
+        pattern_width: i32,
+        pattern_height: i32,
+        output_width: i32,
+        output_height: i32,
+        seed: u32,
+    ) -> Result<WasmGenerator, JsValue> {
+        assert_eq!(input.len(), (input_width * input_height) as usize);
+
+        let extent = lat::Extent::from_min_and_local_supremum(
+            [0, 0, 0].into(),
+            [input_width, input_height, 1].into(),
+        );
+        let mut input_lattice = VecLatticeMap::<_, PeriodicYLevelsIndexer>::fill(extent, 0);
+        for p in extent {
+            *input_lattice.get_world_ref_mut(&p) = input[(p.y * input_width + p.x) as usize];
+        }
+
+        let offset_group = OffsetGroup::new(&edge_2d_offsets()).expect("Invalid built-in offsets");
+        let pattern_shape = PatternShape::with_full_overlap(
+            [pattern_width, pattern_height, 1].into(),
+            offset_group,
+        );
+        let tile_size = [1, 1, 1].into();
+        let model = Model::from_lattice(&input_lattice, &tile_size, &pattern_shape)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let tile_extent = lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), tile_size);
+        let pattern_colors = model.tiles().tiles.map(|tile| {
+            tile.clone()
+                .put_in_extent(tile_extent)
+                .get_world(&[0, 0, 0].into())
+        });
+
+        let mut seed_bytes = [0; NUM_SEED_BYTES];
+        seed_bytes[..4].copy_from_slice(&seed.to_le_bytes());
+        let output_size = [output_width, output_height, 1].into();
+        let generator = model.generator(seed_bytes, output_size);
+
+        Ok(WasmGenerator {
+            pattern_colors,
+            generator,
+            output_size,
+            result: None,
+        })
+    }
+
+    /// Runs up to `max_updates` updates, e.g. once per animation frame.
+    pub fn step(&mut self, max_updates: u32) -> StepResult {
+        if let Some(result) = self.result {
+            return result;
+        }
+        match self.generator.run_steps(max_updates as usize) {
+            UpdateResult::Continue => (),
+            UpdateResult::Success => self.result = Some(StepResult::Success),
+            _ => self.result = Some(StepResult::Failure),
+        }
+
+        self.result.unwrap_or(StepResult::Continue)
+    }
+
+    pub fn num_collapsed(&self) -> usize {
+        self.generator.generator().num_collapsed()
+    }
+
+    pub fn output_width(&self) -> i32 {
+        self.output_size.x
+    }
+
+    pub fn output_height(&self) -> i32 {
+        self.output_size.y
+    }
+
+    /// The number of possible patterns in each slot of the wave.
+    pub fn possible_counts(&self) -> Vec<u32> {
+        self.map_slots(|patterns| patterns.len())
+    }
+
+    /// The current output, with each slot colored by the mean of its possible patterns. Slots with
+    /// no possible patterns are transparent.
+    pub fn colors(&self) -> Vec<u32> {
+        self.map_slots(|patterns| {
+            let mut sum = [0.0; 4];
+            let mut num_patterns = 0;
+            for pattern in patterns.iter() {
+                let color = self.pattern_colors.get(pattern).to_le_bytes();
+                for i in 0..4 {
+                    sum[i] += color[i] as f32;
+                }
+                num_patterns += 1;
+            }
+            if num_patterns == 0 {
+                return 0;
+            }
+            let mut mean = [0; 4];
+            for i in 0..4 {
+                mean[i] = (sum[i] / num_patterns as f32) as u8;
+            }
+
+            u32::from_le_bytes(mean)
+        })
+    }
+}
+
+impl WasmGenerator {
+    /// Applies `f` to each slot in row-major order.
+    fn map_slots<T>(&self, f: impl Fn(&PatternSet) -> T) -> Vec<T> {
+        let wave = self.generator.generator().get_wave_lattice();
+
+        let mut values = Vec::with_capacity((self.output_size.x * self.output_size.y) as usize);
+        for y in 0..self.output_size.y {
+            for x in 0..self.output_size.x {
+                values.push(f(wave.get_world_ref(&[x, y, 0].into())));
+            }
+        }
+
+        values
+    }
+}
@@ -1,14 +1,40 @@
 use crate::{
+    chooser::PatternChooser,
+    connectivity::{ConnectivityConstraint, ConnectivityTracker},
+    count::{CountConstraint, CountTracker},
+    event::ObserverEvent,
+    forensics::{ContradictionReport, RemovalLog},
+    generate::{
+        EntropyMode, FailureReason, GenerationError, GeneratorConfig, Propagation, TieBreak,
+        WaveStorage,
+    },
+    guidance::WeightField,
     offset::OffsetId,
     pattern::{
-        PatternConstraints, PatternId, PatternMap, PatternSampler, PatternSet, PatternSupport,
+        weight_log_weight, PatternConstraints, PatternId, PatternIndex, PatternSampler, PatternSet,
+        SupportCount, SupportCounts, SMALL_SET_CAPACITY,
     },
+    snapshot::WaveSnapshot,
 };
 
+#[cfg(feature = "gpu")]
+use crate::gpu::GpuPropagator;
+
+use hibitset::{BitSet, BitSetLike};
 use ilattice3 as lat;
 use ilattice3::{prelude::*, VecLatticeMap};
-use log::{debug, info, warn};
 use rand::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use tracing::{debug, debug_span, warn};
+
+static NEXT_WAVE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// How many current entropy heap entries `choose_separated_slots` considers per slot it chooses.
+const CANDIDATES_PER_SEPARATED_SLOT: usize = 8;
 
 /// The colloquial "wave function" to be collapsed. Stores the possible remaining patterns that
 /// could go in each slot of the output, as well as related acceleration data structures.
@@ -22,41 +48,461 @@ pub struct Wave {
     /// The current entropy of each slot. It's faster to store this than recompute every frame.
     entropy_cache: VecLatticeMap<SlotEntropyCache>,
 
+    /// The entropy sums of each slot in fixed point, for `EntropyMode::FixedPoint`. The entropies
+    /// in `entropy_cache` are then computed from these instead of the `f32` sums.
+    fixed_entropy: Option<VecLatticeMap<FixedEntropySums>>,
+
+    /// Random noise added to each slot's entropy (by linear index) to break ties between slots.
+    /// Empty if ties go to the lowest index instead.
+    entropy_noise: Vec<f32>,
+
+    /// Min-heap of slot entropies, so choosing a slot doesn't require scanning the whole wave.
+    /// Entries are never updated in place; a new entry is pushed whenever a slot's entropy changes,
+    /// and stale entries are skipped when they reach the top.
+    entropy_heap: BinaryHeap<EntropyHeapEntry>,
+
     /// Counts each pattern's remaining support at each offset. Once a given pattern P, for any
     /// offset, has no supporting patterns at that offset, P is no longer possible.
-    pattern_supports: VecLatticeMap<PatternMap<PatternSupport>>,
+    pattern_supports: SupportCounts,
 
     /// Container of patterns remove from slots. Currently used as a stack, but could eventually be
     /// used as a log for backtracking.
     removal_stack: Vec<(SlotId, PatternId)>,
+
+    /// The removals on `removal_stack`, so none is pushed twice.
+    queued_removals: QueuedRemovals,
+
+    /// How much work propagation has done so far.
+    propagation_stats: PropagationStats,
+    /// Patterns removed from slots so far, for `Generator::stats`.
+    num_removals: u64,
+    /// Contradictions found so far, for `Generator::stats`.
+    num_contradictions: u64,
+    /// Calls to `reopen_region` so far, for `Generator::stats`.
+    num_reopens: u64,
+
+    /// Optional per-slot multipliers of the pattern weights.
+    guidance: Option<Arc<dyn WeightField>>,
+
+    /// The patterns each slot may hold again after `reopen_region`: the initial patterns, narrowed
+    /// by `constrain_slot`, `constrain_slots` and `ban`. `None` while every pattern is allowed
+    /// everywhere.
+    restrictions: Option<VecLatticeMap<PatternSet>>,
+
+    /// Running counts for each `CountConstraint`.
+    count_trackers: Vec<CountTracker>,
+
+    /// Check schedules for each `ConnectivityConstraint`.
+    connectivity_trackers: Vec<ConnectivityTracker>,
+
+    /// Overrides how observed slots choose their pattern.
+    chooser: Option<Arc<dyn PatternChooser>>,
+
+    /// Where to send events, if anyone is listening.
+    events: Option<Sender<ObserverEvent>>,
+
+    /// The slots (by linear index) that lost patterns since `clear_modified_slots`, if tracked.
+    modified_slots: Option<BitSet>,
+
+    /// Tells this wave's `ChangeStamp`s apart from other waves'.
+    wave_id: u64,
+    /// How many times a slot changed while changes were tracked.
+    num_changes: u64,
+    /// The value of `num_changes` when each slot last changed, if tracked.
+    slot_changes: Option<Vec<u64>>,
+
+    /// Every removal so far, if tracked.
+    removal_log: Option<RemovalLog>,
+
+    /// Explains the last contradiction, if removals were tracked.
+    contradiction: Option<ContradictionReport>,
+
+    /// Propagates instead of the removal stack, for `Propagation::Gpu`.
+    #[cfg(feature = "gpu")]
+    gpu: Option<GpuPropagator>,
 }
 
 impl Wave {
-    pub fn new(
+    pub fn new<R: Rng>(
         sampler: &PatternSampler,
         constraints: &PatternConstraints,
         output_size: lat::Point,
+        config: &GeneratorConfig,
+        rng: &mut R,
     ) -> Self {
-        // Start with all possible patterns.
+        #[cfg(not(feature = "gpu"))]
+        {
+            if config.propagation == Propagation::Gpu {
+                warn!("Built without the \"gpu\" feature, propagating on the CPU");
+            }
+        }
+
+        let guidance = config.guidance.clone();
+
+        // Start with all possible patterns, unless the config narrows them down.
         let all_possible = PatternSet::all(constraints.num_patterns());
 
         let extent = lat::Extent::from_min_and_world_supremum([0, 0, 0].into(), output_size);
-        let slots = VecLatticeMap::fill(extent, all_possible.clone());
+        let mut slots = VecLatticeMap::fill(extent, all_possible.clone());
+        // The generator already checked that the initial patterns cover the output.
+        if let Some(initial_patterns) = &config.initial_patterns {
+            for slot in extent {
+                let possible = slots.get_world_ref_mut(&slot);
+                possible.intersect_with(initial_patterns.get_world_ref(&slot));
+                debug_assert!(!possible.is_empty(), "No initial patterns for {}", slot);
+            }
+        }
+        let restrictions = config.initial_patterns.as_ref().map(|_| slots.clone());
 
-        let initial_entropy = slot_entropy(sampler, &all_possible);
+        let initial_entropy = slot_entropy(&all_possible, |p| {
+            (
+                sampler.get_weight(p) as f32,
+                sampler.get_weight_log_weight(p),
+            )
+        });
         debug!("Initial entropy = {:?}", initial_entropy);
-        let entropy_cache = VecLatticeMap::fill(extent, initial_entropy);
+        let mut entropy_cache = VecLatticeMap::fill(extent, initial_entropy);
+        if guidance.is_some() || config.initial_patterns.is_some() {
+            // Every slot has its own weights or patterns.
+            for slot in extent {
+                *entropy_cache.get_world_ref_mut(&slot) =
+                    slot_entropy(slots.get_world_ref(&slot), |p| match &guidance {
+                        Some(guidance) => {
+                            let weight =
+                                sampler.get_weight(p) as f32 * guidance.weight_multiplier(&slot, p);
 
-        let initial_supports = constraints.get_initial_support();
-        let pattern_supports = VecLatticeMap::fill(extent, initial_supports);
+                            (weight, weight_log_weight(weight))
+                        }
+                        None => (
+                            sampler.get_weight(p) as f32,
+                            sampler.get_weight_log_weight(p),
+                        ),
+                    });
+            }
+        }
+
+        let fixed_entropy = match config.entropy {
+            EntropyMode::FixedPoint => Some(fixed_slot_entropies(
+                &slots,
+                &mut entropy_cache,
+                |slot, p| {
+                    let weight = sampler.get_weight(p) as f32;
+                    match &guidance {
+                        Some(guidance) => weight * guidance.weight_multiplier(slot, p),
+                        None => weight,
+                    }
+                },
+            )),
+            EntropyMode::Float => None,
+        };
+
+        let num_slots = extent.volume();
+        let entropy_noise: Vec<f32> = match config.tie_break {
+            TieBreak::Noise { amplitude } => (0..num_slots)
+                .map(|_| amplitude * rng.gen::<f32>())
+                .collect(),
+            TieBreak::LowestIndex => Vec::new(),
+        };
+        let entropy_heap = (0..num_slots)
+            .map(|i| (i, entropy_cache.get_linear_ref(i).entropy))
+            .filter(|(_, entropy)| entropy.is_finite())
+            .map(|(i, entropy)| EntropyHeapEntry::new(SlotId(i), entropy, &entropy_noise))
+            .collect();
+
+        let mut pattern_supports = match &config.storage {
+            WaveStorage::Memory => SupportCounts::new(num_slots, constraints),
+            #[cfg(feature = "mmap")]
+            WaveStorage::MappedFile(path) => SupportCounts::mapped(num_slots, constraints, path)
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "Failed to map {}, keeping supports in memory: {}",
+                        path.display(),
+                        e
+                    );
+
+                    SupportCounts::new(num_slots, constraints)
+                }),
+        };
+
+        // The supports assume every pattern is possible everywhere, so the patterns left out of the
+        // initial patterns are queued as removals, to be propagated before the first observation.
+        let mut collapsed_count = 0;
+        let mut removal_stack = Vec::new();
+        let mut queued_removals = QueuedRemovals::new(num_slots, constraints.num_patterns());
+        if config.initial_patterns.is_some() {
+            for slot_index in 0..num_slots {
+                let possible = slots.get_linear_ref(slot_index);
+                if possible.len() == 1 {
+                    collapsed_count += 1;
+                }
+                for pattern in all_possible.iter().filter(|p| !possible.contains(*p)) {
+                    pattern_supports.clear(slot_index, pattern);
+                    queued_removals.insert(SlotId(slot_index), pattern);
+                    removal_stack.push((SlotId(slot_index), pattern));
+                }
+            }
+        }
 
         Wave {
             slots,
-            collapsed_count: 0,
+            collapsed_count,
+            entropy_cache,
+            fixed_entropy,
+            entropy_noise,
+            entropy_heap,
+            pattern_supports,
+            propagation_stats: PropagationStats {
+                pushes: removal_stack.len() as u64,
+                max_depth: removal_stack.len(),
+                ..PropagationStats::default()
+            },
+            num_removals: 0,
+            num_contradictions: 0,
+            num_reopens: 0,
+            removal_stack,
+            queued_removals,
+            guidance,
+            restrictions,
+            count_trackers: Vec::new(),
+            connectivity_trackers: Vec::new(),
+            chooser: None,
+            events: None,
+            modified_slots: None,
+            wave_id: NEXT_WAVE_ID.fetch_add(1, AtomicOrdering::Relaxed),
+            num_changes: 0,
+            slot_changes: None,
+            removal_log: None,
+            contradiction: None,
+            #[cfg(feature = "gpu")]
+            gpu: match config.propagation {
+                Propagation::Gpu => {
+                    let gpu = GpuPropagator::new(constraints, output_size);
+                    if gpu.is_none() {
+                        warn!("No usable GPU, propagating on the CPU");
+                    }
+
+                    gpu
+                }
+                Propagation::Cpu => None,
+            },
+        }
+    }
+
+    /// Roughly how many bytes a wave of `num_slots` slots takes for a model with `num_patterns`
+    /// patterns and `num_offsets` offsets, not counting the removal stack, which only grows during
+    /// propagation. The pattern supports, with a count for every (slot, pattern, offset), dominate.
+    pub(crate) fn estimate_memory(
+        num_slots: usize,
+        num_patterns: usize,
+        num_offsets: usize,
+    ) -> usize {
+        // Small sets are inline. Otherwise count the lowest layer of the bit set; the layers above
+        // it are 64 times smaller each.
+        let bit_set_words = if num_patterns <= SMALL_SET_CAPACITY as usize {
+            0
+        } else {
+            (num_patterns + 63) / 64
+        };
+        let slot = std::mem::size_of::<PatternSet>()
+            + bit_set_words * std::mem::size_of::<usize>()
+            + std::mem::size_of::<SlotEntropyCache>()
+            + std::mem::size_of::<FixedEntropySums>()
+            + std::mem::size_of::<f32>()
+            + std::mem::size_of::<EntropyHeapEntry>()
+            + num_patterns * num_offsets * std::mem::size_of::<SupportCount>()
+            + num_patterns / 8;
+
+        num_slots * slot
+    }
+
+    /// Flattens the wave for `save_snapshot`. Must be called between propagations.
+    pub(crate) fn to_snapshot(&self) -> WaveSnapshot {
+        debug_assert!(self.removal_stack.is_empty());
+
+        let num_slots = self.num_slots();
+        let slots = (0..num_slots)
+            .map(|i| self.slots.get_linear_ref(i).iter().map(|p| p.0).collect())
+            .collect();
+        let entropies = (0..num_slots)
+            .map(|i| {
+                let cache = self.entropy_cache.get_linear_ref(i);
+
+                [
+                    cache.sum_weights,
+                    cache.sum_weights_log_weights,
+                    cache.entropy,
+                ]
+            })
+            .collect();
+        let fixed_entropies = match &self.fixed_entropy {
+            Some(fixed) => (0..num_slots)
+                .map(|i| {
+                    let sums = fixed.get_linear_ref(i);
+
+                    [sums.sum_weights, sums.sum_weights_log_weights]
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let restrictions = match &self.restrictions {
+            Some(restrictions) => (0..num_slots)
+                .map(|i| restrictions.get_linear_ref(i).iter().map(|p| p.0).collect())
+                .collect(),
+            None => Vec::new(),
+        };
+        let output_size = *self.slots.get_extent().get_local_supremum();
+
+        WaveSnapshot {
+            output_size: output_size.into(),
+            num_patterns: self.pattern_supports.num_patterns() as PatternIndex,
+            num_offsets: self.pattern_supports.num_offsets(),
+            slots,
+            entropies,
+            fixed_entropies,
+            entropy_noise: self.entropy_noise.clone(),
+            supports: self.pattern_supports.to_vec(),
+            restrictions,
+        }
+    }
+
+    /// Rebuilds a wave saved with `to_snapshot`. Returns `None` if the snapshot is inconsistent.
+    pub(crate) fn from_snapshot(
+        snapshot: WaveSnapshot,
+        constraints: &PatternConstraints,
+        config: &GeneratorConfig,
+    ) -> Option<Self> {
+        let num_patterns = constraints.num_patterns();
+        let num_offsets = constraints.get_offset_group().num_offsets();
+        let extent =
+            lat::Extent::from_min_and_local_supremum([0, 0, 0].into(), snapshot.output_size.into());
+        let num_slots = extent.volume();
+        if snapshot.slots.len() != num_slots
+            || snapshot.entropies.len() != num_slots
+            || snapshot.supports.len() != num_slots * num_patterns as usize * num_offsets
+            || !(snapshot.entropy_noise.is_empty() || snapshot.entropy_noise.len() == num_slots)
+            || !(snapshot.fixed_entropies.is_empty() || snapshot.fixed_entropies.len() == num_slots)
+            || !(snapshot.restrictions.is_empty() || snapshot.restrictions.len() == num_slots)
+        {
+            return None;
+        }
+
+        let mut slots = VecLatticeMap::fill(extent, PatternSet::all(num_patterns));
+        let mut collapsed_count = 0;
+        for (i, possible) in snapshot.slots.iter().enumerate() {
+            let set = slots.get_linear_ref_mut(i);
+            *set = pattern_set_from_indices(possible, num_patterns)?;
+            if set.is_empty() {
+                return None;
+            }
+            if set.len() == 1 {
+                collapsed_count += 1;
+            }
+        }
+        let restrictions = if snapshot.restrictions.is_empty() {
+            None
+        } else {
+            let mut restrictions = VecLatticeMap::fill(extent, PatternSet::empty());
+            for (i, allowed) in snapshot.restrictions.iter().enumerate() {
+                *restrictions.get_linear_ref_mut(i) =
+                    pattern_set_from_indices(allowed, num_patterns)?;
+            }
+
+            Some(restrictions)
+        };
+
+        let mut entropy_cache = VecLatticeMap::fill(extent, SlotEntropyCache::default());
+        for (i, [sum_weights, sum_weights_log_weights, entropy]) in
+            snapshot.entropies.into_iter().enumerate()
+        {
+            *entropy_cache.get_linear_ref_mut(i) = SlotEntropyCache {
+                sum_weights,
+                sum_weights_log_weights,
+                entropy,
+            };
+        }
+        // The entropy mode is restored from the snapshot, like the noise, so the resumed run makes
+        // the same choices.
+        let fixed_entropy = if snapshot.fixed_entropies.is_empty() {
+            None
+        } else {
+            let mut fixed = VecLatticeMap::fill(extent, FixedEntropySums::default());
+            for (i, [sum_weights, sum_weights_log_weights]) in
+                snapshot.fixed_entropies.into_iter().enumerate()
+            {
+                *fixed.get_linear_ref_mut(i) = FixedEntropySums {
+                    sum_weights,
+                    sum_weights_log_weights,
+                };
+            }
+
+            Some(fixed)
+        };
+        let entropy_noise = snapshot.entropy_noise;
+        let entropy_heap = (0..num_slots)
+            .map(|i| (i, entropy_cache.get_linear_ref(i).entropy))
+            .filter(|(_, entropy)| entropy.is_finite())
+            .map(|(i, entropy)| EntropyHeapEntry::new(SlotId(i), entropy, &entropy_noise))
+            .collect();
+
+        let pattern_supports =
+            SupportCounts::from_counts(snapshot.supports, num_patterns as usize, num_offsets);
+
+        Some(Wave {
+            collapsed_count,
+            slots,
             entropy_cache,
+            fixed_entropy,
+            entropy_noise,
+            entropy_heap,
             pattern_supports,
             removal_stack: Vec::new(),
+            queued_removals: QueuedRemovals::new(num_slots, num_patterns),
+            propagation_stats: PropagationStats::default(),
+            num_removals: 0,
+            num_contradictions: 0,
+            num_reopens: 0,
+            guidance: config.guidance.clone(),
+            restrictions,
+            count_trackers: Vec::new(),
+            connectivity_trackers: Vec::new(),
+            chooser: None,
+            events: None,
+            modified_slots: None,
+            wave_id: NEXT_WAVE_ID.fetch_add(1, AtomicOrdering::Relaxed),
+            num_changes: 0,
+            slot_changes: None,
+            removal_log: None,
+            contradiction: None,
+            #[cfg(feature = "gpu")]
+            gpu: None,
+        })
+    }
+
+    /// Frees what collapsed slots no longer need: their pattern sets shrink to fit the one pattern
+    /// left, and the support counts of slots whose neighbors have all collapsed too are dropped,
+    /// since no removal can reach them anymore, short of a contradiction. `reopen_region` counts
+    /// them again. Must be called between propagations. Returns how many slots had their supports
+    /// dropped by this call, which is always 0 for `WaveStorage::MappedFile`.
+    pub fn compact(&mut self, constraints: &PatternConstraints) -> usize {
+        debug_assert!(self.removal_stack.is_empty());
+
+        let extent = self.slots.get_extent();
+        let num_slots = self.num_slots();
+        let mut settled = vec![false; num_slots];
+        for (slot_index, is_settled) in settled.iter_mut().enumerate() {
+            if self.slots.get_linear_ref(slot_index).len() != 1 {
+                continue;
+            }
+            self.slots.get_linear_ref_mut(slot_index).shrink_to_fit();
+            let slot = self.slots.local_point_from_index(slot_index);
+            *is_settled = constraints.get_offset_group().iter().all(|(_, offset)| {
+                let neighbor = slot + *offset;
+
+                !extent.contains_world(&neighbor) || self.slots.get_world_ref(&neighbor).len() == 1
+            });
         }
+        self.pattern_supports
+            .retain_slots(num_slots, |slot_index| !settled[slot_index])
     }
 
     pub fn num_slots(&self) -> usize {
@@ -71,53 +517,567 @@ impl Wave {
         self.collapsed_count == self.num_slots()
     }
 
-    pub fn choose_least_entropy_slot<R: Rng>(&self, rng: &mut R) -> (lat::Point, f32) {
-        // Micro-optimization: Don't use the extent iterator, just linear indices. It's involves far
-        // less arithmetic and branching.
-        (0..self.num_slots())
-            .map(|linear_index| {
-                let noise: f32 = rng.gen();
-                let cache = *self.entropy_cache.get_linear_ref(linear_index);
-                let entropy = cache.entropy + 0.1 * noise;
+    /// Returns the uncollapsed slot with the least (noisy) entropy, or `None` if every slot is
+    /// collapsed.
+    pub fn choose_least_entropy_slot(&mut self) -> Option<(lat::Point, f32)> {
+        while let Some(entry) = self.entropy_heap.peek() {
+            let entry = *entry;
+            if entry.is_current(&self.entropy_cache) {
+                return Some((
+                    self.entropy_cache.local_point_from_index(entry.slot.0),
+                    entry.priority,
+                ));
+            }
+            self.entropy_heap.pop();
+        }
 
-                (linear_index, entropy)
-            })
-            .min_by(|(_, e1), (_, e2)| e1.partial_cmp(&e2).expect("Unexpected NaN"))
-            .map(|(i, e)| (self.entropy_cache.local_point_from_index(i), e))
-            .unwrap()
+        None
+    }
+
+    /// Like `choose_least_entropy_slot`, but also chooses up to `max_slots - 1` more uncollapsed
+    /// slots, in order of entropy, that are each at least `min_distance` from every slot chosen
+    /// before along some axis. Only a few slots past `max_slots` are considered, so fewer may be
+    /// chosen if the least entropy is crowded in one place.
+    pub fn choose_separated_slots(
+        &mut self,
+        max_slots: usize,
+        min_distance: i32,
+    ) -> Vec<(lat::Point, f32)> {
+        let max_candidates = max_slots * CANDIDATES_PER_SEPARATED_SLOT;
+        let mut candidates = Vec::new();
+        let mut chosen: Vec<(lat::Point, f32)> = Vec::new();
+        while chosen.len() < max_slots && candidates.len() < max_candidates {
+            let entry = match self.entropy_heap.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if !entry.is_current(&self.entropy_cache) {
+                continue;
+            }
+            candidates.push(entry);
+            let slot = self.entropy_cache.local_point_from_index(entry.slot.0);
+            let separated = chosen.iter().all(|(other, _)| {
+                let [dx, dy, dz]: [i32; 3] = (slot - *other).into();
+
+                dx.abs().max(dy.abs()).max(dz.abs()) >= min_distance
+            });
+            if separated {
+                chosen.push((slot, entry.priority));
+            }
+        }
+        // The chosen slots go back too; their entries go stale once they're observed.
+        for entry in candidates.into_iter() {
+            self.entropy_heap.push(entry);
+        }
+
+        chosen
     }
 
     /// Forces `slot` to conform to a single pattern P. P is chosen by sampling from the prior
-    /// distribution.
+    /// distribution, unless a `PatternChooser` is set.
     pub fn observe_slot<R: Rng>(
         &mut self,
         rng: &mut R,
         sampler: &PatternSampler,
         constraints: &PatternConstraints,
         slot: &lat::Point,
-    ) -> bool {
+    ) -> Result<(), GenerationError> {
         let possible_patterns = self.get_slot(slot);
-        let pattern = sampler.sample_pattern(possible_patterns, rng);
-        debug!("Assigning {:?}", pattern);
+        let pattern = if let Some(chooser) = &self.chooser {
+            let patterns: Vec<PatternId> = possible_patterns.iter().collect();
+            let weights: Vec<f32> = patterns
+                .iter()
+                .map(|p| self.weight(sampler, slot, *p))
+                .collect();
+
+            patterns[chooser.choose(slot, &patterns, &weights, rng)]
+        } else {
+            match &self.guidance {
+                Some(guidance) => sampler.sample_pattern_scaled(possible_patterns, rng, |p| {
+                    guidance.weight_multiplier(slot, p)
+                }),
+                None => sampler.sample_pattern(possible_patterns, rng),
+            }
+        };
+        debug!(pattern = pattern.0, "Assigning pattern");
+
+        self.collapse_slot(sampler, slot, pattern);
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    pub fn set_chooser(&mut self, chooser: Arc<dyn PatternChooser>) {
+        self.chooser = Some(chooser);
+    }
+
+    pub fn set_event_sender(&mut self, events: Sender<ObserverEvent>) {
+        self.events = Some(events);
+    }
+
+    /// Starts recording the slots that lose patterns, for `modified_slots`.
+    pub fn track_modified_slots(&mut self) {
+        if self.modified_slots.is_none() {
+            self.modified_slots = Some(BitSet::new());
+        }
+    }
+
+    pub fn clear_modified_slots(&mut self) {
+        if let Some(modified) = &mut self.modified_slots {
+            modified.clear();
+        }
+    }
+
+    /// The slots that lost patterns since `clear_modified_slots`. Always empty unless
+    /// `track_modified_slots` was called.
+    pub fn modified_slots(&self) -> Vec<lat::Point> {
+        match &self.modified_slots {
+            Some(modified) => modified
+                .iter()
+                .map(|i| self.slots.local_point_from_index(i as usize))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Starts recording when each slot changes, for `changed_slots_since`.
+    pub fn track_changes(&mut self) {
+        if self.slot_changes.is_none() {
+            // Stamps from before now report every slot.
+            self.num_changes += 1;
+            self.slot_changes = Some(vec![self.num_changes; self.num_slots()]);
+        }
+    }
+
+    pub fn change_stamp(&self) -> ChangeStamp {
+        ChangeStamp {
+            wave_id: self.wave_id,
+            num_changes: self.num_changes,
+        }
+    }
+
+    /// The slots that lost or regained patterns after `since` was taken. Every slot if `since` is
+    /// `None`, came from another wave, or changes aren't tracked.
+    pub fn changed_slots_since(&self, since: Option<ChangeStamp>) -> Vec<lat::Point> {
+        match (&self.slot_changes, since) {
+            (Some(changes), Some(since)) if since.wave_id == self.wave_id => changes
+                .iter()
+                .enumerate()
+                .filter(|(_, changed)| **changed > since.num_changes)
+                .map(|(i, _)| self.slots.local_point_from_index(i))
+                .collect(),
+            _ => self.slots.get_extent().into_iter().collect(),
+        }
+    }
+
+    fn mark_changed(&mut self, slot_index: usize) {
+        if let Some(changes) = &mut self.slot_changes {
+            self.num_changes += 1;
+            changes[slot_index] = self.num_changes;
+        }
+    }
+
+    /// Starts recording every removal, so contradictions can be explained by
+    /// `contradiction_report`.
+    pub fn track_removals(&mut self) {
+        if self.removal_log.is_none() {
+            self.removal_log = Some(RemovalLog::default());
+        }
+    }
+
+    /// Explains the last contradiction. Always `None` unless `track_removals` was called before it.
+    pub fn contradiction_report(&self) -> Option<&ContradictionReport> {
+        self.contradiction.as_ref()
+    }
+
+    pub fn emit(&self, event: ObserverEvent) {
+        if let Some(events) = &self.events {
+            // Nobody is listening anymore.
+            let _ = events.send(event);
+        }
+    }
+
+    /// Propagates any removals that haven't been yet, i.e. those of patterns left out of the
+    /// initial patterns. Returns an error iff we find a slot with no possible patterns.
+    pub fn propagate_pending(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> Result<(), GenerationError> {
+        if self.removal_stack.is_empty() {
+            return Ok(());
+        }
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    /// Removes every pattern from `slot` that isn't in `allowed`, then propagates. Returns an error
+    /// iff we find a slot with no possible patterns.
+    pub fn constrain_slot(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        allowed: &PatternSet,
+    ) -> Result<(), GenerationError> {
+        self.remember_restriction(constraints, slot, |p| allowed.contains(p));
+        self.restrict_slot(sampler, constraints, slot, |p| allowed.contains(p))?;
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    /// Removes `pattern` from `slot`, then propagates. Returns an error iff we find a slot with no
+    /// possible patterns.
+    pub fn ban(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        pattern: PatternId,
+    ) -> Result<(), GenerationError> {
+        self.remember_restriction(constraints, slot, |p| p != pattern);
+        self.restrict_slot(sampler, constraints, slot, |p| p != pattern)?;
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    /// Collapses `slot` to `pattern`, then propagates. Returns an error iff we find a slot with no
+    /// possible patterns, including `slot` itself if `pattern` was already removed from it.
+    pub fn force_slot(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        pattern: PatternId,
+    ) -> Result<(), GenerationError> {
+        self.restrict_slot(sampler, constraints, slot, |p| p == pattern)?;
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    /// Restricts every slot in `restrictions` to its allowed patterns, then propagates once. Returns
+    /// an error iff we find a slot with no possible patterns.
+    pub fn constrain_slots<'a, A>(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        restrictions: A,
+    ) -> Result<(), GenerationError>
+    where
+        A: IntoIterator<Item = (lat::Point, &'a PatternSet)>,
+    {
+        for (slot, allowed) in restrictions.into_iter() {
+            self.remember_restriction(constraints, &slot, |p| allowed.contains(p));
+            self.restrict_slot(sampler, constraints, &slot, |p| allowed.contains(p))?;
+        }
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    /// Collapses every slot in `assignments` to its pattern, then propagates once. Returns an error
+    /// iff we find a slot with no possible patterns.
+    pub fn force_slots<A>(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        assignments: A,
+    ) -> Result<(), GenerationError>
+    where
+        A: IntoIterator<Item = (lat::Point, PatternId)>,
+    {
+        for (slot, pattern) in assignments.into_iter() {
+            self.restrict_slot(sampler, constraints, &slot, |p| p == pattern)?;
+        }
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    /// Puts every slot in `region` (clipped to the output) back into superposition, then propagates
+    /// the constraints of the slots around it. Slots get back the patterns they started with, less
+    /// any removed by `constrain_slot`, `constrain_slots` or `ban`. Only the reopened slots can be
+    /// chosen by `choose_least_entropy_slot` afterwards. Returns an error iff we find a slot with
+    /// no possible patterns or a count constraint that can't be met.
+    pub fn reopen_region(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        region: &lat::Extent,
+    ) -> Result<(), GenerationError> {
+        debug_assert!(self.removal_stack.is_empty());
+        self.num_reopens += 1;
+
+        let extent = self.slots.get_extent();
+        let all_possible = PatternSet::all(constraints.num_patterns());
+        let reopened: Vec<lat::Point> = region
+            .into_iter()
+            .filter(|slot| extent.contains_world(slot))
+            .collect();
+        for slot in reopened.iter() {
+            let possible = self.slots.get_world_ref_mut(slot);
+            if possible.len() == 1 {
+                self.collapsed_count -= 1;
+            }
+            *possible = match &self.restrictions {
+                Some(restrictions) => restrictions.get_world_ref(slot).clone(),
+                None => all_possible.clone(),
+            };
+            self.mark_changed(self.slots.index_from_local_point(slot));
+        }
+        #[cfg(feature = "gpu")]
+        {
+            if let Some(gpu) = &mut self.gpu {
+                gpu.invalidate();
+            }
+        }
+
+        // Supports changed in the reopened slots and in any slot next to them.
+        let offset_group = constraints.get_offset_group();
+        let mut recount: Vec<lat::Point> = reopened.clone();
+        for slot in reopened.iter() {
+            for (_, offset) in offset_group.iter() {
+                let neighbor = *slot + *offset;
+                if extent.contains_world(&neighbor) {
+                    recount.push(neighbor);
+                }
+            }
+        }
+        recount.sort_by_key(|slot| self.slots.index_from_local_point(slot));
+        recount.dedup();
+        let initial_supports = constraints.get_initial_support();
+        let num_offsets = offset_group.num_offsets();
+        for slot in recount.iter() {
+            let slot_index = self.slots.index_from_local_point(slot);
+            self.pattern_supports.restore_slot(slot_index);
+            let possible = self.slots.get_world_ref(slot);
+            for pattern in (0..constraints.num_patterns()).map(PatternId) {
+                if !possible.contains(pattern) {
+                    self.pattern_supports.clear(slot_index, pattern);
+                    continue;
+                }
+                let initial = &initial_supports[pattern.0 as usize * num_offsets..];
+                let counts = self.pattern_supports.get_mut(slot_index, pattern);
+                for (offset_id, offset) in offset_group.iter() {
+                    // Nothing past the edge of the output was ever removed, so the initial support
+                    // still holds.
+                    let support_slot = *slot - *offset;
+                    counts[offset_id.0] = if extent.contains_world(&support_slot) {
+                        self.slots
+                            .get_world_ref(&support_slot)
+                            .iter()
+                            .filter(|p| constraints.are_compatible(*p, pattern, offset_id))
+                            .count() as SupportCount
+                    } else {
+                        initial[offset_id.0]
+                    };
+                }
+            }
+        }
+
+        // The counts of reopened slots start over.
+        let trackers = std::mem::take(&mut self.count_trackers);
+        self.count_trackers = trackers
+            .into_iter()
+            .map(|tracker| CountTracker::new(tracker.constraint, &self.slots))
+            .collect();
+
+        for slot in reopened.iter() {
+            if self.slots.get_world_ref(slot).len() == 1 {
+                self.collapsed_count += 1;
+                continue;
+            }
+            let mut entropy = slot_entropy(self.get_slot(slot), |p| {
+                self.weight_and_log_weight(sampler, slot, p)
+            });
+            let fixed = self.fixed_entropy.as_ref().map(|_| {
+                fixed_slot_entropy(self.get_slot(slot), |p| self.weight(sampler, slot, p))
+            });
+            if let (Some(fixed_entropy), Some((sums, fixed))) = (&mut self.fixed_entropy, fixed) {
+                *fixed_entropy.get_world_ref_mut(slot) = sums;
+                entropy.entropy = fixed;
+            }
+            *self.entropy_cache.get_world_ref_mut(slot) = entropy;
+            let slot_id = SlotId(self.slots.index_from_local_point(slot));
+            self.push_entropy(slot_id, entropy.entropy);
+        }
 
-        self.collapse_slot(sampler, constraints, slot, pattern);
+        // Remove the patterns that the slots around the region don't support.
+        let (slots, supports) = (&self.slots, &self.pattern_supports);
+        let unsupported: Vec<(lat::Point, PatternId)> = reopened
+            .iter()
+            .flat_map(|slot| {
+                let slot_index = slots.index_from_local_point(slot);
+
+                slots
+                    .get_linear_ref(slot_index)
+                    .iter()
+                    .filter(move |p| supports.get(slot_index, *p).contains(&0))
+                    .map(move |p| (*slot, p))
+            })
+            .collect();
+        for (slot, pattern) in unsupported.into_iter() {
+            if self.remove_pattern(sampler, &slot, pattern, None) {
+                return Err(self.contradiction(constraints, &slot, pattern, 0));
+            }
+        }
+
+        self.propagate_constraints(sampler, constraints)
+    }
+
+    /// Starts enforcing `count`, which must already be validated, then propagates. Returns an
+    /// error iff we find a slot with no possible patterns or the count can't be met.
+    pub fn add_count_constraint(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        count: CountConstraint,
+    ) -> Result<(), GenerationError> {
+        self.count_trackers
+            .push(CountTracker::new(count, &self.slots));
 
         self.propagate_constraints(sampler, constraints)
     }
 
-    /// Returns `false` iff we find a slot with no possible patterns.
+    /// Starts enforcing `connectivity`. Returns an error iff it already can't be met.
+    pub fn add_connectivity_constraint(
+        &mut self,
+        constraints: &PatternConstraints,
+        connectivity: ConnectivityConstraint,
+    ) -> Result<(), GenerationError> {
+        let tracker = ConnectivityTracker::new(connectivity);
+        self.connectivity_trackers.push(tracker);
+
+        self.check_connectivity(constraints, true)
+    }
+
+    /// Checks the connectivity constraints that are due after a propagation, or all of them if
+    /// `force` or the wave is determined. Returns an error iff one can't be met.
+    fn check_connectivity(
+        &mut self,
+        constraints: &PatternConstraints,
+        force: bool,
+    ) -> Result<(), GenerationError> {
+        let force = force || self.determined();
+        for i in 0..self.connectivity_trackers.len() {
+            if self.connectivity_trackers[i].after_propagation(
+                &self.slots,
+                constraints.get_offset_group(),
+                force,
+            ) {
+                continue;
+            }
+
+            warn!("Connectivity constraint can't be met");
+            let error = GenerationError {
+                reason: FailureReason::ConnectivityUnsatisfiable,
+                slot: None,
+                emptied_by: None,
+                chain_length: 0,
+                recoverable_patterns: Vec::new(),
+            };
+            self.report_contradiction(constraints, &error);
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Keeps `reopen_region` from giving `slot` back the patterns for which `keep` is false.
+    fn remember_restriction<F>(
+        &mut self,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        keep: F,
+    ) where
+        F: Fn(PatternId) -> bool,
+    {
+        let extent = self.slots.get_extent();
+        if !extent.contains_world(slot) {
+            // `restrict_slot` reports it.
+            return;
+        }
+        let num_patterns = constraints.num_patterns();
+        let allowed = self
+            .restrictions
+            .get_or_insert_with(|| VecLatticeMap::fill(extent, PatternSet::all(num_patterns)))
+            .get_world_ref_mut(slot);
+        let removed: Vec<PatternId> = allowed.iter().filter(|p| !keep(*p)).collect();
+        for pattern in removed.into_iter() {
+            allowed.remove(pattern);
+        }
+    }
+
+    /// Removes patterns from `slot` without propagating. Returns an error iff `slot` is left empty.
+    fn restrict_slot<F>(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        keep: F,
+    ) -> Result<(), GenerationError>
+    where
+        F: Fn(PatternId) -> bool,
+    {
+        debug_assert!(
+            self.slots.get_extent().contains_world(slot),
+            "Slot {} is outside of the output",
+            slot
+        );
+
+        let remove_patterns: Vec<PatternId> =
+            self.get_slot(slot).iter().filter(|p| !keep(*p)).collect();
+        for pattern in remove_patterns.into_iter() {
+            let slot_empty = self.remove_pattern(sampler, slot, pattern, None);
+            if slot_empty {
+                return Err(self.contradiction(constraints, slot, pattern, 0));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an error iff we find a slot with no possible patterns or a count or connectivity
+    /// constraint that can't be met.
     fn propagate_constraints(
         &mut self,
         sampler: &PatternSampler,
         constraints: &PatternConstraints,
-    ) -> bool {
+    ) -> Result<(), GenerationError> {
+        let _span = debug_span!("propagate", pending = self.removal_stack.len()).entered();
+
+        loop {
+            self.propagate_adjacency(sampler, constraints)?;
+            if !self.enforce_counts(sampler, constraints)? {
+                break;
+            }
+        }
+
+        self.check_connectivity(constraints, false)
+    }
+
+    /// Returns an error iff we find a slot with no possible patterns.
+    fn propagate_adjacency(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> Result<(), GenerationError> {
+        #[cfg(feature = "gpu")]
+        {
+            if self.gpu.is_some() {
+                return self.propagate_adjacency_gpu(sampler, constraints);
+            }
+        }
+
         // This algorithm is similar to flood fill, but each slot may need to be visited multiple
         // times.
-        while !self.removal_stack.is_empty() {
+        let mut chain_length = 0;
+        while let Some((visit_slot_id, impossible_at_visit_slot)) = self.removal_stack.pop() {
+            chain_length += 1;
+            self.queued_removals
+                .remove(visit_slot_id, impossible_at_visit_slot);
+            self.propagation_stats.pops += 1;
             // We know that this pattern is not longer possible at `visit_slot`, so no adjacent
             // patterns can use it as support.
-            let (visit_slot, impossible_at_visit_slot) = self.removal_stack.pop().unwrap();
-            let visit_slot = self.slots.local_point_from_index(visit_slot.0);
+            let visit_slot = self.slots.local_point_from_index(visit_slot_id.0);
 
             for (offset_id, offset) in constraints.get_offset_group().iter() {
                 // Make sure we don't index out of bounds.
@@ -133,31 +1093,180 @@ impl Wave {
                 {
                     let no_support = self.remove_support(&offset_slot, offset_pattern, offset_id);
                     if no_support {
-                        let slot_empty =
-                            self.remove_pattern(sampler, constraints, &offset_slot, offset_pattern);
+                        let slot_empty = self.remove_pattern(
+                            sampler,
+                            &offset_slot,
+                            offset_pattern,
+                            Some((visit_slot_id.0, impossible_at_visit_slot)),
+                        );
                         if slot_empty {
                             // Failed to fully assign the output lattice. Give up.
-                            warn!("No possible patterns for {}", offset_slot);
-                            return false;
+                            return Err(self.contradiction(
+                                constraints,
+                                &offset_slot,
+                                offset_pattern,
+                                chain_length,
+                            ));
                         }
                     }
                 }
             }
         }
 
-        true
+        Ok(())
+    }
+
+    /// Like `propagate_adjacency`, but the GPU finds the unsupported patterns, and only their
+    /// removal is applied here. Support counts aren't kept up to date.
+    #[cfg(feature = "gpu")]
+    fn propagate_adjacency_gpu(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> Result<(), GenerationError> {
+        let mut changed: Vec<lat::Point> = self
+            .removal_stack
+            .iter()
+            .map(|(slot, _)| self.slots.local_point_from_index(slot.0))
+            .collect();
+        changed.sort_by_key(|slot| self.slots.index_from_local_point(slot));
+        changed.dedup();
+        self.clear_removal_stack();
+
+        let removed = self.gpu.as_mut().unwrap().propagate(&self.slots, &changed);
+        self.propagation_stats.pops += removed.len() as u64;
+        for (slot, pattern) in removed.into_iter() {
+            // Which removal caused this one isn't known.
+            if self.remove_pattern(sampler, &slot, pattern, None) {
+                return Err(self.contradiction(constraints, &slot, pattern, 0));
+            }
+        }
+        // The GPU already propagated these.
+        self.clear_removal_stack();
+
+        Ok(())
     }
 
-    /// Even though this slot has no patterns, it may be recoverable (if it was collapsed).
+    /// Removes patterns for any count constraint that just saturated. Returns `true` iff patterns
+    /// were removed, which still need to be propagated.
+    fn enforce_counts(
+        &mut self,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> Result<bool, GenerationError> {
+        let mut removed_any = false;
+        for i in 0..self.count_trackers.len() {
+            let tracker = &mut self.count_trackers[i];
+            if tracker.violated() {
+                // No change is to blame if the count couldn't be met from the start.
+                let (slot, pattern) = match tracker.last_change {
+                    Some((slot, pattern)) => (Some(slot), Some(pattern)),
+                    None => (None, None),
+                };
+                warn!(
+                    "Count constraint {:?} can't be met, last changed at {:?}",
+                    tracker.constraint, slot
+                );
+                self.clear_removal_stack();
+
+                let error = GenerationError {
+                    reason: FailureReason::CountUnsatisfiable,
+                    slot,
+                    emptied_by: pattern,
+                    chain_length: 0,
+                    recoverable_patterns: Vec::new(),
+                };
+                self.report_contradiction(constraints, &error);
+
+                return Err(error);
+            }
+
+            // Slots that have some, but not only, the constraint's patterns.
+            let keep_counted =
+                if !tracker.max_enforced && tracker.assigned == tracker.constraint.max {
+                    tracker.max_enforced = true;
+
+                    false
+                } else if !tracker.min_enforced && tracker.possible == tracker.constraint.min {
+                    tracker.min_enforced = true;
+
+                    true
+                } else {
+                    continue;
+                };
+            let patterns = tracker.constraint.patterns.clone();
+            let mixed_slots: Vec<usize> = (0..self.num_slots())
+                .filter(|s| {
+                    let remaining = self.count_trackers[i].remaining[*s] as usize;
+
+                    remaining > 0 && remaining < self.slots.get_linear_ref(*s).len() as usize
+                })
+                .collect();
+            for slot_index in mixed_slots.into_iter() {
+                let slot = self.slots.local_point_from_index(slot_index);
+                self.restrict_slot(sampler, constraints, &slot, |p| {
+                    patterns.contains(p) == keep_counted
+                })?;
+                removed_any = true;
+            }
+        }
+
+        Ok(removed_any)
+    }
+
+    fn contradiction(
+        &mut self,
+        constraints: &PatternConstraints,
+        slot: &lat::Point,
+        emptied_by: PatternId,
+        chain_length: usize,
+    ) -> GenerationError {
+        warn!(%slot, emptied_by = emptied_by.0, chain_length, "No possible patterns");
+        // The wave is unusable from here on, so don't leave stale removals for the next
+        // propagation.
+        self.clear_removal_stack();
+
+        let error = GenerationError {
+            reason: FailureReason::EmptySlot,
+            slot: Some(*slot),
+            emptied_by: Some(emptied_by),
+            chain_length,
+            recoverable_patterns: self.check_slot_for_possible_patterns(constraints, slot),
+        };
+        self.report_contradiction(constraints, &error);
+
+        error
+    }
+
+    fn report_contradiction(&mut self, constraints: &PatternConstraints, error: &GenerationError) {
+        self.num_contradictions += 1;
+        if let Some(log) = &self.removal_log {
+            self.contradiction = Some(ContradictionReport::new(
+                error.clone(),
+                log,
+                &self.slots,
+                constraints.get_offset_group(),
+            ));
+        }
+        self.emit(ObserverEvent::Contradiction(error.clone()));
+    }
+
+    /// Even though this slot has no patterns, it may be recoverable (if it was collapsed). Returns
+    /// the patterns that are still compatible with every neighboring slot.
     fn check_slot_for_possible_patterns(
         &self,
         constraints: &PatternConstraints,
         impossible_slot: &lat::Point,
-    ) {
+    ) -> Vec<PatternId> {
+        let mut possible = Vec::new();
         'check_pattern: for pattern in 0..constraints.num_patterns() {
             let pattern = PatternId(pattern);
             'check_offset: for (offset_id, offset) in constraints.get_offset_group().iter() {
                 let offset_slot = *impossible_slot + *offset;
+                if !self.slots.get_extent().contains_world(&offset_slot) {
+                    // Nothing outside of the output constrains the slot.
+                    continue 'check_offset;
+                }
                 for offset_pattern in self.slots.get_local(&offset_slot).iter() {
                     if constraints.are_compatible(pattern, offset_pattern, offset_id) {
                         // Offset pattern is compatible with our pattern. Check the next offset.
@@ -169,56 +1278,140 @@ impl Wave {
                 continue 'check_pattern;
             }
 
-            info!(
-                "Pattern {:?} is possible at slot {}, \
-                but it was removed (likely during collapse)",
-                pattern, impossible_slot
-            );
+            possible.push(pattern);
         }
+
+        possible
     }
 
-    /// Returns `true` iff the slot is empty after removal.
+    /// Returns `true` iff the slot is empty after removal. `cause` is the removal that propagated
+    /// to this one, if any, as a slot index and pattern.
     fn remove_pattern(
         &mut self,
         sampler: &PatternSampler,
-        constraints: &PatternConstraints,
         slot: &lat::Point,
         pattern: PatternId,
+        cause: Option<(usize, PatternId)>,
     ) -> bool {
         let possible_slot_patterns = self.slots.get_world_ref_mut(slot);
         possible_slot_patterns.remove(pattern);
-
         let num_remaining_patterns_in_slot = possible_slot_patterns.len();
+        self.num_removals += 1;
+        self.mark_changed(self.slots.index_from_local_point(slot));
+        self.emit(ObserverEvent::PatternRemoved {
+            slot: *slot,
+            pattern,
+        });
+        if let Some(log) = &mut self.removal_log {
+            log.push(
+                self.slots.index_from_local_point(slot),
+                *slot,
+                pattern,
+                cause,
+            );
+        }
+
         if num_remaining_patterns_in_slot == 0 {
-            self.check_slot_for_possible_patterns(constraints, slot);
             return true;
         }
+        if !self.count_trackers.is_empty() {
+            let slot_index = self.slots.index_from_local_point(slot);
+            let last_pattern = if num_remaining_patterns_in_slot == 1 {
+                self.get_slot(slot).iter().next()
+            } else {
+                None
+            };
+            for tracker in self.count_trackers.iter_mut() {
+                tracker.remove_pattern(slot_index, slot, pattern, last_pattern);
+            }
+        }
         if num_remaining_patterns_in_slot == 1 {
             // Don't want to choose this slot again.
             self.set_max_entropy(slot);
             self.collapsed_count += 1;
+            if self.events.is_some() {
+                let assigned = self.get_slot(slot).iter().next().unwrap();
+                self.emit(ObserverEvent::PatternAssigned {
+                    slot: *slot,
+                    pattern: assigned,
+                });
+            }
         } else {
             self.reduce_entropy(sampler, slot, pattern);
         }
 
         // Even though this pattern is being removed, it may still have support at some offsets.
         // Just clear that support now so we don't trigger another removal.
-        let support = self
-            .pattern_supports
-            .get_world_ref_mut(slot)
-            .get_mut(pattern);
-        support.clear();
+        let slot_index = self.slots.index_from_local_point(slot);
+        self.pattern_supports.clear(slot_index, pattern);
 
-        self.removal_stack
-            .push((SlotId(self.slots.index_from_local_point(slot)), pattern));
+        if let Some(modified) = &mut self.modified_slots {
+            modified.add(slot_index as u32);
+        }
+        self.push_removal(SlotId(slot_index), pattern);
 
         false
     }
 
+    fn push_removal(&mut self, slot: SlotId, pattern: PatternId) {
+        if !self.queued_removals.insert(slot, pattern) {
+            self.propagation_stats.duplicates += 1;
+            return;
+        }
+        self.removal_stack.push((slot, pattern));
+        self.propagation_stats.pushes += 1;
+        self.propagation_stats.max_depth = self
+            .propagation_stats
+            .max_depth
+            .max(self.removal_stack.len());
+    }
+
+    fn clear_removal_stack(&mut self) {
+        for (slot, pattern) in self.removal_stack.drain(..) {
+            self.queued_removals.remove(slot, pattern);
+        }
+    }
+
+    /// Whether constraints are propagated on the GPU, in which case the support counts are stale.
+    pub fn uses_gpu(&self) -> bool {
+        #[cfg(feature = "gpu")]
+        {
+            self.gpu.is_some()
+        }
+        #[cfg(not(feature = "gpu"))]
+        {
+            false
+        }
+    }
+
+    /// How much work propagation has done since the wave was created.
+    pub fn propagation_stats(&self) -> PropagationStats {
+        self.propagation_stats
+    }
+
+    pub fn num_removals(&self) -> u64 {
+        self.num_removals
+    }
+
+    pub fn num_contradictions(&self) -> u64 {
+        self.num_contradictions
+    }
+
+    pub fn num_reopens(&self) -> u64 {
+        self.num_reopens
+    }
+
+    /// The patterns that could still be removed: the sum over all slots of one less than the
+    /// number of possible patterns. Zero once the wave is determined.
+    pub fn remaining_superposition(&self) -> u64 {
+        (0..self.num_slots())
+            .map(|i| self.slots.get_linear_ref(i).len().saturating_sub(1) as u64)
+            .sum()
+    }
+
     fn collapse_slot(
         &mut self,
         sampler: &PatternSampler,
-        constraints: &PatternConstraints,
         slot: &lat::Point,
         assign_pattern: PatternId,
     ) {
@@ -228,7 +1421,7 @@ impl Wave {
             set.iter().filter(|p| *p != assign_pattern).collect()
         };
         for pattern in remove_patterns.iter() {
-            self.remove_pattern(sampler, constraints, slot, *pattern);
+            self.remove_pattern(sampler, slot, *pattern, None);
         }
     }
 
@@ -238,11 +1431,42 @@ impl Wave {
         slot: &lat::Point,
         remove_pattern: PatternId,
     ) {
+        let (weight, weight_log_weight) = self.weight_and_log_weight(sampler, slot, remove_pattern);
         let cache = self.entropy_cache.get_world_ref_mut(slot);
-        let weight = sampler.get_weight(remove_pattern) as f32;
-        cache.sum_weights -= weight;
-        cache.sum_weights_log_weights -= weight * weight.log2();
-        cache.entropy = entropy(cache.sum_weights, cache.sum_weights_log_weights);
+        cache.entropy = match &mut self.fixed_entropy {
+            Some(fixed) => {
+                let sums = fixed.get_world_ref_mut(slot);
+                sums.remove(weight);
+
+                sums.entropy()
+            }
+            None => {
+                cache.sum_weights -= weight;
+                cache.sum_weights_log_weights -= weight_log_weight;
+
+                entropy(cache.sum_weights, cache.sum_weights_log_weights)
+            }
+        };
+        let new_entropy = cache.entropy;
+
+        let slot_id = SlotId(self.slots.index_from_local_point(slot));
+        self.push_entropy(slot_id, new_entropy);
+    }
+
+    fn push_entropy(&mut self, slot: SlotId, entropy: f32) {
+        self.entropy_heap
+            .push(EntropyHeapEntry::new(slot, entropy, &self.entropy_noise));
+
+        // Stale entries pile up as entropies decrease. Drop them once they dominate the heap.
+        if self.entropy_heap.len() > 4 * self.num_slots() {
+            let cache = &self.entropy_cache;
+            let current: Vec<EntropyHeapEntry> = self
+                .entropy_heap
+                .drain()
+                .filter(|e| e.is_current(cache))
+                .collect();
+            self.entropy_heap = current.into();
+        }
     }
 
     fn set_max_entropy(&mut self, slot: &lat::Point) {
@@ -253,22 +1477,84 @@ impl Wave {
         cache.entropy = inf;
     }
 
+    /// The weight of `pattern` in `slot`, including any guidance.
+    fn weight(&self, sampler: &PatternSampler, slot: &lat::Point, pattern: PatternId) -> f32 {
+        let weight = sampler.get_weight(pattern) as f32;
+        match &self.guidance {
+            Some(guidance) => {
+                let multiplier = guidance.weight_multiplier(slot, pattern);
+                debug_assert!(
+                    multiplier > 0.0 && multiplier.is_finite(),
+                    "Weight multipliers must be positive and finite"
+                );
+
+                weight * multiplier
+            }
+            None => weight,
+        }
+    }
+
+    /// The weight of `pattern` in `slot` and `weight * log2(weight)`. Without guidance, both come
+    /// from the sampler's tables instead of computing a logarithm.
+    fn weight_and_log_weight(
+        &self,
+        sampler: &PatternSampler,
+        slot: &lat::Point,
+        pattern: PatternId,
+    ) -> (f32, f32) {
+        match &self.guidance {
+            Some(_) => {
+                let weight = self.weight(sampler, slot, pattern);
+
+                (weight, weight_log_weight(weight))
+            }
+            None => (
+                sampler.get_weight(pattern) as f32,
+                sampler.get_weight_log_weight(pattern),
+            ),
+        }
+    }
+
     pub fn get_slots(&self) -> &VecLatticeMap<PatternSet> {
         &self.slots
     }
 
+    /// The cached entropy of each slot. Collapsed slots are infinite.
+    pub fn get_entropies(&self) -> VecLatticeMap<f32> {
+        self.entropy_cache.map(|cache| cache.entropy)
+    }
+
     fn get_slot(&self, slot: &lat::Point) -> &PatternSet {
         self.slots.get_world_ref(slot)
     }
 
     fn remove_support(&mut self, slot: &lat::Point, pattern: PatternId, offset: OffsetId) -> bool {
-        self.pattern_supports
-            .get_world_ref_mut(slot)
-            .get_mut(pattern)
-            .remove(offset)
+        let slot_index = self.slots.index_from_local_point(slot);
+
+        self.pattern_supports.remove(slot_index, pattern, offset)
     }
 }
 
+/// The set of the patterns in `indices`, as saved in a `WaveSnapshot`. Returns `None` if any is out
+/// of range.
+fn pattern_set_from_indices(
+    indices: &[PatternIndex],
+    num_patterns: PatternIndex,
+) -> Option<PatternSet> {
+    let mut keep = vec![false; num_patterns as usize];
+    for p in indices.iter() {
+        *keep.get_mut(*p as usize)? = true;
+    }
+    let mut set = PatternSet::all(num_patterns);
+    for (p, keep) in keep.into_iter().enumerate() {
+        if !keep {
+            set.remove(PatternId::from(p));
+        }
+    }
+
+    Some(set)
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 struct SlotEntropyCache {
     sum_weights: f32,
@@ -277,12 +1563,21 @@ struct SlotEntropyCache {
 }
 
 fn entropy(sum_weights: f32, sum_weights_log_weights: f32) -> f32 {
+    if sum_weights <= 0.0 {
+        // Only zero-weight patterns remain, and they're all equally (un)likely.
+        return 0.0;
+    }
+
     // This is in fact a correct entropy formula, but it takes some algebra to see that it is
     // equivalent to -Σ p*log(p) where p(n) = weight(n) / Σ weight(n).
     sum_weights.log2() - sum_weights_log_weights / sum_weights
 }
 
-fn slot_entropy(sampler: &PatternSampler, possible_patterns: &PatternSet) -> SlotEntropyCache {
+/// `weight` gives each pattern's weight and `weight * log2(weight)`.
+fn slot_entropy<W>(possible_patterns: &PatternSet, weight: W) -> SlotEntropyCache
+where
+    W: Fn(PatternId) -> (f32, f32),
+{
     assert!(!possible_patterns.is_empty());
 
     // Collapsed slots shouldn't be chosen.
@@ -298,9 +1593,9 @@ fn slot_entropy(sampler: &PatternSampler, possible_patterns: &PatternSet) -> Slo
     let mut sum_weights = 0.0;
     let mut sum_weights_log_weights = 0.0;
     for pattern in possible_patterns.iter() {
-        let weight = sampler.get_weight(pattern) as f32;
+        let (weight, weight_log_weight) = weight(pattern);
         sum_weights += weight;
-        sum_weights_log_weights += weight * weight.log2();
+        sum_weights_log_weights += weight_log_weight;
     }
     let entropy = entropy(sum_weights, sum_weights_log_weights);
 
@@ -311,5 +1606,445 @@ fn slot_entropy(sampler: &PatternSampler, possible_patterns: &PatternSet) -> Slo
     }
 }
 
+/// Fractional bits of the weights in `FixedEntropySums`.
+const WEIGHT_FRACTION_BITS: u32 = 8;
+/// Fractional bits of the logarithms in `FixedEntropySums` and of the entropies computed from them.
+const LOG_FRACTION_BITS: u32 = 16;
+
+/// The sums of `SlotEntropyCache` in fixed point, for `EntropyMode::FixedPoint`. Integer sums come
+/// out the same in any order, and `log2_fixed` only uses integer arithmetic, so the entropy of a
+/// slot is identical on every platform.
+#[derive(Clone, Copy, Debug, Default)]
+struct FixedEntropySums {
+    /// With `WEIGHT_FRACTION_BITS` fractional bits.
+    sum_weights: u128,
+    /// With `WEIGHT_FRACTION_BITS + LOG_FRACTION_BITS` fractional bits.
+    sum_weights_log_weights: u128,
+}
+
+impl FixedEntropySums {
+    fn add(&mut self, weight: f32) {
+        let weight = fixed_weight(weight);
+        self.sum_weights += weight;
+        self.sum_weights_log_weights += fixed_weight_log_weight(weight);
+    }
+
+    fn remove(&mut self, weight: f32) {
+        let weight = fixed_weight(weight);
+        self.sum_weights -= weight;
+        self.sum_weights_log_weights -= fixed_weight_log_weight(weight);
+    }
+
+    /// The same formula as `entropy`. Scaling every weight by the same factor doesn't change the
+    /// entropy, so the fractional bits of the weights cancel out.
+    fn entropy(&self) -> f32 {
+        if self.sum_weights == 0 {
+            return 0.0;
+        }
+
+        let entropy = log2_fixed(self.sum_weights) as i128
+            - (self.sum_weights_log_weights / self.sum_weights) as i128;
+
+        // Both conversions are exact or correctly rounded.
+        entropy as f32 / (1 << LOG_FRACTION_BITS) as f32
+    }
+}
+
+/// Rounds `weight` down to fixed point. Float to integer casts saturate, so huge weights clamp.
+fn fixed_weight(weight: f32) -> u128 {
+    (weight * (1 << WEIGHT_FRACTION_BITS) as f32) as u64 as u128
+}
+
+fn fixed_weight_log_weight(weight: u128) -> u128 {
+    if weight > 0 {
+        weight * log2_fixed(weight)
+    } else {
+        0
+    }
+}
+
+/// The base 2 logarithm of `x > 0`, with `LOG_FRACTION_BITS` fractional bits, rounded down.
+fn log2_fixed(x: u128) -> u128 {
+    let integer_part = 127 - x.leading_zeros();
+    // Normalize to [1, 2) with 31 fractional bits, so squaring fits in a u64.
+    let mut mantissa = if integer_part >= 31 {
+        (x >> (integer_part - 31)) as u64
+    } else {
+        (x << (31 - integer_part)) as u64
+    };
+    let mut log = (integer_part as u128) << LOG_FRACTION_BITS;
+    // Squaring the mantissa doubles its logarithm, so each square reveals the next bit.
+    for bit in (0..LOG_FRACTION_BITS).rev() {
+        mantissa = (mantissa * mantissa) >> 31;
+        if mantissa >= 1 << 32 {
+            mantissa >>= 1;
+            log |= 1 << bit;
+        }
+    }
+
+    log
+}
+
+/// Like `slot_entropy`, but in fixed point. Also returns the entropy.
+fn fixed_slot_entropy<W>(possible_patterns: &PatternSet, weight: W) -> (FixedEntropySums, f32)
+where
+    W: Fn(PatternId) -> f32,
+{
+    // Collapsed slots shouldn't be chosen.
+    if possible_patterns.len() == 1 {
+        return (FixedEntropySums::default(), std::f32::INFINITY);
+    }
+
+    let mut sums = FixedEntropySums::default();
+    for pattern in possible_patterns.iter() {
+        sums.add(weight(pattern));
+    }
+    let entropy = sums.entropy();
+
+    (sums, entropy)
+}
+
+/// Computes the fixed-point sums of every slot, replacing the entropies in `entropy_cache` with
+/// the fixed-point ones.
+fn fixed_slot_entropies<W>(
+    slots: &VecLatticeMap<PatternSet>,
+    entropy_cache: &mut VecLatticeMap<SlotEntropyCache>,
+    weight: W,
+) -> VecLatticeMap<FixedEntropySums>
+where
+    W: Fn(&lat::Point, PatternId) -> f32,
+{
+    let extent = slots.get_extent();
+    let mut fixed = VecLatticeMap::fill(extent, FixedEntropySums::default());
+    for slot in extent {
+        let (sums, entropy) = fixed_slot_entropy(slots.get_world_ref(&slot), |p| weight(&slot, p));
+        *fixed.get_world_ref_mut(&slot) = sums;
+        entropy_cache.get_world_ref_mut(&slot).entropy = entropy;
+    }
+
+    fixed
+}
+
+/// An entry in the `Wave`'s entropy min-heap.
+#[derive(Clone, Copy)]
+struct EntropyHeapEntry {
+    slot: SlotId,
+    /// The slot's entropy at the time this entry was pushed.
+    entropy: f32,
+    /// The entropy plus noise, used for ordering. Ties go to the lowest slot index.
+    priority: f32,
+}
+
+impl EntropyHeapEntry {
+    fn new(slot: SlotId, entropy: f32, noise: &[f32]) -> Self {
+        EntropyHeapEntry {
+            slot,
+            entropy,
+            priority: entropy + noise.get(slot.0).cloned().unwrap_or(0.0),
+        }
+    }
+
+    /// `false` if the slot's entropy has changed since this entry was pushed.
+    fn is_current(&self, entropy_cache: &VecLatticeMap<SlotEntropyCache>) -> bool {
+        entropy_cache.get_linear_ref(self.slot.0).entropy == self.entropy
+    }
+}
+
+impl PartialEq for EntropyHeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for EntropyHeapEntry {}
+
+impl PartialOrd for EntropyHeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EntropyHeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed so the `BinaryHeap` pops the least entropy (then the lowest index) first.
+        other
+            .priority
+            .partial_cmp(&self.priority)
+            .expect("Unexpected NaN")
+            .then_with(|| other.slot.0.cmp(&self.slot.0))
+    }
+}
+
+/// A point in the history of a `Wave`, to find the slots that changed after it with
+/// `Generator::changed_slots_since`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChangeStamp {
+    wave_id: u64,
+    num_changes: u64,
+}
+
+/// Counts of the work done by propagation, for profiling. See `Generator::propagation_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PropagationStats {
+    /// Removals pushed onto the removal stack, including those of observations.
+    pub pushes: u64,
+    /// Removals popped off the stack and propagated to the neighboring slots.
+    pub pops: u64,
+    /// Removals that weren't pushed because they were already on the stack.
+    pub duplicates: u64,
+    /// The most removals on the stack at once.
+    pub max_depth: usize,
+}
+
+/// One bit per (slot, pattern). A `hibitset::BitSet` only holds 2^24 bits, which isn't enough for
+/// large outputs.
+struct QueuedRemovals {
+    words: Vec<u64>,
+    num_patterns: usize,
+}
+
+impl QueuedRemovals {
+    fn new(num_slots: usize, num_patterns: PatternIndex) -> Self {
+        let num_patterns = num_patterns as usize;
+
+        QueuedRemovals {
+            words: vec![0; (num_slots * num_patterns + 63) / 64],
+            num_patterns,
+        }
+    }
+
+    /// Returns `true` iff the removal wasn't already queued.
+    fn insert(&mut self, slot: SlotId, pattern: PatternId) -> bool {
+        let (word, bit) = self.position(slot, pattern);
+        let was_queued = self.words[word] & bit != 0;
+        self.words[word] |= bit;
+
+        !was_queued
+    }
+
+    fn remove(&mut self, slot: SlotId, pattern: PatternId) {
+        let (word, bit) = self.position(slot, pattern);
+        self.words[word] &= !bit;
+    }
+
+    fn position(&self, slot: SlotId, pattern: PatternId) -> (usize, u64) {
+        let i = slot.0 * self.num_patterns + pattern.0 as usize;
+
+        (i / 64, 1 << (i % 64))
+    }
+}
+
 /// Linear index of a slot in the wave lattice.
+#[derive(Clone, Copy)]
 struct SlotId(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        generate::{EntropyMode, Generator, UpdateResult},
+        offset::{edge_2d_offsets, OffsetGroup},
+        socket::{Socket, SocketRules},
+    };
+
+    fn pipe_rules() -> (PatternSampler, PatternConstraints) {
+        let mut rules = SocketRules::new(OffsetGroup::new(&edge_2d_offsets()).unwrap());
+        let (empty, pipe) = (Socket::Symmetric(0), Socket::Symmetric(1));
+        rules.add_tile(&[empty; 4], 4).unwrap();
+        rules
+            .add_tile_rotations(&[pipe, empty, empty, pipe], 4)
+            .unwrap();
+        rules
+            .add_tile_rotations(&[pipe, pipe, empty, pipe], 2)
+            .unwrap();
+        rules.add_tile(&[pipe; 4], 1).unwrap();
+
+        rules.build().unwrap()
+    }
+
+    fn finished_generator(
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+        output_size: lat::Point,
+    ) -> Generator {
+        (0..32)
+            .map(|seed| Generator::new([seed; 16], output_size, sampler, constraints))
+            .find_map(
+                |mut generator| match generator.run_to_completion(sampler, constraints) {
+                    UpdateResult::Success => Some(generator),
+                    _ => None,
+                },
+            )
+            .expect("No seed succeeded")
+    }
+
+    #[test]
+    fn compact_then_reopen_restores_fresh_supports() {
+        let (sampler, constraints) = pipe_rules();
+        let output_size: lat::Point = [12, 12, 1].into();
+        let mut generator = finished_generator(&sampler, &constraints, output_size);
+
+        assert!(generator.compact(&constraints) > 0);
+        // Compacting again doesn't free anything new.
+        assert_eq!(generator.compact(&constraints), 0);
+
+        let output = lat::Extent::from_min_and_world_supremum([0, 0, 0].into(), output_size);
+        let reopened = generator.reopen_region(&sampler, &constraints, &output);
+        assert_eq!(reopened, UpdateResult::Continue);
+
+        let fresh = Generator::new([0; 16], output_size, &sampler, &constraints);
+        let (_, fresh_wave) = fresh.parts();
+        let (_, wave) = generator.parts();
+        assert_eq!(wave.num_collapsed(), 0);
+        assert_eq!(
+            wave.pattern_supports.to_vec(),
+            fresh_wave.pattern_supports.to_vec()
+        );
+    }
+
+    #[test]
+    fn compact_then_partial_reopen_matches_uncompacted() {
+        let (sampler, constraints) = pipe_rules();
+        let output_size: lat::Point = [12, 12, 1].into();
+        let mut compacted = finished_generator(&sampler, &constraints, output_size);
+        let mut uncompacted = finished_generator(&sampler, &constraints, output_size);
+        assert!(compacted.compact(&constraints) > 0);
+
+        let region = lat::Extent::from_min_and_local_supremum([4, 4, 0].into(), [3, 3, 1].into());
+        compacted.reopen_region(&sampler, &constraints, &region);
+        uncompacted.reopen_region(&sampler, &constraints, &region);
+
+        // Slots that are still dropped read as all zeros, so only the others can be compared.
+        let (_, wave) = compacted.parts();
+        let (_, expected) = uncompacted.parts();
+        let slot_len =
+            (constraints.num_patterns() as usize) * constraints.get_offset_group().num_offsets();
+        let counts = wave.pattern_supports.to_vec();
+        let expected_counts = expected.pattern_supports.to_vec();
+        let mut num_compared = 0;
+        for (slot_counts, expected_slot_counts) in counts
+            .chunks(slot_len)
+            .zip(expected_counts.chunks(slot_len))
+        {
+            if slot_counts.iter().all(|count| *count == 0) {
+                continue;
+            }
+            assert_eq!(slot_counts, expected_slot_counts);
+            num_compared += 1;
+        }
+        // At least the reopened slots and their neighbors have counts again.
+        assert!(num_compared >= region.volume());
+    }
+
+    #[test]
+    fn log2_fixed_known_values() {
+        let one = 1 << LOG_FRACTION_BITS;
+        let expected: [(u128, u128); 10] = [
+            (1, 0),
+            (2, one),
+            (3, 103_872),
+            (5, 152_169),
+            (10, 217_705),
+            (255, 523_917),
+            (256, 8 * one),
+            (1000, 653_117),
+            (12_345, 890_741),
+            (1 << 100, 100 * one),
+        ];
+        for &(x, log) in expected.iter() {
+            assert_eq!(log2_fixed(x), log, "log2_fixed({})", x);
+        }
+    }
+
+    fn fixed_point_config() -> GeneratorConfig {
+        GeneratorConfig {
+            entropy: EntropyMode::FixedPoint,
+            ..GeneratorConfig::default()
+        }
+    }
+
+    /// A copy of `generator` with the fixed-point entropies of every uncollapsed slot recomputed
+    /// from its possible patterns, instead of kept up to date one removal at a time.
+    fn recompute_fixed_entropies(
+        generator: &Generator,
+        sampler: &PatternSampler,
+        constraints: &PatternConstraints,
+    ) -> Generator {
+        let (rng, wave) = generator.parts();
+        let mut snapshot = wave.to_snapshot();
+        for slot_index in 0..wave.num_slots() {
+            let possible = wave.slots.get_linear_ref(slot_index);
+            if possible.len() == 1 {
+                continue;
+            }
+            let slot = wave.slots.local_point_from_index(slot_index);
+            let (sums, entropy) = fixed_slot_entropy(possible, |p| wave.weight(sampler, &slot, p));
+            snapshot.fixed_entropies[slot_index] = [sums.sum_weights, sums.sum_weights_log_weights];
+            snapshot.entropies[slot_index][2] = entropy;
+        }
+        let wave = Wave::from_snapshot(snapshot, constraints, &fixed_point_config()).unwrap();
+
+        Generator::from_parts(rng.clone(), wave)
+    }
+
+    #[test]
+    fn fixed_point_entropy_survives_recomputation() {
+        let (sampler, constraints) = pipe_rules();
+        let output_size: lat::Point = [12, 12, 1].into();
+        let new_generator = || {
+            Generator::with_config(
+                [5; 16],
+                output_size,
+                &sampler,
+                &constraints,
+                &fixed_point_config(),
+            )
+            .unwrap()
+        };
+
+        let mut uninterrupted = new_generator();
+        let uninterrupted_result = uninterrupted.run_to_completion(&sampler, &constraints);
+
+        let mut generator = new_generator();
+        for _ in 0..30 {
+            assert_eq!(
+                generator.update(&sampler, &constraints),
+                UpdateResult::Continue
+            );
+        }
+        let (_, wave) = generator.parts();
+        let fixed = wave.fixed_entropy.as_ref().unwrap();
+        let mut recomputed = recompute_fixed_entropies(&generator, &sampler, &constraints);
+        let (_, recomputed_wave) = recomputed.parts();
+        let recomputed_fixed = recomputed_wave.fixed_entropy.as_ref().unwrap();
+        for slot in wave.slots.get_extent() {
+            let (sums, expected) = (
+                fixed.get_world_ref(&slot),
+                recomputed_fixed.get_world_ref(&slot),
+            );
+            assert_eq!(sums.sum_weights, expected.sum_weights);
+            assert_eq!(
+                sums.sum_weights_log_weights,
+                expected.sum_weights_log_weights
+            );
+            assert_eq!(
+                wave.entropy_cache.get_world_ref(&slot).entropy.to_bits(),
+                recomputed_wave
+                    .entropy_cache
+                    .get_world_ref(&slot)
+                    .entropy
+                    .to_bits()
+            );
+        }
+
+        // The same seed makes the same choices either way.
+        assert_eq!(
+            recomputed.run_to_completion(&sampler, &constraints),
+            uninterrupted_result
+        );
+        let (expected, result) = (uninterrupted.result(), recomputed.result());
+        for slot in expected.get_extent() {
+            assert_eq!(result.get_world(&slot), expected.get_world(&slot));
+        }
+    }
+}
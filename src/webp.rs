@@ -0,0 +1,373 @@
+//! A minimal animated WebP encoder. Frames are lossless (VP8L), compressed with Huffman coding
+//! only, since superposition frames are mostly smooth gradients with few exact repeats.
+
+use image::RgbaImage;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Seek, SeekFrom, Write};
+
+const VP8L_SIGNATURE: u8 = 0x2f;
+const MAX_VP8L_SIZE: u32 = 1 << 14;
+/// The green alphabet also has the length prefix codes, which are never used here.
+const GREEN_ALPHABET_SIZE: usize = 256 + 24;
+const DISTANCE_ALPHABET_SIZE: usize = 40;
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+const CODE_LENGTH_CODE_ORDER: [usize; 19] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// Writes an animated WebP one frame at a time. All frames must be the same size, at most 16384
+/// pixels per side. The RIFF header holds the file size, so `finish` seeks back to fill it in.
+pub struct WebpEncoder<W> {
+    writer: W,
+    delay_ms: u32,
+    size: Option<(u32, u32)>,
+    /// Where the RIFF header starts.
+    start_position: u64,
+}
+
+impl<W: Write + Seek> WebpEncoder<W> {
+    /// Each frame is shown for `delay_ms`, and the animation loops forever.
+    pub fn new(writer: W, delay_ms: u32) -> Self {
+        WebpEncoder {
+            writer,
+            delay_ms,
+            size: None,
+            start_position: 0,
+        }
+    }
+
+    pub fn encode_frame(&mut self, frame: &RgbaImage) -> io::Result<()> {
+        let (width, height) = frame.dimensions();
+        match self.size {
+            None => self.write_header(width, height)?,
+            Some(size) if size != (width, height) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Frame is {}x{} but the animation is {}x{}",
+                        width, height, size.0, size.1
+                    ),
+                ))
+            }
+            Some(_) => (),
+        }
+
+        let vp8l = encode_vp8l(frame);
+        let mut anmf = Vec::with_capacity(24 + vp8l.len() + 1);
+        anmf.extend_from_slice(&[0; 6]); // Offset.
+        anmf.extend_from_slice(&u24(width - 1));
+        anmf.extend_from_slice(&u24(height - 1));
+        anmf.extend_from_slice(&u24(self.delay_ms));
+        // Replace the previous frame instead of blending with it, and don't dispose.
+        anmf.push(0b10);
+        write_chunk(&mut anmf, b"VP8L", &vp8l)?;
+
+        write_chunk(&mut self.writer, b"ANMF", &anmf)
+    }
+
+    /// Writes the final file size. Nothing is written if there were no frames.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.size.is_none() {
+            return Ok(self.writer);
+        }
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.start_position + 4))?;
+        self.writer
+            .write_all(&((end - self.start_position - 8) as u32).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self, width: u32, height: u32) -> io::Result<()> {
+        if width > MAX_VP8L_SIZE || height > MAX_VP8L_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "WebP frames can't be larger than {} per side, not {}x{}",
+                    MAX_VP8L_SIZE, width, height
+                ),
+            ));
+        }
+
+        self.start_position = self.writer.stream_position()?;
+        self.writer.write_all(b"RIFF")?;
+        // The file size, filled in by `finish`.
+        self.writer.write_all(&[0; 4])?;
+        self.writer.write_all(b"WEBP")?;
+
+        let mut vp8x = Vec::with_capacity(10);
+        // Has alpha and animation.
+        vp8x.extend_from_slice(&[0x10 | 0x02, 0, 0, 0]);
+        vp8x.extend_from_slice(&u24(width - 1));
+        vp8x.extend_from_slice(&u24(height - 1));
+        write_chunk(&mut self.writer, b"VP8X", &vp8x)?;
+        // Transparent background, looping forever.
+        write_chunk(&mut self.writer, b"ANIM", &[0; 6])?;
+        self.size = Some((width, height));
+
+        Ok(())
+    }
+}
+
+/// Encodes `image` as a VP8L bitstream with no transforms, no color cache, and no backward
+/// references, so each pixel is just the Huffman codes of its 4 channels.
+fn encode_vp8l(image: &RgbaImage) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let mut bits = BitWriter::default();
+    bits.bytes.push(VP8L_SIGNATURE);
+    bits.write(width - 1, 14);
+    bits.write(height - 1, 14);
+    // Alpha is used, version 0.
+    bits.write(1, 1);
+    bits.write(0, 3);
+    // No transforms, color cache, or meta prefix codes.
+    bits.write(0, 1);
+    bits.write(0, 1);
+    bits.write(0, 1);
+
+    let mut green = vec![0; GREEN_ALPHABET_SIZE];
+    let mut red = vec![0; 256];
+    let mut blue = vec![0; 256];
+    let mut alpha = vec![0; 256];
+    for pixel in image.pixels() {
+        let [r, g, b, a] = pixel.0;
+        green[g as usize] += 1;
+        red[r as usize] += 1;
+        blue[b as usize] += 1;
+        alpha[a as usize] += 1;
+    }
+    let green = write_prefix_code(&mut bits, &green);
+    let red = write_prefix_code(&mut bits, &red);
+    let blue = write_prefix_code(&mut bits, &blue);
+    let alpha = write_prefix_code(&mut bits, &alpha);
+    write_prefix_code(&mut bits, &[0; DISTANCE_ALPHABET_SIZE]);
+
+    for pixel in image.pixels() {
+        let [r, g, b, a] = pixel.0;
+        green.write_symbol(&mut bits, g as usize);
+        red.write_symbol(&mut bits, r as usize);
+        blue.write_symbol(&mut bits, b as usize);
+        alpha.write_symbol(&mut bits, a as usize);
+    }
+
+    bits.finish()
+}
+
+/// The canonical Huffman code of each symbol in an alphabet.
+struct PrefixCode {
+    lengths: Vec<u8>,
+    /// Bit-reversed, since codes are read starting from their most significant bit, but the
+    /// bitstream is filled from the least significant bit.
+    codes: Vec<u32>,
+    /// A code with a single symbol is decoded without reading any bits.
+    single_symbol: bool,
+}
+
+impl PrefixCode {
+    fn from_lengths(lengths: Vec<u8>) -> Self {
+        let max_length = lengths.iter().cloned().max().unwrap_or(0) as usize;
+        let mut length_counts = vec![0u32; max_length + 1];
+        for length in lengths.iter().filter(|l| **l > 0) {
+            length_counts[*length as usize] += 1;
+        }
+        let mut next_code = vec![0u32; max_length + 1];
+        let mut code = 0;
+        for length in 1..=max_length {
+            code = (code + length_counts[length - 1]) << 1;
+            next_code[length] = code;
+        }
+
+        let codes = lengths
+            .iter()
+            .map(|length| {
+                if *length == 0 {
+                    return 0;
+                }
+                let code = next_code[*length as usize];
+                next_code[*length as usize] += 1;
+
+                code.reverse_bits() >> (32 - *length as u32)
+            })
+            .collect();
+
+        PrefixCode {
+            single_symbol: lengths.iter().filter(|l| **l > 0).count() == 1,
+            lengths,
+            codes,
+        }
+    }
+
+    fn write_symbol(&self, bits: &mut BitWriter, symbol: usize) {
+        if !self.single_symbol {
+            bits.write(self.codes[symbol], self.lengths[symbol] as u32);
+        }
+    }
+}
+
+/// Writes a prefix code fit to `histogram` and returns it.
+fn write_prefix_code(bits: &mut BitWriter, histogram: &[u32]) -> PrefixCode {
+    let used: Vec<usize> = (0..histogram.len()).filter(|s| histogram[*s] > 0).collect();
+
+    // A "simple" code, for at most 2 symbols below 256. A single symbol takes no bits at all.
+    if used.len() <= 2 && used.iter().all(|s| *s < 256) {
+        bits.write(1, 1);
+        let mut lengths = vec![0; histogram.len()];
+        match used.as_slice() {
+            [] => {
+                bits.write(0, 1);
+                write_simple_symbol(bits, 0);
+            }
+            [symbol] => {
+                bits.write(0, 1);
+                write_simple_symbol(bits, *symbol);
+            }
+            [first, second] => {
+                bits.write(1, 1);
+                write_simple_symbol(bits, *first);
+                bits.write(*second as u32, 8);
+                lengths[*first] = 1;
+                lengths[*second] = 1;
+            }
+            _ => unreachable!(),
+        }
+
+        return PrefixCode::from_lengths(lengths);
+    }
+
+    // A "normal" code, whose code lengths are themselves Huffman coded.
+    bits.write(0, 1);
+    let lengths = huffman_code_lengths(histogram, MAX_CODE_LENGTH);
+    let mut length_histogram = [0; 19];
+    for length in lengths.iter() {
+        length_histogram[*length as usize] += 1;
+    }
+    let length_code = PrefixCode::from_lengths(huffman_code_lengths(
+        &length_histogram,
+        MAX_CODE_LENGTH_CODE_LENGTH,
+    ));
+    let num_length_codes = CODE_LENGTH_CODE_ORDER
+        .iter()
+        .rposition(|s| length_code.lengths[*s] > 0)
+        .map_or(0, |i| i + 1)
+        .max(4);
+    bits.write(num_length_codes as u32 - 4, 4);
+    for symbol in CODE_LENGTH_CODE_ORDER[..num_length_codes].iter() {
+        bits.write(length_code.lengths[*symbol] as u32, 3);
+    }
+    // Every symbol's length is written.
+    bits.write(0, 1);
+    for length in lengths.iter() {
+        length_code.write_symbol(bits, *length as usize);
+    }
+
+    PrefixCode::from_lengths(lengths)
+}
+
+fn write_simple_symbol(bits: &mut BitWriter, symbol: usize) {
+    if symbol < 2 {
+        bits.write(0, 1);
+        bits.write(symbol as u32, 1);
+    } else {
+        bits.write(1, 1);
+        bits.write(symbol as u32, 8);
+    }
+}
+
+/// Huffman code lengths for the symbols of `histogram`, none longer than `max_length`. A lone
+/// symbol gets length 1. If the optimal code is too long, the smallest counts are raised until it
+/// fits.
+fn huffman_code_lengths(histogram: &[u32], max_length: u8) -> Vec<u8> {
+    let mut lengths = vec![0; histogram.len()];
+    let used: Vec<usize> = (0..histogram.len()).filter(|s| histogram[*s] > 0).collect();
+    if used.len() == 1 {
+        lengths[used[0]] = 1;
+    }
+    if used.len() <= 1 {
+        return lengths;
+    }
+
+    let mut min_count = 1;
+    loop {
+        // Leaves are nodes 0..used.len(), and each merge adds a node.
+        let mut parents = vec![0; 2 * used.len() - 1];
+        let mut heap: BinaryHeap<_> = used
+            .iter()
+            .enumerate()
+            .map(|(node, s)| Reverse((histogram[*s].max(min_count) as u64, node)))
+            .collect();
+        let mut next_node = used.len();
+        while heap.len() > 1 {
+            let Reverse((count_a, a)) = heap.pop().unwrap();
+            let Reverse((count_b, b)) = heap.pop().unwrap();
+            parents[a] = next_node;
+            parents[b] = next_node;
+            heap.push(Reverse((count_a + count_b, next_node)));
+            next_node += 1;
+        }
+
+        // Parents come after their children, so depths can be filled in from the root down.
+        let root = next_node - 1;
+        let mut depths = vec![0u8; next_node];
+        for node in (0..root).rev() {
+            depths[node] = depths[parents[node]] + 1;
+        }
+        if depths[..used.len()].iter().all(|d| *d <= max_length) {
+            for (node, s) in used.iter().enumerate() {
+                lengths[*s] = depths[node];
+            }
+
+            return lengths;
+        }
+        min_count *= 2;
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    num_bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `num_bits` of `value`, least significant bit first.
+    fn write(&mut self, value: u32, num_bits: u32) {
+        self.buffer |= (value as u64) << self.num_bits;
+        self.num_bits += num_bits;
+        while self.num_bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.num_bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+
+        self.bytes
+    }
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let bytes = value.to_le_bytes();
+
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+/// RIFF chunks are padded to an even size.
+fn write_chunk<W: Write>(writer: &mut W, fourcc: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(fourcc)?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)?;
+    if data.len() % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+
+    Ok(())
+}